@@ -0,0 +1,89 @@
+//! Overhead of the `Abort` wrapper per poll. The `Counting` policy must
+//! add less than `BUDGET` per poll to the bare future, which
+//! `check_budget` asserts on the medians criterion measured.
+//!
+//! Only `is_pending()` or `is_ready()` of a poll is black-boxed.
+//! Black-boxing the whole `Poll<Result<_, Aborted>>` would measure copying
+//! the error type, which a caller matching on the result never does.
+
+use std::fs;
+use std::future::{poll_fn, Future};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+use std::time::SystemTime;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use futures_core::Stream;
+use futures_test_abort::{abort, abort_with_policy, count_polls, stream, Counting};
+
+fn pending() -> impl Future<Output = ()> {
+    poll_fn(|_| Poll::Pending)
+}
+
+fn bench_poll<F: Future>(c: &mut Criterion, name: &str, future: F) {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    c.bench_function(name, |b| b.iter(|| black_box(future.as_mut().poll(&mut cx).is_pending())));
+}
+
+/// Maximum time the `Counting` policy adds to a poll, in nanoseconds.
+const BUDGET: f64 = 2.0;
+
+/// Median time per iteration of a bench of this run in nanoseconds, read
+/// from the estimates criterion saved. `None` if the bench did not run,
+/// e.g. because of a filter.
+fn median(name: &str, since: SystemTime) -> Option<f64> {
+    let target = std::env::var_os("CARGO_TARGET_DIR").map_or_else(|| PathBuf::from("target"), PathBuf::from);
+    let path = target.join("criterion").join(name.replace('/', "_")).join("new/estimates.json");
+    if fs::metadata(&path).and_then(|meta| meta.modified()).ok()? < since {
+        return None;
+    }
+    let estimates: serde_json::Value = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    estimates["median"]["point_estimate"].as_f64()
+}
+
+/// Fail the bench if the `Counting` policy exceeds `BUDGET`.
+fn check_budget(since: SystemTime) {
+    let (Some(bare), Some(counting)) = (median("poll/bare", since), median("poll/abort_counting", since)) else {
+        return;
+    };
+    let overhead = counting - bare;
+    println!("poll/abort_counting adds {:.2} ns per poll (budget {:.2} ns)", overhead, BUDGET);
+    assert!(overhead < BUDGET, "poll/abort_counting adds {:.2} ns per poll, the budget is {:.2} ns", overhead, BUDGET);
+}
+
+fn overhead(c: &mut Criterion) {
+    let since = SystemTime::now();
+    bench_poll(c, "poll/bare", pending());
+    bench_poll(c, "poll/count_polls", count_polls(pending()));
+    bench_poll(c, "poll/abort_counting", abort_with_policy::<_, Counting>(pending(), usize::MAX));
+    bench_poll(c, "poll/abort", abort(pending(), usize::MAX));
+    check_budget(since);
+}
+
+/// Stream which never ends.
+struct Forever;
+
+impl Stream for Forever {
+    type Item = ();
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<()>> {
+        Poll::Ready(Some(()))
+    }
+}
+
+fn bench_poll_next<S: Stream>(c: &mut Criterion, name: &str, stream: S) {
+    let mut stream = pin!(stream);
+    let mut cx = Context::from_waker(Waker::noop());
+    c.bench_function(name, |b| b.iter(|| black_box(stream.as_mut().poll_next(&mut cx).is_ready())));
+}
+
+fn stream_overhead(c: &mut Criterion) {
+    bench_poll_next(c, "poll_next/bare", Forever);
+    bench_poll_next(c, "poll_next/abort", stream::abort(Forever, usize::MAX));
+    bench_poll_next(c, "poll_next/abort_batched", stream::abort_batched(Forever, usize::MAX, 64));
+}
+
+criterion_group!(benches, overhead, stream_overhead);
+criterion_main!(benches);
@@ -0,0 +1,21 @@
+//! Throughput of a `Sweep` with many abort points.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_test_abort::{after, Sweep};
+
+fn sweep(c: &mut Criterion) {
+    let mut runtime = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+    let make = |_: &()| after((), 200);
+    c.bench_function("sweep/200", |b| {
+        b.iter(|| runtime.block_on(Sweep::new().max_polls(1000).report(|| (), make, |_| {})))
+    });
+    c.bench_function("sweep/200_capacity", |b| {
+        b.iter(|| {
+            let sweep = Sweep::new().max_polls(1000).points_capacity(201).trace_capacity(512);
+            runtime.block_on(sweep.report(|| (), make, |_| {}))
+        })
+    });
+}
+
+criterion_group!(benches, sweep);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+#!/bin/sh
+#
+# Abort self-referential futures, coroutines and I/O wrappers at every
+# point under Miri and AddressSanitizer. This checks the unsafe pinning
+# code of the wrappers against the aliasing model and for use after free.
+# Requires a nightly toolchain with the `miri` component.
+
+set -eu
+
+FEATURES=coroutine,tokio-io
+TESTS="self_referential abort_read_write"
+
+cargo +nightly miri test --lib --features "$FEATURES" -- $TESTS
+
+TARGET=$(rustc +nightly -vV | sed -n 's/^host: //p')
+RUSTFLAGS=-Zsanitizer=address cargo +nightly test --lib --target "$TARGET" --target-dir target/asan \
+    --features "$FEATURES" -- $TESTS
@@ -0,0 +1,117 @@
+//! Procedural macros of `futures-test-abort`. Use them via the `macros`
+//! feature of that crate instead of depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Error, ItemFn, LitInt, Path};
+
+/// Generate one test per abort point of an `async fn` without arguments.
+///
+/// `#[abort_test(max_polls = 10)]` keeps the function and adds a module of
+/// the same name with the tests `abort_at_poll_0` to `abort_at_poll_9`,
+/// which abort the future after the given number of polls, and the test
+/// `completes`, which runs it to completion. The failing abort point is
+/// therefore part of the name of the failing test.
+///
+/// Without further arguments a test only fails if the future panics. Pass
+/// `setup = path` to create a state for every test, which the function
+/// then takes by reference, and `check = path` to check it after every
+/// abort point like the check of a `Sweep`. Without `setup` the check
+/// takes no arguments and can only check global invariants. The paths are
+/// resolved in the module of the function, e.g.
+/// `#[abort_test(max_polls = 3, setup = Cell::default, check = at_most_once)]`
+/// on `async fn increment(counter: &Cell<u32>)`.
+#[proc_macro_attribute]
+pub fn abort_test(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut parsed = Args::default();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("max_polls") {
+            let value: LitInt = meta.value()?.parse()?;
+            parsed.max_polls = Some(value.base10_parse::<usize>()?);
+            Ok(())
+        } else if meta.path.is_ident("setup") {
+            parsed.setup = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("check") {
+            parsed.check = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported argument, expected `max_polls`, `setup` or `check`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let function = parse_macro_input!(item as ItemFn);
+    match expand(parsed, function) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Arguments of `abort_test`.
+#[derive(Default)]
+struct Args {
+    max_polls: Option<usize>,
+    setup: Option<Path>,
+    check: Option<Path>,
+}
+
+fn expand(args: Args, function: ItemFn) -> Result<proc_macro2::TokenStream, Error> {
+    let max_polls = args
+        .max_polls
+        .ok_or_else(|| Error::new(Span::call_site(), "expected `max_polls = <n>`"))?;
+    let signature = &function.sig;
+    if signature.asyncness.is_none() {
+        return Err(Error::new_spanned(signature.fn_token, "`abort_test` requires an `async fn`"));
+    }
+    let num_inputs = if args.setup.is_some() { 1 } else { 0 };
+    if signature.inputs.len() != num_inputs || !signature.generics.params.is_empty() {
+        let message = if args.setup.is_some() {
+            "`abort_test` with `setup` requires a function with one argument and no generics"
+        } else {
+            "`abort_test` requires a function without arguments and generics"
+        };
+        return Err(Error::new_spanned(&signature.inputs, message));
+    }
+    let name = &signature.ident;
+    // The tests live in a module named after the function, so the paths
+    // given as arguments are resolved via a glob import of its parent.
+    let (imports, setup, make, check) = match (&args.setup, &args.check) {
+        (None, None) => (quote!(), quote!(|| ()), quote!(|_: &()| super::#name()), quote!(|_: &()| ())),
+        (None, Some(check)) => (
+            quote!(use super::*;),
+            quote!(|| ()),
+            quote!(|_: &()| super::#name()),
+            quote!(|_: &()| #check()),
+        ),
+        (Some(setup), check) => {
+            let check = check.as_ref().map_or_else(|| quote!(|_: &_| ()), |check| quote!(#check));
+            (quote!(use super::*;), quote!(#setup), quote!(super::#name), check)
+        }
+    };
+    let points = (0..max_polls).map(|point| {
+        let test = format_ident!("abort_at_poll_{}", point);
+        quote! {
+            #[test]
+            fn #test() {
+                ::futures_test_abort::__abort_test(
+                    module_path!(), #setup, #make, #check, #max_polls, Some(#point),
+                );
+            }
+        }
+    });
+    Ok(quote! {
+        #function
+
+        mod #name {
+            #imports
+
+            #(#points)*
+
+            #[test]
+            fn completes() {
+                ::futures_test_abort::__abort_test(module_path!(), #setup, #make, #check, #max_polls, None);
+            }
+        }
+    })
+}
@@ -0,0 +1,244 @@
+//! Abort testing of actor style message handlers.
+//!
+//! Actor frameworks run one handler future per message. A supervisor may
+//! cancel the running handler at any await point and continue with the
+//! next message, so the state of the actor must be valid between any two
+//! messages even if a handler was cancelled half way. A `Mailbox` delivers
+//! its messages to a fresh actor for every abort point, cancels the
+//! handler which is running when the poll budget is used up and checks
+//! the invariants of the actor after every message.
+//!
+//! ```rust
+//! use std::cell::Cell;
+//!
+//! use futures_test_abort::actor::Mailbox;
+//! use futures_test_abort::after;
+//!
+//! #[derive(Default)]
+//! struct Accounts {
+//!     a: Cell<i32>,
+//!     b: Cell<i32>,
+//! }
+//!
+//! async fn transfer(accounts: &Accounts, amount: i32) {
+//!     after((), 1).await;
+//!     accounts.a.set(accounts.a.get() - amount);
+//!     accounts.b.set(accounts.b.get() + amount);
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! Mailbox::new(vec![10, 20])
+//!     .run(Accounts::default, transfer, |accounts| {
+//!         assert_eq!(accounts.a.get() + accounts.b.get(), 0)
+//!     })
+//!     .await;
+//! # }
+//! ```
+
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::Poll;
+
+use crate::future::AbortReason;
+use crate::harness::{Clock, Profile, SystemClock};
+use crate::invariant::{self, CheckResult};
+use crate::registry;
+use crate::report::{PointReport, Report};
+
+/// Factory for the handler futures of an actor.
+///
+/// This trait is implemented for all functions and closures taking a `&A`
+/// and a message and returning a future, e.g. an
+/// `async fn handle(actor: &Actor, message: Message)`.
+pub trait Handler<'a, A, M> {
+    /// The future handling a message.
+    type Future: Future + 'a;
+    /// Create the future handling the given message.
+    fn handle(&mut self, actor: &'a A, message: M) -> Self::Future;
+}
+
+impl<'a, A, M, F, Fut> Handler<'a, A, M> for F
+where
+    A: 'a,
+    F: FnMut(&'a A, M) -> Fut,
+    Fut: Future + 'a,
+{
+    type Future = Fut;
+
+    fn handle(&mut self, actor: &'a A, message: M) -> Self::Future {
+        self(actor, message)
+    }
+}
+
+/// Messages delivered to an actor.
+///
+/// Abort point `k` cancels the handler which is running when the handlers
+/// have been polled `k` times in total. All following messages are handled
+/// normally. The sweep ends once all messages were handled without
+/// cancellation.
+#[derive(Clone, Debug)]
+pub struct Mailbox<M> {
+    name: Option<String>,
+    messages: Vec<M>,
+    max_polls: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl<M> Mailbox<M>
+where
+    M: Clone,
+{
+    /// Create a mailbox containing the given messages. The maximum number
+    /// of polls is taken from the current profile like for `Sweep::new`.
+    pub fn new(messages: impl IntoIterator<Item = M>) -> Self {
+        Self {
+            name: None,
+            messages: messages.into_iter().collect(),
+            max_polls: Profile::from_env().unwrap_or_default().settings().max_polls,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Set the name of the scenario. The name is included in the report.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the maximum number of polls all handlers together are allowed
+    /// to take.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Set the clock used to measure the time of every abort point.
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Deliver the messages for every abort point and return the report.
+    /// Panics if the invariant failed for any abort point or if the
+    /// handlers did not complete within `max_polls`.
+    pub async fn run<A, Setup, H, Check, R>(&self, setup: Setup, handler: H, check: Check) -> Report
+    where
+        Setup: FnMut() -> A,
+        H: for<'a> Handler<'a, A, M>,
+        Check: FnMut(&A) -> R,
+        R: CheckResult,
+    {
+        let report = self.report(setup, handler, check).await;
+        report.assert_safe();
+        report
+    }
+
+    /// Deliver the messages for every abort point and return the report.
+    /// Unlike `run` this method does not panic but records failed
+    /// invariants in the report.
+    pub async fn report<A, Setup, H, Check, R>(
+        &self,
+        mut setup: Setup,
+        mut handler: H,
+        mut check: Check,
+    ) -> Report
+    where
+        Setup: FnMut() -> A,
+        H: for<'a> Handler<'a, A, M>,
+        Check: FnMut(&A) -> R,
+        R: CheckResult,
+    {
+        let mut report = Report {
+            name: self.name.clone(),
+            max_polls: self.max_polls,
+            ..Report::default()
+        };
+        for max_polls in 0..=self.max_polls {
+            let start = self.clock.now();
+            let actor = setup();
+            let mut num_polls = 0;
+            let mut cancelled = None;
+            let mut failure = None;
+            let mut invariant_errors = Vec::new();
+            for (index, message) in self.messages.iter().cloned().enumerate() {
+                {
+                    let mut future = pin!(handler.handle(&actor, message));
+                    poll_fn(|cx| {
+                        if cancelled.is_none() && num_polls == max_polls {
+                            cancelled = Some(index);
+                            return Poll::Ready(());
+                        }
+                        num_polls += 1;
+                        future.as_mut().poll(cx).map(drop)
+                    })
+                    .await;
+                }
+                let (message_failure, errors) = invariant::evaluate(&mut check, &actor);
+                invariant_errors.extend(errors);
+                if let Some(message_failure) = message_failure {
+                    let state = if cancelled == Some(index) { "cancelled" } else { "handled" };
+                    failure = Some(format!("after message {} was {}: {}", index, state, message_failure));
+                    break;
+                }
+            }
+            report.points.push(PointReport {
+                max_polls,
+                completed: cancelled.is_none(),
+                never_polled: cancelled == Some(0) && num_polls == 0,
+                failure,
+                elapsed: self.clock.now().saturating_duration_since(start),
+                invariant_errors,
+                reason: cancelled.is_some().then(|| AbortReason::Dropped.to_string()),
+                ..PointReport::default()
+            });
+            if cancelled.is_none() {
+                report.num_polls = Some(num_polls);
+                break;
+            }
+        }
+        registry::record(&report);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use super::Mailbox;
+    use crate::{after, ManualClock};
+
+    #[derive(Default)]
+    struct Accounts {
+        a: Cell<i32>,
+        b: Cell<i32>,
+    }
+
+    async fn transfer(accounts: &Accounts, amount: i32) {
+        accounts.a.set(accounts.a.get() - amount);
+        after((), 1).await;
+        accounts.b.set(accounts.b.get() + amount);
+    }
+
+    #[tokio::test]
+    async fn actor_mailbox() {
+        let report = Mailbox::new(vec![10, 20])
+            .report(Accounts::default, transfer, |accounts| {
+                assert!(accounts.a.get() + accounts.b.get() == 0, "money lost")
+            })
+            .await;
+        let failures: Vec<&str> = report.points.iter().filter_map(|point| point.failure.as_deref()).collect();
+        assert_eq!(failures, ["after message 0 was cancelled: money lost", "after message 1 was cancelled: money lost"]);
+        assert_eq!(report.num_polls, Some(4));
+        let clock = ManualClock::new();
+        let report = Mailbox::new(vec![10])
+            .clock(clock.clone())
+            .report(Accounts::default, transfer, |_| clock.advance(Duration::from_secs(1)))
+            .await;
+        assert!(report.points.iter().all(|point| point.elapsed == Duration::from_secs(1)));
+    }
+}
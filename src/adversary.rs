@@ -0,0 +1,347 @@
+//! Executor which behaves as badly as an executor legally may.
+//!
+//! Futures must not rely on the details of the executor polling them, but
+//! most executors are well-behaved enough to hide such assumptions. The
+//! `Adversary` runs tasks on a single thread like `executor::Executor`
+//! and can be configured to
+//!
+//! - pass a fresh waker on every poll, so `Waker::will_wake` never holds,
+//! - deliver wakes only some ticks after they happened,
+//! - poll tasks which were not woken,
+//! - poll tasks on another thread every other time and
+//! - abort tasks after a given or random number of polls.
+//!
+//! All decisions are drawn from the seed, so a failure can be reproduced.
+//!
+//! ```rust
+//! use futures_test_abort::{adversary::Adversary, after};
+//!
+//! let mut adversary = Adversary::new(7).fresh_wakers().delay_wakes(3).spurious_polls(50).migrate();
+//! let task = adversary.spawn(after((), 5));
+//! let execution = adversary.run();
+//! assert_eq!(execution.completed, [task]);
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Wake, Waker};
+use std::thread;
+
+use crate::executor::Execution;
+use crate::rng::{RandomSource, RandomSources};
+
+/// Wakes of a task. Every waker handed out for the task counts here.
+#[derive(Default)]
+struct Wakes(AtomicUsize);
+
+/// Waker of a single poll if fresh wakers are requested.
+struct FreshWaker(Arc<Wakes>);
+
+impl Wake for Wakes {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Wake for FreshWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.wake_by_ref();
+    }
+}
+
+struct Task<'a> {
+    future: Pin<Box<dyn Future<Output = ()> + Send + 'a>>,
+    wakes: Arc<Wakes>,
+    /// Wakes which were taken into account already.
+    seen_wakes: usize,
+    /// Ticks at which delayed wakes are delivered.
+    deliveries: Vec<u64>,
+    ready: bool,
+    num_polls: usize,
+    abort_after: Option<usize>,
+}
+
+/// Single-threaded executor doing nasty but legal things. See the module
+/// documentation.
+pub struct Adversary<'a> {
+    seed: u64,
+    random_sources: Option<RandomSources>,
+    max_polls: usize,
+    fresh_wakers: bool,
+    wake_delay: u64,
+    spurious_percent: u8,
+    migrate: bool,
+    tasks: Vec<Option<Task<'a>>>,
+}
+
+impl<'a> Adversary<'a> {
+    /// Create a well-behaved executor drawing its decisions from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            random_sources: None,
+            max_polls: 10_000,
+            fresh_wakers: false,
+            wake_delay: 0,
+            spurious_percent: 0,
+            migrate: false,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Set the maximum number of polls of all tasks together.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Draw all decisions from the given sources instead of the `Rng`
+    /// substreams of the seed.
+    pub fn random_sources(mut self, random_sources: RandomSources) -> Self {
+        self.random_sources = Some(random_sources);
+        self
+    }
+
+    /// Pass a new waker on every poll. Wakers of earlier polls still
+    /// wake the task.
+    pub fn fresh_wakers(mut self) -> Self {
+        self.fresh_wakers = true;
+        self
+    }
+
+    /// Deliver every wake up to `ticks` polls of any task after it
+    /// happened. The actual delay of every wake is drawn from the seed.
+    pub fn delay_wakes(mut self, ticks: u64) -> Self {
+        self.wake_delay = ticks;
+        self
+    }
+
+    /// Poll a task which was not woken instead of a woken one in
+    /// `percent` percent of the polls.
+    pub fn spurious_polls(mut self, percent: u8) -> Self {
+        self.spurious_percent = percent.min(100);
+        self
+    }
+
+    /// Poll the tasks on a different thread every other poll. This is
+    /// why tasks need to be `Send`.
+    pub fn migrate(mut self) -> Self {
+        self.migrate = true;
+        self
+    }
+
+    /// Add a task and return its id. Ids start at `0`.
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'a) -> usize {
+        self.tasks.push(Some(Task {
+            future: Box::pin(future),
+            wakes: Arc::default(),
+            seen_wakes: 0,
+            deliveries: Vec::new(),
+            ready: true,
+            num_polls: 0,
+            abort_after: None,
+        }));
+        self.tasks.len() - 1
+    }
+
+    /// Abort (drop) the task after it was polled the given number of
+    /// times.
+    pub fn abort_after(&mut self, task: usize, num_polls: usize) {
+        self.task(task).abort_after = Some(num_polls);
+    }
+
+    /// Abort (drop) the task after a number of polls in `0..=max_polls`
+    /// drawn from the seed and return that number.
+    pub fn abort_randomly(&mut self, task: usize, max_polls: usize) -> usize {
+        let mut rng = self.sources().substream(&format!("adversary-abort-{}", task));
+        let num_polls = rng.below(max_polls.saturating_add(1).max(1));
+        self.abort_after(task, num_polls);
+        num_polls
+    }
+
+    fn sources(&self) -> RandomSources {
+        self.random_sources.clone().unwrap_or_else(|| RandomSources::seeded(self.seed))
+    }
+
+    fn task(&mut self, task: usize) -> &mut Task<'a> {
+        self.tasks[task].as_mut().expect("task already finished")
+    }
+
+    /// Run the tasks until all of them completed, were aborted or are
+    /// stuck.
+    pub fn run(&mut self) -> Execution {
+        let mut execution = Execution::default();
+        let mut rng = self.sources().substream("adversary");
+        for (id, task) in self.tasks.iter_mut().enumerate() {
+            if task.as_ref().is_some_and(|task| task.abort_after == Some(0)) {
+                *task = None;
+                execution.aborted.push(id);
+            }
+        }
+        let mut tick = 0;
+        while execution.polled.len() < self.max_polls {
+            self.deliver(tick, &mut *rng);
+            let (ready, idle): (Vec<usize>, Vec<usize>) = (0..self.tasks.len())
+                .filter(|&id| self.tasks[id].is_some())
+                .partition(|&id| self.tasks[id].as_ref().is_some_and(|task| task.ready));
+            if ready.is_empty() {
+                // Skip the ticks in which nothing happens.
+                let next = self.tasks.iter().flatten().flat_map(|task| task.deliveries.iter()).min();
+                match next {
+                    Some(&next) => {
+                        tick = next;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            let spurious = !idle.is_empty() && rng.below(100) < usize::from(self.spurious_percent);
+            let id = match spurious {
+                true => idle[rng.below(idle.len())],
+                false => ready[rng.below(ready.len())],
+            };
+            execution.polled.push(id);
+            let migrate = self.migrate && execution.polled.len() % 2 == 0;
+            let fresh_wakers = self.fresh_wakers;
+            let task = self.tasks[id].as_mut().unwrap();
+            task.ready = false;
+            task.num_polls += 1;
+            let waker = match fresh_wakers {
+                true => Waker::from(Arc::new(FreshWaker(task.wakes.clone()))),
+                false => Waker::from(task.wakes.clone()),
+            };
+            let future = task.future.as_mut();
+            let result = match migrate {
+                true => thread::scope(|scope| {
+                    scope
+                        .spawn(|| future.poll(&mut Context::from_waker(&waker)))
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                }),
+                false => future.poll(&mut Context::from_waker(&waker)),
+            };
+            if result.is_ready() {
+                self.tasks[id] = None;
+                execution.completed.push(id);
+            } else if task.abort_after == Some(task.num_polls) {
+                self.tasks[id] = None;
+                execution.aborted.push(id);
+            }
+            tick += 1;
+        }
+        execution.stuck = (0..self.tasks.len()).filter(|&id| self.tasks[id].is_some()).collect();
+        execution
+    }
+
+    /// Schedule the delivery of new wakes and mark the tasks whose wakes
+    /// are due as ready.
+    fn deliver(&mut self, tick: u64, rng: &mut dyn RandomSource) {
+        for task in self.tasks.iter_mut().flatten() {
+            let wakes = task.wakes.0.load(Ordering::SeqCst);
+            for _ in task.seen_wakes..wakes {
+                let delay = match self.wake_delay {
+                    0 => 0,
+                    max => rng.next_u64() % (max + 1),
+                };
+                task.deliveries.push(tick + delay);
+            }
+            task.seen_wakes = wakes;
+            let due = task.deliveries.len();
+            task.deliveries.retain(|&at| at > tick);
+            task.ready |= task.deliveries.len() < due;
+        }
+    }
+}
+
+impl fmt::Debug for Adversary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Adversary")
+            .field("seed", &self.seed)
+            .field("max_polls", &self.max_polls)
+            .field("fresh_wakers", &self.fresh_wakers)
+            .field("wake_delay", &self.wake_delay)
+            .field("spurious_percent", &self.spurious_percent)
+            .field("migrate", &self.migrate)
+            .field("tasks", &self.tasks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::task::{Poll, Waker};
+
+    use super::Adversary;
+    use crate::after;
+
+    #[derive(Default)]
+    struct Mailslot {
+        value: std::sync::Mutex<Option<u32>>,
+        waker: std::sync::Mutex<Option<Waker>>,
+    }
+
+    /// Returns whatever is in the slot once it is polled after it
+    /// registered its waker, assuming that it was woken by the sender.
+    async fn naive_take(slot: &Mailslot) -> Option<u32> {
+        let mut registered = false;
+        poll_fn(|cx| {
+            if registered {
+                return Poll::Ready(slot.value.lock().unwrap().take());
+            }
+            registered = true;
+            *slot.waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn adversary_executor() {
+        let run = |adversary: Adversary<'_>| {
+            let (slot, received) = (Mailslot::default(), std::sync::Mutex::new(None));
+            let mut adversary = adversary;
+            adversary.spawn(async {
+                *received.lock().unwrap() = Some(naive_take(&slot).await);
+            });
+            adversary.spawn(async {
+                after((), 3).await;
+                *slot.value.lock().unwrap() = Some(7);
+                if let Some(waker) = slot.waker.lock().unwrap().take() {
+                    waker.wake();
+                }
+            });
+            let execution = adversary.run();
+            assert!(!execution.is_stuck());
+            let received = received.lock().unwrap().take();
+            received.unwrap()
+        };
+        assert_eq!(run(Adversary::new(1).fresh_wakers().delay_wakes(5).migrate()), Some(7));
+        assert_eq!(run(Adversary::new(1).spurious_polls(100)), None);
+
+        let threads = std::sync::Mutex::new(Vec::new());
+        let mut adversary = Adversary::new(3).migrate();
+        let task = adversary.spawn(async {
+            for _ in 0..4 {
+                threads.lock().unwrap().push(std::thread::current().id());
+                after((), 1).await;
+            }
+        });
+        let aborted_after = adversary.abort_randomly(task, 3);
+        let execution = adversary.run();
+        assert_eq!(execution.aborted, [task]);
+        assert_eq!(execution.polled.len(), aborted_after);
+        let mut replay = Adversary::new(3);
+        let task = replay.spawn(async {});
+        assert_eq!(replay.abort_randomly(task, 3), aborted_after);
+        let threads = threads.lock().unwrap().clone();
+        assert!(threads.len() < 2 || threads[0] != threads[1]);
+    }
+}
@@ -0,0 +1,230 @@
+//! Baseline of known unsafe abort points.
+//!
+//! Adopting the crate on existing code usually reveals more unsafe abort
+//! points than can be fixed at once. A baseline file records the unsafe
+//! abort points of every named scenario which are accepted for now. A
+//! `Sweep` with `Sweep::baseline` only fails for unsafe abort points
+//! which are not in the baseline and for accepted ones which became safe,
+//! so the baseline can only shrink over time.
+//!
+//! Run the tests with the `UPDATE_BASELINE` environment variable set to a
+//! value other than `0` to write the current unsafe abort points to the
+//! baseline instead of checking them.
+//!
+//! The file is JSON with a format version:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "scenarios": {
+//!     "checkout": {
+//!       "unsafe_points": [2, 3]
+//!     }
+//!   }
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::report::Report;
+
+/// Name of the environment variable which updates the baseline instead of
+/// checking it if it is set to a value other than `0`.
+pub const UPDATE_VAR: &str = "UPDATE_BASELINE";
+
+/// Version of the file format written by this release.
+pub const VERSION: u32 = 1;
+
+/// Serializes updates of baseline files by tests running in parallel.
+static UPDATE: Mutex<()> = Mutex::new(());
+
+/// Accepted unsafe abort points of all scenarios.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Baseline {
+    /// Version of the file format.
+    pub version: u32,
+    /// Accepted unsafe abort points by scenario name.
+    pub scenarios: BTreeMap<String, Scenario>,
+}
+
+/// Accepted unsafe abort points of a single scenario.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Scenario {
+    /// Number of polls after which the future is aborted, see
+    /// `PointReport::max_polls`.
+    pub unsafe_points: Vec<usize>,
+}
+
+impl Baseline {
+    /// Create an empty baseline.
+    pub fn new() -> Self {
+        Self {
+            version: VERSION,
+            scenarios: BTreeMap::new(),
+        }
+    }
+
+    /// Read a baseline file. A missing file is an empty baseline.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(format!("failed to read baseline {}: {}", path.display(), e)),
+        };
+        let baseline: Self = serde_json::from_str(&content)
+            .map_err(|e| format!("invalid baseline {}: {}", path.display(), e))?;
+        if baseline.version != VERSION {
+            return Err(format!(
+                "baseline {} has version {} but only version {} is supported",
+                path.display(),
+                baseline.version,
+                VERSION
+            ));
+        }
+        Ok(baseline)
+    }
+
+    /// Write the baseline to a file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let mut content = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        content.push('\n');
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        fs::write(path, content).map_err(|e| format!("failed to write baseline {}: {}", path.display(), e))
+    }
+
+    /// Check a report against the baseline. Fails for unsafe abort points
+    /// which are not accepted, for accepted abort points which are safe now
+    /// and if the future did not complete.
+    pub fn check(&self, report: &Report) -> Result<(), String> {
+        let name = scenario_name(report)?;
+        if report.num_polls.is_none() {
+            return Err(format!("{}: future did not complete within {} polls", name, report.max_polls));
+        }
+        let accepted = self.scenarios.get(name).map_or(&[][..], |scenario| &scenario.unsafe_points[..]);
+        let current = unsafe_points(report);
+        if let Some(point) = report
+            .points
+            .iter()
+            .find(|point| !point.is_safe() && !accepted.contains(&point.max_polls))
+        {
+            return Err(format!(
+                "{}: check failed at abort point {} which is not in the baseline: {}\nexplanation:\n{}",
+                name,
+                point.max_polls,
+                point.failure.as_deref().unwrap_or_default(),
+                point.explanation()
+            ));
+        }
+        let fixed: Vec<String> = accepted
+            .iter()
+            .filter(|point| !current.contains(point))
+            .map(ToString::to_string)
+            .collect();
+        if !fixed.is_empty() {
+            return Err(format!(
+                "{}: abort points {} are safe now, remove them from the baseline or run with {}=1",
+                name,
+                fixed.join(", "),
+                UPDATE_VAR
+            ));
+        }
+        Ok(())
+    }
+
+    /// Accept exactly the unsafe abort points of the report.
+    pub fn update(&mut self, report: &Report) -> Result<(), String> {
+        let name = scenario_name(report)?;
+        let unsafe_points = unsafe_points(report);
+        if unsafe_points.is_empty() {
+            self.scenarios.remove(name);
+        } else {
+            self.scenarios.insert(name.to_string(), Scenario { unsafe_points });
+        }
+        Ok(())
+    }
+}
+
+impl Default for Baseline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns `true` if the `UPDATE_BASELINE` environment variable is set.
+pub fn is_update() -> bool {
+    env::var(UPDATE_VAR).is_ok_and(|value| value != "0")
+}
+
+/// Check the report against the baseline file or update the file if
+/// `is_update`. Panics with a descriptive message on failure.
+pub fn assert(path: impl AsRef<Path>, report: &Report) {
+    let path = path.as_ref();
+    let result = if is_update() {
+        let _update = UPDATE.lock().unwrap_or_else(|e| e.into_inner());
+        Baseline::load(path).and_then(|mut baseline| {
+            baseline.update(report)?;
+            baseline.save(path)
+        })
+    } else {
+        Baseline::load(path).and_then(|baseline| baseline.check(report))
+    };
+    if let Err(msg) = result {
+        panic!("{}", msg);
+    }
+}
+
+fn scenario_name(report: &Report) -> Result<&str, String> {
+    report
+        .name
+        .as_deref()
+        .ok_or_else(|| "sweeps checked against a baseline need a name, see `Sweep::name`".to_string())
+}
+
+fn unsafe_points(report: &Report) -> Vec<usize> {
+    report
+        .points
+        .iter()
+        .filter(|point| !point.is_safe())
+        .map(|point| point.max_polls)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::Baseline;
+    use crate::Sweep;
+    use crate::test_util::{count_to_three, count_unsafe, Counter};
+
+    #[cfg_attr(miri, ignore = "accesses the file system")]
+    #[tokio::test]
+    async fn sweep_baseline() {
+        let path = "target/fta-baseline/fta-test-sweep-baseline.json";
+        let _ = std::fs::remove_file(path);
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let check = |counter: &Counter| assert_eq!(counter.count.get(), 0);
+        let report = Sweep::new().name("unsafe").report(setup, count_unsafe, check).await;
+        let mut baseline = Baseline::load(path).unwrap();
+        assert!(baseline.check(&report).unwrap_err().contains("abort point 1 which is not in the baseline"));
+        baseline.update(&report).unwrap();
+        assert_eq!(baseline.scenarios["unsafe"].unsafe_points, [1, 2]);
+        baseline.save(path).unwrap();
+        let baseline = Baseline::load(path).unwrap();
+        baseline.check(&report).unwrap();
+        Sweep::new().name("unsafe").baseline(path).run(setup, count_unsafe, check).await;
+        // fixed abort points must be removed from the baseline
+        let report = Sweep::new().name("unsafe").report(setup, count_to_three, |_| {}).await;
+        assert!(baseline.check(&report).unwrap_err().contains("abort points 1, 2 are safe now"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+//! On-disk cache of the poll counts and labels discovered by sweeps.
+//!
+//! Every named scenario of a `Sweep` with `Sweep::cache` stores the number
+//! of polls the future needed and the labels it reached in its own file.
+//! Entries are only used if the version string matches so a changed
+//! scenario can invalidate its entry. The cache is stored in the directory
+//! named by the `FTA_CACHE_DIR` environment variable or `target/fta-cache`.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::harness::{decode_field, encode_field, Entry};
+
+/// Name of the environment variable overriding the cache directory.
+pub const DIR_VAR: &str = "FTA_CACHE_DIR";
+
+fn path(name: &str) -> PathBuf {
+    let dir = env::var_os(DIR_VAR).map_or_else(|| PathBuf::from("target/fta-cache"), PathBuf::from);
+    let file: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    dir.join(format!("{}.txt", file))
+}
+
+/// Load the entry of the named scenario if it was stored with the same
+/// version.
+pub(crate) fn load(name: &str, version: &str) -> Option<Entry> {
+    let content = fs::read_to_string(path(name)).ok()?;
+    let mut lines = content.lines();
+    if lines.next()? != format!("fta:cache {} {}", encode_field(Some(name)), encode_field(Some(version))) {
+        return None;
+    }
+    let mut entry = Entry::default();
+    for line in lines {
+        match line.split(' ').collect::<Vec<_>>().as_slice() {
+            ["num_polls", num_polls] => entry.num_polls = num_polls.parse().ok()?,
+            ["label", label, poll] => entry.labels.push((decode_field(label)?, poll.parse().ok()?)),
+            _ => return None,
+        }
+    }
+    Some(entry)
+}
+
+/// Store the entry of the named scenario. Errors are ignored as the cache
+/// is only an optimization.
+pub(crate) fn store(name: &str, version: &str, entry: &Entry) {
+    let mut content = format!("fta:cache {} {}\n", encode_field(Some(name)), encode_field(Some(version)));
+    content.push_str(&format!("num_polls {}\n", entry.num_polls));
+    for (label, poll) in &entry.labels {
+        content.push_str(&format!("label {} {}\n", encode_field(Some(label)), poll));
+    }
+    let path = path(name);
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = fs::write(path, content);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::{after, Sweep};
+    use crate::test_util::{labeled_loop, Counter};
+
+    #[cfg_attr(miri, ignore = "accesses the file system")]
+    #[tokio::test]
+    async fn sweep_cache() {
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let sweep = || Sweep::new().name("fta-test-sweep-cache").cache("v1");
+        let _ = std::fs::remove_file("target/fta-cache/fta-test-sweep-cache.txt");
+        let report = sweep().abort_after_label("loop").run(setup, labeled_loop, |_| {}).await;
+        assert_eq!(report.previous_polls, None);
+        let report = sweep().abort_after_label("loop").run(setup, labeled_loop, |_| {}).await;
+        assert_eq!(report.previous_polls, report.num_polls);
+        assert_eq!(report.abort_points().map(|point| point.max_polls).collect::<Vec<_>>(), [3]);
+        assert_eq!(report.poll_count_change(), None);
+        let report = sweep().run(|| (), |_: &()| after((), 1), |_| {}).await;
+        assert_eq!(report.poll_count_change().unwrap(), "fta-test-sweep-cache: 6 -> 2 polls");
+        let report = sweep().cache("v2").run(|| (), |_: &()| after((), 1), |_| {}).await;
+        assert_eq!(report.previous_polls, None);
+        std::fs::remove_file("target/fta-cache/fta-test-sweep-cache.txt").unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+//! Instrumented channels which detect lost and duplicated messages.
+//!
+//! Received messages are handed out as `Delivery` which must be
+//! acknowledged via `Delivery::ack` once the message was processed. A
+//! delivery dropped without acknowledgement, e.g. because the receiving
+//! future was aborted, is recorded as lost in the `Ledger` of the channel.
+//! Messages acknowledged more than once are recorded as duplicated.
+//! Messages are compared by value so they should carry a unique id.
+//!
+//! The wrappers for `async-channel` and `flume` are available behind the
+//! features of the same name.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use crate::sync::Settled;
+
+/// Record of the messages which passed through an instrumented channel.
+/// Clones of a ledger share the same record.
+pub struct Ledger<T> {
+    state: Arc<Mutex<LedgerState<T>>>,
+}
+
+struct LedgerState<T> {
+    sent: Vec<T>,
+    acked: Vec<T>,
+    lost: Vec<T>,
+}
+
+impl<T> Ledger<T>
+where
+    T: Clone + PartialEq,
+{
+    /// Create a new empty ledger.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Messages which were sent successfully.
+    pub fn sent(&self) -> Vec<T> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Messages which were acknowledged by the receiver.
+    pub fn acked(&self) -> Vec<T> {
+        self.state.lock().unwrap().acked.clone()
+    }
+
+    /// Messages which were received but dropped without acknowledgement.
+    pub fn lost(&self) -> Vec<T> {
+        self.state.lock().unwrap().lost.clone()
+    }
+
+    /// Messages which were acknowledged more than once, e.g. because the
+    /// sender retried a message which was already sent.
+    pub fn duplicated(&self) -> Vec<T> {
+        let state = self.state.lock().unwrap();
+        let mut duplicated: Vec<T> = Vec::new();
+        for (index, message) in state.acked.iter().enumerate() {
+            if !duplicated.contains(message) && state.acked[index + 1..].contains(message) {
+                duplicated.push(message.clone());
+            }
+        }
+        duplicated
+    }
+
+    /// Record that a message was sent. This and `deliver` make it possible
+    /// to instrument other channel implementations.
+    pub fn record_sent(&self, message: &T) {
+        self.state.lock().unwrap().sent.push(message.clone());
+    }
+
+    /// Wrap a received message in a `Delivery` which must be acknowledged.
+    pub fn deliver(&self, message: T) -> Delivery<T> {
+        Delivery {
+            ledger: self.clone(),
+            message: Some(message),
+        }
+    }
+}
+
+impl<T> Clone for Ledger<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Default for Ledger<T> {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LedgerState {
+                sent: Vec::new(),
+                acked: Vec::new(),
+                lost: Vec::new(),
+            })),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Ledger<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("Ledger")
+            .field("sent", &state.sent)
+            .field("acked", &state.acked)
+            .field("lost", &state.lost)
+            .finish()
+    }
+}
+
+impl<T> Settled for Ledger<T>
+where
+    T: Clone + PartialEq,
+{
+    /// Returns `true` if no message was lost or duplicated.
+    fn is_settled(&self) -> bool {
+        self.lost().is_empty() && self.duplicated().is_empty()
+    }
+}
+
+/// Message received from an instrumented channel.
+#[must_use]
+pub struct Delivery<T>
+where
+    T: Clone + PartialEq,
+{
+    ledger: Ledger<T>,
+    message: Option<T>,
+}
+
+impl<T> Delivery<T>
+where
+    T: Clone + PartialEq,
+{
+    /// Acknowledge that the message was processed and return it.
+    pub fn ack(mut self) -> T {
+        let message = self.message.take().unwrap();
+        self.ledger.state.lock().unwrap().acked.push(message.clone());
+        message
+    }
+}
+
+impl<T> Deref for Delivery<T>
+where
+    T: Clone + PartialEq,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.message.as_ref().unwrap()
+    }
+}
+
+impl<T> Drop for Delivery<T>
+where
+    T: Clone + PartialEq,
+{
+    fn drop(&mut self) {
+        if let Some(message) = self.message.take() {
+            self.ledger.state.lock().unwrap().lost.push(message);
+        }
+    }
+}
+
+impl<T> fmt::Debug for Delivery<T>
+where
+    T: Clone + PartialEq + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Delivery").field(&self.message).finish()
+    }
+}
+
+/// Instrumented wrappers for the `async-channel` crate.
+#[cfg(feature = "async-channel")]
+pub mod async_channel {
+    use super::{Delivery, Ledger};
+
+    pub use ::async_channel::{RecvError, SendError};
+
+    /// Sending side of an instrumented `async-channel`.
+    #[derive(Debug)]
+    pub struct Sender<T> {
+        inner: ::async_channel::Sender<T>,
+        ledger: Ledger<T>,
+    }
+
+    impl<T> Sender<T>
+    where
+        T: Clone + PartialEq,
+    {
+        /// Send a message and record it in the ledger once it was sent.
+        pub async fn send(&self, message: T) -> Result<(), SendError<T>> {
+            let copy = message.clone();
+            self.inner.send(message).await?;
+            self.ledger.record_sent(&copy);
+            Ok(())
+        }
+
+        /// The ledger of this channel.
+        pub fn ledger(&self) -> &Ledger<T> {
+            &self.ledger
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+                ledger: self.ledger.clone(),
+            }
+        }
+    }
+
+    /// Receiving side of an instrumented `async-channel`.
+    #[derive(Debug)]
+    pub struct Receiver<T> {
+        inner: ::async_channel::Receiver<T>,
+        ledger: Ledger<T>,
+    }
+
+    impl<T> Receiver<T>
+    where
+        T: Clone + PartialEq,
+    {
+        /// Receive a message which must be acknowledged.
+        pub async fn recv(&self) -> Result<Delivery<T>, RecvError> {
+            let message = self.inner.recv().await?;
+            Ok(self.ledger.deliver(message))
+        }
+
+        /// The ledger of this channel.
+        pub fn ledger(&self) -> &Ledger<T> {
+            &self.ledger
+        }
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+                ledger: self.ledger.clone(),
+            }
+        }
+    }
+
+    /// Create an instrumented bounded channel.
+    pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+        wrap(::async_channel::bounded(cap))
+    }
+
+    /// Create an instrumented unbounded channel.
+    pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+        wrap(::async_channel::unbounded())
+    }
+
+    fn wrap<T>((tx, rx): (::async_channel::Sender<T>, ::async_channel::Receiver<T>)) -> (Sender<T>, Receiver<T>) {
+        let ledger = Ledger::default();
+        (
+            Sender {
+                inner: tx,
+                ledger: ledger.clone(),
+            },
+            Receiver { inner: rx, ledger },
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{unbounded, Receiver, Sender};
+        use crate::{after, Settled, Sweep};
+
+        async fn forward((tx, rx): &(Sender<u32>, Receiver<u32>)) {
+            tx.send(1).await.unwrap();
+            let message = rx.recv().await.unwrap();
+            after((), 1).await;
+            message.ack();
+        }
+
+        #[tokio::test]
+        async fn channel_lost_message() {
+            let report = Sweep::new().report(unbounded, forward, |(tx, _)| tx.ledger().assert_settled()).await;
+            let unsafe_points: Vec<usize> = report.abort_points().filter(|p| !p.is_safe()).map(|p| p.max_polls).collect();
+            assert_eq!(unsafe_points, [1]);
+        }
+    }
+}
+
+/// Instrumented wrappers for the `flume` crate.
+#[cfg(feature = "flume")]
+pub mod flume {
+    use super::{Delivery, Ledger};
+
+    pub use ::flume::{RecvError, SendError};
+
+    /// Sending side of an instrumented `flume` channel.
+    #[derive(Debug)]
+    pub struct Sender<T> {
+        inner: ::flume::Sender<T>,
+        ledger: Ledger<T>,
+    }
+
+    impl<T> Sender<T>
+    where
+        T: Clone + PartialEq,
+    {
+        /// Send a message and record it in the ledger once it was sent.
+        pub async fn send(&self, message: T) -> Result<(), SendError<T>> {
+            let copy = message.clone();
+            self.inner.send_async(message).await?;
+            self.ledger.record_sent(&copy);
+            Ok(())
+        }
+
+        /// The ledger of this channel.
+        pub fn ledger(&self) -> &Ledger<T> {
+            &self.ledger
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+                ledger: self.ledger.clone(),
+            }
+        }
+    }
+
+    /// Receiving side of an instrumented `flume` channel.
+    #[derive(Debug)]
+    pub struct Receiver<T> {
+        inner: ::flume::Receiver<T>,
+        ledger: Ledger<T>,
+    }
+
+    impl<T> Receiver<T>
+    where
+        T: Clone + PartialEq,
+    {
+        /// Receive a message which must be acknowledged.
+        pub async fn recv(&self) -> Result<Delivery<T>, RecvError> {
+            let message = self.inner.recv_async().await?;
+            Ok(self.ledger.deliver(message))
+        }
+
+        /// The ledger of this channel.
+        pub fn ledger(&self) -> &Ledger<T> {
+            &self.ledger
+        }
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+                ledger: self.ledger.clone(),
+            }
+        }
+    }
+
+    /// Create an instrumented bounded channel.
+    pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+        wrap(::flume::bounded(cap))
+    }
+
+    /// Create an instrumented unbounded channel.
+    pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+        wrap(::flume::unbounded())
+    }
+
+    fn wrap<T>((tx, rx): (::flume::Sender<T>, ::flume::Receiver<T>)) -> (Sender<T>, Receiver<T>) {
+        let ledger = Ledger::default();
+        (
+            Sender {
+                inner: tx,
+                ledger: ledger.clone(),
+            },
+            Receiver { inner: rx, ledger },
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::unbounded;
+        use crate::Settled;
+
+        #[tokio::test]
+        async fn flume_duplicated_message() {
+            let (tx, rx) = unbounded();
+            tx.send(1).await.unwrap();
+            tx.send(1).await.unwrap();
+            rx.recv().await.unwrap().ack();
+            assert!(tx.ledger().is_settled());
+            rx.recv().await.unwrap().ack();
+            assert!(!tx.ledger().is_settled());
+            assert_eq!(tx.ledger().duplicated(), [1]);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Abort testing of combinators like `race`, `merge` and `zip`.
+//!
+//! Combinators poll several branches concurrently. Whether a branch loses
+//! a race, the remaining branches of a `zip` are aborted or the combined
+//! future itself is aborted, every branch must be dropped exactly once and
+//! its cleanup must run. `Branches` wraps every branch to track its drops
+//! and hands out cleanup guards which the branches hold while they are
+//! running. `Branches::sweep` aborts the combined future at every point
+//! and checks all branches afterwards.
+//!
+//! ```rust
+//! use std::future::{poll_fn, Future};
+//! use std::pin::pin;
+//! use std::task::Poll;
+//!
+//! use futures_test_abort::after;
+//! use futures_test_abort::combinator::Branches;
+//!
+//! async fn race<A: Future, B: Future<Output = A::Output>>(a: A, b: B) -> A::Output {
+//!     let (mut a, mut b) = (pin!(a), pin!(b));
+//!     poll_fn(|cx| match a.as_mut().poll(cx) {
+//!         Poll::Ready(v) => Poll::Ready(v),
+//!         Poll::Pending => b.as_mut().poll(cx),
+//!     })
+//!     .await
+//! }
+//!
+//! async fn branch(branches: &Branches, index: usize, polls: usize) {
+//!     let _cleanup = branches.cleanup(index);
+//!     after((), polls).await;
+//! }
+//!
+//! async fn combined(branches: &Branches) {
+//!     race(branches.branch(0, branch(branches, 0, 2)), branches.branch(1, branch(branches, 1, 3))).await;
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! Branches::sweep(2, combined).await;
+//! # }
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use crate::future::Pinned;
+use crate::harness::{MakeFuture, Sweep};
+use crate::invariant::InvariantError;
+use crate::report::Report;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct BranchState {
+    created: usize,
+    completed: bool,
+    drops: usize,
+    cleanups: usize,
+    cleaned: usize,
+}
+
+/// Drop tracking of the branches of a combined future.
+#[derive(Debug)]
+pub struct Branches {
+    branches: Mutex<Vec<BranchState>>,
+}
+
+impl Branches {
+    /// Track the given number of branches.
+    pub fn new(branches: usize) -> Self {
+        Self {
+            branches: Mutex::new(vec![BranchState::default(); branches]),
+        }
+    }
+
+    /// Wrap the future of the branch with the given index. Panics if the
+    /// index is out of range.
+    pub fn branch<F>(&self, index: usize, future: F) -> Branch<'_, F>
+    where
+        F: Future,
+    {
+        self.branches.lock().unwrap()[index].created += 1;
+        Branch {
+            branches: self,
+            index,
+            future: Pinned::new(future),
+        }
+    }
+
+    /// Create a guard which marks the cleanup of the branch as done when it
+    /// is dropped. Branches hold it while they are running.
+    pub fn cleanup(&self, index: usize) -> Cleanup<'_> {
+        self.branches.lock().unwrap()[index].cleanups += 1;
+        Cleanup { branches: self, index }
+    }
+
+    /// Number of times the branch was dropped.
+    pub fn num_drops(&self, index: usize) -> usize {
+        self.branches.lock().unwrap()[index].drops
+    }
+
+    /// Returns `true` if the branch completed before it was dropped.
+    pub fn is_completed(&self, index: usize) -> bool {
+        self.branches.lock().unwrap()[index].completed
+    }
+
+    /// Check that every created branch was dropped exactly once and that
+    /// all of its cleanup guards were dropped.
+    pub fn check(&self) -> Result<(), Vec<InvariantError>> {
+        let mut errors = Vec::new();
+        for (index, branch) in self.branches.lock().unwrap().iter().enumerate() {
+            if branch.created == 0 {
+                continue;
+            }
+            let state = if branch.completed { "completed" } else { "pending" };
+            if branch.drops != branch.created {
+                errors.push(
+                    InvariantError::new(format!("branch {} was dropped {} times", index, branch.drops))
+                        .context(format!("{} branch", state))
+                        .with("created", branch.created),
+                );
+            }
+            if branch.cleaned != branch.cleanups {
+                errors.push(
+                    InvariantError::new(format!("cleanup of branch {} did not run", index))
+                        .context(format!("{} branch", state))
+                        .with("pending cleanups", branch.cleanups - branch.cleaned),
+                );
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Run a `Sweep` over the combined future created by `make` with the
+    /// given number of branches and panic if a branch was not dropped
+    /// exactly once or its cleanup did not run.
+    pub async fn sweep<Make>(branches: usize, make: Make) -> Report
+    where
+        Make: for<'a> MakeFuture<'a, Branches>,
+    {
+        Sweep::new().run(|| Self::new(branches), make, Self::check).await
+    }
+}
+
+/// Future of a branch returned by `Branches::branch`.
+#[derive(Debug)]
+pub struct Branch<'a, F>
+where
+    F: Future,
+{
+    branches: &'a Branches,
+    index: usize,
+    future: Pinned<F>,
+}
+
+impl<F> Future for Branch<'_, F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            let result = me.future.as_pin_mut().poll(cx);
+            if result.is_ready() {
+                me.branches.branches.lock().unwrap()[me.index].completed = true;
+            }
+            result
+        }
+    }
+}
+
+impl<F> Drop for Branch<'_, F>
+where
+    F: Future,
+{
+    fn drop(&mut self) {
+        self.branches.branches.lock().unwrap()[self.index].drops += 1;
+    }
+}
+
+/// Guard returned by `Branches::cleanup`.
+#[must_use]
+#[derive(Debug)]
+pub struct Cleanup<'a> {
+    branches: &'a Branches,
+    index: usize,
+}
+
+impl Drop for Cleanup<'_> {
+    fn drop(&mut self) {
+        self.branches.branches.lock().unwrap()[self.index].cleaned += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{poll_fn, Future};
+    use std::pin::pin;
+    use std::task::Poll;
+
+    use super::Branches;
+    use crate::{after, Sweep};
+
+    async fn branch(branches: &Branches, index: usize, polls: usize) {
+        let _cleanup = branches.cleanup(index);
+        after((), polls).await;
+    }
+
+    async fn race(branches: &Branches) {
+        let mut a = pin!(branches.branch(0, branch(branches, 0, 1)));
+        let mut b = pin!(branches.branch(1, branch(branches, 1, 3)));
+        poll_fn(|cx| match a.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(()),
+            Poll::Pending => b.as_mut().poll(cx),
+        })
+        .await;
+    }
+
+    async fn leaky_race(branches: &Branches) {
+        let mut a = Box::pin(branches.branch(0, branch(branches, 0, 1)));
+        let mut b = Box::pin(branches.branch(1, branch(branches, 1, 3)));
+        poll_fn(|cx| {
+            let _ = b.as_mut().poll(cx);
+            a.as_mut().poll(cx)
+        })
+        .await;
+        std::mem::forget(b);
+    }
+
+    #[tokio::test]
+    async fn combinator_branches() {
+        let report = Branches::sweep(2, race).await;
+        assert_eq!(report.points.len(), 3);
+        let report = Sweep::new().report(|| Branches::new(2), leaky_race, Branches::check).await;
+        assert_eq!(report.points.len(), 3);
+        assert!(report.points[..2].iter().all(|point| point.is_safe()));
+        assert_eq!(
+            report.points[2].failure.as_deref(),
+            Some(
+                "branch 1 was dropped 0 times (pending branch) {created: 1}; \
+                 cleanup of branch 1 did not run (pending branch) {pending cleanups: 1}"
+            )
+        );
+        assert_eq!(report.points[2].invariant_errors.len(), 2);
+    }
+}
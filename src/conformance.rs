@@ -0,0 +1,603 @@
+//! Conformance suite for `Future`, `Stream` and `Sink` implementations.
+//!
+//! Implementors of these traits usually want the same battery of checks:
+//! the waker contract, the fuse contract of streams, dropping at every
+//! poll and cancel safety. The `Sink` checks require the `sink` feature. `Conformance` runs each of them for values
+//! created by a constructor and `conformance!` generates one test per
+//! check.
+//!
+//! All checks run on the current thread. Wakes must happen before the
+//! next poll, e.g. because the value wakes itself or an earlier poll
+//! released what it waits for. Values waiting for other threads or
+//! timers are not supported.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "sink")]
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+#[cfg(feature = "sink")]
+use futures_sink::Sink;
+
+use crate::executor::Flag;
+use crate::harness::panic_message;
+#[cfg(feature = "sink")]
+use crate::sink::abort_sink;
+use crate::stream::Termination;
+
+/// Checks of the conformance suite.
+#[derive(Clone, Copy, Debug)]
+pub struct Conformance {
+    max_polls: usize,
+}
+
+impl Conformance {
+    /// Create a suite which polls every value at most `1000` times.
+    pub fn new() -> Self {
+        Self { max_polls: 1000 }
+    }
+
+    /// Set the number of polls after which every value must be done.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Check the waker contract of a future created by `make`.
+    ///
+    /// Every poll gets a fresh waker and the future is only polled again
+    /// after that waker was woken. A future which returns `Pending`
+    /// without arranging a wake of the latest waker is reported, also if
+    /// it only wakes a waker of an earlier poll.
+    pub fn check_future_waker<T, Make>(&self, mut make: Make) -> Result<(), String>
+    where
+        T: Future,
+        Make: FnMut() -> T,
+    {
+        let mut future = Box::pin(make());
+        self.check_waker("future", |cx| future.as_mut().poll(cx).map(|_| None::<()>))
+    }
+
+    /// Check the waker contract of a stream created by `make`. See
+    /// `check_future_waker`.
+    pub fn check_stream_waker<T, Make>(&self, mut make: Make) -> Result<(), String>
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+    {
+        let mut stream = Box::pin(make());
+        self.check_waker("stream", |cx| stream.as_mut().poll_next(cx).map(|item| item.map(|_| ())))
+    }
+
+    /// Check the fuse contract of a stream created by `make`. See
+    /// `Termination::check_producer`.
+    pub fn check_stream_fuse<T, Make>(&self, make: Make) -> Result<(), String>
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+    {
+        Termination::new().max_polls(self.max_polls).check_producer(make)
+    }
+
+    /// Check that a future created by `make` can be dropped after every
+    /// number of polls without panicking.
+    pub fn check_future_abort<T, Make>(&self, mut make: Make) -> Result<(), String>
+    where
+        T: Future,
+        Make: FnMut() -> T,
+    {
+        let num_polls = self.future_polls(&mut make)?;
+        self.check_abort("future", num_polls, || {
+            let mut future = Box::pin(make());
+            move || future.as_mut().poll(&mut Context::from_waker(Waker::noop())).map(|_| None::<()>)
+        })
+    }
+
+    /// Check that a stream created by `make` can be dropped after every
+    /// number of polls without panicking.
+    pub fn check_stream_abort<T, Make>(&self, mut make: Make) -> Result<(), String>
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+    {
+        let num_polls = self.stream_polls(&mut make)?;
+        self.check_abort("stream", num_polls, || {
+            let mut stream = Box::pin(make());
+            move || {
+                stream
+                    .as_mut()
+                    .poll_next(&mut Context::from_waker(Waker::noop()))
+                    .map(|item| item.map(|_| ()))
+            }
+        })
+    }
+
+    /// Check the cancel safety of futures created by `make`.
+    ///
+    /// For every abort point a future is aborted and then a fresh one
+    /// must still complete. This catches state shared via the constructor
+    /// which an aborted future leaves behind, e.g. a lock which is never
+    /// released.
+    pub fn check_future_cancel<T, Make>(&self, mut make: Make) -> Result<(), String>
+    where
+        T: Future,
+        Make: FnMut() -> T,
+    {
+        let num_polls = self.future_polls(&mut make)?;
+        for abort_after in 0..num_polls {
+            {
+                let mut future = Box::pin(make());
+                for _ in 0..abort_after {
+                    let _ = future.as_mut().poll(&mut Context::from_waker(Waker::noop()));
+                }
+            }
+            if self.future_polls(&mut make).is_err() {
+                return Err(format!(
+                    "future did not complete within {} polls after a future was aborted after {} polls",
+                    self.max_polls, abort_after
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the cancel safety of streams created by `make`. See
+    /// `check_future_cancel`.
+    pub fn check_stream_cancel<T, Make>(&self, mut make: Make) -> Result<(), String>
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+    {
+        let num_polls = self.stream_polls(&mut make)?;
+        for abort_after in 0..num_polls {
+            {
+                let mut stream = Box::pin(make());
+                for _ in 0..abort_after {
+                    let _ = stream.as_mut().poll_next(&mut Context::from_waker(Waker::noop()));
+                }
+            }
+            if self.stream_polls(&mut make).is_err() {
+                return Err(format!(
+                    "stream did not end within {} polls after a stream was aborted after {} polls",
+                    self.max_polls, abort_after
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the waker contract of a sink created by `make` while
+    /// `items` are sent and the sink is closed. See `check_future_waker`.
+    #[cfg(feature = "sink")]
+    pub fn check_sink_waker<T, Item, Make>(&self, mut make: Make, items: &[Item]) -> Result<(), String>
+    where
+        T: Sink<Item>,
+        Item: Clone,
+        Make: FnMut() -> T,
+    {
+        let mut feed = Feed::new(make(), items);
+        self.check_waker("sink", |cx| feed.poll(cx).map(|result| result.unwrap_or(None)))
+    }
+
+    /// Check that a sink created by `make` can be dropped after every
+    /// number of calls of its methods without panicking. The calls are
+    /// limited via `AbortSink`.
+    #[cfg(feature = "sink")]
+    pub fn check_sink_abort<T, Item, Make>(&self, mut make: Make, items: &[Item]) -> Result<(), String>
+    where
+        T: Sink<Item>,
+        Item: Clone,
+        Make: FnMut() -> T,
+    {
+        let num_calls = self.sink_calls(&mut make, items)?;
+        for abort_after in 0..num_calls {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut feed = Feed::new(abort_sink(make(), abort_after), items);
+                for _ in 0..self.max_polls {
+                    if let Poll::Ready(Ok(None) | Err(_)) = feed.poll(&mut Context::from_waker(Waker::noop())) {
+                        break;
+                    }
+                }
+            }));
+            if let Err(payload) = result {
+                return Err(format!(
+                    "sink panicked when dropped after {} calls: {}",
+                    abort_after,
+                    panic_message(&*payload)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the cancel safety of sinks created by `make`: after a sink
+    /// was aborted via `AbortSink` after every number of calls a fresh
+    /// one must still accept all `items` and close. See
+    /// `check_future_cancel`.
+    #[cfg(feature = "sink")]
+    pub fn check_sink_cancel<T, Item, Make>(&self, mut make: Make, items: &[Item]) -> Result<(), String>
+    where
+        T: Sink<Item>,
+        Item: Clone,
+        Make: FnMut() -> T,
+    {
+        let num_calls = self.sink_calls(&mut make, items)?;
+        for abort_after in 0..num_calls {
+            {
+                let mut feed = Feed::new(abort_sink(make(), abort_after), items);
+                for _ in 0..self.max_polls {
+                    if let Poll::Ready(Ok(None) | Err(_)) = feed.poll(&mut Context::from_waker(Waker::noop())) {
+                        break;
+                    }
+                }
+            }
+            if self.sink_calls(&mut make, items).is_err() {
+                return Err(format!(
+                    "sink did not close within {} polls after a sink was aborted after {} calls",
+                    self.max_polls, abort_after
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll via `poll` with a fresh waker per poll until it yields
+    /// `Ready(None)`. `Ready(Some(_))` is an item of a stream.
+    fn check_waker<P>(&self, kind: &str, mut poll: P) -> Result<(), String>
+    where
+        P: FnMut(&mut Context<'_>) -> Poll<Option<()>>,
+    {
+        for num_polls in 0..self.max_polls {
+            let flag = Arc::new(Flag(AtomicBool::new(false)));
+            let waker = Waker::from(flag.clone());
+            match poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(None) => return Ok(()),
+                Poll::Ready(Some(())) => {}
+                Poll::Pending => {
+                    if !flag.0.load(Ordering::SeqCst) {
+                        return Err(format!(
+                            "{} returned Pending in poll {} but never woke the waker of that poll",
+                            kind, num_polls
+                        ));
+                    }
+                }
+            }
+        }
+        Err(format!("{} did not complete within {} polls", kind, self.max_polls))
+    }
+
+    /// Drop a value created by `start` after every number of polls up to
+    /// `num_polls`. `start` returns the function polling the value.
+    fn check_abort<S, P>(&self, kind: &str, num_polls: usize, mut start: S) -> Result<(), String>
+    where
+        S: FnMut() -> P,
+        P: FnMut() -> Poll<Option<()>>,
+    {
+        for abort_after in 0..num_polls {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut poll = start();
+                for _ in 0..abort_after {
+                    let _ = poll();
+                }
+            }));
+            if let Err(payload) = result {
+                return Err(format!(
+                    "{} panicked when dropped after {} polls: {}",
+                    kind,
+                    abort_after,
+                    panic_message(&*payload)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of polls a future created by `make` needs to complete.
+    fn future_polls<T, Make>(&self, make: &mut Make) -> Result<usize, String>
+    where
+        T: Future,
+        Make: FnMut() -> T,
+    {
+        let mut future = Box::pin(make());
+        for num_polls in 1..=self.max_polls {
+            if future.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_ready() {
+                return Ok(num_polls);
+            }
+        }
+        Err(format!("future did not complete within {} polls", self.max_polls))
+    }
+
+    /// Number of polls a stream created by `make` needs to end.
+    fn stream_polls<T, Make>(&self, make: &mut Make) -> Result<usize, String>
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+    {
+        let mut stream = Box::pin(make());
+        for num_polls in 1..=self.max_polls {
+            if let Poll::Ready(None) = stream.as_mut().poll_next(&mut Context::from_waker(Waker::noop())) {
+                return Ok(num_polls);
+            }
+        }
+        Err(format!("stream did not end within {} polls", self.max_polls))
+    }
+    /// Number of calls of its methods a sink created by `make` needs to
+    /// accept `items` and close.
+    #[cfg(feature = "sink")]
+    fn sink_calls<T, Item, Make>(&self, make: &mut Make, items: &[Item]) -> Result<usize, String>
+    where
+        T: Sink<Item>,
+        Item: Clone,
+        Make: FnMut() -> T,
+    {
+        let mut feed = Feed::new(abort_sink(make(), usize::MAX), items);
+        for _ in 0..self.max_polls {
+            match feed.poll(&mut Context::from_waker(Waker::noop())) {
+                Poll::Ready(Ok(None)) => return Ok(feed.sink.num_calls()),
+                Poll::Ready(Err(_)) => return Err("sink failed".into()),
+                _ => {}
+            }
+        }
+        Err(format!("sink did not close within {} polls", self.max_polls))
+    }
+}
+
+/// Sends items to a sink and closes it afterwards, one step per poll.
+#[cfg(feature = "sink")]
+struct Feed<'i, T, Item> {
+    sink: Pin<Box<T>>,
+    items: std::slice::Iter<'i, Item>,
+    /// Item which is sent once the sink is ready.
+    next: Option<Item>,
+}
+
+#[cfg(feature = "sink")]
+impl<'i, T, Item> Feed<'i, T, Item>
+where
+    T: Sink<Item>,
+    Item: Clone,
+{
+    fn new(sink: T, items: &'i [Item]) -> Self {
+        Self {
+            sink: Box::pin(sink),
+            items: items.iter(),
+            next: None,
+        }
+    }
+
+    /// Wait until the sink is ready and send the next item, which yields
+    /// `Ready(Ok(Some(())))`, or close the sink once all items were sent,
+    /// which yields `Ready(Ok(None))`.
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<()>, T::Error>> {
+        let Some(item) = self.next.take().or_else(|| self.items.next().cloned()) else {
+            return self.sink.as_mut().poll_close(cx).map_ok(|()| None);
+        };
+        match self.sink.as_mut().poll_ready(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(self.sink.as_mut().start_send(item).map(Some)),
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+            Poll::Pending => {
+                self.next = Some(item);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Default for Conformance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Generate the conformance suite of a `Future`, `Stream` or `Sink`
+/// implementation.
+///
+/// `conformance!(my_stream: Stream, constructor = || MyStream::new())`
+/// expands to a module named `my_stream` with the tests `waker_contract`,
+/// `fuse_contract`, `abort_sweep` and `cancel_safety`. Futures and sinks
+/// get the same tests except `fuse_contract`. Sinks also need the items
+/// to send, e.g. `conformance!(my_sink: Sink, constructor = || MySink::new(),
+/// items = [1, 2, 3])`, and the `sink` feature. The constructor is called
+/// for every value and may share state between them. See `Conformance`
+/// for the individual checks.
+///
+/// ```rust
+/// futures_test_abort::conformance!(after: Future, constructor = || futures_test_abort::after(42, 3));
+/// ```
+#[macro_export]
+macro_rules! conformance {
+    ($name:ident: Future, constructor = $make:expr $(,)?) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn waker_contract() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_future_waker($make) {
+                    panic!("{}", msg);
+                }
+            }
+
+            #[test]
+            fn abort_sweep() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_future_abort($make) {
+                    panic!("{}", msg);
+                }
+            }
+
+            #[test]
+            fn cancel_safety() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_future_cancel($make) {
+                    panic!("{}", msg);
+                }
+            }
+        }
+    };
+    ($name:ident: Stream, constructor = $make:expr $(,)?) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn waker_contract() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_stream_waker($make) {
+                    panic!("{}", msg);
+                }
+            }
+
+            #[test]
+            fn fuse_contract() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_stream_fuse($make) {
+                    panic!("{}", msg);
+                }
+            }
+
+            #[test]
+            fn abort_sweep() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_stream_abort($make) {
+                    panic!("{}", msg);
+                }
+            }
+
+            #[test]
+            fn cancel_safety() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_stream_cancel($make) {
+                    panic!("{}", msg);
+                }
+            }
+        }
+    };
+    ($name:ident: Sink, constructor = $make:expr, items = $items:expr $(,)?) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn waker_contract() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_sink_waker($make, &$items) {
+                    panic!("{}", msg);
+                }
+            }
+
+            #[test]
+            fn abort_sweep() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_sink_abort($make, &$items) {
+                    panic!("{}", msg);
+                }
+            }
+
+            #[test]
+            fn cancel_safety() {
+                if let Err(msg) = $crate::conformance::Conformance::new().check_sink_cancel($make, &$items) {
+                    panic!("{}", msg);
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::future::poll_fn;
+    #[cfg(feature = "sink")]
+    use std::pin::Pin;
+    #[cfg(feature = "sink")]
+    use std::task::Context;
+    use std::task::Poll;
+
+    use super::Conformance;
+    use crate::after;
+    use crate::test_util::Count;
+
+    crate::conformance!(count_conformance: Stream, constructor = || Count(0));
+
+    crate::conformance!(after_conformance: Future, constructor = || after((), 2));
+
+    #[test]
+    fn conformance_failures() {
+        let conformance = Conformance::new().max_polls(10);
+        let msg = conformance.check_future_waker(|| poll_fn(|_| Poll::<()>::Pending)).unwrap_err();
+        assert_eq!(msg, "future returned Pending in poll 0 but never woke the waker of that poll");
+        let locked = &Cell::new(false);
+        let lock = || {
+            let mut acquired = false;
+            poll_fn(move |cx| {
+                cx.waker().wake_by_ref();
+                if acquired {
+                    locked.set(false);
+                    return Poll::Ready(());
+                }
+                if !locked.get() {
+                    locked.set(true);
+                    acquired = true;
+                }
+                Poll::Pending
+            })
+        };
+        assert_eq!(conformance.check_future_abort(lock), Ok(()));
+        locked.set(false);
+        let msg = conformance.check_future_cancel(lock).unwrap_err();
+        assert_eq!(
+            msg,
+            "future did not complete within 10 polls after a future was aborted after 1 polls"
+        );
+    }
+
+    #[cfg(feature = "sink")]
+    crate::conformance!(vec_conformance: Sink, constructor = Vec::<u32>::new, items = [1, 2, 3]);
+
+    /// Sink which holds a shared lock from the first `poll_ready` until it
+    /// is closed and wakes itself while waiting for it.
+    #[cfg(feature = "sink")]
+    struct Locking<'a> {
+        locked: &'a Cell<bool>,
+        acquired: bool,
+    }
+
+    #[cfg(feature = "sink")]
+    impl futures_sink::Sink<u32> for Locking<'_> {
+        type Error = ();
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            if !self.acquired {
+                if self.locked.replace(true) {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                self.acquired = true;
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: u32) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.locked.set(false);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "sink")]
+    #[test]
+    fn conformance_sink_failures() {
+        let conformance = Conformance::new().max_polls(10);
+        let locked = &Cell::new(false);
+        let lock = || Locking { locked, acquired: false };
+        assert_eq!(conformance.check_sink_waker(lock, &[1, 2]), Ok(()));
+        assert_eq!(conformance.check_sink_abort(lock, &[1, 2]), Ok(()));
+        locked.set(false);
+        let msg = conformance.check_sink_cancel(lock, &[1, 2]).unwrap_err();
+        assert_eq!(msg, "sink did not close within 10 polls after a sink was aborted after 1 calls");
+    }
+}
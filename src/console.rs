@@ -0,0 +1,39 @@
+//! Integration with `tokio-console`.
+//!
+//! `console-subscriber` shows every span named `runtime.spawn` with the
+//! target `tokio::task` as a task and every time the span is entered as a
+//! poll of that task. The iterations of a `Sweep` are wrapped in such spans
+//! so they can be observed live like tasks spawned on a runtime.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub(crate) use tracing::Span as TaskSpan;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Create the span of a stage (`run` or `cleanup`) of an iteration.
+pub(crate) fn task_span(sweep: Option<&str>, max_polls: usize, stage: &str) -> TaskSpan {
+    let name = format!("fta {} abort point {} {}", sweep.unwrap_or("sweep"), max_polls, stage);
+    tracing::trace_span!(
+        target: "tokio::task",
+        "runtime.spawn",
+        kind = "task",
+        task.name = %name,
+        task.id = NEXT_ID.fetch_add(1, Ordering::Relaxed),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ScopedCounter, Sweep};
+    use crate::test_util::enter_repeatedly;
+
+    #[tokio::test]
+    async fn console_task_spans() {
+        let report = Sweep::new()
+            .name("console_task_spans")
+            .run(ScopedCounter::new, enter_repeatedly, |counter| assert_eq!(counter.get(), 0))
+            .await;
+        assert_eq!(report.summary().num_unsafe, 0);
+    }
+}
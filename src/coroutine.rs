@@ -0,0 +1,284 @@
+//! Abort testing of coroutine based state machines (nightly only).
+//!
+//! Some libraries hand-roll futures from coroutines. The wrappers in this
+//! module budget the number of resumes of a `Coroutine` like `Abort`
+//! budgets the polls of a future. An aborted coroutine is dropped at the
+//! yield point it was suspended at. This module requires the `coroutine`
+//! feature and a nightly compiler.
+
+use std::ops::{Coroutine, CoroutineState};
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+
+use crate::future::{AbortReason, Aborted, Pinned};
+use crate::harness::{Clock, Profile, SystemClock};
+use crate::invariant::{self, CheckResult};
+use crate::registry;
+use crate::report::{PointReport, Report};
+
+/// Wrapper for a `Coroutine` which limits the times it can be resumed.
+pub struct AbortCoroutine<C> {
+    num_resumes: usize,
+    max_resumes: usize,
+    /// `None` once the coroutine was dropped after being aborted.
+    coroutine: Pinned<Option<C>>,
+}
+
+impl<C> AbortCoroutine<C> {
+    /// Number of times the inner coroutine has been resumed.
+    pub fn num_resumes(&self) -> usize {
+        self.num_resumes
+    }
+
+    /// Returns `true` if the inner coroutine was aborted and dropped.
+    pub fn is_aborted(&self) -> bool {
+        self.coroutine.get_ref().is_none()
+    }
+}
+
+impl<C, R> Coroutine<R> for AbortCoroutine<C>
+where
+    C: Coroutine<R>,
+{
+    type Yield = C::Yield;
+    type Return = Result<C::Return, Aborted>;
+
+    fn resume(self: Pin<&mut Self>, arg: R) -> CoroutineState<Self::Yield, Self::Return> {
+        // Safety: we never move `self.coroutine`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let mut coroutine = unsafe { me.coroutine.as_pin_mut() };
+        if me.num_resumes >= me.max_resumes {
+            // Drop the coroutine at its current yield point.
+            coroutine.set(None);
+            return CoroutineState::Complete(Err(Aborted {
+                num_polls: me.num_resumes,
+                ..Aborted::default()
+            }));
+        }
+        me.num_resumes += 1;
+        let coroutine = coroutine.as_pin_mut().expect("coroutine resumed after it was aborted");
+        match coroutine.resume(arg) {
+            CoroutineState::Yielded(value) => CoroutineState::Yielded(value),
+            CoroutineState::Complete(value) => CoroutineState::Complete(Ok(value)),
+        }
+    }
+}
+
+/// Create a `AbortCoroutine` wrapper which limits the times a coroutine
+/// can be resumed before it completes with `Err(Aborted)`.
+pub fn abort_coroutine<C>(coroutine: C, max_resumes: usize) -> AbortCoroutine<C> {
+    AbortCoroutine {
+        num_resumes: 0,
+        max_resumes,
+        coroutine: Pinned::new(Some(coroutine)),
+    }
+}
+
+/// Factory for the coroutines tested by `sweep`. This works like
+/// `MakeFuture` for coroutines.
+pub trait MakeCoroutine<'a, S> {
+    /// The created coroutine.
+    type Coroutine: Coroutine<()> + 'a;
+    /// Create a new coroutine borrowing the given state.
+    fn make(&mut self, state: &'a S) -> Self::Coroutine;
+}
+
+impl<'a, S, F, C> MakeCoroutine<'a, S> for F
+where
+    S: 'a,
+    F: FnMut(&'a S) -> C,
+    C: Coroutine<()> + 'a,
+{
+    type Coroutine = C;
+
+    fn make(&mut self, state: &'a S) -> Self::Coroutine {
+        self(state)
+    }
+}
+
+/// Abort a coroutine after every possible number of resumes like `Sweep`
+/// does for futures. The coroutine is resumed with `()` and the maximum
+/// number of resumes is taken from the current profile. Unlike `Sweep`
+/// no executor is needed.
+///
+/// This is `CoroutineSweep::report` with the default settings.
+pub fn sweep<S, Setup, Make, Check, R>(setup: Setup, make: Make, check: Check) -> Report
+where
+    Setup: FnMut() -> S,
+    Make: for<'a> MakeCoroutine<'a, S>,
+    Check: FnMut(&S) -> R,
+    R: CheckResult,
+{
+    CoroutineSweep::new().report(setup, make, check)
+}
+
+/// Settings of a sweep over the resumes of a coroutine. See `sweep`.
+#[derive(Clone, Debug)]
+pub struct CoroutineSweep {
+    name: Option<String>,
+    max_resumes: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl CoroutineSweep {
+    /// Create a sweep taking the maximum number of resumes from the
+    /// current profile.
+    pub fn new() -> Self {
+        Self {
+            name: None,
+            max_resumes: Profile::from_env().unwrap_or_default().settings().max_polls,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Set the name of the scenario. The name is included in the report.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the maximum number of resumes.
+    pub fn max_resumes(mut self, max_resumes: usize) -> Self {
+        self.max_resumes = max_resumes;
+        self
+    }
+
+    /// Set the clock used to measure the time of every abort point.
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Abort the coroutine after every number of resumes until it
+    /// completes and return the report.
+    pub fn report<S, Setup, Make, Check, R>(&self, mut setup: Setup, mut make: Make, mut check: Check) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeCoroutine<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let mut report = Report {
+            name: self.name.clone(),
+            max_polls: self.max_resumes,
+            ..Report::default()
+        };
+        for max_polls in 0..=self.max_resumes {
+            let start = self.clock.now();
+            let state = setup();
+            let (completed, num_resumes) = {
+                let mut coroutine = pin!(abort_coroutine(make.make(&state), max_polls));
+                loop {
+                    if let CoroutineState::Complete(result) = coroutine.as_mut().resume(()) {
+                        break (result.is_ok(), coroutine.num_resumes());
+                    }
+                }
+            };
+            let (failure, invariant_errors) = invariant::evaluate(&mut check, &state);
+            report.points.push(PointReport {
+                max_polls,
+                completed,
+                never_polled: !completed && num_resumes == 0,
+                failure,
+                elapsed: self.clock.now().saturating_duration_since(start),
+                invariant_errors,
+                reason: (!completed).then(|| AbortReason::Dropped.to_string()),
+                ..PointReport::default()
+            });
+            if completed {
+                report.num_polls = Some(num_resumes);
+                break;
+            }
+        }
+        registry::record(&report);
+        report
+    }
+}
+
+impl Default for CoroutineSweep {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::ops::{Coroutine, CoroutineState};
+    use std::pin::Pin;
+    use std::time::Duration;
+
+    use super::{abort_coroutine, sweep, CoroutineSweep};
+    use crate::{CounterGuard, ManualClock, ScopedCounter, Settled};
+
+    /// Holds a reference into its own state across every yield.
+    fn self_referential(count: &Cell<usize>) -> impl Coroutine<(), Yield = (), Return = ()> + '_ {
+        #[coroutine]
+        static move || {
+            let mut buf = [0u8; 3];
+            let slots = &mut buf;
+            for slot in slots.iter_mut() {
+                count.set(count.get() + 1);
+                yield;
+                *slot = 1;
+                count.set(count.get() - 1);
+            }
+            assert_eq!(buf, [1; 3]);
+        }
+    }
+
+    // Checked against the aliasing model by `check-unsafe.sh`.
+    #[test]
+    fn self_referential_coroutine() {
+        let report = sweep(|| Cell::new(0), self_referential, |_| {});
+        assert_eq!(report.num_polls, Some(4));
+    }
+
+    /// Coroutine which yields twice while the counter is entered.
+    struct Entering<'a> {
+        counter: &'a ScopedCounter,
+        entered: Option<CounterGuard<'a>>,
+        yields: usize,
+    }
+
+    impl Coroutine<()> for Entering<'_> {
+        type Yield = usize;
+        type Return = ();
+
+        fn resume(mut self: Pin<&mut Self>, _: ()) -> CoroutineState<usize, ()> {
+            if self.yields == 2 {
+                self.entered = None;
+                return CoroutineState::Complete(());
+            }
+            let counter = self.counter;
+            self.entered.get_or_insert_with(|| counter.enter());
+            self.yields += 1;
+            CoroutineState::Yielded(self.yields)
+        }
+    }
+
+    #[test]
+    fn coroutine_sweep() {
+        let counter = ScopedCounter::new();
+        let mut coroutine = Box::pin(abort_coroutine(Entering { counter: &counter, entered: None, yields: 0 }, 1));
+        assert!(matches!(coroutine.as_mut().resume(()), CoroutineState::Yielded(1)));
+        assert_eq!(counter.get(), 1);
+        assert!(matches!(coroutine.as_mut().resume(()), CoroutineState::Complete(Err(_))));
+        assert!(coroutine.is_aborted());
+        assert_eq!(counter.get(), 0);
+        fn make(counter: &ScopedCounter) -> Entering<'_> {
+            Entering { counter, entered: None, yields: 0 }
+        }
+        let report = sweep(ScopedCounter::new, make, ScopedCounter::assert_settled);
+        assert!(report.is_safe());
+        assert_eq!(report.num_polls, Some(3));
+        let clock = ManualClock::new();
+        let report = CoroutineSweep::new()
+            .name("entering")
+            .clock(clock.clone())
+            .report(ScopedCounter::new, make, |_| clock.advance(Duration::from_secs(1)));
+        assert_eq!(report.name.as_deref(), Some("entering"));
+        assert!(report.points.iter().all(|point| point.elapsed == Duration::from_secs(1)));
+    }
+}
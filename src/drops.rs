@@ -0,0 +1,206 @@
+//! Values recording that they were dropped.
+//!
+//! Moving a `DropSpy` into a future and aborting the future checks that
+//! the future dropped the values it captured. `DropCounter` does the same
+//! for any number of values, e.g. one per spawned subtask.
+//!
+//! ```rust
+//! use futures_test_abort::{abort, after, DropSpy};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let (spy, probe) = DropSpy::new();
+//! let future = async move {
+//!     let _spy = spy;
+//!     after((), 1).await;
+//! };
+//! abort(future, 1).await.unwrap_err();
+//! probe.assert_dropped();
+//! # }
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::sync::Settled;
+
+/// Source of the drop order shared by all spies of the process.
+static ORDER: AtomicUsize = AtomicUsize::new(1);
+
+/// Value which records in its `DropProbe` that it was dropped.
+#[derive(Debug)]
+pub struct DropSpy {
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Probe telling whether and when its `DropSpy` was dropped.
+#[derive(Clone, Debug)]
+pub struct DropProbe {
+    dropped: Arc<AtomicUsize>,
+}
+
+impl DropSpy {
+    /// Create a spy and the probe observing it.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> (DropSpy, DropProbe) {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        (
+            DropSpy {
+                dropped: dropped.clone(),
+            },
+            DropProbe { dropped },
+        )
+    }
+}
+
+impl Drop for DropSpy {
+    fn drop(&mut self) {
+        self.dropped.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+impl DropProbe {
+    /// Returns `true` if the spy was dropped.
+    pub fn is_dropped(&self) -> bool {
+        self.dropped_at().is_some()
+    }
+
+    /// Position of the drop among the drops of all spies of the process
+    /// or `None` if the spy is alive. Only the relative order of two
+    /// positions is meaningful, e.g. to assert that a guard was dropped
+    /// before the connection it belongs to.
+    pub fn dropped_at(&self) -> Option<usize> {
+        match self.dropped.load(Ordering::SeqCst) {
+            0 => None,
+            order => Some(order),
+        }
+    }
+
+    /// Panics unless the spy was dropped.
+    pub fn assert_dropped(&self) {
+        assert!(self.is_dropped(), "DropSpy was not dropped");
+    }
+
+    /// Panics unless the spy is still alive.
+    pub fn assert_alive(&self) {
+        assert!(!self.is_dropped(), "DropSpy was dropped");
+    }
+}
+
+impl Settled for DropProbe {
+    fn is_settled(&self) -> bool {
+        self.is_dropped()
+    }
+}
+
+/// Counter of the tokens it created and of the ones which were dropped.
+///
+/// Clones share the counts.
+#[derive(Clone, Default)]
+pub struct DropCounter {
+    counts: Arc<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    created: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+/// Token created by `DropCounter::token`.
+#[must_use]
+#[derive(Debug)]
+pub struct DropToken {
+    counts: Arc<Counts>,
+}
+
+impl DropCounter {
+    /// Create a counter without tokens.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a token which is counted as dropped once it is dropped.
+    pub fn token(&self) -> DropToken {
+        self.counts.created.fetch_add(1, Ordering::SeqCst);
+        DropToken {
+            counts: self.counts.clone(),
+        }
+    }
+
+    /// Number of tokens created so far.
+    pub fn created(&self) -> usize {
+        self.counts.created.load(Ordering::SeqCst)
+    }
+
+    /// Number of tokens dropped so far.
+    pub fn dropped(&self) -> usize {
+        self.counts.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Number of tokens which are still alive.
+    pub fn alive(&self) -> usize {
+        self.created() - self.dropped()
+    }
+}
+
+impl Drop for DropToken {
+    fn drop(&mut self) {
+        self.counts.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Counts")
+            .field("created", &self.created.load(Ordering::SeqCst))
+            .field("dropped", &self.dropped.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+impl fmt::Debug for DropCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DropCounter")
+            .field("created", &self.created())
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl Settled for DropCounter {
+    fn is_settled(&self) -> bool {
+        self.alive() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DropCounter, DropSpy};
+    use crate::{abort, after, Settled};
+
+    #[tokio::test]
+    async fn drop_spies() {
+        let (first, first_probe) = DropSpy::new();
+        let (second, second_probe) = DropSpy::new();
+        let counter = DropCounter::new();
+        let future = {
+            let tokens = (counter.token(), counter.token());
+            async move {
+                let _second = second;
+                let _first = first;
+                let _tokens = tokens;
+                after((), 1).await;
+            }
+        };
+        let mut future = Box::pin(abort(future, 1));
+        assert!(future.as_mut().await.is_err());
+        first_probe.assert_alive();
+        assert_eq!((counter.created(), counter.alive()), (2, 2));
+        drop(future);
+        first_probe.assert_dropped();
+        assert!(first_probe.dropped_at() < second_probe.dropped_at());
+        assert!(counter.is_settled());
+    }
+}
@@ -0,0 +1,160 @@
+//! Intentionally cancel-unsafe futures.
+//!
+//! Every fixture in this module is guaranteed to be flagged by a `Sweep`
+//! when used together with its `check` method. They are useful to verify
+//! that invariant checks and CI wiring actually detect failures:
+//!
+//! ```rust
+//! use futures_test_abort::{examples, Sweep};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let report = Sweep::new()
+//!     .report(
+//!         examples::leaky_counter,
+//!         examples::LeakyCounter::run,
+//!         examples::LeakyCounter::check,
+//!     )
+//!     .await;
+//! assert!(!report.is_safe());
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::after;
+
+/// Counter which is incremented before and decremented after an await
+/// point without using a guard.
+#[derive(Debug, Default)]
+pub struct LeakyCounter {
+    count: AtomicUsize,
+}
+
+impl LeakyCounter {
+    /// Current value of the counter.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Increment the counter, yield and decrement the counter again.
+    pub async fn run(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        after((), 1).await;
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Panics unless the counter is back at `0`.
+    pub fn check(&self) {
+        assert_eq!(self.count(), 0, "counter leaked");
+    }
+}
+
+/// Create a `LeakyCounter`.
+pub fn leaky_counter() -> LeakyCounter {
+    LeakyCounter::default()
+}
+
+/// Queue of messages which are taken out of the queue before an await
+/// point and only delivered afterwards.
+#[derive(Debug)]
+pub struct LostMessage {
+    queue: Mutex<VecDeque<usize>>,
+    delivered: Mutex<Vec<usize>>,
+    num_messages: usize,
+}
+
+impl LostMessage {
+    /// Messages which have been delivered.
+    pub fn delivered(&self) -> Vec<usize> {
+        self.delivered.lock().unwrap().clone()
+    }
+
+    /// Take one message from the queue, yield and deliver it.
+    pub async fn run(&self) {
+        let message = self.queue.lock().unwrap().pop_front();
+        after((), 1).await;
+        if let Some(message) = message {
+            self.delivered.lock().unwrap().push(message);
+        }
+    }
+
+    /// Panics unless every message is either queued or delivered.
+    pub fn check(&self) {
+        let queued = self.queue.lock().unwrap().len();
+        let delivered = self.delivered.lock().unwrap().len();
+        assert_eq!(queued + delivered, self.num_messages, "message lost");
+    }
+}
+
+/// Create a `LostMessage` with a single queued message.
+pub fn lost_message() -> LostMessage {
+    LostMessage {
+        queue: Mutex::new(vec![0].into()),
+        delivered: Mutex::default(),
+        num_messages: 1,
+    }
+}
+
+/// Lock which is acquired before and released after an await point
+/// without using a guard.
+#[derive(Debug, Default)]
+pub struct HeldLock {
+    locked: AtomicBool,
+}
+
+impl HeldLock {
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Acquire the lock, yield and release it.
+    pub async fn run(&self) {
+        assert!(!self.locked.swap(true, Ordering::Relaxed), "lock already held");
+        after((), 1).await;
+        self.locked.store(false, Ordering::Relaxed);
+    }
+
+    /// Panics if the lock is still held.
+    pub fn check(&self) {
+        assert!(!self.is_locked(), "lock still held");
+    }
+}
+
+/// Create a `HeldLock`.
+pub fn held_lock() -> HeldLock {
+    HeldLock::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{held_lock, leaky_counter, lost_message, HeldLock, LeakyCounter, LostMessage};
+    use crate::Sweep;
+
+    #[tokio::test]
+    async fn leaky_counter_flagged() {
+        let report = Sweep::new()
+            .report(leaky_counter, LeakyCounter::run, LeakyCounter::check)
+            .await;
+        assert_eq!(report.summary().num_unsafe, 1);
+    }
+
+    #[tokio::test]
+    async fn lost_message_flagged() {
+        let report = Sweep::new()
+            .report(lost_message, LostMessage::run, LostMessage::check)
+            .await;
+        assert_eq!(report.summary().num_unsafe, 1);
+    }
+
+    #[tokio::test]
+    async fn held_lock_flagged() {
+        let report = Sweep::new()
+            .report(held_lock, HeldLock::run, HeldLock::check)
+            .await;
+        assert_eq!(report.summary().num_unsafe, 1);
+    }
+}
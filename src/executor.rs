@@ -0,0 +1,548 @@
+//! Deterministic executor for multiple tasks.
+//!
+//! Bugs which involve several tasks and a cancellation often depend on the
+//! order in which the tasks run. The `Executor` runs tasks on the current
+//! thread in a scriptable or seeded order and can abort a task after a
+//! given number of polls, so such bugs can be reproduced deterministically.
+//! Only tasks which were woken are polled like a real executor would do.
+//!
+//! For a single future `block_on` and `Stepper` need no runtime at all.
+//! A `Stepper` polls the future one step at a time so the state it
+//! borrows can be inspected between the polls.
+//!
+//! ```rust
+//! use std::cell::Cell;
+//!
+//! use futures_test_abort::{after, Stepper};
+//!
+//! let count = Cell::new(0);
+//! let mut stepper = Stepper::new(async {
+//!     count.set(1);
+//!     after((), 1).await;
+//!     count.set(2);
+//! });
+//! assert!(stepper.step().is_pending());
+//! assert_eq!(count.get(), 1);
+//! assert!(stepper.is_woken());
+//! assert!(stepper.step().is_ready());
+//! assert_eq!(count.get(), 2);
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use crate::invariant::{self, CheckResult};
+use crate::rng::RandomSources;
+
+/// Order in which the `Executor` picks the next task among the woken ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Order {
+    /// Cycle through the tasks in the order they were spawned.
+    RoundRobin,
+    /// Pick a random task. The same seed produces the same order.
+    Random(u64),
+    /// Run a different task than the last one whenever possible. This
+    /// maximizes interleaving, e.g. a woken task never runs right after
+    /// the task which woke it if another task is ready.
+    Adversarial,
+    /// Run the tasks in the given order. Entries of tasks which are not
+    /// ready are skipped. Once the script is exhausted the executor falls
+    /// back to `RoundRobin`.
+    Script(Vec<usize>),
+}
+
+/// Result of `Executor::run`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Execution {
+    /// Ids of the tasks in the order they were polled.
+    pub polled: Vec<usize>,
+    /// Tasks which completed.
+    pub completed: Vec<usize>,
+    /// Tasks which were aborted via `Executor::abort_after`.
+    pub aborted: Vec<usize>,
+    /// Tasks which were never woken again or did not complete within the
+    /// maximum number of polls.
+    pub stuck: Vec<usize>,
+}
+
+impl Execution {
+    /// Returns `true` if any task is stuck.
+    pub fn is_stuck(&self) -> bool {
+        !self.stuck.is_empty()
+    }
+}
+
+struct Task<'a> {
+    future: Pin<Box<dyn Future<Output = ()> + 'a>>,
+    flag: Arc<Flag>,
+    num_polls: usize,
+    abort_after: Option<usize>,
+}
+
+/// Executor running tasks in a deterministic order.
+pub struct Executor<'a> {
+    order: Order,
+    random_sources: Option<RandomSources>,
+    max_polls: usize,
+    tasks: Vec<Option<Task<'a>>>,
+}
+
+impl<'a> Executor<'a> {
+    /// Create an executor which runs tasks in the given order.
+    pub fn new(order: Order) -> Self {
+        Self {
+            order,
+            random_sources: None,
+            max_polls: 10_000,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Set the maximum number of polls of all tasks together.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Draw the picks of `Order::Random` from the given sources instead of
+    /// the `Rng` substreams of its seed.
+    pub fn random_sources(mut self, random_sources: RandomSources) -> Self {
+        self.random_sources = Some(random_sources);
+        self
+    }
+
+    /// Add a task and return its id. Ids start at `0`.
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'a) -> usize {
+        self.tasks.push(Some(Task {
+            future: Box::pin(future),
+            flag: Arc::new(Flag(AtomicBool::new(true))),
+            num_polls: 0,
+            abort_after: None,
+        }));
+        self.tasks.len() - 1
+    }
+
+    /// Abort (drop) the task after it was polled the given number of
+    /// times.
+    pub fn abort_after(&mut self, task: usize, num_polls: usize) {
+        self.tasks[task].as_mut().expect("task already finished").abort_after = Some(num_polls);
+    }
+
+    /// Run the tasks until all of them completed, were aborted or are
+    /// stuck.
+    pub fn run(&mut self) -> Execution {
+        let mut execution = Execution::default();
+        let mut rng = match self.order {
+            Order::Random(seed) => {
+                let sources = self.random_sources.clone().unwrap_or_else(|| RandomSources::seeded(seed));
+                Some(sources.substream("executor"))
+            }
+            _ => None,
+        };
+        let mut script = match &self.order {
+            Order::Script(script) => script.clone().into_iter(),
+            _ => Vec::new().into_iter(),
+        };
+        let mut last = None;
+        for (id, task) in self.tasks.iter_mut().enumerate() {
+            if task.as_ref().is_some_and(|task| task.abort_after == Some(0)) {
+                *task = None;
+                execution.aborted.push(id);
+            }
+        }
+        while execution.polled.len() < self.max_polls {
+            let ready: Vec<usize> = (0..self.tasks.len())
+                .filter(|&id| self.tasks[id].as_ref().is_some_and(|task| task.flag.0.load(Ordering::SeqCst)))
+                .collect();
+            if ready.is_empty() {
+                break;
+            }
+            let next_round_robin = || {
+                let start = last.map_or(0, |last| last + 1);
+                *ready.iter().find(|&&id| id >= start).unwrap_or(&ready[0])
+            };
+            let id = match &self.order {
+                Order::RoundRobin => next_round_robin(),
+                Order::Random(_) => ready[rng.as_mut().unwrap().below(ready.len())],
+                Order::Adversarial => *ready.iter().find(|&&id| Some(id) != last).unwrap_or(&ready[0]),
+                Order::Script(_) => script.by_ref().find(|id| ready.contains(id)).unwrap_or_else(next_round_robin),
+            };
+            last = Some(id);
+            execution.polled.push(id);
+            let task = self.tasks[id].as_mut().unwrap();
+            task.flag.0.store(false, Ordering::SeqCst);
+            task.num_polls += 1;
+            let waker = Waker::from(task.flag.clone());
+            if task.future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+                self.tasks[id] = None;
+                execution.completed.push(id);
+            } else if task.abort_after == Some(task.num_polls) {
+                self.tasks[id] = None;
+                execution.aborted.push(id);
+            }
+        }
+        execution.stuck = (0..self.tasks.len()).filter(|&id| self.tasks[id].is_some()).collect();
+        execution
+    }
+}
+
+impl fmt::Debug for Executor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Executor")
+            .field("order", &self.order)
+            .field("max_polls", &self.max_polls)
+            .field("tasks", &self.tasks.len())
+            .finish()
+    }
+}
+
+/// Bounded search for a scheduling order which breaks an invariant.
+///
+/// The first task spawned by the `spawn` closure is aborted after every
+/// possible number of polls. For every abort point several orders are
+/// tried: `Order::Adversarial` first as it maximizes the interleaving of
+/// the cleanup of the aborted task with the other tasks, then
+/// `Order::RoundRobin` and finally random orders derived from the seed.
+/// The exact schedule of every failure is reported for replay via
+/// `Order::Script`.
+#[derive(Clone, Debug)]
+pub struct Search {
+    orders: usize,
+    seed: u64,
+    max_polls: usize,
+}
+
+/// Failure found by `Search`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduleFailure {
+    /// Number of polls after which the first task was aborted.
+    pub abort_after: usize,
+    /// Order in which the tasks were polled. Replay it with
+    /// `Order::Script`.
+    pub schedule: Vec<usize>,
+    /// Message of the failed check.
+    pub message: String,
+}
+
+/// Result of `Search::run`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchReport {
+    /// Number of runs of the executor.
+    pub num_runs: usize,
+    /// All failures found.
+    pub failures: Vec<ScheduleFailure>,
+}
+
+impl SearchReport {
+    /// Returns `true` if no failure was found.
+    pub fn is_safe(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Panic with the schedule of the first failure unless `is_safe`.
+    pub fn assert_safe(&self) {
+        if let Some(failure) = self.failures.first() {
+            panic!(
+                "check failed with the first task aborted after {} polls: {}\nreplay with Order::Script(vec!{:?})",
+                failure.abort_after, failure.message, failure.schedule
+            );
+        }
+    }
+}
+
+impl Search {
+    /// Create a search trying 8 orders per abort point.
+    pub fn new() -> Self {
+        Self {
+            orders: 8,
+            seed: 0,
+            max_polls: 10_000,
+        }
+    }
+
+    /// Set the number of orders tried per abort point.
+    pub fn orders(mut self, orders: usize) -> Self {
+        self.orders = orders;
+        self
+    }
+
+    /// Set the seed of the random orders.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Set the maximum number of polls of every run.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    fn order(&self, index: usize) -> Order {
+        match index {
+            0 => Order::Adversarial,
+            1 => Order::RoundRobin,
+            n => Order::Random(self.seed.wrapping_add(n as u64)),
+        }
+    }
+
+    /// Run the search and panic if a failure was found.
+    pub fn run<S, Setup, Spawn, Check, R>(&self, setup: Setup, spawn: Spawn, check: Check) -> SearchReport
+    where
+        Setup: FnMut() -> S,
+        Spawn: for<'a> FnMut(&'a S, &mut Executor<'a>),
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let report = self.report(setup, spawn, check);
+        report.assert_safe();
+        report
+    }
+
+    /// Run the search and return all failures. Stuck tasks are reported
+    /// as failure, too.
+    pub fn report<S, Setup, Spawn, Check, R>(
+        &self,
+        mut setup: Setup,
+        mut spawn: Spawn,
+        mut check: Check,
+    ) -> SearchReport
+    where
+        Setup: FnMut() -> S,
+        Spawn: for<'a> FnMut(&'a S, &mut Executor<'a>),
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let mut report = SearchReport::default();
+        for abort_after in 0.. {
+            let mut first_completed = true;
+            for index in 0..self.orders {
+                let state = setup();
+                let execution = {
+                    let mut executor = Executor::new(self.order(index)).max_polls(self.max_polls);
+                    spawn(&state, &mut executor);
+                    executor.abort_after(0, abort_after);
+                    executor.run()
+                };
+                report.num_runs += 1;
+                first_completed &= execution.completed.contains(&0);
+                let (failure, _) = invariant::evaluate(&mut check, &state);
+                let message = match failure {
+                    Some(failure) => failure,
+                    None if execution.is_stuck() => format!("tasks {:?} are stuck", execution.stuck),
+                    None => continue,
+                };
+                report.failures.push(ScheduleFailure {
+                    abort_after,
+                    schedule: execution.polled,
+                    message,
+                });
+            }
+            // Stop once the first task completes before it can be aborted
+            // in every order.
+            if first_completed || abort_after >= self.max_polls {
+                break;
+            }
+        }
+        report
+    }
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a future to completion on the current thread. The thread is
+/// parked while the future is pending, so a future which is never woken
+/// blocks forever.
+pub fn block_on<F>(future: F) -> F::Output
+where
+    F: Future,
+{
+    struct Unpark(Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Poll a future one step at a time.
+///
+/// Unlike `block_on` the caller decides when the future is polled, so the
+/// state it borrows can be inspected between the polls. The future is
+/// aborted by dropping the stepper.
+pub struct Stepper<'a, T> {
+    future: Option<Pin<Box<dyn Future<Output = T> + 'a>>>,
+    flag: Arc<Flag>,
+    num_polls: usize,
+}
+
+impl<'a, T> Stepper<'a, T> {
+    /// Create a stepper for `future`. It is not polled until `step` is
+    /// called.
+    pub fn new(future: impl Future<Output = T> + 'a) -> Self {
+        Self {
+            future: Some(Box::pin(future)),
+            flag: Arc::new(Flag(AtomicBool::new(true))),
+            num_polls: 0,
+        }
+    }
+
+    /// Poll the future once. Panics if it already completed.
+    pub fn step(&mut self) -> Poll<T> {
+        let future = self.future.as_mut().expect("stepper polled after completion");
+        self.flag.0.store(false, Ordering::SeqCst);
+        self.num_polls += 1;
+        let waker = Waker::from(self.flag.clone());
+        let result = future.as_mut().poll(&mut Context::from_waker(&waker));
+        if result.is_ready() {
+            self.future = None;
+        }
+        result
+    }
+
+    /// Poll the future while it wakes itself and at most `max_polls`
+    /// times. Returns the output if it completed.
+    pub fn run_until_stalled(&mut self, max_polls: usize) -> Poll<T> {
+        for _ in 0..max_polls {
+            if !self.is_woken() {
+                break;
+            }
+            if let Poll::Ready(output) = self.step() {
+                return Poll::Ready(output);
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Returns `true` if the future was woken since the last poll. A new
+    /// stepper counts as woken.
+    pub fn is_woken(&self) -> bool {
+        self.flag.0.load(Ordering::SeqCst)
+    }
+
+    /// Number of times the future has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+
+    /// Returns `true` if the future completed.
+    pub fn is_completed(&self) -> bool {
+        self.future.is_none()
+    }
+}
+
+impl<T> fmt::Debug for Stepper<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stepper")
+            .field("num_polls", &self.num_polls)
+            .field("woken", &self.is_woken())
+            .field("completed", &self.is_completed())
+            .finish()
+    }
+}
+
+/// Waker which remembers that it was woken.
+pub(crate) struct Flag(pub(crate) AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::future::poll_fn;
+    use std::task::Poll;
+
+    use super::{Executor, Order, Search};
+    use crate::{abort, after, never};
+    use crate::test_util::{count_unsafe, Counter};
+
+    /// Takes the token, yields and puts it back. Aborting the task while it
+    /// holds the token makes the other task wait forever.
+    async fn use_token(token: &Cell<bool>) {
+        poll_fn(|cx| {
+            if token.replace(false) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+        after((), 1).await;
+        token.set(true);
+    }
+
+    #[test]
+    fn executor_order() {
+        let token = Cell::new(true);
+        let run = |order: Order, abort: Option<usize>| {
+            let mut executor = Executor::new(order).max_polls(20);
+            executor.spawn(use_token(&token));
+            executor.spawn(use_token(&token));
+            if let Some(polls) = abort {
+                executor.abort_after(0, polls);
+            }
+            token.set(true);
+            executor.run()
+        };
+        assert_eq!(run(Order::RoundRobin, None).polled, [0, 1, 0, 1, 1]);
+        assert_eq!(run(Order::Script(vec![0, 0, 1]), None).polled, [0, 0, 1, 1]);
+        assert_eq!(run(Order::Random(7), None), run(Order::Random(7), None));
+        let execution = run(Order::RoundRobin, Some(1));
+        assert_eq!(execution.aborted, [0]);
+        assert_eq!(execution.stuck, [1]);
+    }
+
+    #[test]
+    fn executor_search() {
+        fn spawn<'a>(token: &'a Cell<bool>, executor: &mut Executor<'a>) {
+            executor.spawn(use_token(token));
+            executor.spawn(use_token(token));
+        }
+        let report = Search::new().orders(3).report(|| Cell::new(true), spawn, |token| assert!(token.get()));
+        assert_eq!(report.num_runs, 9);
+        let failure = &report.failures[0];
+        assert_eq!((failure.abort_after, failure.message.as_str()), (1, "assertion failed: token.get()"));
+        let token = Cell::new(true);
+        let mut executor = Executor::new(Order::Script(failure.schedule.clone()));
+        spawn(&token, &mut executor);
+        executor.abort_after(0, failure.abort_after);
+        assert_eq!(executor.run().polled, failure.schedule);
+    }
+
+    #[test]
+    fn stepper_without_runtime() {
+        let counter = Counter { count: Cell::new(0), started: Cell::new(0) };
+        let mut stepper = crate::Stepper::new(count_unsafe(&counter));
+        assert!(stepper.step().is_pending());
+        assert_eq!(counter.count.get(), 1);
+        assert!(stepper.run_until_stalled(10).is_ready());
+        assert_eq!((stepper.num_polls(), counter.count.get()), (3, 0));
+        assert!(stepper.is_completed());
+        let mut stepper = crate::Stepper::new(never());
+        assert!(stepper.run_until_stalled(10).is_pending());
+        assert_eq!(stepper.num_polls(), 10);
+        assert!(stepper.is_woken());
+        assert!(crate::block_on(abort(never(), 3)).is_err());
+    }
+}
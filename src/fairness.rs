@@ -0,0 +1,639 @@
+//! Fairness of wakeups after a waiter is aborted.
+//!
+//! Synchronization primitives like mutexes, semaphores and notifications
+//! keep a queue of waiting tasks. If a waiter is aborted while it is queued
+//! the wakeup meant for it must be passed on, otherwise the remaining
+//! waiters are never woken. `Fairness` lets several waiters wait on a
+//! shared primitive, aborts one of them, releases the primitive and checks
+//! that all remaining waiters are woken and complete within a bounded
+//! number of polls. Waiters are only polled after they were woken like a
+//! real executor would do, so a lost wakeup is detected reliably.
+//!
+//! `RwFairness` does the same for read-write locks, whose writers are the
+//! waiters that get aborted, using an `InstrumentedRwLock` which tracks
+//! the queued readers and writers.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::executor::Flag;
+use crate::future::Pinned;
+use crate::harness::MakeFuture;
+
+/// Fairness check of a shared primitive.
+#[derive(Clone, Copy, Debug)]
+pub struct Fairness {
+    waiters: usize,
+    max_polls: usize,
+}
+
+impl Fairness {
+    /// Check the primitive with the given number of waiters. The remaining
+    /// waiters must complete within `10 * waiters` polls by default.
+    pub fn new(waiters: usize) -> Self {
+        assert!(waiters >= 2, "at least two waiters are needed");
+        Self {
+            waiters,
+            max_polls: 10 * waiters,
+        }
+    }
+
+    /// Set the number of polls the remaining waiters may take in total
+    /// after the primitive was released.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Run the check for every waiter being aborted and panic on failure.
+    pub fn run<S, Setup, Make, Release>(&self, setup: Setup, make: Make, release: Release)
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Release: FnMut(&S),
+    {
+        if let Err(msg) = self.check(setup, make, release) {
+            panic!("{}", msg);
+        }
+    }
+
+    /// Run the check for every waiter being aborted.
+    ///
+    /// For every waiter a fresh state is created and all waiters are polled
+    /// once in order. They must all be pending. Then the waiter is aborted,
+    /// `release` is called and the woken waiters are polled until all of
+    /// them completed. Returns a description of the first failure.
+    pub fn check<S, Setup, Make, Release>(
+        &self,
+        mut setup: Setup,
+        mut make: Make,
+        mut release: Release,
+    ) -> Result<(), String>
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Release: FnMut(&S),
+    {
+        for aborted in 0..self.waiters {
+            let state = setup();
+            let mut waiters = Vec::with_capacity(self.waiters);
+            for index in 0..self.waiters {
+                let flag = Arc::new(Flag(AtomicBool::new(false)));
+                let mut future: Pin<Box<dyn Future<Output = _>>> = Box::pin(make.make(&state));
+                let waker = Waker::from(flag.clone());
+                if future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+                    return Err(format!("waiter {} completed before the primitive was released", index));
+                }
+                waiters.push(Some((index, future, flag)));
+            }
+            waiters[aborted] = None;
+            release(&state);
+            let mut num_polls = 0;
+            while waiters.iter().any(Option::is_some) {
+                let woken: Vec<usize> = (0..waiters.len())
+                    .filter(|&i| waiters[i].as_ref().is_some_and(|(_, _, flag)| flag.0.swap(false, Ordering::SeqCst)))
+                    .collect();
+                if woken.is_empty() {
+                    let pending: Vec<String> = waiters.iter().flatten().map(|(index, _, _)| index.to_string()).collect();
+                    return Err(format!(
+                        "lost wakeup after waiter {} was aborted: waiters {} are never woken",
+                        aborted,
+                        pending.join(", ")
+                    ));
+                }
+                for i in woken {
+                    if num_polls == self.max_polls {
+                        return Err(format!(
+                            "starvation after waiter {} was aborted: waiters did not complete within {} polls",
+                            aborted, self.max_polls
+                        ));
+                    }
+                    num_polls += 1;
+                    let (_, future, flag) = waiters[i].as_mut().unwrap();
+                    let waker = Waker::from(flag.clone());
+                    if future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+                        waiters[i] = None;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Async read-write lock checked by `RwFairness`.
+///
+/// Implement it for the lock under test, boxing the futures if they
+/// can't be named:
+///
+/// ```rust,ignore
+/// impl<T> AsyncRwLock for tokio::sync::RwLock<T> {
+///     type Read<'a> = Pin<Box<dyn Future<Output = RwLockReadGuard<'a, T>> + 'a>> where T: 'a;
+///     type Write<'a> = Pin<Box<dyn Future<Output = RwLockWriteGuard<'a, T>> + 'a>> where T: 'a;
+///     fn read(&self) -> Self::Read<'_> { Box::pin(self.read()) }
+///     fn write(&self) -> Self::Write<'_> { Box::pin(self.write()) }
+/// }
+/// ```
+pub trait AsyncRwLock {
+    /// Future resolving to a read guard.
+    type Read<'a>: Future + 'a
+    where
+        Self: 'a;
+    /// Future resolving to a write guard.
+    type Write<'a>: Future + 'a
+    where
+        Self: 'a;
+
+    /// Acquire the lock for reading.
+    fn read(&self) -> Self::Read<'_>;
+
+    /// Acquire the lock for writing.
+    fn write(&self) -> Self::Write<'_>;
+}
+
+/// Number of queued and holding readers and writers of an
+/// `InstrumentedRwLock`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RwLockStats {
+    /// Readers which were polled but did not acquire the lock yet.
+    pub queued_readers: usize,
+    /// Writers which were polled but did not acquire the lock yet.
+    pub queued_writers: usize,
+    /// Read guards which are alive.
+    pub readers: usize,
+    /// Write guards which are alive.
+    pub writers: usize,
+}
+
+impl RwLockStats {
+    /// Returns `true` if nobody is queued or holds the lock.
+    pub fn is_idle(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Wrapper of an `AsyncRwLock` which tracks queued readers and writers.
+///
+/// A reader or writer counts as queued from its first pending poll until
+/// it acquires the lock or is aborted, and as holding until its guard is
+/// dropped.
+#[derive(Debug, Default)]
+pub struct InstrumentedRwLock<L> {
+    lock: L,
+    stats: Mutex<RwLockStats>,
+}
+
+impl<L> InstrumentedRwLock<L>
+where
+    L: AsyncRwLock,
+{
+    /// Wrap `lock`.
+    pub fn new(lock: L) -> Self {
+        Self {
+            lock,
+            stats: Mutex::default(),
+        }
+    }
+
+    /// Current numbers of queued and holding readers and writers.
+    pub fn stats(&self) -> RwLockStats {
+        *self.stats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Acquire the wrapped lock for reading.
+    pub fn read(&self) -> Queued<'_, L::Read<'_>> {
+        Queued::new(&self.stats, false, self.lock.read())
+    }
+
+    /// Acquire the wrapped lock for writing.
+    pub fn write(&self) -> Queued<'_, L::Write<'_>> {
+        Queued::new(&self.stats, true, self.lock.write())
+    }
+}
+
+fn update(stats: &Mutex<RwLockStats>, f: impl FnOnce(&mut RwLockStats)) {
+    f(&mut stats.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+fn count(stats: &mut RwLockStats, write: bool, queued: bool) -> &mut usize {
+    match (write, queued) {
+        (false, true) => &mut stats.queued_readers,
+        (true, true) => &mut stats.queued_writers,
+        (false, false) => &mut stats.readers,
+        (true, false) => &mut stats.writers,
+    }
+}
+
+/// Future of a reader or writer of an `InstrumentedRwLock`.
+pub struct Queued<'a, T> {
+    stats: &'a Mutex<RwLockStats>,
+    write: bool,
+    queued: bool,
+    future: Pinned<T>,
+}
+
+impl<'a, T> Queued<'a, T> {
+    fn new(stats: &'a Mutex<RwLockStats>, write: bool, future: T) -> Self {
+        Self {
+            stats,
+            write,
+            queued: false,
+            future: Pinned::new(future),
+        }
+    }
+}
+
+impl<'a, T> Future for Queued<'a, T>
+where
+    T: Future,
+{
+    type Output = Held<'a, T::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let result = unsafe { me.future.as_pin_mut() }.poll(cx);
+        let (write, was_queued) = (me.write, me.queued);
+        me.queued = result.is_pending();
+        update(me.stats, |stats| {
+            if was_queued != me.queued {
+                let queued = count(stats, write, true);
+                *queued = if me.queued { *queued + 1 } else { *queued - 1 };
+            }
+            if result.is_ready() {
+                *count(stats, write, false) += 1;
+            }
+        });
+        result.map(|guard| Held {
+            stats: me.stats,
+            write,
+            guard,
+        })
+    }
+}
+
+impl<T> Drop for Queued<'_, T> {
+    fn drop(&mut self) {
+        if self.queued {
+            update(self.stats, |stats| *count(stats, self.write, true) -= 1);
+        }
+    }
+}
+
+/// Guard of an `InstrumentedRwLock` wrapping the guard of the lock.
+#[derive(Debug)]
+pub struct Held<'a, G> {
+    stats: &'a Mutex<RwLockStats>,
+    write: bool,
+    guard: G,
+}
+
+impl<G> Held<'_, G> {
+    /// Guard of the wrapped lock.
+    pub fn guard(&self) -> &G {
+        &self.guard
+    }
+}
+
+impl<G> Drop for Held<'_, G> {
+    fn drop(&mut self) {
+        update(self.stats, |stats| *count(stats, self.write, false) -= 1);
+    }
+}
+
+/// Fairness check of an `AsyncRwLock` when queued writers are aborted.
+///
+/// Write-preferring locks queue new readers behind a waiting writer. If
+/// the writer is aborted and the lock does not pass the wakeup on, the
+/// queued readers are starved and later writers wedge. Every scenario
+/// runs on a fresh `InstrumentedRwLock` and polls the futures only after
+/// they were woken like a real executor would do:
+///
+/// 1. A reader holds the lock, a writer and then a second reader queue
+///    up, and the writer is aborted. The second reader must acquire the
+///    lock while the first one still holds it.
+/// 2. A reader holds the lock, two writers queue up and the first writer
+///    is aborted. Once the reader releases the lock the second writer
+///    must acquire it.
+///
+/// Afterwards a fresh writer must acquire the lock and nobody may be
+/// queued anymore.
+#[derive(Clone, Copy, Debug)]
+pub struct RwFairness {
+    max_polls: usize,
+}
+
+impl RwFairness {
+    /// Create a check in which every waiter may take up to 10 polls.
+    pub fn new() -> Self {
+        Self { max_polls: 10 }
+    }
+
+    /// Set the number of polls every waiter may take.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Run the check and panic on failure.
+    pub fn run<L>(&self, setup: impl FnMut() -> L)
+    where
+        L: AsyncRwLock,
+    {
+        if let Err(msg) = self.check(setup) {
+            panic!("{}", msg);
+        }
+    }
+
+    /// Run all scenarios. Returns a description of the first failure.
+    pub fn check<L>(&self, mut setup: impl FnMut() -> L) -> Result<(), String>
+    where
+        L: AsyncRwLock,
+    {
+        let lock = InstrumentedRwLock::new(setup());
+        {
+            let reader = self.acquire(&lock, lock.read(), "first reader")?;
+            let mut writer = Waiter::new(lock.write());
+            if writer.poll().is_some() {
+                return Err("writer acquired the lock while a reader held it".into());
+            }
+            let mut second = Waiter::new(lock.read());
+            let second_guard = second.poll();
+            drop(writer);
+            let second_guard = match second_guard {
+                Some(guard) => guard,
+                None => second.drive(self.max_polls).ok_or_else(|| {
+                    starved(&lock, "queued reader was starved after the queued writer was aborted")
+                })?,
+            };
+            drop((reader, second_guard));
+        }
+        self.settled(&lock)?;
+        let lock = InstrumentedRwLock::new(setup());
+        {
+            let reader = self.acquire(&lock, lock.read(), "first reader")?;
+            let mut first = Waiter::new(lock.write());
+            let mut second = Waiter::new(lock.write());
+            if first.poll().is_some() || second.poll().is_some() {
+                return Err("writer acquired the lock while a reader held it".into());
+            }
+            drop(first);
+            drop(reader);
+            let guard = second.drive(self.max_polls).ok_or_else(|| {
+                starved(&lock, "write queue wedged: second writer never acquired the lock after the first one was aborted")
+            })?;
+            drop(guard);
+        }
+        self.settled(&lock)
+    }
+
+    fn acquire<'a, L, T>(&self, lock: &InstrumentedRwLock<L>, future: T, name: &str) -> Result<T::Output, String>
+    where
+        L: AsyncRwLock,
+        T: Future + 'a,
+    {
+        let mut waiter = Waiter::new(future);
+        match waiter.poll() {
+            Some(guard) => Ok(guard),
+            None => waiter
+                .drive(self.max_polls)
+                .ok_or_else(|| starved(lock, &format!("{} never acquired the idle lock", name))),
+        }
+    }
+
+    /// Check that a fresh writer acquires the lock and nobody is queued.
+    fn settled<L>(&self, lock: &InstrumentedRwLock<L>) -> Result<(), String>
+    where
+        L: AsyncRwLock,
+    {
+        drop(self.acquire(lock, lock.write(), "final writer")?);
+        let stats = lock.stats();
+        if !stats.is_idle() {
+            return Err(format!("lock not idle after all waiters finished: {:?}", stats));
+        }
+        Ok(())
+    }
+}
+
+impl Default for RwFairness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn starved<L>(lock: &InstrumentedRwLock<L>, message: &str) -> String
+where
+    L: AsyncRwLock,
+{
+    format!("{} ({:?})", message, lock.stats())
+}
+
+/// Future which is only polled after it was woken.
+struct Waiter<'a, T> {
+    future: Pin<Box<dyn Future<Output = T> + 'a>>,
+    flag: Arc<Flag>,
+}
+
+impl<'a, T> Waiter<'a, T> {
+    fn new(future: impl Future<Output = T> + 'a) -> Self {
+        Self {
+            future: Box::pin(future),
+            flag: Arc::new(Flag(AtomicBool::new(false))),
+        }
+    }
+
+    /// Poll the future once regardless of wakes.
+    fn poll(&mut self) -> Option<T> {
+        let waker = Waker::from(self.flag.clone());
+        match self.future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => Some(output),
+            Poll::Pending => None,
+        }
+    }
+
+    /// Poll the future whenever it was woken, at most `max_polls` times.
+    fn drive(&mut self, max_polls: usize) -> Option<T> {
+        for _ in 0..max_polls {
+            if !self.flag.0.swap(false, Ordering::SeqCst) {
+                return None;
+            }
+            if let Some(output) = self.poll() {
+                return Some(output);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    use super::{AsyncRwLock, Fairness, InstrumentedRwLock, RwFairness, RwLockStats};
+
+    /// Gate which hands the wakeup on from one waiter to the next.
+    #[derive(Default)]
+    struct Gate {
+        open: Cell<bool>,
+        wakers: RefCell<std::collections::VecDeque<Waker>>,
+    }
+
+    impl Gate {
+        fn wake_next(&self) {
+            if let Some(waker) = self.wakers.borrow_mut().pop_front() {
+                waker.wake();
+            }
+        }
+    }
+
+    async fn pass_gate(gate: &Gate) {
+        poll_fn(|cx| {
+            if gate.open.get() {
+                gate.wake_next();
+                return Poll::Ready(());
+            }
+            gate.wakers.borrow_mut().push_back(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn fairness_lost_wakeup() {
+        let fairness = Fairness::new(3);
+        let open_one = |gate: &Gate| {
+            gate.open.set(true);
+            gate.wake_next();
+        };
+        assert_eq!(
+            fairness.check(Gate::default, pass_gate, open_one).unwrap_err(),
+            "lost wakeup after waiter 0 was aborted: waiters 1, 2 are never woken"
+        );
+        let open_all = |gate: &Gate| {
+            gate.open.set(true);
+            gate.wakers.borrow_mut().drain(..).for_each(Waker::wake);
+        };
+        fairness.run(Gate::default, pass_gate, open_all);
+    }
+
+    /// Write-preferring lock. Unless `forget_aborted` is set an aborted
+    /// writer leaves the queue and wakes the other waiters.
+    #[derive(Default)]
+    struct TestRwLock {
+        forget_aborted: bool,
+        readers: Cell<usize>,
+        writer: Cell<bool>,
+        queue: RefCell<std::collections::VecDeque<usize>>,
+        next_id: Cell<usize>,
+        wakers: RefCell<Vec<Waker>>,
+    }
+
+    impl TestRwLock {
+        fn wake_all(&self) {
+            self.wakers.borrow_mut().drain(..).for_each(Waker::wake);
+        }
+
+        fn wait(&self, cx: &mut Context<'_>) -> Poll<()> {
+            self.wakers.borrow_mut().push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    struct TestRwGuard<'a>(&'a TestRwLock, bool);
+
+    impl Drop for TestRwGuard<'_> {
+        fn drop(&mut self) {
+            if self.1 {
+                self.0.writer.set(false);
+            } else {
+                self.0.readers.set(self.0.readers.get() - 1);
+            }
+            self.0.wake_all();
+        }
+    }
+
+    /// Removes a queued writer when it is dropped.
+    struct WriterTicket<'a>(&'a TestRwLock, Option<usize>);
+
+    impl Drop for WriterTicket<'_> {
+        fn drop(&mut self) {
+            let (lock, id) = (self.0, self.1);
+            if let (Some(id), false) = (id, lock.forget_aborted) {
+                lock.queue.borrow_mut().retain(|&queued| queued != id);
+                lock.wake_all();
+            }
+        }
+    }
+
+    impl AsyncRwLock for TestRwLock {
+        type Read<'a> = Pin<Box<dyn Future<Output = TestRwGuard<'a>> + 'a>>;
+        type Write<'a> = Pin<Box<dyn Future<Output = TestRwGuard<'a>> + 'a>>;
+
+        fn read(&self) -> Self::Read<'_> {
+            Box::pin(poll_fn(move |cx| {
+                if self.writer.get() || !self.queue.borrow().is_empty() {
+                    return self.wait(cx).map(|()| unreachable!());
+                }
+                self.readers.set(self.readers.get() + 1);
+                Poll::Ready(TestRwGuard(self, false))
+            }))
+        }
+
+        fn write(&self) -> Self::Write<'_> {
+            let mut ticket = WriterTicket(self, None);
+            Box::pin(poll_fn(move |cx| {
+                let id = *ticket.1.get_or_insert_with(|| {
+                    let id = self.next_id.replace(self.next_id.get() + 1);
+                    self.queue.borrow_mut().push_back(id);
+                    id
+                });
+                if self.writer.get() || self.readers.get() > 0 || self.queue.borrow().front() != Some(&id) {
+                    return self.wait(cx).map(|()| unreachable!());
+                }
+                self.queue.borrow_mut().pop_front();
+                ticket.1 = None;
+                self.writer.set(true);
+                Poll::Ready(TestRwGuard(self, true))
+            }))
+        }
+    }
+
+    #[test]
+    fn rw_fairness() {
+        use crate::executor::Stepper;
+
+        RwFairness::new().run(TestRwLock::default);
+        let forgetful = || TestRwLock {
+            forget_aborted: true,
+            ..TestRwLock::default()
+        };
+        assert_eq!(
+            RwFairness::new().check(forgetful).unwrap_err(),
+            "queued reader was starved after the queued writer was aborted \
+             (RwLockStats { queued_readers: 1, queued_writers: 0, readers: 1, writers: 0 })"
+        );
+
+        let lock = InstrumentedRwLock::new(TestRwLock::default());
+        let read = lock.read();
+        assert!(lock.stats().is_idle());
+        let guard = Stepper::new(read).step();
+        let mut write = Stepper::new(lock.write());
+        assert!(write.step().is_pending());
+        assert_eq!(
+            lock.stats(),
+            RwLockStats {
+                queued_writers: 1,
+                readers: 1,
+                ..RwLockStats::default()
+            }
+        );
+        drop((guard, write));
+        assert!(lock.stats().is_idle());
+    }
+}
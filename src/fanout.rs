@@ -0,0 +1,305 @@
+//! Abort-safety of broadcast channels with several consumers.
+//!
+//! A broadcast (fanout) channel keeps a buffer for every consumer. If a
+//! consumer is aborted, its buffer must be released and the producer must
+//! not wait for it anymore. Otherwise the producer blocks forever once the
+//! buffer of the aborted consumer is full, or the buffers leak. `Fanout`
+//! runs one producer and several consumers on an `executor::Executor`,
+//! aborts a random subset of the consumers after every number of polls and
+//! checks that
+//!
+//! - the producer sends all messages and closes the channel,
+//! - the remaining consumers receive all messages and
+//! - nothing is buffered anymore once the remaining consumers are done.
+
+use std::cell::RefCell;
+use std::future::Future;
+
+use crate::executor::{Executor, Order};
+use crate::rng::RandomSources;
+
+/// Broadcast channel checked by `Fanout`. Every message sent is received
+/// by every receiver which subscribed before.
+///
+/// Implement it for the channel under test, boxing the futures if they
+/// can't be named:
+///
+/// ```rust,ignore
+/// impl Broadcast for MyChannel {
+///     type Receiver = MyReceiver;
+///     type Send<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+///     type Recv<'a> = Pin<Box<dyn Future<Output = Option<u64>> + 'a>>;
+///     fn subscribe(&self) -> MyReceiver { self.subscribe() }
+///     fn send(&self, message: u64) -> Self::Send<'_> { Box::pin(self.send(message)) }
+///     fn recv(receiver: &mut MyReceiver) -> Self::Recv<'_> { Box::pin(receiver.recv()) }
+///     fn close(&self) { self.close() }
+///     fn buffered(&self) -> usize { self.len() }
+/// }
+/// ```
+pub trait Broadcast {
+    /// Receiving half of a single consumer.
+    type Receiver;
+    /// Future sending a message, waiting while buffers are full.
+    type Send<'a>: Future<Output = ()> + 'a
+    where
+        Self: 'a;
+    /// Future resolving to the next message or `None` once the channel
+    /// is closed and drained.
+    type Recv<'a>: Future<Output = Option<u64>> + 'a
+    where
+        Self: 'a;
+
+    /// Create a new receiver.
+    fn subscribe(&self) -> Self::Receiver;
+
+    /// Send a message to all receivers.
+    fn send(&self, message: u64) -> Self::Send<'_>;
+
+    /// Receive the next message.
+    fn recv(receiver: &mut Self::Receiver) -> Self::Recv<'_>;
+
+    /// Close the channel. Receivers get `None` once they received all
+    /// messages sent before.
+    fn close(&self);
+
+    /// Number of messages buffered for all receivers together, counted
+    /// once per receiver.
+    fn buffered(&self) -> usize;
+}
+
+/// Abort-safety check of a broadcast channel. See the module
+/// documentation.
+#[derive(Clone, Debug)]
+pub struct Fanout {
+    consumers: usize,
+    messages: u64,
+    seed: u64,
+    random_sources: Option<RandomSources>,
+    max_polls: usize,
+}
+
+impl Fanout {
+    /// Check the channel with the given number of consumers and 4
+    /// messages.
+    pub fn new(consumers: usize) -> Self {
+        assert!(consumers >= 2, "at least two consumers are needed");
+        Self {
+            consumers,
+            messages: 4,
+            seed: 0,
+            random_sources: None,
+            max_polls: 10_000,
+        }
+    }
+
+    /// Set the number of messages sent by the producer.
+    pub fn messages(mut self, messages: u64) -> Self {
+        self.messages = messages;
+        self
+    }
+
+    /// Set the seed of the aborted consumers and the order of the tasks.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Draw the aborted consumers and the order of the tasks from the
+    /// given sources instead of the `Rng` substreams of the seed.
+    pub fn random_sources(mut self, random_sources: RandomSources) -> Self {
+        self.random_sources = Some(random_sources);
+        self
+    }
+
+    /// Set the maximum number of polls of all tasks of a run together.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Run the check and panic on failure.
+    pub fn run<B>(&self, setup: impl FnMut() -> B)
+    where
+        B: Broadcast,
+    {
+        if let Err(msg) = self.check(setup) {
+            panic!("{}", msg);
+        }
+    }
+
+    /// Run the scenario once for every number of polls from `0` to
+    /// `messages + 1` after which a random non-empty subset of the
+    /// consumers is aborted. Returns a description of the first failure.
+    pub fn check<B>(&self, mut setup: impl FnMut() -> B) -> Result<(), String>
+    where
+        B: Broadcast,
+    {
+        let sources = self.random_sources.clone().unwrap_or_else(|| RandomSources::seeded(self.seed));
+        for point in 0..=self.messages as usize + 1 {
+            let mut picks = sources.substream(&format!("fanout-{}", point));
+            let mut aborted: Vec<usize> = (0..self.consumers).filter(|_| picks.below(2) == 0).collect();
+            if aborted.is_empty() {
+                aborted.push(picks.below(self.consumers));
+            }
+            let context = format!("consumers {:?} were aborted after {} polls", aborted, point);
+            let channel = setup();
+            let received = RefCell::new(vec![0; self.consumers]);
+            let mut executor = Executor::new(Order::Random(self.seed))
+                .random_sources(sources.clone())
+                .max_polls(self.max_polls);
+            let producer = executor.spawn(async {
+                for message in 0..self.messages {
+                    channel.send(message).await;
+                }
+                channel.close();
+            });
+            for consumer in 0..self.consumers {
+                let mut receiver = channel.subscribe();
+                let received = &received;
+                let task = executor.spawn(async move {
+                    while B::recv(&mut receiver).await.is_some() {
+                        received.borrow_mut()[consumer] += 1;
+                    }
+                });
+                if aborted.contains(&consumer) {
+                    executor.abort_after(task, point);
+                }
+            }
+            let execution = executor.run();
+            drop(executor);
+            if execution.stuck.contains(&producer) {
+                return Err(format!("producer blocked forever after {}", context));
+            }
+            let received = received.into_inner();
+            for (consumer, &count) in received.iter().enumerate() {
+                if !aborted.contains(&consumer) && count != self.messages {
+                    return Err(format!(
+                        "consumer {} received {} of {} messages after {}",
+                        consumer, count, self.messages, context
+                    ));
+                }
+            }
+            let buffered = channel.buffered();
+            if buffered > 0 {
+                return Err(format!("{} messages still buffered after {}", buffered, context));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::task::{Context, Poll, Waker};
+
+    use super::{Broadcast, Fanout};
+
+    /// Broadcast channel with a bounded queue per receiver.
+    struct TestBroadcast(Rc<BroadcastShared>);
+
+    #[derive(Default)]
+    struct BroadcastShared {
+        capacity: usize,
+        /// Keep the queue of a dropped receiver.
+        leak_dropped: bool,
+        queues: RefCell<Vec<Option<VecDeque<u64>>>>,
+        closed: Cell<bool>,
+        wakers: RefCell<Vec<Waker>>,
+    }
+
+    impl BroadcastShared {
+        fn wake_all(&self) {
+            self.wakers.borrow_mut().drain(..).for_each(Waker::wake);
+        }
+
+        fn wait<T>(&self, cx: &mut Context<'_>) -> Poll<T> {
+            self.wakers.borrow_mut().push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    struct TestReceiver(Rc<BroadcastShared>, usize);
+
+    impl Drop for TestReceiver {
+        fn drop(&mut self) {
+            if !self.0.leak_dropped {
+                self.0.queues.borrow_mut()[self.1] = None;
+                self.0.wake_all();
+            }
+        }
+    }
+
+    impl Broadcast for TestBroadcast {
+        type Receiver = TestReceiver;
+        type Send<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+        type Recv<'a> = Pin<Box<dyn Future<Output = Option<u64>> + 'a>>;
+
+        fn subscribe(&self) -> TestReceiver {
+            let mut queues = self.0.queues.borrow_mut();
+            queues.push(Some(VecDeque::new()));
+            TestReceiver(self.0.clone(), queues.len() - 1)
+        }
+
+        fn send(&self, message: u64) -> Self::Send<'_> {
+            Box::pin(poll_fn(move |cx| {
+                let mut queues = self.0.queues.borrow_mut();
+                if queues.iter().flatten().any(|queue| queue.len() == self.0.capacity) {
+                    return self.0.wait(cx);
+                }
+                queues.iter_mut().flatten().for_each(|queue| queue.push_back(message));
+                drop(queues);
+                self.0.wake_all();
+                Poll::Ready(())
+            }))
+        }
+
+        fn recv(receiver: &mut TestReceiver) -> Self::Recv<'_> {
+            Box::pin(poll_fn(move |cx| {
+                let shared = &receiver.0;
+                let message = shared.queues.borrow_mut()[receiver.1].as_mut().unwrap().pop_front();
+                match message {
+                    Some(message) => {
+                        shared.wake_all();
+                        Poll::Ready(Some(message))
+                    }
+                    None if shared.closed.get() => Poll::Ready(None),
+                    None => shared.wait(cx),
+                }
+            }))
+        }
+
+        fn close(&self) {
+            self.0.closed.set(true);
+            self.0.wake_all();
+        }
+
+        fn buffered(&self) -> usize {
+            self.0.queues.borrow().iter().flatten().map(VecDeque::len).sum()
+        }
+    }
+
+    #[test]
+    fn fanout() {
+        let channel = |capacity, leak_dropped| {
+            move || {
+                TestBroadcast(Rc::new(BroadcastShared {
+                    capacity,
+                    leak_dropped,
+                    ..BroadcastShared::default()
+                }))
+            }
+        };
+        for seed in 0..4 {
+            Fanout::new(3).seed(seed).run(channel(1, false));
+        }
+        let blocked = Fanout::new(3).check(channel(1, true)).unwrap_err();
+        assert!(blocked.starts_with("producer blocked forever after consumers"), "{}", blocked);
+        let leaked = Fanout::new(3).check(channel(8, true)).unwrap_err();
+        assert!(leaked.contains(" messages still buffered after consumers"), "{}", leaked);
+    }
+}
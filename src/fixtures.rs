@@ -0,0 +1,202 @@
+//! Resources which require cleanup for use in examples and tests.
+//!
+//! All fixtures are registered via `track` so a `Sweep` lists them in the
+//! explanation of a failed check if they were not dropped, and they
+//! implement `Settled` so they can be checked directly.
+
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::future::after;
+use crate::harness::{track, Tracked};
+use crate::sync::Settled;
+
+/// Counter which must be decremented manually after being incremented.
+///
+/// Use `increment` and `decrement` to write code which is not abort-safe
+/// and `guard` for the abort-safe variant.
+#[derive(Debug, Default)]
+pub struct TempCounter {
+    count: AtomicUsize,
+}
+
+impl TempCounter {
+    /// Create a new counter starting at `0`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current value of the counter.
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Increment the counter.
+    pub fn increment(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Decrement the counter.
+    pub fn decrement(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Increment the counter and return a guard which decrements it again
+    /// when dropped.
+    pub fn guard(&self) -> TempCounterGuard<'_> {
+        self.increment();
+        TempCounterGuard {
+            counter: self,
+            _tracked: track("fta::fixtures::TempCounterGuard"),
+        }
+    }
+}
+
+impl Settled for TempCounter {
+    fn is_settled(&self) -> bool {
+        self.get() == 0
+    }
+}
+
+/// Guard returned by `TempCounter::guard`.
+#[must_use]
+#[derive(Debug)]
+pub struct TempCounterGuard<'a> {
+    counter: &'a TempCounter,
+    _tracked: Tracked,
+}
+
+impl Drop for TempCounterGuard<'_> {
+    fn drop(&mut self) {
+        self.counter.decrement();
+    }
+}
+
+/// Temporary directory which is removed when the guard is dropped.
+#[derive(Debug)]
+pub struct TempDirGuard {
+    path: PathBuf,
+    _tracked: Tracked,
+}
+
+impl TempDirGuard {
+    /// Create a new empty directory inside the temporary directory of the
+    /// operating system.
+    pub fn create() -> io::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("fta-{}-{}", process::id(), NEXT.fetch_add(1, Ordering::SeqCst));
+        let path = env::temp_dir().join(name);
+        fs::create_dir(&path)?;
+        Ok(Self {
+            path,
+            _tracked: track("fta::fixtures::TempDirGuard"),
+        })
+    }
+
+    /// Path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Settled for TempDirGuard {
+    /// Returns `true` if the directory is empty.
+    fn is_settled(&self) -> bool {
+        fs::read_dir(&self.path).map_or(true, |mut entries| entries.next().is_none())
+    }
+}
+
+impl Drop for TempDirGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Connection speaking a request/response protocol. A request which is
+/// aborted after it was sent but before the response was read leaves the
+/// connection out of sync and therefore broken.
+#[derive(Debug)]
+pub struct FakeConnection {
+    in_request: Cell<bool>,
+    broken: Cell<bool>,
+    _tracked: Tracked,
+}
+
+impl FakeConnection {
+    /// Open a new connection.
+    pub fn open() -> Self {
+        Self {
+            in_request: Cell::new(false),
+            broken: Cell::new(false),
+            _tracked: track("fta::fixtures::FakeConnection"),
+        }
+    }
+
+    /// Send a request and wait `polls` polls for the response. Panics if
+    /// the connection is broken.
+    pub async fn request(&self, polls: usize) {
+        assert!(!self.is_broken(), "connection is broken");
+        self.in_request.set(true);
+        let _guard = Aborting(self);
+        after((), polls).await;
+        self.in_request.set(false);
+    }
+
+    /// Returns `true` if a request was aborted half way.
+    pub fn is_broken(&self) -> bool {
+        self.broken.get()
+    }
+
+    /// Close the connection gracefully.
+    pub async fn close(self) {
+        after((), 1).await;
+    }
+}
+
+impl Settled for FakeConnection {
+    fn is_settled(&self) -> bool {
+        !self.is_broken()
+    }
+}
+
+/// Marks the connection as broken if a request is dropped half way.
+struct Aborting<'a>(&'a FakeConnection);
+
+impl Drop for Aborting<'_> {
+    fn drop(&mut self) {
+        if self.0.in_request.get() {
+            self.0.broken.set(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FakeConnection, TempDirGuard};
+    use crate::{Settled, Sweep};
+
+    async fn request(connection: &FakeConnection) {
+        connection.request(2).await;
+    }
+
+    #[cfg_attr(miri, ignore = "accesses the file system")]
+    #[tokio::test]
+    async fn fixtures_connection() {
+        let report = Sweep::new()
+            .report(FakeConnection::open, request, FakeConnection::assert_settled)
+            .await;
+        let safe: Vec<bool> = report.points.iter().map(|point| point.is_safe()).collect();
+        assert_eq!(safe, [true, false, false, true]);
+        let dir = TempDirGuard::create().unwrap();
+        let path = dir.path().to_owned();
+        std::fs::write(path.join("file"), "").unwrap();
+        assert!(!dir.is_settled());
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
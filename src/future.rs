@@ -0,0 +1,2947 @@
+//! Wrappers which abort, label and instrument futures.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::fmt;
+use std::future::{poll_fn, Future, PollFn};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe, Location};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use crate::harness::{panic_message, trace_event, Trace};
+use crate::report::TraceEvent;
+use crate::rng::Rng;
+use crate::scope::Defaults;
+
+/// This error is returned when an `AbortN` future resolves
+/// aborting the inner future.
+///
+/// Fields which don't matter for an assertion can be filled in via
+/// `..Aborted::default()`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Aborted {
+    /// Number of polls that were made before aborting the future.
+    pub num_polls: usize,
+    /// Number of iterations of every loop marked with `loop_iter!` which
+    /// were started before aborting the future.
+    pub iterations: Vec<(&'static str, usize)>,
+    /// Chain of `Labeled` futures (outermost first) in which the future
+    /// was suspended when it was aborted. If the future has several
+    /// pending branches the chain describes the last one polled.
+    pub chain: Vec<Suspension>,
+    /// Number of polls the future was expected to need as set via
+    /// `Abort::with_expected_polls`.
+    pub expected_polls: Option<usize>,
+    /// Reason the future was aborted for as set via `Abort::with_reason`.
+    pub reason: AbortReason,
+    /// Seed the abort point was drawn from if the wrapper was created via
+    /// `abort_random`.
+    pub seed: Option<u64>,
+}
+
+impl Aborted {
+    /// Logical async backtrace of the `Labeled` futures (outermost first)
+    /// in which the future was suspended when it was aborted. One frame
+    /// per line.
+    pub fn backtrace(&self) -> String {
+        let frames: Vec<String> = self.chain.iter().map(ToString::to_string).collect();
+        frames.join("\n")
+    }
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "aborted at {}", self.num_polls)?;
+        if let Some(expected_polls) = self.expected_polls {
+            write!(f, " of ~{} expected", expected_polls)?;
+        }
+        write!(f, " polls")?;
+        if !matches!(self.reason, AbortReason::Dropped) {
+            write!(f, " because of {}", self.reason)?;
+        }
+        if let Some(seed) = self.seed {
+            write!(f, " (seed {})", seed)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Reason a future is aborted for.
+///
+/// Different causes of cancellation often require different cleanup. The
+/// reason is set via `Abort::with_reason` or `Sweep::reason` and carried
+/// through `Aborted` and the reports. During the grace polls of an `Abort`
+/// wrapper the code under test can branch on it via `abort_reason`.
+#[derive(Clone, Debug, Default)]
+pub enum AbortReason {
+    /// The future is simply dropped.
+    #[default]
+    Dropped,
+    /// The client waiting for the result disconnected.
+    ClientDisconnect,
+    /// The deadline of the operation passed.
+    Timeout,
+    /// Application specific reason. See `AbortReason::custom`.
+    Custom(Arc<dyn Any + Send + Sync>),
+}
+
+impl AbortReason {
+    /// Create an application specific reason.
+    pub fn custom<T>(reason: T) -> Self
+    where
+        T: Any + Send + Sync,
+    {
+        Self::Custom(Arc::new(reason))
+    }
+
+    /// The application specific reason if it is of type `T`.
+    pub fn downcast_ref<T>(&self) -> Option<&T>
+    where
+        T: Any,
+    {
+        match self {
+            Self::Custom(reason) => reason.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Custom reasons are only equal if they are the same instance.
+impl PartialEq for AbortReason {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            (a, b) => mem::discriminant(a) == mem::discriminant(b),
+        }
+    }
+}
+
+impl Eq for AbortReason {}
+
+impl fmt::Display for AbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dropped => "drop",
+            Self::ClientDisconnect => "client disconnect",
+            Self::Timeout => "timeout",
+            Self::Custom(_) => "custom reason",
+        })
+    }
+}
+
+thread_local! {
+    static REASON: RefCell<Option<AbortReason>> = const { RefCell::new(None) };
+}
+
+/// Make `reason` the result of `abort_reason` while `f` is running.
+fn with_reason<R>(reason: &AbortReason, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<AbortReason>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            REASON.with(|reason| *reason.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(REASON.with(|current| current.replace(Some(reason.clone()))));
+    f()
+}
+
+/// Reason the current future is being aborted for. This is only `Some`
+/// during the grace polls of an `Abort` wrapper (see
+/// `Abort::with_grace_polls`), which lets the code under test clean up
+/// depending on the reason.
+pub fn abort_reason() -> Option<AbortReason> {
+    REASON.with(|reason| reason.borrow().clone())
+}
+
+/// Instrumentation of the polls made by an `Abort` wrapper.
+///
+/// The policy is a type parameter of `Abort` so the hot path is
+/// monomorphized and a policy which records nothing costs nothing.
+pub trait Policy: Default {
+    /// Poll the inner future. `poll` is the index of the poll starting at
+    /// `0`.
+    fn poll<T>(&mut self, poll: usize, future: Pin<&mut T>, cx: &mut Context<'_>) -> Poll<T::Output>
+    where
+        T: Future;
+
+    /// Add the recorded details to the error of an aborted future.
+    fn aborted(&self, aborted: &mut Aborted);
+
+    /// Returns `true` if the inner future reached the label. Policies which
+    /// do not record labels always return `false`.
+    fn reached(&self, _label: &str) -> bool {
+        false
+    }
+
+    /// `false` if the wrapper only counts the polls. Its poll is then an
+    /// increment and a compare with the limit: wakes are not counted (a
+    /// `count_pending_only` of `with_defaults` is ignored), no label can
+    /// be targeted and panics of the inner future are not recorded, see
+    /// `Abort::panicked`.
+    const INSTRUMENTED: bool = true;
+}
+
+/// Default policy of `Abort` which records labels and suspension chains.
+#[derive(Debug, Default)]
+pub struct Instrumented {
+    labels: Vec<Label>,
+    chain: Vec<Suspension>,
+}
+
+impl Policy for Instrumented {
+    fn poll<T>(&mut self, poll: usize, future: Pin<&mut T>, cx: &mut Context<'_>) -> Poll<T::Output>
+    where
+        T: Future,
+    {
+        let (result, recording) = record(|| future.poll(cx));
+        if result.is_pending() {
+            self.chain = recording.chain();
+        }
+        for (name, is_loop) in recording.labels {
+            let iteration = if is_loop {
+                let previous = self.labels.iter().filter(|label| label.name == name && label.iteration.is_some());
+                Some(previous.count() + 1)
+            } else {
+                None
+            };
+            self.labels.push(Label { name, poll, iteration });
+        }
+        result
+    }
+
+    fn aborted(&self, aborted: &mut Aborted) {
+        for label in &self.labels {
+            if let Some(iteration) = label.iteration {
+                match aborted.iterations.iter_mut().find(|(name, _)| *name == label.name) {
+                    Some(entry) => entry.1 = iteration,
+                    None => aborted.iterations.push((label.name, iteration)),
+                }
+            }
+        }
+        aborted.chain = self.chain.clone();
+    }
+
+    fn reached(&self, label: &str) -> bool {
+        self.labels.iter().any(|l| l.name == label)
+    }
+}
+
+/// Policy which only counts polls. Labels reached by the inner future are
+/// not recorded by this wrapper and every poll counts, even if
+/// `count_pending_only` is enabled by `with_defaults`. This makes it
+/// suitable for soak runs with millions of polls.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counting;
+
+impl Policy for Counting {
+    const INSTRUMENTED: bool = false;
+
+    #[inline(always)]
+    fn poll<T>(&mut self, _poll: usize, future: Pin<&mut T>, cx: &mut Context<'_>) -> Poll<T::Output>
+    where
+        T: Future,
+    {
+        future.poll(cx)
+    }
+
+    fn aborted(&self, _aborted: &mut Aborted) {}
+}
+
+/// Wrapper for a `Future` which limits the times it can be polled.
+pub struct Abort<T, P = Instrumented>
+where
+    T: Future
+{
+    num_polls: usize,
+    max_polls: usize,
+    expected_polls: Option<usize>,
+    reason: AbortReason,
+    grace_polls: usize,
+    /// Number of grace polls made so far.
+    grace: usize,
+    /// `true` if the inner future completed in a grace poll.
+    finished: bool,
+    /// Layer detecting wakes if only polls after a wake or only wakes
+    /// are counted. Always `None` for policies which are not
+    /// `Policy::INSTRUMENTED`, like `label`.
+    woken: Option<WakerLayer<Woken>>,
+    /// Count wakes instead of polls. See `AbortOpts::count_wakes`.
+    count_wakes: bool,
+    label: Option<&'static str>,
+    seed: Option<u64>,
+    /// Poll in which the inner future panicked and the panic message.
+    panicked: Option<(usize, String)>,
+    policy: P,
+    future: Pinned<T>,
+}
+
+/// Storage of the inner future of a wrapper with `&self` accessors.
+///
+/// Self-referential futures, e.g. the ones of `async fn`, hold references
+/// into themselves while they are suspended. A shared reference to the
+/// wrapper must not claim that the inner future is immutable or these
+/// references become invalid, which Miri reports. The `UnsafeCell` opts
+/// the inner future out of that like `UnsafePinned` of the standard
+/// library will.
+pub(crate) struct Pinned<T>(UnsafeCell<T>);
+
+impl<T> Pinned<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self(UnsafeCell::new(value))
+    }
+
+    /// Safety: the value must never be moved after this was called.
+    pub(crate) unsafe fn as_pin_mut(&mut self) -> Pin<&mut T> {
+        Pin::new_unchecked(self.0.get_mut())
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        // Safety: no `&mut T` exists while `&self` is borrowed.
+        unsafe { &*self.0.get() }
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.0.into_inner()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Pinned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get_ref().fmt(f)
+    }
+}
+
+// Safety: `&Pinned` only hands out `&T`, which is fine across threads
+// for `T: Sync` just like a plain field.
+unsafe impl<T: Sync> Sync for Pinned<T> {}
+
+/// Hooks recording that and how often the inner future of an `Abort` was
+/// woken.
+#[derive(Debug)]
+struct Woken {
+    woken: AtomicBool,
+    wakes: AtomicUsize,
+}
+
+impl WakerHooks for Woken {
+    fn on_wake(&self) {
+        self.on_wake_by_ref();
+    }
+
+    fn on_wake_by_ref(&self) {
+        self.woken.store(true, Ordering::SeqCst);
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl<T, P> Abort<T, P>
+where
+    T: Future,
+{
+    /// Set the number of polls the inner future is expected to need. This
+    /// is only a hint which is passed on to `Aborted` for reporting.
+    pub fn with_expected_polls(mut self, expected_polls: usize) -> Self {
+        self.expected_polls = Some(expected_polls);
+        self
+    }
+
+    /// Set the reason the inner future is aborted for. Policies may
+    /// override it in `Policy::aborted`.
+    pub fn with_reason(mut self, reason: AbortReason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// Poll the inner future up to `grace_polls` more times once the limit
+    /// is reached. During these polls `abort_reason` returns the reason so
+    /// the inner future can clean up. The wrapper resolves to
+    /// `Err(Aborted)` even if the inner future completes.
+    pub fn with_grace_polls(mut self, grace_polls: usize) -> Self {
+        self.grace_polls = grace_polls;
+        self
+    }
+
+    /// Allow `n` more polls of the inner future. A wrapper which resolved
+    /// to `Err(Aborted)` keeps the inner future alive, so this resumes it
+    /// where it was aborted, e.g. to test that a paused operation keeps
+    /// its partial progress. Grace polls are granted again once the new
+    /// limit is reached.
+    ///
+    /// Panics if the inner future completed in a grace poll.
+    ///
+    /// ```rust
+    /// use std::pin::pin;
+    ///
+    /// use futures_test_abort::{abort, after};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let mut future = pin!(abort(after(1, 3), 2));
+    /// assert!(future.as_mut().await.is_err());
+    /// future.as_mut().grant_more_polls(2);
+    /// assert_eq!(future.await, Ok(1));
+    /// # }
+    /// ```
+    pub fn grant_more_polls(self: Pin<&mut Self>, n: usize) {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        assert!(!me.finished, "cannot resume an inner future which completed in a grace poll");
+        me.max_polls = me.max_polls.max(me.num_polls).saturating_add(n);
+        me.grace = 0;
+    }
+
+    /// Number of times the inner future has been polled not counting
+    /// grace polls. If wakes are counted (see `abort_after_wakes`) this is
+    /// the number of wakes observed by the last poll.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+
+    /// Number of polls the inner future is expected to need.
+    pub fn expected_polls(&self) -> Option<usize> {
+        self.expected_polls
+    }
+
+    /// Poll (starting at `0`) in which the inner future panicked and the
+    /// panic message. A wrapper whose inner future panicked is poisoned:
+    /// polling it again panics right away instead of polling the inner
+    /// future in an undefined state. Wrappers with the `Counting` policy
+    /// do not record panics.
+    pub fn panicked(&self) -> Option<(usize, &str)> {
+        self.panicked.as_ref().map(|(poll, message)| (*poll, message.as_str()))
+    }
+
+    /// Reference to the inner future, e.g. to inspect its progress after
+    /// the wrapper resolved to `Err(Aborted)`.
+    pub fn get_ref(&self) -> &T {
+        self.future.get_ref()
+    }
+
+    /// Consume the wrapper and return the inner future instead of dropping
+    /// it. After an abort the inner future can be inspected or wrapped
+    /// again with a larger budget to resume it where it was aborted.
+    ///
+    /// Only a wrapper which is not pinned can be consumed, so polling it
+    /// requires the inner future to be `Unpin`. Box futures which are not:
+    ///
+    /// ```rust
+    /// use futures_test_abort::{abort, after};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let mut future = abort(Box::pin(after(1, 3)), 2);
+    /// assert!((&mut future).await.is_err());
+    /// let resumed = abort(future.into_inner(), 2);
+    /// assert_eq!(resumed.await, Ok(1));
+    /// # }
+    /// ```
+    pub fn into_inner(self) -> T {
+        self.future.into_inner()
+    }
+}
+
+/// Call `f` which polls the inner future of an `Abort` and record the
+/// panic in `panicked` if it panics.
+fn poison_on_panic<R>(panicked: &mut Option<(usize, String)>, poll: usize, f: impl FnOnce() -> R) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            *panicked = Some((poll, panic_message(&*payload)));
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+impl<T> Abort<T>
+where
+    T: Future,
+{
+    /// Labels which were reached by the inner future so far.
+    pub fn labels(&self) -> &[Label] {
+        &self.policy.labels
+    }
+}
+
+impl<T, P> Abort<T, P>
+where
+    T: Future,
+    P: Policy,
+{
+    /// Poll with everything but the bare counting of `Counting`: grace
+    /// polls, wake counting, label targets and panic poisoning.
+    #[inline(never)]
+    fn poll_instrumented(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T::Output, Aborted>> {
+        if let Some((poll, message)) = &self.panicked {
+            panic!("Abort polled after the inner future panicked at poll {}: {}", poll, message);
+        }
+        if self.count_wakes {
+            // Safety: we never move `self.future`
+            let me = unsafe { self.as_mut().get_unchecked_mut() };
+            if let Some(layer) = &me.woken {
+                me.num_polls = layer.hooks().wakes.load(Ordering::SeqCst);
+            }
+        }
+        if self.num_polls >= self.max_polls {
+            // Safety: we never move `self.future`
+            let me = unsafe { Pin::into_inner_unchecked(self) };
+            if me.grace < me.grace_polls {
+                me.grace += 1;
+                let future = unsafe { me.future.as_pin_mut() };
+                let poll = me.num_polls + me.grace - 1;
+                let reason = &me.reason;
+                let result = poison_on_panic(&mut me.panicked, poll, || with_reason(reason, || future.poll(cx)));
+                if result.is_ready() {
+                    me.grace = me.grace_polls;
+                    me.finished = true;
+                } else if me.grace < me.grace_polls {
+                    return Poll::Pending;
+                }
+            }
+            let mut aborted = Aborted {
+                num_polls: me.num_polls,
+                expected_polls: me.expected_polls,
+                reason: me.reason.clone(),
+                seed: me.seed,
+                ..Aborted::default()
+            };
+            me.policy.aborted(&mut aborted);
+            return Poll::Ready(Err(aborted));
+        }
+        // Safety: we never move `self.num_polls`, `self.policy` or `self.future`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            let poll = me.num_polls;
+            let future = me.future.as_pin_mut();
+            let (num_polls, policy, woken) = (&mut me.num_polls, &mut me.policy, &me.woken);
+            let (count_wakes, label) = (me.count_wakes, me.label);
+            let result = poison_on_panic(&mut me.panicked, poll, || {
+                with_target(label, || match woken {
+                    None => {
+                        *num_polls += 1;
+                        policy.poll(poll, future, cx)
+                    }
+                    Some(layer) => {
+                        if layer.hooks().woken.swap(false, Ordering::SeqCst) && !count_wakes {
+                            *num_polls += 1;
+                        }
+                        let waker = layer.wrap(cx.waker());
+                        policy.poll(poll, future, &mut Context::from_waker(&waker))
+                    }
+                })
+            });
+            match result {
+                Poll::Ready(v) => Poll::Ready(Ok(v)),
+                Poll::Pending => {
+                    if me.label.is_some_and(|label| me.policy.reached(label)) {
+                        me.max_polls = me.max_polls.min(me.num_polls);
+                    }
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl<T, P> Future for Abort<T, P>
+where
+    T: Future,
+    P: Policy,
+{
+    type Output = Result<T::Output, Aborted>;
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if P::INSTRUMENTED || self.num_polls >= self.max_polls {
+            return self.poll_instrumented(cx);
+        }
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let poll = me.num_polls;
+        me.num_polls += 1;
+        me.policy.poll(poll, unsafe { me.future.as_pin_mut() }, cx).map(Ok)
+    }
+}
+
+/// Create a `Abort` future wrapper which limits the times a future
+/// can be polled before it returns `Err(Aborted(max_polls))`. If the
+/// future is ready before reaching `max_polls` `Ok(T)` is returned
+/// instead.
+pub fn abort<T>(future: T, max_polls: usize) -> Abort<T>
+where
+    T: Future,
+{
+    abort_with_opts(
+        future,
+        AbortOpts {
+            max_polls,
+            ..AbortOpts::default()
+        },
+    )
+}
+
+/// Create a `Abort` future wrapper like `abort` which aborts the future
+/// after a number of polls in `0..=max_range` drawn from `seed`. The seed
+/// is part of `Aborted`, so a failing fuzz-style run can be reproduced.
+pub fn abort_random<T>(future: T, max_range: usize, seed: u64) -> Abort<T>
+where
+    T: Future,
+{
+    let max_polls = match max_range.checked_add(1) {
+        Some(n) => Rng::substream(seed, "abort-random").below(n),
+        None => Rng::substream(seed, "abort-random").next_u64() as usize,
+    };
+    let mut abort = abort(future, max_polls);
+    abort.seed = Some(seed);
+    abort
+}
+
+/// Create a `Abort` future wrapper like `abort` which counts the wakes of
+/// the inner future instead of its polls. The future is aborted at the
+/// first poll after it was woken `max_wakes` times, so the abort points
+/// do not depend on executors polling more often than they are woken.
+pub fn abort_after_wakes<T>(future: T, max_wakes: usize) -> Abort<T>
+where
+    T: Future,
+{
+    abort_with_opts(
+        future,
+        AbortOpts {
+            max_polls: max_wakes,
+            count_wakes: true,
+            ..AbortOpts::default()
+        },
+    )
+}
+
+/// Create a `Abort` future wrapper like `abort` which uses the given
+/// policy, e.g. `Counting` for the fastest possible wrapper.
+pub fn abort_with_policy<T, P>(future: T, max_polls: usize) -> Abort<T, P>
+where
+    T: Future,
+    P: Policy,
+{
+    new_abort(
+        future,
+        AbortOpts {
+            max_polls,
+            ..AbortOpts::default()
+        },
+    )
+}
+
+/// Create a `Abort` future wrapper which aborts the future exactly at the
+/// named `checkpoint`. The code before the checkpoint has run, the code
+/// after it has not. If the checkpoint is never reached `Ok(T)` is
+/// returned.
+pub fn abort_at_checkpoint<T>(future: T, name: &'static str) -> Abort<T>
+where
+    T: Future,
+{
+    abort_with_opts(
+        future,
+        AbortOpts {
+            label: Some(name),
+            ..AbortOpts::default()
+        },
+    )
+}
+
+/// Options of an `Abort` wrapper created by `abort_with_opts`.
+///
+/// New options are added as fields with a default value, so create the
+/// options with `..AbortOpts::default()` to stay compatible with future
+/// releases.
+#[derive(Clone, Debug)]
+pub struct AbortOpts {
+    /// Number of polls after which the future is aborted. Defaults to
+    /// `usize::MAX`.
+    pub max_polls: usize,
+    /// Only count the first poll after every wake of the inner future.
+    /// Spurious polls, e.g. by combinators polling all of their branches,
+    /// do not count towards `max_polls` so the abort points do not depend
+    /// on the surrounding code.
+    pub count_pending_only: bool,
+    /// Count the wakes of the inner future instead of its polls. The
+    /// future is aborted at the first poll after it was woken `max_polls`
+    /// times. This takes precedence over `count_pending_only`.
+    pub count_wakes: bool,
+    /// See `Abort::with_grace_polls`.
+    pub grace_polls: usize,
+    /// Abort the future right after the poll in which it reached this
+    /// label. A `checkpoint` of this name suspends the future so it is
+    /// aborted exactly there. This requires the default `Instrumented`
+    /// policy.
+    pub label: Option<&'static str>,
+    /// See `Abort::with_reason`.
+    pub reason: AbortReason,
+    /// See `Abort::with_expected_polls`.
+    pub expected_polls: Option<usize>,
+}
+
+impl Default for AbortOpts {
+    /// Options without a limit. `count_pending_only`, `grace_polls` and
+    /// `reason` are taken from the enclosing `with_defaults` scope.
+    fn default() -> Self {
+        let defaults = Defaults::current();
+        Self {
+            max_polls: usize::MAX,
+            count_pending_only: defaults.count_pending_only,
+            count_wakes: false,
+            grace_polls: defaults.grace_polls,
+            label: None,
+            reason: defaults.reason,
+            expected_polls: None,
+        }
+    }
+}
+
+/// Create a `Abort` future wrapper configured by `opts`. This is the
+/// extensible form of `abort`.
+pub fn abort_with_opts<T>(future: T, opts: AbortOpts) -> Abort<T>
+where
+    T: Future,
+{
+    new_abort(future, opts)
+}
+
+fn new_abort<T, P>(future: T, opts: AbortOpts) -> Abort<T, P>
+where
+    T: Future,
+    P: Policy,
+{
+    Abort {
+        num_polls: 0,
+        max_polls: opts.max_polls,
+        expected_polls: opts.expected_polls,
+        reason: opts.reason,
+        grace_polls: opts.grace_polls,
+        grace: 0,
+        woken: (P::INSTRUMENTED && (opts.count_pending_only || opts.count_wakes)).then(|| {
+            WakerLayer::new(Woken {
+                woken: AtomicBool::new(true),
+                wakes: AtomicUsize::new(0),
+            })
+        }),
+        count_wakes: P::INSTRUMENTED && opts.count_wakes,
+        label: opts.label.filter(|_| P::INSTRUMENTED),
+        seed: None,
+        panicked: None,
+        finished: false,
+        policy: P::default(),
+        future: Pinned::new(future),
+    }
+}
+
+/// Wrapper for a `Future` which polls it a limited number of times and
+/// then returns it instead of dropping it.
+///
+/// This makes it possible to inspect intermediate state without
+/// committing to an abort: once the limit is reached the still pending
+/// future is returned as `Err` and can be awaited normally. The inner
+/// future is pinned on the heap so it can be moved out of the wrapper.
+pub struct Probe<T>
+where
+    T: Future,
+{
+    num_polls: usize,
+    max_polls: usize,
+    future: Option<Pin<Box<T>>>,
+}
+
+impl<T> Probe<T>
+where
+    T: Future,
+{
+    /// Number of times the inner future has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+}
+
+impl<T> Future for Probe<T>
+where
+    T: Future,
+{
+    type Output = Result<T::Output, Pin<Box<T>>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut future = self.future.take().expect("Probe polled after completion");
+        if self.num_polls >= self.max_polls {
+            return Poll::Ready(Err(future));
+        }
+        self.num_polls += 1;
+        match future.as_mut().poll(cx) {
+            Poll::Ready(v) => Poll::Ready(Ok(v)),
+            Poll::Pending => {
+                self.future = Some(future);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Create a `Probe` future wrapper which polls a future at most
+/// `max_polls` times. If the future is still pending afterwards it is
+/// returned untouched as `Err` so it can be awaited later.
+pub fn try_abort<T>(future: T, max_polls: usize) -> Probe<T>
+where
+    T: Future,
+{
+    Probe {
+        num_polls: 0,
+        max_polls,
+        future: Some(Box::pin(future)),
+    }
+}
+
+/// Wrapper for a `Future` which is aborted via an `AbortHandle` instead
+/// of after a fixed number of polls.
+///
+/// Once the handle was used the wrapper resolves to `Err(Aborted)` the
+/// next time it is polled. The waker of the last poll is woken so the
+/// abort is noticed without another wake of the inner future.
+pub struct Abortable<T>
+where
+    T: Future,
+{
+    num_polls: usize,
+    shared: Arc<Mutex<Trigger>>,
+    future: Pinned<T>,
+}
+
+/// State shared by an `Abortable` and its handles.
+#[derive(Debug, Default)]
+struct Trigger {
+    reason: Option<AbortReason>,
+    waker: Option<Waker>,
+}
+
+impl<T> Abortable<T>
+where
+    T: Future,
+{
+    /// Number of times the inner future has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+}
+
+impl<T> Future for Abortable<T>
+where
+    T: Future,
+{
+    type Output = Result<T::Output, Aborted>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        {
+            let mut trigger = me.shared.lock().unwrap();
+            if let Some(reason) = &trigger.reason {
+                return Poll::Ready(Err(Aborted {
+                    num_polls: me.num_polls,
+                    reason: reason.clone(),
+                    ..Aborted::default()
+                }));
+            }
+            trigger.waker = Some(cx.waker().clone());
+        }
+        me.num_polls += 1;
+        unsafe { me.future.as_pin_mut() }.poll(cx).map(Ok)
+    }
+}
+
+/// Handle which aborts an `Abortable` from another task or thread, e.g.
+/// a controller simulating a client disconnect notification.
+#[derive(Clone, Debug)]
+pub struct AbortHandle {
+    shared: Arc<Mutex<Trigger>>,
+}
+
+impl AbortHandle {
+    /// Abort the future. See `abort_with_reason`.
+    pub fn abort(&self) {
+        self.abort_with_reason(AbortReason::Dropped);
+    }
+
+    /// Abort the future for the given reason. Only the first abort takes
+    /// effect. The wrapper resolves to `Err(Aborted)` the next time it is
+    /// polled.
+    pub fn abort_with_reason(&self, reason: AbortReason) {
+        let waker = {
+            let mut trigger = self.shared.lock().unwrap();
+            if trigger.reason.is_some() {
+                return;
+            }
+            trigger.reason = Some(reason);
+            trigger.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Returns `true` if the future was aborted via a handle.
+    pub fn is_aborted(&self) -> bool {
+        self.shared.lock().unwrap().reason.is_some()
+    }
+}
+
+/// Create an `Abortable` future wrapper and the `AbortHandle` which
+/// aborts it. If the future is ready before the handle is used `Ok(T)` is
+/// returned.
+pub fn abort_with_handle<T>(future: T) -> (Abortable<T>, AbortHandle)
+where
+    T: Future,
+{
+    let shared = Arc::new(Mutex::new(Trigger::default()));
+    let handle = AbortHandle { shared: shared.clone() };
+    let future = Abortable {
+        num_polls: 0,
+        shared,
+        future: Pinned::new(future),
+    };
+    (future, handle)
+}
+
+/// Wrapper for a `Future` which is aborted once a predicate over shared
+/// state holds.
+///
+/// The predicate is evaluated before every poll, so the abort happens
+/// right after the poll which caused the observed side effect, e.g. the
+/// row being inserted, instead of at a guessed number of polls.
+pub struct AbortWhen<T, F>
+where
+    T: Future,
+{
+    num_polls: usize,
+    reason: AbortReason,
+    predicate: F,
+    future: Pinned<T>,
+}
+
+impl<T, F> AbortWhen<T, F>
+where
+    T: Future,
+{
+    /// Set the reason the inner future is aborted for.
+    pub fn with_reason(mut self, reason: AbortReason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// Number of times the inner future has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+}
+
+impl<T, F> Future for AbortWhen<T, F>
+where
+    T: Future,
+    F: FnMut() -> bool,
+{
+    type Output = Result<T::Output, Aborted>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        if (me.predicate)() {
+            return Poll::Ready(Err(Aborted {
+                num_polls: me.num_polls,
+                reason: me.reason.clone(),
+                ..Aborted::default()
+            }));
+        }
+        me.num_polls += 1;
+        unsafe { me.future.as_pin_mut() }.poll(cx).map(Ok)
+    }
+}
+
+/// Create a `AbortWhen` future wrapper which returns `Err(Aborted)` at
+/// the first poll at which `predicate` returns `true`. If the future is
+/// ready before that `Ok(T)` is returned instead.
+///
+/// ```rust
+/// use std::cell::RefCell;
+/// use futures_test_abort::{abort_when, after};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let rows = RefCell::new(Vec::new());
+/// let insert = async {
+///     for row in 0..3 {
+///         rows.borrow_mut().push(row);
+///         after((), 1).await;
+///     }
+/// };
+/// let aborted = abort_when(insert, || rows.borrow().len() == 2).await.unwrap_err();
+/// assert_eq!(aborted.num_polls, 2);
+/// assert_eq!(*rows.borrow(), [0, 1]);
+/// # }
+/// ```
+pub fn abort_when<T, F>(future: T, predicate: F) -> AbortWhen<T, F>
+where
+    T: Future,
+    F: FnMut() -> bool,
+{
+    AbortWhen {
+        num_polls: 0,
+        reason: Defaults::current().reason,
+        predicate,
+        future: Pinned::new(future),
+    }
+}
+
+/// Wrapper for an aborting future, e.g. `Abort`, `Abortable` or
+/// `AbortWhen`, which resolves to a value computed from `Aborted` instead
+/// of `Err(Aborted)`.
+///
+/// This preserves the output type of the wrapped future, so it can be
+/// passed to code which expects that type, e.g. a `Service` pipeline
+/// whose errors include a cancellation variant.
+pub struct OrOutput<W, F> {
+    output: Option<F>,
+    future: Pinned<W>,
+}
+
+impl<W, F, O> Future for OrOutput<W, F>
+where
+    W: Future<Output = Result<O, Aborted>>,
+    F: FnOnce(Aborted) -> O,
+{
+    type Output = O;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        unsafe { me.future.as_pin_mut() }.poll(cx).map(|result| {
+            result.unwrap_or_else(|aborted| {
+                let output = me.output.take().expect("OrOutput polled after completion");
+                output(aborted)
+            })
+        })
+    }
+}
+
+/// Create a `OrOutput` future wrapper around an aborting future which
+/// resolves to `output(aborted)` if the future was aborted.
+pub fn or_output<W, F, O>(future: W, output: F) -> OrOutput<W, F>
+where
+    W: Future<Output = Result<O, Aborted>>,
+    F: FnOnce(Aborted) -> O,
+{
+    OrOutput {
+        output: Some(output),
+        future: Pinned::new(future),
+    }
+}
+
+/// Create a `Abort` future wrapper like `abort` which resolves to
+/// `output(aborted)` instead of `Err(aborted)` once `max_polls` is
+/// reached. The wrapper has the same output type as `future`.
+///
+/// ```rust
+/// use futures_test_abort::{abort_with_output, after};
+///
+/// #[derive(Debug, PartialEq)]
+/// enum MyError {
+///     Cancelled,
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let future = async {
+///     after((), 1).await;
+///     Ok::<_, MyError>(42)
+/// };
+/// let result = abort_with_output(future, 1, |_| Err(MyError::Cancelled)).await;
+/// assert_eq!(result, Err(MyError::Cancelled));
+/// # }
+/// ```
+pub fn abort_with_output<T, F>(future: T, max_polls: usize, output: F) -> OrOutput<Abort<T>, F>
+where
+    T: Future,
+    F: FnOnce(Aborted) -> T::Output,
+{
+    or_output(abort(future, max_polls), output)
+}
+
+/// Wrapper for a `Future` which only counts the times it is polled. It
+/// resolves to the output of the inner future and the number of polls,
+/// e.g. to assert that a combinator completes within a bounded number of
+/// polls.
+pub struct CountPolls<T>
+where
+    T: Future,
+{
+    num_polls: usize,
+    future: Pinned<T>,
+}
+
+impl<T> CountPolls<T>
+where
+    T: Future,
+{
+    /// Number of times the inner future has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+}
+
+impl<T> Future for CountPolls<T>
+where
+    T: Future,
+{
+    type Output = (T::Output, usize);
+
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.num_polls` or `self.future`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            me.num_polls += 1;
+            me.future.as_pin_mut().poll(cx).map(|output| (output, me.num_polls))
+        }
+    }
+}
+
+/// Create a `CountPolls` future wrapper which resolves to
+/// `(output, num_polls)`.
+///
+/// ```rust
+/// use futures_test_abort::{after, count_polls};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let (output, polls) = count_polls(after(42, 2)).await;
+/// assert_eq!(output, 42);
+/// assert!(polls <= 3);
+/// # }
+/// ```
+pub fn count_polls<T>(future: T) -> CountPolls<T>
+where
+    T: Future,
+{
+    CountPolls {
+        num_polls: 0,
+        future: Pinned::new(future),
+    }
+}
+
+/// Create a `Abort` future wrapper around a poll function. This makes it
+/// possible to test low level poll code without wrapping it into a future
+/// first. The returned future behaves exactly like the one returned by
+/// `abort`.
+pub fn abort_poll_fn<T, F>(max_polls: usize, f: F) -> Abort<PollFn<F>>
+where
+    F: FnMut(&mut Context<'_>) -> Poll<T>,
+{
+    abort(poll_fn(f), max_polls)
+}
+
+/// Asynchronous teardown of a future which was aborted.
+///
+/// This mirrors the `poll_drop_ready` protocol discussed for async drop in
+/// Rust. Until the language supports it natively futures can implement
+/// this trait to make their teardown testable with `abort_async_drop`.
+pub trait AsyncDrop {
+    /// Drive the teardown. The future is dropped once this returns
+    /// `Poll::Ready`.
+    fn poll_drop_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+/// This error is returned when an `AbortAsyncDrop` future resolves after
+/// aborting the inner future.
+#[derive(Debug)]
+pub struct AsyncDropAborted {
+    /// Details about the abort of the inner future.
+    pub aborted: Aborted,
+    /// Number of times `poll_drop_ready` was called.
+    pub drop_polls: usize,
+    /// `true` if the teardown completed within the budget. Otherwise it was
+    /// aborted too and the future was dropped synchronously.
+    pub drop_completed: bool,
+}
+
+/// Wrapper which works like `Abort` but also drives and budgets the
+/// `AsyncDrop` teardown of the aborted future.
+pub struct AbortAsyncDrop<T>
+where
+    T: Future,
+{
+    abort: Abort<T>,
+    aborted: Option<Aborted>,
+    drop_polls: usize,
+    max_drop_polls: usize,
+}
+
+impl<T> Future for AbortAsyncDrop<T>
+where
+    T: Future + AsyncDrop,
+{
+    type Output = Result<T::Output, AsyncDropAborted>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.abort`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            if me.aborted.is_none() {
+                match Pin::new_unchecked(&mut me.abort).poll(cx) {
+                    Poll::Ready(Ok(v)) => return Poll::Ready(Ok(v)),
+                    Poll::Ready(Err(aborted)) => me.aborted = Some(aborted),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            let drop_completed = if me.drop_polls < me.max_drop_polls {
+                me.drop_polls += 1;
+                match me.abort.future.as_pin_mut().poll_drop_ready(cx) {
+                    Poll::Ready(()) => true,
+                    Poll::Pending => return Poll::Pending,
+                }
+            } else {
+                false
+            };
+            Poll::Ready(Err(AsyncDropAborted {
+                aborted: me.aborted.take().unwrap(),
+                drop_polls: me.drop_polls,
+                drop_completed,
+            }))
+        }
+    }
+}
+
+/// Create an `AbortAsyncDrop` future wrapper. After the inner future was
+/// polled `max_polls` times its `AsyncDrop` teardown is polled at most
+/// `max_drop_polls` times before the wrapper resolves.
+pub fn abort_async_drop<T>(future: T, max_polls: usize, max_drop_polls: usize) -> AbortAsyncDrop<T>
+where
+    T: Future + AsyncDrop,
+{
+    AbortAsyncDrop {
+        abort: abort(future, max_polls),
+        aborted: None,
+        drop_polls: 0,
+        max_drop_polls,
+    }
+}
+
+thread_local! {
+    static RECORDINGS: RefCell<Vec<Recording>> = const { RefCell::new(Vec::new()) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Waker hooks which detect stale wakes and record wakes in the trace.
+#[derive(Debug)]
+pub(crate) struct WakeHooks {
+    pub(crate) trace: Option<Trace>,
+    /// Poll in which the waker was handed out.
+    pub(crate) poll: usize,
+    /// Poll of the most recently handed out waker.
+    pub(crate) current: Arc<AtomicUsize>,
+    pub(crate) stale_wakes: Arc<AtomicUsize>,
+}
+
+impl WakerHooks for WakeHooks {
+    fn on_wake(&self) {
+        let event = if self.poll == self.current.load(Ordering::SeqCst) {
+            TraceEvent::Wake
+        } else {
+            self.stale_wakes.fetch_add(1, Ordering::SeqCst);
+            TraceEvent::StaleWake(self.poll)
+        };
+        if let Some(trace) = &self.trace {
+            trace.lock().unwrap().push(event);
+        }
+    }
+
+    fn on_wake_by_ref(&self) {
+        self.on_wake();
+    }
+}
+
+/// Wrapper which simulates a task migrating between executor threads by
+/// passing a fresh waker to the inner future on every poll.
+///
+/// Futures which cache the waker of an earlier poll instead of updating it
+/// work fine on most executors but lose wakes after a migration. Wakes via
+/// such stale wakers are counted and still passed on so the test does not
+/// hang.
+pub struct Migrate<T> {
+    num_polls: usize,
+    current: Arc<AtomicUsize>,
+    stale_wakes: Arc<AtomicUsize>,
+    future: Pinned<T>,
+}
+
+impl<T> Migrate<T> {
+    /// Number of wakes via a waker which was handed out in an earlier poll
+    /// than the latest one.
+    pub fn stale_wakes(&self) -> usize {
+        self.stale_wakes.load(Ordering::SeqCst)
+    }
+}
+
+impl<T> Future for Migrate<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            let layer = WakerLayer::new(WakeHooks {
+                trace: None,
+                poll: me.num_polls,
+                current: me.current.clone(),
+                stale_wakes: me.stale_wakes.clone(),
+            });
+            me.current.store(me.num_polls, Ordering::SeqCst);
+            me.num_polls += 1;
+            let waker = layer.wrap(cx.waker());
+            me.future.as_pin_mut().poll(&mut Context::from_waker(&waker))
+        }
+    }
+}
+
+/// Create a `Migrate` future wrapper.
+pub fn migrate<T>(future: T) -> Migrate<T>
+where
+    T: Future,
+{
+    Migrate {
+        num_polls: 0,
+        current: Arc::default(),
+        stale_wakes: Arc::default(),
+        future: Pinned::new(future),
+    }
+}
+
+/// Wrapper which wakes the task additional times whenever the inner
+/// future returns `Poll::Pending`.
+///
+/// Executors may poll a future more often than it asked for, e.g. because
+/// a waker was cloned into several places. Code which assumes that it is
+/// only polled after the event it waits for happened breaks under such
+/// spurious wakeups. The extra wakes are deterministic so a failure can be
+/// reproduced.
+pub struct SpuriousWakes<T> {
+    extra_wakes_per_poll: usize,
+    num_polls: usize,
+    future: Pinned<T>,
+}
+
+impl<T> SpuriousWakes<T> {
+    /// Number of times the inner future has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+}
+
+impl<T> Future for SpuriousWakes<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        me.num_polls += 1;
+        let result = unsafe { me.future.as_pin_mut() }.poll(cx);
+        if result.is_pending() {
+            for _ in 0..me.extra_wakes_per_poll {
+                cx.waker().wake_by_ref();
+            }
+        }
+        result
+    }
+}
+
+/// Create a `SpuriousWakes` future wrapper which wakes the task
+/// `extra_wakes_per_poll` times on every pending poll.
+///
+/// ```rust
+/// use futures_test_abort::{after, count_polls, spurious_wakes};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// // `after` is woken right away, so one extra wake costs no extra poll
+/// // here, but a future waiting for a channel would be polled in vain.
+/// let (output, polls) = count_polls(spurious_wakes(after(42, 2), 1)).await;
+/// assert_eq!(output, 42);
+/// assert_eq!(polls, 3);
+/// # }
+/// ```
+pub fn spurious_wakes<T>(future: T, extra_wakes_per_poll: usize) -> SpuriousWakes<T>
+where
+    T: Future,
+{
+    SpuriousWakes {
+        extra_wakes_per_poll,
+        num_polls: 0,
+        future: Pinned::new(future),
+    }
+}
+
+/// Hooks called by the wakers created by a `WakerLayer`. All methods do
+/// nothing by default.
+pub trait WakerHooks: Send + Sync + 'static {
+    /// Called when a waker is consumed by `Waker::wake`.
+    fn on_wake(&self) {}
+
+    /// Called on `Waker::wake_by_ref`.
+    fn on_wake_by_ref(&self) {}
+
+    /// Called when a waker is cloned.
+    fn on_clone(&self) {}
+
+    /// Called when a waker is dropped without being woken.
+    fn on_drop(&self) {}
+}
+
+/// Layer which wraps wakers so that every operation on them calls the
+/// `WakerHooks` before it is passed on to the real waker.
+///
+/// This is what the `Sweep` uses to observe wakes. It can also be used
+/// standalone, e.g. to instrument a custom executor.
+#[derive(Debug)]
+pub struct WakerLayer<H> {
+    hooks: Arc<H>,
+}
+
+impl<H> WakerLayer<H>
+where
+    H: WakerHooks,
+{
+    /// Create a new layer calling `hooks`.
+    pub fn new(hooks: H) -> Self {
+        Self { hooks: Arc::new(hooks) }
+    }
+
+    /// The hooks of this layer.
+    pub fn hooks(&self) -> &H {
+        &self.hooks
+    }
+
+    /// Wrap `waker`. The returned waker calls the hooks of this layer and
+    /// then forwards to a clone of `waker`.
+    pub fn wrap(&self, waker: &Waker) -> Waker {
+        let data = Arc::new(LayeredWaker {
+            hooks: self.hooks.clone(),
+            waker: waker.clone(),
+        });
+        // Safety: the vtable matches the type of the data pointer.
+        unsafe { Waker::from_raw(LayeredWaker::<H>::raw(data)) }
+    }
+}
+
+impl<H> Clone for WakerLayer<H> {
+    fn clone(&self) -> Self {
+        Self {
+            hooks: self.hooks.clone(),
+        }
+    }
+}
+
+/// Spy which counts the operations on the wakers it hands out.
+///
+/// Wakers created via `wrap` or passed to a future wrapped by `spy_wakers`
+/// forward to the real waker. `assert_balanced` catches futures which
+/// leak wakers, e.g. by storing them in a queue which is never cleaned up
+/// when the future is aborted.
+#[derive(Clone, Debug)]
+pub struct WakerSpy {
+    layer: WakerLayer<SpyCounts>,
+}
+
+/// Counters of a `WakerSpy`.
+#[derive(Debug, Default)]
+struct SpyCounts {
+    created: AtomicUsize,
+    clones: AtomicUsize,
+    wakes: AtomicUsize,
+    wakes_by_ref: AtomicUsize,
+    drops: AtomicUsize,
+}
+
+impl WakerHooks for SpyCounts {
+    fn on_wake(&self) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_wake_by_ref(&self) {
+        self.wakes_by_ref.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_clone(&self) {
+        self.clones.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_drop(&self) {
+        self.drops.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl WakerSpy {
+    /// Create a spy without any wakers.
+    pub fn new() -> Self {
+        Self {
+            layer: WakerLayer::new(SpyCounts::default()),
+        }
+    }
+
+    /// Wrap `waker` so operations on it and its clones are counted.
+    pub fn wrap(&self, waker: &Waker) -> Waker {
+        self.layer.hooks().created.fetch_add(1, Ordering::SeqCst);
+        self.layer.wrap(waker)
+    }
+
+    /// Number of wakers created via `wrap`.
+    pub fn created(&self) -> usize {
+        self.layer.hooks().created.load(Ordering::SeqCst)
+    }
+
+    /// Number of times a waker was cloned.
+    pub fn clones(&self) -> usize {
+        self.layer.hooks().clones.load(Ordering::SeqCst)
+    }
+
+    /// Number of calls of `Waker::wake`.
+    pub fn wakes(&self) -> usize {
+        self.layer.hooks().wakes.load(Ordering::SeqCst)
+    }
+
+    /// Number of calls of `Waker::wake_by_ref`.
+    pub fn wakes_by_ref(&self) -> usize {
+        self.layer.hooks().wakes_by_ref.load(Ordering::SeqCst)
+    }
+
+    /// Number of wakers dropped without being woken.
+    pub fn drops(&self) -> usize {
+        self.layer.hooks().drops.load(Ordering::SeqCst)
+    }
+
+    /// Number of wakers which are still alive, i.e. were created or
+    /// cloned but neither consumed by `wake` nor dropped.
+    pub fn alive(&self) -> usize {
+        (self.created() + self.clones()).saturating_sub(self.wakes() + self.drops())
+    }
+
+    /// Panic unless every waker was consumed by `wake` or dropped.
+    pub fn assert_balanced(&self) {
+        let alive = self.alive();
+        if alive > 0 {
+            panic!(
+                "{} wakers leaked ({} created, {} clones, {} wakes, {} drops)",
+                alive,
+                self.created(),
+                self.clones(),
+                self.wakes(),
+                self.drops()
+            );
+        }
+    }
+}
+
+impl Default for WakerSpy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wrapper for a `Future` which passes the wakers of a `WakerSpy` to it.
+pub struct SpyWakers<T> {
+    spy: WakerSpy,
+    future: Pinned<T>,
+}
+
+impl<T> Future for SpyWakers<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let waker = me.spy.wrap(cx.waker());
+        unsafe { me.future.as_pin_mut() }.poll(&mut Context::from_waker(&waker))
+    }
+}
+
+/// Create a `SpyWakers` future wrapper and the `WakerSpy` counting the
+/// operations on the wakers passed to `future`. The spy outlives the
+/// wrapper so it can be checked after the future was aborted.
+pub fn spy_wakers<T>(future: T) -> (SpyWakers<T>, WakerSpy)
+where
+    T: Future,
+{
+    let spy = WakerSpy::new();
+    (
+        SpyWakers {
+            spy: spy.clone(),
+            future: Pinned::new(future),
+        },
+        spy,
+    )
+}
+
+/// Data behind the raw wakers created by a `WakerLayer`.
+struct LayeredWaker<H> {
+    hooks: Arc<H>,
+    waker: Waker,
+}
+
+impl<H> LayeredWaker<H>
+where
+    H: WakerHooks,
+{
+    const VTABLE: RawWakerVTable =
+        RawWakerVTable::new(Self::clone_raw, Self::wake_raw, Self::wake_by_ref_raw, Self::drop_raw);
+
+    fn raw(data: Arc<Self>) -> RawWaker {
+        RawWaker::new(Arc::into_raw(data) as *const (), &Self::VTABLE)
+    }
+
+    // Safety: all functions below are only called with pointers created by
+    // `raw` which own one strong reference of the `Arc`.
+
+    unsafe fn clone_raw(data: *const ()) -> RawWaker {
+        let data = data as *const Self;
+        Arc::increment_strong_count(data);
+        let data = Arc::from_raw(data);
+        data.hooks.on_clone();
+        Self::raw(data)
+    }
+
+    unsafe fn wake_raw(data: *const ()) {
+        let data = Arc::from_raw(data as *const Self);
+        data.hooks.on_wake();
+        data.waker.wake_by_ref();
+    }
+
+    unsafe fn wake_by_ref_raw(data: *const ()) {
+        let data = &*(data as *const Self);
+        data.hooks.on_wake_by_ref();
+        data.waker.wake_by_ref();
+    }
+
+    unsafe fn drop_raw(data: *const ()) {
+        let data = Arc::from_raw(data as *const Self);
+        data.hooks.on_drop();
+    }
+}
+
+/// Events recorded while an `Abort` wrapper polls its inner future.
+#[derive(Clone, Debug, Default)]
+struct Recording {
+    /// Labels and whether they were created by `loop_iter!`.
+    labels: Vec<(&'static str, bool)>,
+    /// `Labeled` wrappers which returned `Pending` together with their
+    /// nesting depth in the order they returned.
+    suspensions: Vec<(usize, Suspension)>,
+}
+
+impl Recording {
+    fn extend(&mut self, other: Recording) {
+        self.labels.extend(other.labels);
+        self.suspensions.extend(other.suspensions);
+    }
+
+    /// Chain of `Labeled` wrappers (outermost first) leading to the
+    /// suspension point of the last pending branch.
+    fn chain(&self) -> Vec<Suspension> {
+        let mut chain = Vec::new();
+        let mut depth = None;
+        for (entry_depth, suspension) in self.suspensions.iter().rev() {
+            match depth {
+                None => {}
+                Some(depth) if *entry_depth == depth + 1 => {}
+                Some(depth) if *entry_depth <= depth => break,
+                Some(_) => continue,
+            }
+            depth = Some(*entry_depth);
+            chain.push(*suspension);
+        }
+        chain
+    }
+}
+
+/// Label reached by a future wrapped in `Abort`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label {
+    /// Name passed to `label`.
+    pub name: &'static str,
+    /// Index of the poll (starting at `0`) in which the label was reached.
+    pub poll: usize,
+    /// Number of the loop iteration (starting at `1`) if the label was
+    /// created by `loop_iter!`.
+    pub iteration: Option<usize>,
+}
+
+/// Mark an await point in the code under test.
+///
+/// The label is recorded by all `Abort` wrappers which are currently
+/// polling the calling code. Outside of an `Abort` wrapper this function
+/// does nothing. Labels make it possible to target abort points by name
+/// rather than by poll index, e.g. via `Sweep::abort_after_label`.
+pub fn label(name: &'static str) {
+    push_label(name, false);
+}
+
+thread_local! {
+    static TARGETS: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Make `label` a target of `checkpoint` while `f` is running.
+fn with_target<R>(label: Option<&'static str>, f: impl FnOnce() -> R) -> R {
+    struct Pop;
+    impl Drop for Pop {
+        fn drop(&mut self) {
+            TARGETS.with(|targets| targets.borrow_mut().pop());
+        }
+    }
+    let Some(label) = label else {
+        return f();
+    };
+    TARGETS.with(|targets| targets.borrow_mut().push(label));
+    let _pop = Pop;
+    f()
+}
+
+/// Future returned by `checkpoint`.
+#[derive(Debug)]
+pub struct Checkpoint {
+    name: &'static str,
+    reached: bool,
+}
+
+impl Future for Checkpoint {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.reached {
+            return Poll::Ready(());
+        }
+        self.reached = true;
+        push_label(self.name, false);
+        if TARGETS.with(|targets| targets.borrow().contains(&self.name)) {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Mark a named await point in the code under test.
+///
+/// The checkpoint is recorded like a `label`. If an enclosing `Abort`
+/// wrapper targets it, e.g. one created by `abort_at_checkpoint`, the
+/// future is suspended once so it is aborted exactly at the checkpoint.
+/// Otherwise it is ready immediately and does not change the poll
+/// counts, so checkpoints can stay in the code or be compiled in for
+/// tests only. Unlike poll counts checkpoints keep abort tests stable
+/// when the code under test changes.
+pub fn checkpoint(name: &'static str) -> Checkpoint {
+    Checkpoint { name, reached: false }
+}
+
+/// Suspension of a future wrapped in `Labeled`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Suspension {
+    /// Label of the `Labeled` wrapper.
+    pub label: &'static str,
+    /// Number of times the labeled future has been polled.
+    pub num_polls: usize,
+    /// Location at which the `Labeled` wrapper was created.
+    pub location: &'static Location<'static>,
+}
+
+impl fmt::Display for Suspension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (poll {}) at {}", self.label, self.num_polls, self.location)
+    }
+}
+
+/// Wrapper which gives a future a name for reporting purposes.
+///
+/// When labeled futures are nested (e.g. an outer request handler and an
+/// inner database call) `Aborted::chain` describes where in the nesting
+/// the future was suspended when it was aborted.
+pub struct Labeled<T> {
+    label: &'static str,
+    location: &'static Location<'static>,
+    num_polls: usize,
+    future: Pinned<T>,
+}
+
+impl<T> Future for Labeled<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        struct Depth(usize);
+        impl Drop for Depth {
+            fn drop(&mut self) {
+                DEPTH.with(|depth| depth.set(self.0));
+            }
+        }
+        let depth = Depth(DEPTH.with(|depth| depth.replace(depth.get() + 1)));
+        // Safety: we never move `self.num_polls` or `self.future`
+        let result = unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            me.num_polls += 1;
+            let result = me.future.as_pin_mut().poll(cx);
+            if result.is_pending() {
+                let suspension = Suspension {
+                    label: me.label,
+                    num_polls: me.num_polls,
+                    location: me.location,
+                };
+                RECORDINGS.with(|recordings| {
+                    if let Some(recording) = recordings.borrow_mut().last_mut() {
+                        recording.suspensions.push((depth.0, suspension));
+                    }
+                });
+            }
+            result
+        };
+        drop(depth);
+        result
+    }
+}
+
+/// Create a `Labeled` future wrapper. The location of the caller is
+/// recorded for the async backtrace of `Aborted`.
+#[track_caller]
+pub fn labeled<T>(label: &'static str, future: T) -> Labeled<T>
+where
+    T: Future,
+{
+    Labeled {
+        label,
+        location: Location::caller(),
+        num_polls: 0,
+        future: Pinned::new(future),
+    }
+}
+
+/// Extension trait offering the wrappers of this module as methods, e.g.
+/// `future.abort_after(3)` instead of `abort(future, 3)`. It is
+/// implemented for all futures.
+pub trait AbortExt: Future + Sized {
+    /// Limit the times the future can be polled. See `abort`.
+    fn abort_after(self, max_polls: usize) -> Abort<Self> {
+        abort(self, max_polls)
+    }
+
+    /// Limit the times the future can be woken. See `abort_after_wakes`.
+    fn abort_after_wakes(self, max_wakes: usize) -> Abort<Self> {
+        abort_after_wakes(self, max_wakes)
+    }
+
+    /// Wrap the future in an `Abort` configured by `opts`. See
+    /// `abort_with_opts`.
+    fn abort_with_opts(self, opts: AbortOpts) -> Abort<Self> {
+        abort_with_opts(self, opts)
+    }
+
+    /// Poll the future at most `max_polls` times and return it if it is
+    /// still pending. See `try_abort`.
+    fn try_abort(self, max_polls: usize) -> Probe<Self> {
+        try_abort(self, max_polls)
+    }
+
+    /// Abort the future via the returned handle. See `abort_with_handle`.
+    fn abort_with_handle(self) -> (Abortable<Self>, AbortHandle) {
+        abort_with_handle(self)
+    }
+
+    /// Abort the future once `predicate` holds. See `abort_when`.
+    fn abort_when<F>(self, predicate: F) -> AbortWhen<Self, F>
+    where
+        F: FnMut() -> bool,
+    {
+        abort_when(self, predicate)
+    }
+
+    /// Resolve to `output(aborted)` instead of `Err(aborted)` if the
+    /// future was aborted. See `or_output`.
+    fn or_output<F, O>(self, output: F) -> OrOutput<Self, F>
+    where
+        Self: Future<Output = Result<O, Aborted>>,
+        F: FnOnce(Aborted) -> O,
+    {
+        or_output(self, output)
+    }
+
+    /// Count the times the future is polled. See `count_polls`.
+    fn count_polls(self) -> CountPolls<Self> {
+        count_polls(self)
+    }
+
+    /// Label the future. See `labeled`.
+    #[track_caller]
+    fn labeled(self, label: &'static str) -> Labeled<Self> {
+        labeled(label, self)
+    }
+
+    /// Pass a fresh waker on every poll. See `migrate`.
+    fn migrate(self) -> Migrate<Self> {
+        migrate(self)
+    }
+
+    /// Wake the task additionally on every pending poll. See
+    /// `spurious_wakes`.
+    fn spurious_wakes(self, extra_wakes_per_poll: usize) -> SpuriousWakes<Self> {
+        spurious_wakes(self, extra_wakes_per_poll)
+    }
+}
+
+impl<T> AbortExt for T where T: Future {}
+
+#[doc(hidden)]
+pub fn __loop_iter(name: &'static str) {
+    push_label(name, true);
+}
+
+/// Mark the start of a loop iteration in the code under test.
+///
+/// This works like `label` but the `Abort` wrapper counts the iterations
+/// and reports them in `Aborted::iterations`. The name defaults to the
+/// location of the macro call.
+///
+/// ```rust
+/// # async fn handle(_: u32) {}
+/// # async fn example(messages: Vec<u32>) {
+/// for message in messages {
+///     futures_test_abort::loop_iter!("messages");
+///     handle(message).await;
+/// }
+/// # }
+/// ```
+#[macro_export]
+macro_rules! loop_iter {
+    () => {
+        $crate::__loop_iter(concat!(file!(), ":", line!()))
+    };
+    ($name:expr) => {
+        $crate::__loop_iter($name)
+    };
+}
+
+fn push_label(name: &'static str, is_loop: bool) {
+    trace_event(TraceEvent::Label(name.into()));
+    RECORDINGS.with(|recordings| {
+        if let Some(recording) = recordings.borrow_mut().last_mut() {
+            recording.labels.push((name, is_loop));
+        }
+    });
+}
+
+/// Call `f` and return everything recorded while it was running. The
+/// recording is passed on to the enclosing recording, if any.
+fn record<R>(f: impl FnOnce() -> R) -> (R, Recording) {
+    struct Frame;
+    impl Drop for Frame {
+        fn drop(&mut self) {
+            RECORDINGS.with(|recordings| {
+                let mut recordings = recordings.borrow_mut();
+                let recording = recordings.pop().unwrap_or_default();
+                if let Some(parent) = recordings.last_mut() {
+                    parent.extend(recording);
+                }
+            });
+        }
+    }
+    RECORDINGS.with(|recordings| recordings.borrow_mut().push(Recording::default()));
+    let frame = Frame;
+    let result = f();
+    let recording = RECORDINGS.with(|recordings| recordings.borrow().last().cloned().unwrap_or_default());
+    drop(frame);
+    (result, recording)
+}
+
+/// A future that never resolves but schedules itself to be continuously
+/// polled.
+pub struct Never;
+
+impl Future for Never {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Create a `Never` future which never resolves but schedules itself
+/// to be woken all the time.
+pub fn never() -> Never {
+    Never
+}
+
+/// A future that never resolves and never wakes its task.
+pub struct NeverSilent;
+
+impl Future for NeverSilent {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Pending
+    }
+}
+
+/// Create a `NeverSilent` future which never resolves and, unlike
+/// `never`, never wakes its task. An executor only polls it again if
+/// something else wakes the task, so it stands in for an event which never
+/// happens, e.g. to test timeout wrappers or that code does not rely on
+/// being polled again.
+///
+/// ```rust
+/// use futures_test_abort::executor::{Executor, Order};
+/// use futures_test_abort::never_silent;
+///
+/// let mut executor = Executor::new(Order::RoundRobin);
+/// executor.spawn(never_silent());
+/// let execution = executor.run();
+/// assert_eq!(execution.polled, [0]);
+/// assert_eq!(execution.stuck, [0]);
+/// ```
+pub fn never_silent() -> NeverSilent {
+    NeverSilent
+}
+
+/// A future that panics after a given number of polls.
+pub struct PanicAfter {
+    num_polls: usize,
+    max_polls: usize,
+}
+
+impl Future for PanicAfter {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.num_polls >= self.max_polls {
+            panic!("panic_after: panicked at poll {}", self.num_polls);
+        }
+        self.num_polls += 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Create a `PanicAfter` future which is pending for `max_polls` polls
+/// like `after` and panics in the next poll. Awaiting it inside the code
+/// under test checks that the state stays consistent when an inner future
+/// panics instead of being cancelled.
+///
+/// ```rust
+/// use futures_test_abort::{abort, panic_after};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// // Aborted before the panic.
+/// assert!(abort(panic_after(2), 2).await.is_err());
+/// # }
+/// ```
+pub fn panic_after(max_polls: usize) -> PanicAfter {
+    PanicAfter {
+        num_polls: 0,
+        max_polls,
+    }
+}
+
+/// A future that is ready after a given number of polls.
+pub struct After<T> {
+    value: Option<T>,
+    num_polls: usize,
+    max_polls: usize,
+}
+
+impl<T> Future for After<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ready = self.num_polls >= self.max_polls;
+        // Safety: we never move `self.num_polls`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            if ready {
+                let value = me.value.take().unwrap();
+                return Poll::Ready(value);
+            }
+            me.num_polls += 1;
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Create future that is ready after a given number of polls.
+pub fn after<T>(value: T, max_polls: usize) -> After<T> {
+    After {
+        value: Some(value),
+        num_polls: 0,
+        max_polls,
+    }
+}
+
+/// A future that computes its value once it is ready after a given number
+/// of polls.
+pub struct AfterFn<F> {
+    value: Option<F>,
+    num_polls: usize,
+    max_polls: usize,
+}
+
+impl<F, T> Future for AfterFn<F>
+where
+    F: FnOnce() -> T,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: no field is pinned
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        if me.num_polls >= me.max_polls {
+            let value = me.value.take().expect("AfterFn polled after completion");
+            return Poll::Ready(value());
+        }
+        me.num_polls += 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Create a future like `after` which calls `value` only once it is
+/// ready, so the value need not be constructed up front.
+///
+/// ```rust
+/// use futures_test_abort::{abort, after_fn};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let future = after_fn(|| panic!("never constructed"), 2);
+/// assert!(abort(future, 1).await.is_err());
+/// assert_eq!(after_fn(|| vec![1, 2], 2).await, [1, 2]);
+/// # }
+/// ```
+pub fn after_fn<F, T>(value: F, max_polls: usize) -> AfterFn<F>
+where
+    F: FnOnce() -> T,
+{
+    AfterFn {
+        value: Some(value),
+        num_polls: 0,
+        max_polls,
+    }
+}
+
+/// A future that creates a future once it is ready after a given number
+/// of polls and resolves to its output.
+pub struct AfterAsync<F, Fut> {
+    make: Option<F>,
+    num_polls: usize,
+    max_polls: usize,
+    future: Pinned<Option<Fut>>,
+}
+
+impl<F, Fut> Future for AfterAsync<F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let mut future = unsafe { me.future.as_pin_mut() };
+        if me.num_polls >= me.max_polls {
+            if let Some(make) = me.make.take() {
+                future.set(Some(make()));
+            }
+        }
+        match future.as_pin_mut() {
+            Some(future) => future.poll(cx),
+            None => {
+                me.num_polls += 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Create a future like `after_fn` which calls `make` once it is ready
+/// and then resolves to the output of the created future. The polls of
+/// the created future are not counted in `max_polls`.
+pub fn after_async<F, Fut>(make: F, max_polls: usize) -> AfterAsync<F, Fut>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    AfterAsync {
+        make: Some(make),
+        num_polls: 0,
+        max_polls,
+        future: Pinned::new(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::future::{poll_fn, Future};
+    use std::pin::{pin, Pin};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::time::Duration;
+
+    use super::{
+        abort, abort_async_drop, abort_poll_fn, abort_random, abort_reason, abort_with_opts, abort_with_policy, after,
+        count_polls, label, labeled, migrate, never, spurious_wakes, AbortExt, AbortOpts, AbortReason, Aborted,
+        AsyncDrop, Counting, WakerHooks, WakerLayer,
+    };
+    use crate::{InvariantError, Sweep};
+    use crate::executor::{Executor, Order};
+    use crate::test_util::{labeled_loop, Counter};
+
+    #[tokio::test]
+    async fn abort_n_0_err() {
+        assert!(abort(async { 42 }, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn abort_n_1_ok() {
+        let result = abort(async { 42usize }, 1).await;
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 42usize);
+    }
+
+    #[tokio::test]
+    async fn abort_n_err() {
+        for max_polls in 0..100 {
+            let result = abort(async { never().await }, max_polls).await;
+            assert!(result.is_err());
+            assert_eq!(result.unwrap_err().num_polls, max_polls);
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_n_ok() {
+        for max_polls in 0..100 {
+            let result = abort(async { after(max_polls, max_polls).await }, max_polls+1).await;
+            assert!(result.is_ok());
+            assert_eq!(result.unwrap(), max_polls);
+        }
+    }
+
+    fn countdown(mut remaining: usize) -> impl FnMut(&mut Context<'_>) -> Poll<usize> {
+        move |cx| {
+            if remaining == 0 {
+                return Poll::Ready(42);
+            }
+            remaining -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_poll_fn_budget() {
+        assert_eq!(abort_poll_fn(3, countdown(3)).await.unwrap_err().num_polls, 3);
+        assert_eq!(abort_poll_fn(4, countdown(3)).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn abort_labels() {
+        let counter = Counter { count: Cell::new(0), started: Cell::new(0) };
+        let mut future = Box::pin(abort(labeled_loop(&counter), 4));
+        let aborted = future.as_mut().await.unwrap_err();
+        assert_eq!(aborted.iterations, vec![("loop", 2)]);
+        let labels: Vec<_> = future
+            .labels()
+            .iter()
+            .map(|label| (label.name, label.poll, label.iteration))
+            .collect();
+        assert_eq!(labels, vec![("start", 0, None), ("loop", 2, Some(1)), ("loop", 3, Some(2))]);
+    }
+
+    async fn query(polls: usize) {
+        labeled("query", after((), polls)).await
+    }
+
+    async fn handler() {
+        labeled("handler", async {
+            query(1).await;
+            // Both queries are pending in the same poll but only the last
+            // one polled ends up in the chain.
+            let mut first = Box::pin(query(2));
+            let mut second = Box::pin(labeled("cache", after((), 2)));
+            std::future::poll_fn(move |cx| {
+                // Both futures need the same number of polls.
+                let first = first.as_mut().poll(cx);
+                let second = second.as_mut().poll(cx);
+                if first.is_ready() && second.is_ready() {
+                    return Poll::Ready(());
+                }
+                Poll::Pending
+            })
+            .await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn aborted_chain() {
+        let aborted = abort(handler(), 1).await.unwrap_err();
+        let chain: Vec<_> = aborted.chain.iter().map(|s| (s.label, s.num_polls)).collect();
+        assert_eq!(chain, vec![("handler", 1), ("query", 1)]);
+        let aborted = abort(handler(), 2).await.unwrap_err();
+        let chain: Vec<_> = aborted.chain.iter().map(|s| (s.label, s.num_polls)).collect();
+        assert_eq!(chain, vec![("handler", 2), ("cache", 1)]);
+    }
+
+    #[tokio::test]
+    async fn aborted_backtrace() {
+        let aborted = abort(handler(), 1).await.unwrap_err();
+        let backtrace = aborted.backtrace();
+        let frames: Vec<&str> = backtrace.lines().collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].starts_with("handler (poll 1) at src/future.rs:"), "{}", frames[0]);
+        assert!(frames[1].starts_with("query (poll 1) at src/future.rs:"), "{}", frames[1]);
+        let report = Sweep::new().report(|| (), |_: &()| handler(), |_| Err(InvariantError::new("unsafe"))).await;
+        assert_eq!(report.points[1].backtrace, frames);
+        assert!(report.points[1].explanation().contains("suspended at:\n  handler (poll 1)"));
+    }
+
+    #[tokio::test]
+    async fn abort_into_inner() {
+        struct Steps(usize);
+        impl Future for Steps {
+            type Output = usize;
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<usize> {
+                self.0 += 1;
+                if self.0 == 5 {
+                    return Poll::Ready(self.0);
+                }
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+        let mut future = abort(Steps(0), 3);
+        assert!((&mut future).await.is_err());
+        assert_eq!(future.get_ref().0, 3);
+        let steps = future.into_inner();
+        assert_eq!(steps.0, 3);
+        assert_eq!(abort(steps, 2).await, Ok(5));
+    }
+
+    #[tokio::test]
+    async fn abort_grant_more_polls() {
+        let steps = RefCell::new(Vec::new());
+        let work = async {
+            for step in 0..3 {
+                steps.borrow_mut().push(step);
+                after((), 1).await;
+            }
+        };
+        let mut future = pin!(abort(work, 2).with_grace_polls(1));
+        assert_eq!(future.as_mut().await.unwrap_err().num_polls, 2);
+        // the grace poll ran the third step
+        assert_eq!(*steps.borrow(), [0, 1, 2]);
+        future.as_mut().grant_more_polls(1);
+        assert!(future.as_mut().await.is_ok());
+        assert_eq!(future.num_polls(), 3);
+        let mut finished = pin!(abort(after((), 1), 1).with_grace_polls(1));
+        assert!(finished.as_mut().await.is_err());
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| finished.as_mut().grant_more_polls(1)));
+        assert!(panic.is_err());
+    }
+
+    /// Panics in its second poll unless it was aborted before.
+    async fn panics_later(counter: &Counter) {
+        counter.count.set(counter.count.get() + 1);
+        after((), 1).await;
+        panic!("broken invariant");
+    }
+
+    #[tokio::test]
+    async fn sweep_poisoned_by_panic() {
+        let counter = Counter { count: Cell::new(0), started: Cell::new(0) };
+        let mut future = Box::pin(abort(panics_later(&counter), 5));
+        let waker = Waker::from(Arc::new(crate::executor::Flag(std::sync::atomic::AtomicBool::new(false))));
+        let mut cx = Context::from_waker(&waker);
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.as_mut().poll(&mut cx))).unwrap_err();
+        assert_eq!(crate::harness::panic_message(&*payload), "broken invariant");
+        assert_eq!(future.panicked(), Some((1, "broken invariant")));
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.as_mut().poll(&mut cx))).unwrap_err();
+        assert_eq!(
+            crate::harness::panic_message(&*payload),
+            "Abort polled after the inner future panicked at poll 1: broken invariant"
+        );
+        assert_eq!(counter.count.get(), 1);
+
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let report = Sweep::new().report(setup, panics_later, |_: &Counter| ()).await;
+        assert_eq!(report.points.len(), 3);
+        assert!(report.points[..2].iter().all(|point| point.failure.is_none()));
+        let point = &report.points[2];
+        assert_eq!(point.failure.as_deref(), Some("future panicked at poll 1: broken invariant"));
+        assert_eq!(point.trace.last(), Some(&crate::TraceEvent::Panicked("broken invariant".into())));
+        assert_eq!((point.completed, point.reason.as_deref()), (false, None));
+        assert_eq!(report.num_polls, None);
+    }
+
+    #[tokio::test]
+    async fn sweep_panic_after() {
+        async fn enter(counter: &Counter) {
+            counter.count.set(counter.count.get() + 1);
+            crate::panic_after(1).await;
+            counter.count.set(counter.count.get() - 1);
+        }
+        let payload = std::panic::catch_unwind(|| crate::block_on(crate::panic_after(2))).unwrap_err();
+        assert_eq!(crate::harness::panic_message(&*payload), "panic_after: panicked at poll 2");
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let check = |counter: &Counter| assert_eq!(counter.count.get(), 0);
+        let report = Sweep::new().report(setup, enter, check).await;
+        assert_eq!(report.points.len(), 3);
+        let point = &report.points[2];
+        assert_eq!(point.failure.as_deref(), Some("future panicked at poll 1: panic_after: panicked at poll 1"));
+        assert_eq!(report.points[1].failure.as_deref(), Some("assertion `left == right` failed\n  left: 1\n right: 0"));
+    }
+
+    #[derive(Default)]
+    struct CountHooks {
+        wakes: AtomicUsize,
+        clones: AtomicUsize,
+        drops: AtomicUsize,
+    }
+
+    impl WakerHooks for CountHooks {
+        fn on_wake(&self) {
+            self.wakes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_wake_by_ref(&self) {
+            self.on_wake();
+        }
+
+        fn on_clone(&self) {
+            self.clones.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_drop(&self) {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Default)]
+    struct CountWake(AtomicUsize);
+
+    impl Wake for CountWake {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn waker_layer_hooks() {
+        let layer = WakerLayer::new(CountHooks::default());
+        let inner = Arc::new(CountWake::default());
+        let waker = layer.wrap(&Waker::from(inner.clone()));
+        waker.wake_by_ref();
+        let clone = waker.clone();
+        clone.wake();
+        drop(waker);
+        let hooks = layer.hooks();
+        assert_eq!(hooks.wakes.load(Ordering::Relaxed), 2);
+        assert_eq!(hooks.clones.load(Ordering::Relaxed), 1);
+        assert_eq!(hooks.drops.load(Ordering::Relaxed), 1);
+        assert_eq!(inner.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn waker_spy() {
+        let queue = RefCell::new(Vec::new());
+        let register = poll_fn(|cx| {
+            queue.borrow_mut().push(cx.waker().clone());
+            Poll::<()>::Pending
+        });
+        let (future, spy) = crate::spy_wakers(register);
+        let mut future = Box::pin(future);
+        assert!(future.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_pending());
+        drop(future);
+        assert_eq!((spy.created(), spy.clones(), spy.drops()), (1, 1, 1));
+        assert_eq!(spy.alive(), 1);
+        let leaked = std::panic::catch_unwind(|| spy.assert_balanced()).unwrap_err();
+        assert_eq!(
+            crate::harness::panic_message(&*leaked),
+            "1 wakers leaked (1 created, 1 clones, 0 wakes, 1 drops)"
+        );
+        queue.borrow_mut().pop().unwrap().wake();
+        assert_eq!(spy.wakes(), 1);
+        spy.assert_balanced();
+    }
+
+    /// Future which only stores the waker of its first poll.
+    struct CachedWaker {
+        waker: Option<Waker>,
+        num_polls: usize,
+    }
+
+    impl Future for CachedWaker {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.num_polls += 1;
+            if self.num_polls > 2 {
+                return Poll::Ready(());
+            }
+            let waker = self.waker.get_or_insert_with(|| cx.waker().clone());
+            waker.wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn sweep_migrate() {
+        let make = |_: &()| CachedWaker {
+            waker: None,
+            num_polls: 0,
+        };
+        assert!(Sweep::new().report(|| (), make, |_| {}).await.is_safe());
+        let report = Sweep::new().migrate().report(|| (), make, |_| {}).await;
+        let unsafe_points: Vec<usize> = report
+            .points
+            .iter()
+            .filter(|point| !point.is_safe())
+            .map(|point| point.max_polls)
+            .collect();
+        assert_eq!(unsafe_points, [2, 3]);
+        let mut future = migrate(make(&()));
+        (&mut future).await;
+        assert_eq!(future.stale_wakes(), 1);
+    }
+
+    /// Holds a reference into its own state across every await like most
+    /// generated futures do.
+    async fn self_referential(counter: &Counter) {
+        let mut buf = [0u8; 3];
+        let slots = &mut buf;
+        for slot in slots.iter_mut() {
+            counter.count.set(counter.count.get() + 1);
+            after((), 1).await;
+            *slot = 1;
+            counter.count.set(counter.count.get() - 1);
+        }
+        assert_eq!(buf, [1; 3]);
+    }
+
+    // Checked against the aliasing model by `check-unsafe.sh`. This uses
+    // `block_on` because the wakers of the tokio runtime fail under Miri.
+    #[test]
+    fn self_referential_wrappers() {
+        crate::block_on(async {
+            let counter = Counter { count: Cell::new(0), started: Cell::new(0) };
+            for max_polls in 0..6 {
+                let inner = migrate(count_polls(self_referential(&counter)));
+                let inner = labeled("self", crate::timeout::timeout(Duration::from_secs(1), inner));
+                let inner = crate::with_defaults(crate::Defaults::default(), inner);
+                let (inner, _spy) = crate::spy_wakers(inner);
+                let mut future = Box::pin(abort(inner, max_polls));
+                let result = poll_fn(|cx| {
+                    let _ = future.num_polls();
+                    future.as_mut().poll(cx)
+                })
+                .await;
+                assert_eq!(result.is_ok(), max_polls > 3);
+                drop(future);
+                counter.count.set(0);
+            }
+            let report = Sweep::new()
+                .report(
+                    || Counter { count: Cell::new(0), started: Cell::new(0) },
+                    self_referential,
+                    |_| {},
+                )
+                .await;
+            assert_eq!(report.points.len(), 5);
+        });
+    }
+
+    #[test]
+    fn never_silent_does_not_wake() {
+        let run = |silent: bool| {
+            let mut executor = Executor::new(Order::RoundRobin).max_polls(10);
+            match silent {
+                true => executor.spawn(crate::never_silent()),
+                false => executor.spawn(never()),
+            };
+            executor.run()
+        };
+        assert_eq!(run(false).polled.len(), 10);
+        let execution = run(true);
+        assert_eq!(execution.polled, [0]);
+        assert_eq!(execution.stuck, [0]);
+    }
+
+    /// Assumes the value was sent once it is polled again after it
+    /// registered with the sender.
+    async fn naive_recv(slot: &Cell<Option<u32>>) -> Option<u32> {
+        let mut registered = false;
+        poll_fn(|cx| {
+            if registered {
+                return Poll::Ready(slot.take());
+            }
+            registered = true;
+            drop(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn spurious_wakeups() {
+        let slot = Cell::new(None);
+        let mut stepper = crate::Stepper::new(naive_recv(&slot));
+        assert!(stepper.step().is_pending());
+        assert!(!stepper.is_woken());
+        let mut stepper = crate::Stepper::new(spurious_wakes(naive_recv(&slot), 2));
+        assert!(stepper.step().is_pending());
+        assert!(stepper.is_woken());
+        assert_eq!(stepper.step(), Poll::Ready(None));
+        let mut stepper = crate::Stepper::new(spurious_wakes(never(), 3));
+        assert!(stepper.run_until_stalled(5).is_pending());
+        assert_eq!(stepper.num_polls(), 5);
+    }
+
+    #[tokio::test]
+    async fn abort_when_inserted() {
+        let rows = RefCell::new(Vec::new());
+        let insert = |count| {
+            let rows = &rows;
+            async move {
+                for row in 0..count {
+                    rows.borrow_mut().push(row);
+                    after((), 1).await;
+                }
+                rows.borrow().len()
+            }
+        };
+        let inserted = || rows.borrow().contains(&1);
+        let aborted = crate::abort_when(insert(3), inserted)
+            .with_reason(AbortReason::Timeout)
+            .await
+            .unwrap_err();
+        assert_eq!((aborted.num_polls, aborted.reason), (2, AbortReason::Timeout));
+        assert_eq!(*rows.borrow(), [0, 1]);
+        rows.borrow_mut().clear();
+        assert_eq!(crate::abort_when(insert(1), inserted).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn abort_with_output_type() {
+        let handler = |polls| async move {
+            after((), polls).await;
+            Ok::<_, String>(polls)
+        };
+        let cancelled = |aborted: Aborted| Err(format!("cancelled after {} polls", aborted.num_polls));
+        assert_eq!(crate::abort_with_output(handler(3), 2, cancelled).await, Err("cancelled after 2 polls".into()));
+        assert_eq!(crate::abort_with_output(handler(1), 2, cancelled).await, Ok(1));
+        let (future, handle) = crate::abort_with_handle(handler(3));
+        handle.abort();
+        assert_eq!(crate::AbortExt::or_output(future, |_| Ok(0)).await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn after_lazy_values() {
+        let created = Cell::new(0);
+        let create = || {
+            created.set(created.get() + 1);
+            std::sync::Mutex::new(42)
+        };
+        assert!(abort(crate::after_fn(create, 2), 2).await.is_err());
+        assert_eq!(created.get(), 0);
+        let (value, polls) = count_polls(crate::after_fn(create, 2)).await;
+        assert_eq!((*value.lock().unwrap(), polls, created.get()), (42, 3, 1));
+        let make = || async {
+            after((), 2).await;
+            create()
+        };
+        assert!(abort(crate::after_async(make, 1), 2).await.is_err());
+        assert_eq!(created.get(), 1);
+        let (value, polls) = count_polls(crate::after_async(make, 1)).await;
+        assert_eq!((*value.lock().unwrap(), polls, created.get()), (42, 4, 2));
+    }
+
+    async fn start_twice() {
+        label("started");
+        after((), 1).await;
+        after((), 1).await;
+    }
+
+    #[test]
+    fn abort_opts() {
+        let waker = RefCell::new(None);
+        let ready = Cell::new(false);
+        let inner = poll_fn(|cx| {
+            if ready.get() {
+                return Poll::Ready(());
+            }
+            *waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        });
+        let opts = AbortOpts {
+            max_polls: 2,
+            count_pending_only: true,
+            ..AbortOpts::default()
+        };
+        let mut future = pin!(abort_with_opts(inner, opts));
+        let mut cx = Context::from_waker(Waker::noop());
+        for _ in 0..5 {
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(future.num_polls(), 1);
+        ready.set(true);
+        waker.take().unwrap().wake();
+        assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(future.num_polls(), 2);
+        let opts = AbortOpts {
+            label: Some("started"),
+            reason: AbortReason::Timeout,
+            ..AbortOpts::default()
+        };
+        let mut future = pin!(abort_with_opts(start_twice(), opts));
+        let aborted = loop {
+            if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                break result.unwrap_err();
+            }
+        };
+        assert_eq!(aborted.to_string(), "aborted at 1 polls because of timeout");
+        let expected = Aborted {
+            num_polls: 1,
+            reason: AbortReason::Timeout,
+            ..Aborted::default()
+        };
+        assert_eq!(aborted, expected);
+        let error: Box<dyn std::error::Error + Send + Sync> = Box::new(aborted);
+        assert_eq!(error.to_string(), "aborted at 1 polls because of timeout");
+    }
+
+    #[test]
+    fn abort_random_seed() {
+        let run = |seed| {
+            let mut future = pin!(abort_random(never(), 10, seed));
+            loop {
+                if let Poll::Ready(result) = future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+                    break result.unwrap_err();
+                }
+            }
+        };
+        let aborted = run(42);
+        assert!(aborted.num_polls <= 10);
+        assert_eq!(aborted.seed, Some(42));
+        assert_eq!(run(42), aborted);
+        assert!(aborted.to_string().ends_with(" (seed 42)"));
+        let points: std::collections::HashSet<usize> = (0..50).map(|seed| run(seed).num_polls).collect();
+        assert!(points.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn abort_ext() {
+        assert_eq!(after(42, 2).abort_after(1).await.unwrap_err().num_polls, 1);
+        assert_eq!(after(42, 2).count_polls().await, (42, 3));
+        let aborted = after((), 1).labeled("outer").abort_after(1).await.unwrap_err();
+        assert_eq!(aborted.chain[0].label, "outer");
+        assert!(matches!(after(42, 0).try_abort(1).await, Ok(42)));
+    }
+
+    async fn checkpoints(log: &RefCell<Vec<&'static str>>) {
+        log.borrow_mut().push("start");
+        crate::checkpoint("first").await;
+        log.borrow_mut().push("middle");
+        crate::checkpoint("second").await;
+        log.borrow_mut().push("end");
+    }
+
+    #[tokio::test]
+    async fn abort_at_checkpoint() {
+        let log = RefCell::new(Vec::new());
+        let aborted = crate::abort_at_checkpoint(checkpoints(&log), "second").await.unwrap_err();
+        assert_eq!(aborted.num_polls, 1);
+        assert_eq!(*log.borrow(), ["start", "middle"]);
+        log.borrow_mut().clear();
+        assert_eq!(count_polls(checkpoints(&log)).await, ((), 1));
+        assert!(crate::abort_at_checkpoint(checkpoints(&log), "missing").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn abort_with_handle() {
+        let (future, handle) = crate::abort_with_handle(poll_fn(|_| Poll::<()>::Pending));
+        let controller = std::thread::spawn({
+            let handle = handle.clone();
+            move || handle.abort_with_reason(AbortReason::ClientDisconnect)
+        });
+        let aborted = future.await.unwrap_err();
+        controller.join().unwrap();
+        assert!(handle.is_aborted());
+        assert!(matches!(aborted.reason, AbortReason::ClientDisconnect));
+        let (future, handle) = crate::abort_with_handle(after(42, 2));
+        assert_eq!(future.await.unwrap(), 42);
+        assert!(!handle.is_aborted());
+    }
+
+    #[tokio::test]
+    async fn try_abort_probe() {
+        let counter = Cell::new(0);
+        let future = async {
+            counter.set(1);
+            after((), 2).await;
+            counter.set(2);
+            42
+        };
+        let mut probe = crate::try_abort(future, 2);
+        let future = (&mut probe).await.unwrap_err();
+        assert_eq!(probe.num_polls(), 2);
+        assert_eq!(counter.get(), 1);
+        assert_eq!(future.await, 42);
+        assert_eq!(counter.get(), 2);
+        assert!(matches!(crate::try_abort(async { 42 }, 1).await, Ok(42)));
+    }
+
+    async fn cancellable(log: &RefCell<Vec<String>>) {
+        for _ in 0..3 {
+            after((), 1).await;
+            if let Some(reason) = abort_reason() {
+                log.borrow_mut().push(reason.to_string());
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_reason_grace() {
+        let cleanups = Cell::new(0);
+        let report = Sweep::new()
+            .reason(AbortReason::Timeout)
+            .grace_polls(1)
+            .run(
+                || RefCell::new(Vec::new()),
+                cancellable,
+                |log| {
+                    assert!(log.borrow().iter().all(|reason| reason == "timeout"));
+                    cleanups.set(cleanups.get() + log.borrow().len());
+                },
+            )
+            .await;
+        assert_eq!(report.points.len(), 5);
+        assert_eq!(cleanups.get(), 3);
+        assert!(report.points[..4].iter().all(|point| point.reason.as_deref() == Some("timeout")));
+        assert_eq!(report.points[4].reason, None);
+        let aborted = abort(after((), 1), 0).with_reason(AbortReason::custom(7u8)).await.unwrap_err();
+        assert_eq!(aborted.reason.downcast_ref::<u8>(), Some(&7));
+        assert_eq!(aborted.to_string(), "aborted at 0 polls because of custom reason");
+        assert_eq!(abort_reason().map(|reason| reason.to_string()), None);
+    }
+
+    /// Future which needs two polls to complete and two polls to tear down.
+    #[derive(Default)]
+    struct Teardown {
+        num_polls: usize,
+        drop_polls: usize,
+    }
+
+    impl Future for Teardown {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.num_polls += 1;
+            if self.num_polls > 2 {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    impl AsyncDrop for Teardown {
+        fn poll_drop_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.drop_polls += 1;
+            if self.drop_polls > 1 {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_async_drop_budget() {
+        let aborted = abort_async_drop(Teardown::default(), 1, 5).await.unwrap_err();
+        assert_eq!(aborted.aborted.num_polls, 1);
+        assert_eq!(aborted.drop_polls, 2);
+        assert!(aborted.drop_completed);
+        let aborted = abort_async_drop(Teardown::default(), 1, 1).await.unwrap_err();
+        assert_eq!(aborted.drop_polls, 1);
+        assert!(!aborted.drop_completed);
+        assert!(abort_async_drop(Teardown::default(), 3, 0).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn abort_expected_polls() {
+        let aborted = abort(after((), 5), 3).with_expected_polls(6).await.unwrap_err();
+        assert_eq!(aborted.to_string(), "aborted at 3 of ~6 expected polls");
+    }
+
+    #[tokio::test]
+    async fn abort_counting() {
+        let aborted = abort_with_policy::<_, Counting>(labeled("outer", after((), 5)), 3).await.unwrap_err();
+        assert_eq!(aborted.num_polls, 3);
+        assert!(aborted.chain.is_empty());
+        let mut future = Box::pin(count_polls(after(7, 2)));
+        assert_eq!(future.as_mut().await, (7, 3));
+        assert_eq!(future.num_polls(), 3);
+        let defaults = crate::Defaults {
+            count_pending_only: true,
+            ..crate::Defaults::current()
+        };
+        let spurious = crate::with_defaults(defaults, async {
+            let mut future = pin!(abort_with_policy::<_, Counting>(never(), 2));
+            let mut cx = Context::from_waker(Waker::noop());
+            (0..3).map(|_| future.as_mut().poll(&mut cx).is_ready()).collect::<Vec<_>>()
+        });
+        assert_eq!(spurious.await, [false, false, true]);
+    }
+
+    #[test]
+    fn abort_after_wakes() {
+        let mut stepper = crate::Stepper::new(crate::abort_after_wakes(std::future::pending::<()>(), 1));
+        for _ in 0..5 {
+            assert!(stepper.step().is_pending());
+        }
+        let mut stepper = crate::Stepper::new(crate::abort_after_wakes(after((), 5), 2));
+        assert!(stepper.step().is_pending());
+        assert!(stepper.step().is_pending());
+        match stepper.step() {
+            Poll::Ready(Err(aborted)) => assert_eq!(aborted.num_polls, 2),
+            _ => panic!("future was not aborted after two wakes"),
+        }
+        assert!(crate::block_on(crate::abort_after_wakes(after(1, 1), 2)).is_ok());
+    }
+}
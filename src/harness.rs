@@ -0,0 +1,2747 @@
+//! Harness which runs a future against every possible abort point.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "baseline")]
+use std::path::PathBuf;
+use std::pin::{pin, Pin};
+use std::process::{self, Command, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+
+#[cfg(feature = "baseline")]
+use crate::baseline;
+#[cfg(feature = "cache")]
+use crate::cache;
+#[cfg(feature = "console")]
+use crate::console::{self, TaskSpan};
+use crate::executor::block_on;
+use crate::future::{abort, after, AbortReason, Aborted, Label, Pinned, WakeHooks, WakerLayer};
+use crate::invariant::{self, CheckResult};
+use crate::registry;
+use crate::report::{PointReport, Report, Skipped, SpuriousPolls, TimeoutMatrix, TimeoutRow, TraceEvent};
+use crate::timeout;
+use crate::watchdog::Watchdog;
+use crate::rng::{self, RandomSources, Streams};
+
+/// Factory for the futures tested by a `Sweep`.
+///
+/// The created future may borrow from the state which the harness creates
+/// fresh for every iteration. This trait is implemented for all functions
+/// and closures taking a `&S` and returning a future, e.g. an
+/// `async fn do_something(state: &State)`.
+///
+/// `Bound` only exists so `for<'a> MakeFuture<'a, S>` implies `S: 'a`.
+/// Leave it at its default.
+pub trait MakeFuture<'a, S, Bound = &'a S> {
+    /// The future created by this factory.
+    type Future: Future + 'a;
+    /// Create a new future borrowing the given state.
+    fn make(&mut self, state: &'a S) -> Self::Future;
+}
+
+impl<'a, S, F, Fut> MakeFuture<'a, S> for F
+where
+    S: 'a,
+    F: FnMut(&'a S) -> Fut,
+    Fut: Future + 'a,
+{
+    type Future = Fut;
+
+    fn make(&mut self, state: &'a S) -> Self::Future {
+        self(state)
+    }
+}
+
+/// Harness which aborts a future at every possible poll.
+///
+/// For every abort point a fresh state is created, the future is created
+/// from it and aborted after the given number of polls. Afterwards the
+/// check is called with the state. This is repeated until the future
+/// completes without being aborted.
+///
+/// With the `console` feature every iteration appears as a task named
+/// after the sweep and the abort point in `tokio-console` if the test
+/// binary installs `console-subscriber`. Dropping the aborted future
+/// appears as a separate cleanup task so cleanup which is stuck stands
+/// out in long soak runs.
+#[derive(Debug)]
+pub struct Sweep {
+    name: Option<String>,
+    max_polls: usize,
+    time_budget: Option<Duration>,
+    schedule: Option<Schedule>,
+    label_target: Option<LabelTarget>,
+    only_tagged: Option<&'static str>,
+    clock: Arc<dyn Clock>,
+    subprocess: Option<Subprocess>,
+    migrate: bool,
+    expected_polls: Option<usize>,
+    drop_timing: DropTiming,
+    seed: Option<u64>,
+    random_sources: Option<RandomSources>,
+    capacity: Capacity,
+    reason: AbortReason,
+    grace_polls: usize,
+    watchdog: Option<Duration>,
+    setup_hook: Option<Hook>,
+    teardown_hook: Option<Hook>,
+    /// Poll budget of the hooks or `None` for `max_polls`.
+    hook_max_polls: Option<usize>,
+    #[cfg(feature = "cache")]
+    cache_version: Option<String>,
+    #[cfg(feature = "baseline")]
+    baseline: Option<PathBuf>,
+}
+
+/// Async function run around every iteration. See `Sweep::setup`.
+struct Hook(Box<dyn Fn() -> Pin<Box<dyn Future<Output = ()>>> + Send + Sync>);
+
+impl Hook {
+    fn new<F, Fut>(hook: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        Self(Box::new(move || Box::pin(hook())))
+    }
+}
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// Span in which an iteration runs. See the `console` feature.
+#[cfg(not(feature = "console"))]
+#[derive(Debug)]
+struct TaskSpan;
+
+#[cfg(not(feature = "console"))]
+impl TaskSpan {
+    fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+}
+
+/// Capacity hints of a `Sweep`.
+#[derive(Clone, Copy, Debug, Default)]
+struct Capacity {
+    points: usize,
+    trace: usize,
+}
+
+#[derive(Debug)]
+struct Subprocess {
+    test_name: String,
+    isolate: bool,
+}
+
+#[derive(Debug)]
+struct LabelTarget {
+    name: &'static str,
+    /// Occurrence (starting at `1`) to abort after or `None` for every
+    /// occurrence.
+    occurrence: Option<usize>,
+}
+
+impl Sweep {
+    /// Create a new `Sweep` with default settings. The settings are taken
+    /// from the profile named by the `FTA_PROFILE` environment variable
+    /// or `Profile::Standard` if it is not set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the settings of the given profile.
+    pub fn profile(mut self, profile: Profile) -> Self {
+        let settings = profile.settings();
+        self.max_polls = settings.max_polls;
+        self.time_budget = settings.time_budget;
+        self
+    }
+
+    /// Set the name of the scenario tested by this sweep. The name is
+    /// included in the report.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set the maximum number of polls a future is allowed to take.
+    /// The sweep stops if the future does not complete within this
+    /// number of polls.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Set the time after which the sweep stops aborting the future. The
+    /// future is still run to completion once at the end of the sweep
+    /// and the remaining abort points are missing from the report.
+    pub fn time_budget(mut self, time_budget: Duration) -> Self {
+        self.time_budget = Some(time_budget);
+        self
+    }
+
+    /// Only abort the future at the points of the given schedule instead
+    /// of every possible poll. The future is still run to completion
+    /// once at the end of the sweep.
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Only abort the future immediately after it reaches the given
+    /// label for the first time. Unlike poll indices labels don't shift
+    /// when unrelated code changes.
+    pub fn abort_after_label(mut self, name: &'static str) -> Self {
+        self.label_target = Some(LabelTarget {
+            name,
+            occurrence: Some(1),
+        });
+        self
+    }
+
+    /// Abort the future immediately after every occurrence of the given
+    /// label, e.g. once for every iteration of a loop.
+    pub fn abort_after_every_label(mut self, name: &'static str) -> Self {
+        self.label_target = Some(LabelTarget {
+            name,
+            occurrence: None,
+        });
+        self
+    }
+
+    /// Abort the future immediately after the given iteration (starting
+    /// at `1`) of a loop marked with `loop_iter!`. Bugs often only appear
+    /// after the first iteration.
+    pub fn abort_at_iteration(mut self, name: &'static str, iteration: usize) -> Self {
+        self.label_target = Some(LabelTarget {
+            name,
+            occurrence: Some(iteration),
+        });
+        self
+    }
+
+    /// Only abort the future at abort points following a call of `tag`
+    /// with the given name, e.g. to sweep critical sections more deeply
+    /// than the rest. The polls in which the tag is recorded are
+    /// discovered by running the future to completion once.
+    pub fn only_tagged(mut self, name: &'static str) -> Self {
+        self.only_tagged = Some(name);
+        self
+    }
+
+    /// Set the clock used for all time measurements of this sweep.
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Run the sweep in a subprocess of the test binary.
+    ///
+    /// The test binary is spawned with the given test name as filter and
+    /// runs the sweep instead of the parent process. If a check panics in
+    /// a binary built with `panic = "abort"` or the cleanup of a future
+    /// crashes the process, the abort point is recorded as failure and the
+    /// sweep continues in a new subprocess. `test_name` must be the full
+    /// path of the test calling this method, e.g. `"tests::my_test"`.
+    pub fn subprocess(mut self, test_name: impl Into<String>) -> Self {
+        self.subprocess = Some(Subprocess {
+            test_name: test_name.into(),
+            isolate: false,
+        });
+        self
+    }
+
+    /// Run every iteration of the sweep in its own subprocess of the test
+    /// binary. This works like `subprocess` but also isolates the abort
+    /// points from each other so a corrupted process state can't affect
+    /// the following iterations. Crashes are recorded in the report.
+    pub fn isolate(mut self, test_name: impl Into<String>) -> Self {
+        self.subprocess = Some(Subprocess {
+            test_name: test_name.into(),
+            isolate: true,
+        });
+        self
+    }
+
+    /// Set the number of polls the future is expected to need. This is
+    /// only a hint which makes reports easier to interpret.
+    pub fn expected_polls(mut self, expected_polls: usize) -> Self {
+        self.expected_polls = Some(expected_polls);
+        self
+    }
+
+    /// Abort the future for the given reason. See `AbortReason`.
+    pub fn reason(mut self, reason: AbortReason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// Poll the future up to `grace_polls` more times after it was aborted
+    /// so it can clean up depending on `abort_reason`. See
+    /// `Abort::with_grace_polls`.
+    pub fn grace_polls(mut self, grace_polls: usize) -> Self {
+        self.grace_polls = grace_polls;
+        self
+    }
+
+    /// Derive all randomness of the sweep from this seed. See the `rng`
+    /// module.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Draw all randomness of the sweep from the given sources instead of
+    /// the `Rng` substreams of the seed, e.g. to replay fuzzer input. The
+    /// seed is still reported but no longer used.
+    pub fn random_sources(mut self, random_sources: RandomSources) -> Self {
+        self.random_sources = Some(random_sources);
+        self
+    }
+
+    /// Cache the number of polls and the labels of the future on disk. The
+    /// entry is keyed by the name of the sweep and only used if it was
+    /// stored with the same version. A cached entry replaces the discovery
+    /// run of `abort_after_label` and is reported as
+    /// `Report::previous_polls`. See the `cache` module.
+    #[cfg(feature = "cache")]
+    pub fn cache(mut self, version: impl Into<String>) -> Self {
+        self.cache_version = Some(version.into());
+        self
+    }
+
+    /// Check the unsafe abort points against the baseline file at `path`
+    /// in `run` instead of failing for all of them. The entry is keyed by
+    /// the name of the sweep. See the `baseline` module.
+    #[cfg(feature = "baseline")]
+    pub fn baseline(mut self, path: impl Into<PathBuf>) -> Self {
+        self.baseline = Some(path.into());
+        self
+    }
+
+    /// Reserve space for the given number of abort points in the report
+    /// up front. This avoids reallocations in sweeps with many points.
+    pub fn points_capacity(mut self, points: usize) -> Self {
+        self.capacity.points = points;
+        self
+    }
+
+    /// Reserve space for the given number of trace events per abort
+    /// point. The trace buffer is reused across iterations.
+    pub fn trace_capacity(mut self, events: usize) -> Self {
+        self.capacity.trace = events;
+        self
+    }
+
+    /// Pass a fresh waker to the future on every poll like `migrate` does.
+    /// Abort points at which the future was woken via a stale waker are
+    /// reported as failed.
+    pub fn migrate(mut self) -> Self {
+        self.migrate = true;
+        self
+    }
+
+    /// Hold aborted futures for a while before dropping them like some
+    /// executors do with cancelled tasks, or leak them. The check is called
+    /// both while the future is held and after it was dropped.
+    pub fn drop_timing(mut self, drop_timing: DropTiming) -> Self {
+        self.drop_timing = drop_timing;
+        self
+    }
+
+    /// Watch every poll from a separate thread and print diagnostics once
+    /// a single poll takes longer than `limit`: the abort point, the
+    /// elapsed time and the events recorded so far including labels. The
+    /// iteration fails when the poll returns. A poll which never returns
+    /// still blocks the test, but no longer silently.
+    pub fn watchdog(mut self, limit: Duration) -> Self {
+        self.watchdog = Some(limit);
+        self
+    }
+
+    /// Run `hook` before every iteration, e.g. to reset a temporary
+    /// database or a mock server used by the future. The state is created
+    /// after the hook completed. A hook which does not complete within
+    /// its poll budget (see `hook_max_polls`) fails the iteration.
+    ///
+    /// The runs which discover labels, tags or state hashes are not
+    /// iterations and run without hooks.
+    pub fn setup<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.setup_hook = Some(Hook::new(hook));
+        self
+    }
+
+    /// Run `hook` after every iteration once the check was called. See
+    /// `setup`.
+    pub fn teardown<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.teardown_hook = Some(Hook::new(hook));
+        self
+    }
+
+    /// Set the number of polls the `setup` and `teardown` hooks may take
+    /// each. Defaults to `max_polls`.
+    pub fn hook_max_polls(mut self, max_polls: usize) -> Self {
+        self.hook_max_polls = Some(max_polls);
+        self
+    }
+
+    /// Run the sweep and return the report. Panics if the check failed
+    /// for any abort point or if the future did not complete within
+    /// `max_polls`.
+    pub async fn run<S, Setup, Make, Check, R>(&self, setup: Setup, make: Make, check: Check) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let report = self.report(setup, make, check).await;
+        self.assert_safe(&report);
+        report
+    }
+
+    /// Run the sweep and return the report. Unlike `run` this method
+    /// does not panic but records failed checks in the report.
+    ///
+    /// Panics in checks can only be recorded if the binary is built with
+    /// `panic = "unwind"`. Use `subprocess` for `panic = "abort"` builds.
+    pub async fn report<S, Setup, Make, Check, R>(&self, setup: Setup, make: Make, check: Check) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let report = self.sweep(setup, make, check, None, None).await;
+        registry::record(&report);
+        report
+    }
+
+    /// Run the sweep lazily and yield the report of every iteration as
+    /// soon as it is done. The next iteration only runs when the next
+    /// item is requested, so dropping the stream ends the sweep early.
+    ///
+    /// The last item is the iteration in which the future completed
+    /// unless the future did not complete within `max_polls`.
+    pub fn stream<'a, S, Setup, Make, Check, R>(&'a self, setup: Setup, make: Make, check: Check) -> Iterations<'a>
+    where
+        S: 'a,
+        Setup: FnMut() -> S + 'a,
+        Make: for<'b> MakeFuture<'b, S> + 'a,
+        Check: FnMut(&S) -> R + 'a,
+        R: CheckResult + 'a,
+    {
+        let emitted = Emitted::default();
+        let points = emitted.clone();
+        let sweep = async move { self.sweep(setup, make, check, None, Some(&emitted)).await };
+        Iterations {
+            points,
+            sweep: Some(Box::pin(sweep)),
+        }
+    }
+
+    /// Run the sweep like `report` once for every poll at which a
+    /// `timeout::timeout` of the future may fire and once without a
+    /// timeout firing. The reports form a matrix of timeout polls and
+    /// abort points.
+    ///
+    /// The timeout polls are counted in an additional run of the future
+    /// before the sweeps start.
+    pub async fn report_timeouts<S, Setup, Make, Check, R>(
+        &self,
+        mut setup: Setup,
+        mut make: Make,
+        mut check: Check,
+    ) -> TimeoutMatrix
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let state = setup();
+        let run = abort(timeout::Restart(&mut make).make(&state), self.max_polls);
+        let (_, num_polls) = timeout::Scoped::new(None, run).await;
+        drop(state);
+        let mut rows = Vec::with_capacity(num_polls + 1);
+        for fire_at in std::iter::once(None).chain((0..num_polls).map(Some)) {
+            let sweep = self.report(&mut setup, timeout::Restart(&mut make), &mut check);
+            let (report, _) = timeout::Scoped::new(fire_at, sweep).await;
+            rows.push(TimeoutRow::new(fire_at, report));
+        }
+        TimeoutMatrix::new(rows)
+    }
+
+    /// Run the sweep like `report` once for every poll of the future
+    /// before which the paused tokio clock is advanced by `duration` and
+    /// once without advancing it. This sweeps code which calls
+    /// `tokio::time::timeout` itself: every timeout of at most `duration`
+    /// which is pending at that poll fires. The reports form a matrix of
+    /// polls and abort points like the one of `report_timeouts`.
+    ///
+    /// Time must be paused by `tokio::time::pause` before. The timers fire
+    /// while the runtime parks, so the future is polled again only after
+    /// the extra polls in which the sweep waits for that. They are abort
+    /// points of their own and their number depends on the runtime. While
+    /// the future waits for other timers the runtime advances the paused
+    /// clock to them, which may fire the timeout earlier.
+    ///
+    /// ```rust
+    /// use std::cell::Cell;
+    /// use std::time::Duration;
+    ///
+    /// use futures_test_abort::{after, Sweep};
+    ///
+    /// async fn request(open: &Cell<u32>) {
+    ///     open.set(open.get() + 1);
+    ///     let _ = tokio::time::timeout(Duration::from_secs(1), after((), 2)).await;
+    ///     open.set(open.get() - 1);
+    /// }
+    ///
+    /// # #[tokio::main(basic_scheduler)]
+    /// # async fn main() {
+    /// tokio::time::pause();
+    /// let matrix = Sweep::new()
+    ///     .report_tokio_timeouts(Duration::from_secs(1), || Cell::new(0), request, |open| assert!(open.get() <= 1))
+    ///     .await;
+    /// matrix.assert_safe();
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio-time")]
+    pub async fn report_tokio_timeouts<S, Setup, Make, Check, R>(
+        &self,
+        duration: Duration,
+        mut setup: Setup,
+        mut make: Make,
+        mut check: Check,
+    ) -> TimeoutMatrix
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let state = setup();
+        let num_polls = {
+            let mut run = pin!(abort(make.make(&state), self.max_polls));
+            let _ = run.as_mut().await;
+            run.num_polls()
+        };
+        drop(state);
+        let mut rows = Vec::with_capacity(num_polls + 1);
+        for advance_at in std::iter::once(None).chain((0..num_polls).map(Some)) {
+            let make = timeout::AdvanceAt {
+                advance_at,
+                duration,
+                make: &mut make,
+            };
+            let report = self.report(&mut setup, make, &mut check).await;
+            rows.push(TimeoutRow::new(advance_at, report));
+        }
+        TimeoutMatrix::new(rows)
+    }
+
+    /// Run the sweep like `report_spurious_polls`. Panics if the check
+    /// failed for any abort point or if the outcome of an abort point
+    /// changed with the spurious polls.
+    pub async fn run_spurious_polls<S, Setup, Make, Check, R>(
+        &self,
+        extra_polls: usize,
+        setup: Setup,
+        make: Make,
+        check: Check,
+    ) -> SpuriousPolls
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let reports = self.report_spurious_polls(extra_polls, setup, make, check).await;
+        reports.assert_safe();
+        reports
+    }
+
+    /// Run the sweep like `report` twice: once as usual and once with
+    /// `extra_polls` spurious polls after every poll in which the future
+    /// returned `Poll::Pending`. The spurious polls happen within the same
+    /// poll of the abort wrapper, so the future is aborted at the same
+    /// await point in both sweeps.
+    ///
+    /// Code which only makes progress when it is woken is not affected by
+    /// the spurious polls. An abort point at which the check passes in one
+    /// sweep and fails in the other is listed by `SpuriousPolls::sensitive`:
+    /// either the future or the test counts polls instead of awaiting
+    /// events.
+    pub async fn report_spurious_polls<S, Setup, Make, Check, R>(
+        &self,
+        extra_polls: usize,
+        mut setup: Setup,
+        mut make: Make,
+        mut check: Check,
+    ) -> SpuriousPolls
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let plain = self.report(&mut setup, Spurious(0, &mut make), &mut check).await;
+        let spurious = self.report(&mut setup, Spurious(extra_polls, &mut make), &mut check).await;
+        SpuriousPolls::new(extra_polls, plain, spurious)
+    }
+
+    /// Run the sweep like `run` but skip abort points at which the state
+    /// has the same hash as at an abort point which was already tested.
+    /// This shrinks sweeps over loops with many identical iterations.
+    pub async fn run_deduplicated<S, Setup, Make, Check, R, Hash>(
+        &self,
+        setup: Setup,
+        make: Make,
+        check: Check,
+        state_hash: Hash,
+    ) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+        Hash: FnMut(&S) -> u64,
+    {
+        let report = self.report_deduplicated(setup, make, check, state_hash).await;
+        self.assert_safe(&report);
+        report
+    }
+
+    /// Run the sweep like `report` but skip abort points at which the
+    /// state has the same hash as at an abort point which was already
+    /// tested. The skipped points are listed in `Report::skipped`.
+    ///
+    /// The hashes are computed in an additional run of the future before
+    /// the sweep starts. The hash of an abort point is the hash of the
+    /// state right before the future is aborted.
+    pub async fn report_deduplicated<S, Setup, Make, Check, R, Hash>(
+        &self,
+        setup: Setup,
+        make: Make,
+        check: Check,
+        mut state_hash: Hash,
+    ) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+        Hash: FnMut(&S) -> u64,
+    {
+        let report = self.sweep(setup, make, check, Some(&mut state_hash), None).await;
+        registry::record(&report);
+        report
+    }
+
+    /// Run the sweep for an operation which is retried until it succeeds
+    /// like `report_retries`. Panics if the check failed for any abort
+    /// point.
+    pub async fn run_retries<S, Setup, Make, Check, R>(
+        &self,
+        attempts: usize,
+        setup: Setup,
+        make: Make,
+        check: Check,
+    ) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let report = self.report_retries(attempts, setup, make, check).await;
+        self.assert_safe(&report);
+        report
+    }
+
+    /// Drop `future` without ever polling it and call `invariant`
+    /// afterwards like the free function `abort_before_first_poll`, timed
+    /// with the `clock` of this sweep. Panics if the invariant failed.
+    pub fn abort_before_first_poll<Fut, Check, R>(&self, future: Fut, invariant: Check) -> PointReport
+    where
+        Fut: Future,
+        Check: FnOnce() -> R,
+        R: CheckResult,
+    {
+        let start = self.clock.now();
+        let trace: Trace = Arc::new(Mutex::new(vec![TraceEvent::Aborted]));
+        with_trace(&trace, || drop(future));
+        let mut invariant = Some(invariant);
+        let mut check = |_: &()| invariant.take().unwrap()();
+        let (failure, invariant_errors) = invariant::evaluate(&mut check, &());
+        let point = PointReport {
+            never_polled: true,
+            failure,
+            elapsed: self.clock.now().saturating_duration_since(start),
+            trace: trace.lock().unwrap().drain(..).collect(),
+            invariant_errors,
+            reason: Some(AbortReason::Dropped.to_string()),
+            ..PointReport::default()
+        };
+        if let Some(failure) = &point.failure {
+            panic!("not abort-safe: check failed for a future which was never polled: {}", failure);
+        }
+        point
+    }
+
+    /// Run the sweep for an operation which is retried until it succeeds,
+    /// e.g. a request resent by a client after a timeout.
+    ///
+    /// For every abort point the first `attempts - 1` attempts are aborted
+    /// at that point and the last attempt runs to completion. All attempts
+    /// are created from the same state and the check is called once after
+    /// the last attempt, so it can assert that the effects of the
+    /// operation happened exactly once no matter how many attempts were
+    /// aborted. The sweep ends with the point at which the first attempt
+    /// completes. A last attempt which does not complete within
+    /// `max_polls` is recorded as failure.
+    ///
+    /// The attempts honour `reason`, `grace_polls`, `seed`,
+    /// `random_sources`, `time_budget`, `clock` and the hooks. Panics if
+    /// `attempts` is less than `2` or if `schedule`, `abort_after_label`,
+    /// `only_tagged`, `subprocess`, `migrate`, `watchdog` or a
+    /// `drop_timing` other than `DropTiming::Immediate` is set, as these
+    /// only apply to a single future per abort point.
+    pub async fn report_retries<S, Setup, Make, Check, R>(
+        &self,
+        attempts: usize,
+        mut setup: Setup,
+        mut make: Make,
+        mut check: Check,
+    ) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        assert!(attempts >= 2, "an operation needs at least 2 attempts to be retried");
+        self.assert_single_future_settings("report_retries");
+        let mut report = Report {
+            name: self.name.clone(),
+            max_polls: self.max_polls,
+            expected_polls: self.expected_polls,
+            seed: self.seed,
+            points: Vec::with_capacity(self.capacity.points),
+            ..Report::default()
+        };
+        let sweep_start = self.clock.now();
+        for mut max_polls in 0..=self.max_polls {
+            let elapsed = self.clock.now().saturating_duration_since(sweep_start);
+            if self.time_budget.is_some_and(|time_budget| elapsed >= time_budget) {
+                max_polls = self.max_polls;
+            }
+            let start = self.clock.now();
+            let trace: Trace = Arc::new(Mutex::new(Vec::with_capacity(self.capacity.trace)));
+            let setup_failure = self.run_hook(&self.setup_hook, "setup").await;
+            let state = setup();
+            let mut streams = self.streams();
+            let mut completed = None;
+            let mut aborted = None;
+            for _ in 1..attempts {
+                match self.attempt(&trace, &mut streams, make.make(&state), max_polls).await {
+                    Ok(num_polls) => {
+                        completed = Some(num_polls);
+                        break;
+                    }
+                    Err(error) => aborted = Some(error),
+                }
+            }
+            let mut failure = None;
+            if completed.is_none() {
+                let last = self.attempt(&trace, &mut streams, make.make(&state), self.max_polls).await;
+                if last.is_err() {
+                    failure = Some(format!("retry did not complete within {} polls", self.max_polls));
+                }
+            }
+            let (check_failure, invariant_errors) = invariant::evaluate(&mut check, &state);
+            drop(state);
+            let teardown_failure = self.run_hook(&self.teardown_hook, "teardown").await;
+            report.points.push(PointReport {
+                max_polls,
+                completed: completed.is_some(),
+                never_polled: aborted.as_ref().is_some_and(|aborted| aborted.num_polls == 0),
+                failure: setup_failure.or(failure).or(check_failure).or(teardown_failure),
+                elapsed: self.clock.now().saturating_duration_since(start),
+                trace: trace.lock().unwrap().drain(..).collect(),
+                invariant_errors,
+                backtrace: aborted
+                    .as_ref()
+                    .map(|aborted| aborted.chain.iter().map(ToString::to_string).collect())
+                    .unwrap_or_default(),
+                reason: aborted.map(|aborted| aborted.reason.to_string()),
+                ..PointReport::default()
+            });
+            if completed.is_some() {
+                report.num_polls = completed;
+                break;
+            }
+        }
+        registry::record(&report);
+        report
+    }
+
+    async fn sweep<S, Setup, Make, Check, R>(
+        &self,
+        mut setup: Setup,
+        mut make: Make,
+        mut check: Check,
+        state_hash: Option<&mut dyn FnMut(&S) -> u64>,
+        emitted: Option<&Emitted>,
+    ) -> Report
+    where
+        Setup: FnMut() -> S,
+        Make: for<'a> MakeFuture<'a, S>,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let mut report = Report {
+            name: self.name.clone(),
+            max_polls: self.max_polls,
+            expected_polls: self.expected_polls,
+            seed: self.seed,
+            points: Vec::with_capacity(self.capacity.points),
+            ..Report::default()
+        };
+        let cached = self.load_cache();
+        report.previous_polls = cached.as_ref().map(|entry| entry.num_polls);
+        let mut child_start = None;
+        if let Some(subprocess) = &self.subprocess {
+            match subprocess_start(&subprocess.test_name) {
+                Some(start) => child_start = Some(start),
+                None => return self.report_in_subprocess(&subprocess.test_name, report),
+            }
+        }
+        let mut schedule = self.schedule.clone();
+        if let Some(target) = &self.label_target {
+            // Discover the polls in which the label is reached by running
+            // the future to completion once unless they are cached.
+            let labels = match &cached {
+                Some(entry) => entry.labels.clone(),
+                None => {
+                    let state = setup();
+                    let mut future = pin!(abort(make.make(&state), self.max_polls));
+                    let _ = future.as_mut().await;
+                    label_polls(future.labels())
+                }
+            };
+            let found = labels
+                .iter()
+                .filter(|(name, _)| name == target.name)
+                .map(|(_, poll)| poll + 1)
+                .enumerate()
+                .filter(|(index, _)| target.occurrence.is_none_or(|occurrence| index + 1 == occurrence))
+                .map(|(_, point)| point);
+            let points = schedule.iter().flat_map(|schedule| schedule.points().iter().copied());
+            schedule = Some(Schedule::from_points(points.chain(found).collect::<Vec<_>>()));
+        }
+        if let Some(tag) = self.only_tagged {
+            let trace: Trace = Arc::new(Mutex::new(Vec::new()));
+            let state = setup();
+            let mut future = pin!(abort(make.make(&state), self.max_polls));
+            let _ = poll_fn(|cx| {
+                trace.lock().unwrap().push(TraceEvent::Poll(future.num_polls()));
+                with_trace(&trace, || future.as_mut().poll(cx))
+            })
+            .await;
+            let first = tagged_poll(&trace.lock().unwrap(), tag);
+            let tagged = |point: &usize| first.is_some_and(|first| *point > first);
+            let points: Vec<usize> = match &schedule {
+                Some(schedule) => schedule.points().iter().copied().filter(tagged).collect(),
+                None => (0..future.num_polls()).filter(tagged).collect(),
+            };
+            schedule = Some(Schedule::from_points(points));
+        }
+        // Hash of the state after every poll of a complete run.
+        let mut hashes = Vec::new();
+        if let Some(state_hash) = state_hash {
+            let state = setup();
+            hashes.push(state_hash(&state));
+            let mut future = pin!(abort(make.make(&state), self.max_polls));
+            let _ = poll_fn(|cx| {
+                let result = future.as_mut().poll(cx);
+                if result.is_pending() {
+                    hashes.push(state_hash(&state));
+                }
+                result
+            })
+            .await;
+        }
+        // Abort point which was tested first for every hash.
+        let mut tested: Vec<(u64, usize)> = Vec::new();
+        let points: Box<dyn Iterator<Item = usize>> = match &schedule {
+            Some(schedule) => Box::new(
+                schedule
+                    .points()
+                    .iter()
+                    .copied()
+                    .filter(|point| *point < self.max_polls)
+                    .chain(Some(self.max_polls)),
+            ),
+            None => Box::new(0..=self.max_polls),
+        };
+        let sweep_start = self.clock.now();
+        let mut pool = Pool::new(self.capacity.trace);
+        let watchdog = self.watchdog.map(Watchdog::start);
+        for mut max_polls in points {
+            if let Some(hash) = hashes.get(max_polls) {
+                if let Some((_, duplicate_of)) = tested.iter().find(|(h, _)| h == hash) {
+                    let skipped = Skipped::new(max_polls, *duplicate_of);
+                    if child_start.is_some_and(|start| max_polls >= start) {
+                        println!("fta:skipped {} {}", skipped.max_polls, skipped.duplicate_of);
+                    }
+                    report.skipped.push(skipped);
+                    continue;
+                }
+                tested.push((*hash, max_polls));
+            }
+            if child_start.is_some_and(|start| max_polls < start) {
+                continue;
+            }
+            let elapsed = self.clock.now().saturating_duration_since(sweep_start);
+            if self.time_budget.is_some_and(|time_budget| elapsed >= time_budget) {
+                max_polls = self.max_polls;
+            }
+            if child_start.is_some() {
+                println!("fta:start {}", max_polls);
+            }
+            let start = self.clock.now();
+            let setup_failure = self.run_hook(&self.setup_hook, "setup").await;
+            let state = setup();
+            let (trace, current, stale_wakes) = pool.recycle();
+            let mut layer = None;
+            let mut streams = self.streams();
+            let mut wedged = None;
+            let mut panicked = None;
+            let task = self.task_span(max_polls, "run");
+            let (result, num_polls, (last_label, backtrace), (held_failure, mut invariant_errors), labels) = {
+                // The future is boxed so it can be dropped while tracing.
+                let mut future = Box::pin(with_trace(&trace, || {
+                    rng::enter(&mut streams, || {
+                        abort(make.make(&state), max_polls)
+                            .with_reason(self.reason.clone())
+                            .with_grace_polls(self.grace_polls)
+                    })
+                }));
+                let result = poll_fn(|cx| {
+                    if future.num_polls() < max_polls {
+                        trace.lock().unwrap().push(TraceEvent::Poll(future.num_polls()));
+                    }
+                    let poll = if self.migrate { future.num_polls() } else { 0 };
+                    if self.migrate || layer.is_none() {
+                        current.store(poll, Ordering::SeqCst);
+                        layer = Some(WakerLayer::new(WakeHooks {
+                            trace: Some(trace.clone()),
+                            poll,
+                            current: current.clone(),
+                            stale_wakes: stale_wakes.clone(),
+                        }));
+                    }
+                    let waker = layer.as_ref().unwrap().wrap(cx.waker());
+                    if let Some(watchdog) = &watchdog {
+                        watchdog.enter(self.name.as_deref(), max_polls, future.num_polls(), &trace);
+                    }
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        task.in_scope(|| {
+                            with_trace(&trace, || {
+                                rng::enter(&mut streams, || future.as_mut().poll(&mut Context::from_waker(&waker)))
+                            })
+                        })
+                    }));
+                    if let Some(failure) = watchdog.as_ref().and_then(Watchdog::leave) {
+                        wedged.get_or_insert(failure);
+                    }
+                    match result {
+                        Ok(result) => result,
+                        Err(payload) => {
+                            // The wrapper is poisoned, so end the iteration
+                            // like an abort and report the panic.
+                            let (poll, message) = match future.panicked() {
+                                Some((poll, message)) => (poll, message.to_string()),
+                                None => (future.num_polls(), panic_message(&*payload)),
+                            };
+                            panicked = Some((poll, message));
+                            Poll::Ready(Err(Aborted {
+                                num_polls: future.num_polls(),
+                                ..Aborted::default()
+                            }))
+                        }
+                    }
+                })
+                .await;
+                trace.lock().unwrap().push(match (&result, &panicked) {
+                    (_, Some((_, message))) => TraceEvent::Panicked(message.clone()),
+                    (Ok(_), None) => TraceEvent::Completed,
+                    (Err(_), None) => TraceEvent::Aborted,
+                });
+                let last_label = future.labels().last().map(|label| label.name.to_string());
+                let backtrace = match &result {
+                    Ok(_) => Vec::new(),
+                    Err(aborted) => aborted.chain.iter().map(ToString::to_string).collect(),
+                };
+                let labels = if result.is_ok() { label_polls(future.labels()) } else { Vec::new() };
+                let num_polls = future.num_polls();
+                let mut held_failure = None;
+                let mut held_errors = Vec::new();
+                let leaked = result.is_err() && self.drop_timing == DropTiming::Never;
+                if result.is_err() && !matches!(self.drop_timing, DropTiming::Immediate | DropTiming::Never) {
+                    self.hold().await;
+                    let (failure, errors) = invariant::evaluate(&mut check, &state);
+                    held_failure = failure.map(|failure| format!("while the aborted future was held: {}", failure));
+                    held_errors = errors;
+                }
+                if leaked {
+                    std::mem::forget(future);
+                } else {
+                    let cleanup = self.task_span(max_polls, "cleanup");
+                    cleanup.in_scope(|| with_trace(&trace, || rng::enter(&mut streams, || drop(future))));
+                }
+                (result, num_polls, (last_label, backtrace), (held_failure, held_errors), labels)
+            };
+            // Copy the events so the buffer keeps its capacity.
+            let trace: Vec<_> = trace.lock().unwrap().drain(..).collect();
+            if child_start.is_some() {
+                for event in &trace {
+                    println!("fta:event {}", event.encode());
+                }
+                for frame in &backtrace {
+                    println!("fta:frame {}", encode_field(Some(frame)));
+                }
+                println!("fta:polled {} {}", result.is_ok() as u8, num_polls);
+            }
+            let (failure, errors) = invariant::evaluate(&mut check, &state);
+            invariant_errors.extend(errors);
+            let teardown_failure = self.run_hook(&self.teardown_hook, "teardown").await;
+            let panic_failure =
+                panicked.as_ref().map(|(poll, message)| format!("future panicked at poll {}: {}", poll, message));
+            let failure = setup_failure
+                .or(panic_failure)
+                .or(held_failure)
+                .or(failure)
+                .or(wedged)
+                .or_else(|| match stale_wakes.load(Ordering::SeqCst) {
+                    0 => None,
+                    n => {
+                        let reactors = stale_reactors(&trace);
+                        let reactors = if reactors.is_empty() {
+                            String::new()
+                        } else {
+                            format!(" registered with {}", reactors.join(", "))
+                        };
+                        Some(format!("future was woken {} times via a waker of an earlier poll{}", n, reactors))
+                    }
+                })
+                .or(teardown_failure);
+            let mut point = PointReport {
+                max_polls,
+                completed: result.is_ok(),
+                never_polled: result.is_err() && num_polls == 0,
+                failure,
+                leaked: self.leaks(result.is_ok()),
+                elapsed: self.clock.now().saturating_duration_since(start),
+                last_label,
+                trace,
+                invariant_errors,
+                backtrace,
+                reason: result.as_ref().err().filter(|_| panicked.is_none()).map(|aborted| aborted.reason.to_string()),
+                ..PointReport::default()
+            };
+            let still_registered = point.still_registered();
+            if point.failure.is_none() && !point.leaked && !still_registered.is_empty() {
+                point.failure = Some(format!(
+                    "waker still registered with {} after the future was dropped",
+                    still_registered.join(", ")
+                ));
+            }
+            if child_start.is_some() {
+                println!(
+                    "fta:point {} {} {} {} {}",
+                    point.max_polls,
+                    point.completed as u8,
+                    point.elapsed.as_nanos(),
+                    encode_field(point.last_label.as_deref()),
+                    encode_field(point.failure.as_deref())
+                );
+            }
+            if let Some(emitted) = emitted {
+                emitted.borrow_mut().push_back(point.clone());
+            }
+            report.points.push(point);
+            if result.is_ok() {
+                report.num_polls = Some(num_polls);
+                self.store_cache(Entry { num_polls, labels });
+                break;
+            }
+            if panicked.is_some() {
+                // Later abort points would panic at the same poll again.
+                break;
+            }
+            if child_start.is_some() && self.subprocess.as_ref().is_some_and(|s| s.isolate) {
+                process::exit(0);
+            }
+            if emitted.is_some() {
+                // Hand the iteration to the stream before running the next.
+                after((), 1).await;
+            }
+        }
+        if child_start.is_some() {
+            println!("fta:done {}", encode_field(report.num_polls.map(|n| n.to_string()).as_deref()));
+            process::exit(0);
+        }
+        report
+    }
+
+    /// Substreams of a single iteration or `None` if neither a seed nor
+    /// random sources are set.
+    fn streams(&self) -> Option<Streams> {
+        match &self.random_sources {
+            Some(sources) => Some(Streams::from_sources(sources.clone())),
+            None => self.seed.map(Streams::new),
+        }
+    }
+
+    /// Panics if a setting is set which only applies to sweeps running a
+    /// single future per abort point.
+    fn assert_single_future_settings(&self, entry: &str) {
+        let settings = [
+            (self.schedule.is_some(), "schedule"),
+            (self.label_target.is_some(), "abort_after_label"),
+            (self.only_tagged.is_some(), "only_tagged"),
+            (self.subprocess.is_some(), "subprocess"),
+            (self.migrate, "migrate"),
+            (self.watchdog.is_some(), "watchdog"),
+            (self.drop_timing != DropTiming::Immediate, "drop_timing"),
+        ];
+        for (set, setting) in settings {
+            assert!(!set, "Sweep::{} is not supported by Sweep::{}", setting, entry);
+        }
+    }
+
+    /// Run one attempt of `report_retries` which is aborted after
+    /// `max_polls` polls. Returns the number of polls if it completed.
+    async fn attempt<T>(
+        &self,
+        trace: &Trace,
+        streams: &mut Option<Streams>,
+        future: T,
+        max_polls: usize,
+    ) -> Result<usize, Aborted>
+    where
+        T: Future,
+    {
+        let mut future = Box::pin(with_trace(trace, || {
+            abort(future, max_polls)
+                .with_reason(self.reason.clone())
+                .with_grace_polls(self.grace_polls)
+        }));
+        let result = poll_fn(|cx| {
+            if future.num_polls() < max_polls {
+                trace.lock().unwrap().push(TraceEvent::Poll(future.num_polls()));
+            }
+            with_trace(trace, || rng::enter(streams, || future.as_mut().poll(cx)))
+        })
+        .await;
+        trace.lock().unwrap().push(match result {
+            Ok(_) => TraceEvent::Completed,
+            Err(_) => TraceEvent::Aborted,
+        });
+        let num_polls = future.num_polls();
+        with_trace(trace, || rng::enter(streams, || drop(future)));
+        result.map(|_| num_polls)
+    }
+
+    /// Run the setup or teardown hook. Returns a failure if it did not
+    /// complete within its poll budget.
+    async fn run_hook(&self, hook: &Option<Hook>, name: &str) -> Option<String> {
+        let hook = hook.as_ref()?;
+        let max_polls = self.hook_max_polls.unwrap_or(self.max_polls);
+        match abort(hook.0(), max_polls).await {
+            Ok(()) => None,
+            Err(_) => Some(format!("{} hook did not complete within {} polls", name, max_polls)),
+        }
+    }
+
+    #[cfg(feature = "baseline")]
+    fn assert_safe(&self, report: &Report) {
+        match &self.baseline {
+            Some(path) => baseline::assert(path, report),
+            None => report.assert_safe(),
+        }
+    }
+
+    #[cfg(not(feature = "baseline"))]
+    fn assert_safe(&self, report: &Report) {
+        report.assert_safe();
+    }
+
+    #[cfg(feature = "cache")]
+    fn load_cache(&self) -> Option<Entry> {
+        cache::load(self.name.as_deref()?, self.cache_version.as_deref()?)
+    }
+
+    #[cfg(not(feature = "cache"))]
+    fn load_cache(&self) -> Option<Entry> {
+        None
+    }
+
+    #[cfg(feature = "cache")]
+    fn store_cache(&self, entry: Entry) {
+        if let (Some(name), Some(version)) = (&self.name, &self.cache_version) {
+            cache::store(name, version, &entry);
+        }
+    }
+
+    #[cfg(not(feature = "cache"))]
+    fn store_cache(&self, _entry: Entry) {}
+
+    #[cfg(feature = "console")]
+    fn task_span(&self, max_polls: usize, stage: &str) -> TaskSpan {
+        console::task_span(self.name.as_deref(), max_polls, stage)
+    }
+
+    #[cfg(not(feature = "console"))]
+    fn task_span(&self, _max_polls: usize, _stage: &str) -> TaskSpan {
+        TaskSpan
+    }
+
+    /// Returns `true` if the future of an iteration is leaked.
+    fn leaks(&self, completed: bool) -> bool {
+        !completed && self.drop_timing == DropTiming::Never
+    }
+
+    /// Wait according to `drop_timing` without polling the aborted future.
+    async fn hold(&self) {
+        match self.drop_timing {
+            DropTiming::Immediate | DropTiming::Never => {}
+            DropTiming::AfterYields(n) => after((), n).await,
+            DropTiming::AfterDuration(duration) => {
+                let start = self.clock.now();
+                loop {
+                    let elapsed = self.clock.now().saturating_duration_since(start);
+                    if elapsed >= duration {
+                        break;
+                    }
+                    after((), 1).await;
+                    self.clock.idle(duration - elapsed);
+                }
+            }
+        }
+    }
+
+    /// Run the sweep by spawning the test binary as subprocess. If the
+    /// subprocess crashes the abort point it was working on is recorded
+    /// as failure and a new subprocess is spawned which continues with
+    /// the next abort point. Isolated subprocesses exit after every
+    /// abort point.
+    fn report_in_subprocess(&self, test_name: &str, mut report: Report) -> Report {
+        let exe = env::current_exe().expect("cannot determine test executable");
+        let mut start = 0;
+        loop {
+            let output = Command::new(&exe)
+                .args([test_name, "--exact", "--nocapture", "--test-threads=1"])
+                .env(SUBPROCESS_VAR, format!("{} {}", test_name, start))
+                .stdin(Stdio::null())
+                .output()
+                .expect("cannot spawn subprocess");
+            // Abort point the subprocess was working on and the outcome
+            // of the future if it got that far.
+            let mut running: Option<(usize, Option<(bool, usize)>)> = None;
+            let mut finished = None;
+            let mut trace = Vec::new();
+            let mut backtrace = Vec::new();
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                // The test harness may print the test name in front of the
+                // first line of output.
+                let line = line.find("fta:").map_or("", |index| &line[index..]);
+                let fields: Vec<&str> = line.split(' ').collect();
+                match fields.as_slice() {
+                    ["fta:start", max_polls] => {
+                        running = max_polls.parse().ok().map(|max_polls| (max_polls, None));
+                        trace.clear();
+                        backtrace.clear();
+                    }
+                    ["fta:event", kind, field] => {
+                        trace.extend(TraceEvent::decode(kind, field));
+                    }
+                    ["fta:frame", frame] => {
+                        backtrace.extend(decode_field(frame));
+                    }
+                    ["fta:polled", completed, num_polls] => {
+                        if let (Some(running), Ok(num_polls)) = (running.as_mut(), num_polls.parse()) {
+                            running.1 = Some((*completed == "1", num_polls));
+                        }
+                    }
+                    ["fta:point", max_polls, completed, elapsed, last_label, failure] => {
+                        let polled = running.take().and_then(|(_, polled)| polled);
+                        let max_polls = max_polls.parse().unwrap_or_default();
+                        finished = Some(max_polls);
+                        report.points.push(PointReport {
+                            max_polls,
+                            completed: *completed == "1",
+                            never_polled: polled == Some((false, 0)),
+                            failure: decode_field(failure),
+                            leaked: self.leaks(*completed == "1"),
+                            elapsed: Duration::from_nanos(elapsed.parse().unwrap_or_default()),
+                            last_label: decode_field(last_label),
+                            trace: std::mem::take(&mut trace),
+                            backtrace: std::mem::take(&mut backtrace),
+                            reason: (*completed != "1").then(|| self.reason.to_string()),
+                            ..PointReport::default()
+                        });
+                    }
+                    ["fta:skipped", max_polls, duplicate_of] => {
+                        if let (Ok(max_polls), Ok(duplicate_of)) = (max_polls.parse(), duplicate_of.parse()) {
+                            report.skipped.push(Skipped::new(max_polls, duplicate_of));
+                        }
+                    }
+                    ["fta:done", num_polls] => {
+                        report.num_polls = decode_field(num_polls).and_then(|n| n.parse().ok());
+                        return report;
+                    }
+                    _ => {}
+                }
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let (max_polls, polled) = match (running, finished) {
+                (Some(running), _) => running,
+                (None, Some(max_polls)) if output.status.success() => {
+                    start = max_polls + 1;
+                    continue;
+                }
+                _ => panic!(
+                    "sweep subprocess for test {:?} exited without result ({}):\n{}",
+                    test_name, output.status, stderr
+                ),
+            };
+            let completed = polled.is_some_and(|(completed, _)| completed);
+            report.points.push(PointReport {
+                max_polls,
+                completed,
+                never_polled: polled == Some((false, 0)),
+                failure: Some(format!("process crashed ({}): {}", output.status, crash_message(&stderr))),
+                crashed: true,
+                leaked: self.leaks(completed),
+                trace,
+                backtrace,
+                reason: (!completed).then(|| self.reason.to_string()),
+                ..PointReport::default()
+            });
+            if completed {
+                report.num_polls = polled.map(|(_, num_polls)| num_polls);
+                return report;
+            }
+            start = max_polls + 1;
+        }
+    }
+}
+
+/// Reports of the iterations a `Sweep` run via `Sweep::stream` did not
+/// hand out, yet.
+type Emitted = Rc<RefCell<VecDeque<PointReport>>>;
+
+/// Stream of the iterations of a `Sweep`. See `Sweep::stream`.
+pub struct Iterations<'a> {
+    points: Emitted,
+    sweep: Option<Pin<Box<dyn Future<Output = Report> + 'a>>>,
+}
+
+impl Stream for Iterations<'_> {
+    type Item = PointReport;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(point) = self.points.borrow_mut().pop_front() {
+            return Poll::Ready(Some(point));
+        }
+        let Some(sweep) = self.sweep.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let done = sweep.as_mut().poll(cx).is_ready();
+        if done {
+            self.sweep = None;
+        }
+        match self.points.borrow_mut().pop_front() {
+            Some(point) => Poll::Ready(Some(point)),
+            None if done => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Default for Sweep {
+    fn default() -> Self {
+        let settings = Profile::from_env().unwrap_or_default().settings();
+        Self {
+            name: None,
+            max_polls: settings.max_polls,
+            time_budget: settings.time_budget,
+            schedule: None,
+            label_target: None,
+            only_tagged: None,
+            clock: Arc::new(SystemClock),
+            subprocess: None,
+            migrate: false,
+            expected_polls: None,
+            drop_timing: DropTiming::Immediate,
+            seed: None,
+            random_sources: None,
+            capacity: Capacity::default(),
+            reason: AbortReason::Dropped,
+            grace_polls: 0,
+            watchdog: None,
+            setup_hook: None,
+            teardown_hook: None,
+            hook_max_polls: None,
+            #[cfg(feature = "cache")]
+            cache_version: None,
+            #[cfg(feature = "baseline")]
+            baseline: None,
+        }
+    }
+}
+
+/// Cached result of a scenario. See `Sweep::cache`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub(crate) num_polls: usize,
+    /// Name and poll index of every label reached by the future.
+    pub(crate) labels: Vec<(String, usize)>,
+}
+
+/// Name and poll index of every label.
+fn label_polls(labels: &[Label]) -> Vec<(String, usize)> {
+    labels.iter().map(|label| (label.name.to_string(), label.poll)).collect()
+}
+
+/// Buffers reused across the iterations of a sweep. A buffer is only
+/// reused if no waker of an earlier iteration still refers to it.
+struct Pool {
+    trace: Trace,
+    current: Arc<AtomicUsize>,
+    stale_wakes: Arc<AtomicUsize>,
+}
+
+impl Pool {
+    fn new(trace_capacity: usize) -> Self {
+        Self {
+            trace: Arc::new(Mutex::new(Vec::with_capacity(trace_capacity))),
+            current: Arc::default(),
+            stale_wakes: Arc::default(),
+        }
+    }
+
+    /// Reset the buffers for the next iteration.
+    fn recycle(&mut self) -> (Trace, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        match Arc::get_mut(&mut self.trace) {
+            Some(trace) => trace.get_mut().unwrap().clear(),
+            None => {
+                let capacity = self.trace.lock().unwrap().capacity();
+                self.trace = Arc::new(Mutex::new(Vec::with_capacity(capacity)));
+            }
+        }
+        for counter in [&mut self.current, &mut self.stale_wakes] {
+            match Arc::get_mut(counter) {
+                Some(counter) => *counter.get_mut() = 0,
+                None => *counter = Arc::default(),
+            }
+        }
+        (self.trace.clone(), self.current.clone(), self.stale_wakes.clone())
+    }
+}
+
+/// Time at which a `Sweep` drops an aborted future.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DropTiming {
+    /// Drop the future right after it was aborted.
+    #[default]
+    Immediate,
+    /// Hold the future unpolled while yielding to the executor the given
+    /// number of times before dropping it.
+    AfterYields(usize),
+    /// Hold the future unpolled while yielding to the executor until the
+    /// clock of the sweep has advanced by the given duration. A
+    /// `ManualClock` is advanced by the duration after the first yield.
+    AfterDuration(Duration),
+    /// Never drop the future but leak it via `mem::forget` to check the
+    /// behavior when destructors never run. Only aborted futures are
+    /// leaked.
+    Never,
+}
+
+/// Environment variable which tells a test binary that it was spawned by
+/// `Sweep::subprocess`. The value is the test name and the first abort
+/// point to run separated by a space.
+const SUBPROCESS_VAR: &str = "FTA_SUBPROCESS";
+
+fn subprocess_start(test_name: &str) -> Option<usize> {
+    let value = env::var(SUBPROCESS_VAR).ok()?;
+    let (name, start) = value.rsplit_once(' ')?;
+    if name != test_name {
+        return None;
+    }
+    start.parse().ok()
+}
+
+/// Encode an optional string as a single field of the subprocess protocol.
+pub(crate) fn encode_field(value: Option<&str>) -> String {
+    match value {
+        Some(value) => {
+            let escaped = value.replace('\\', "\\\\").replace('\n', "\\n").replace(' ', "\\s");
+            format!("+{}", escaped)
+        }
+        None => String::new(),
+    }
+}
+
+pub(crate) fn decode_field(field: &str) -> Option<String> {
+    let field = field.strip_prefix('+')?;
+    let mut value = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('s') => value.push(' '),
+            Some(c) => value.push(c),
+            None => {}
+        }
+    }
+    Some(value)
+}
+
+/// Extract the panic message from the stderr output of a crashed process.
+fn crash_message(stderr: &str) -> String {
+    let mut lines = stderr.lines().skip_while(|line| !line.contains("panicked at"));
+    lines.next();
+    let message: Vec<&str> = lines
+        .take_while(|line| !line.starts_with("note:") && !line.starts_with("stack backtrace:"))
+        .collect();
+    message.join("\n")
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "check panicked".into()
+    }
+}
+
+/// Named presets for the settings of a `Sweep`.
+///
+/// This makes it possible to run cheap checks on every PR and deep checks
+/// overnight using the same test code by setting the `FTA_PROFILE`
+/// environment variable to `quick`, `standard` or `nightly`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// Shallow and time-boxed checks suitable for every commit.
+    Quick,
+    /// Default settings.
+    #[default]
+    Standard,
+    /// Deep checks for scheduled runs.
+    Nightly,
+}
+
+impl Profile {
+    /// Name of the environment variable used by `from_env`.
+    pub const ENV_VAR: &'static str = "FTA_PROFILE";
+
+    /// Read the profile from the `FTA_PROFILE` environment variable.
+    /// Returns `None` if the variable is not set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the variable contains an unknown profile name so that
+    /// typos don't silently fall back to the default profile.
+    pub fn from_env() -> Option<Self> {
+        let value = env::var(Self::ENV_VAR).ok()?;
+        match value.to_ascii_lowercase().as_str() {
+            "quick" => Some(Self::Quick),
+            "standard" => Some(Self::Standard),
+            "nightly" => Some(Self::Nightly),
+            _ => panic!("unknown {}: {:?}", Self::ENV_VAR, value),
+        }
+    }
+
+    /// Settings of this profile.
+    pub fn settings(self) -> ProfileSettings {
+        match self {
+            Self::Quick => ProfileSettings {
+                max_polls: 100,
+                time_budget: Some(Duration::from_secs(1)),
+            },
+            Self::Standard => ProfileSettings {
+                max_polls: 1000,
+                time_budget: None,
+            },
+            Self::Nightly => ProfileSettings {
+                max_polls: 100_000,
+                time_budget: None,
+            },
+        }
+    }
+}
+
+/// Settings of a `Profile`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProfileSettings {
+    /// See `Sweep::max_polls`.
+    pub max_polls: usize,
+    /// See `Sweep::time_budget`.
+    pub time_budget: Option<Duration>,
+}
+
+/// Source of time for all time based features of this crate.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Called when a `Sweep` has nothing to do but wait for `duration` to
+    /// pass, e.g. for `DropTiming::AfterDuration`. Clocks which never move
+    /// on their own must advance here or the sweep waits forever. Does
+    /// nothing by default.
+    fn idle(&self, _duration: Duration) {}
+}
+
+/// Clock using `std::time::Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock using `tokio::time::Instant::now` which respects the mocked
+/// time of `tokio::time::pause`.
+#[cfg(feature = "tokio-time")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio-time")]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+/// Clock which only moves forward when `advance` is called or a `Sweep`
+/// waits on it, which skips the wait. Clones of this clock share the same
+/// time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Create a new manual clock starting at the current time.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn idle(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Set of abort points used by a `Sweep`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    points: Vec<usize>,
+}
+
+impl Schedule {
+    /// Create a schedule from the given abort points. The points are
+    /// sorted and deduplicated.
+    pub fn from_points(points: impl IntoIterator<Item = usize>) -> Self {
+        let mut points: Vec<usize> = points.into_iter().collect();
+        points.sort_unstable();
+        points.dedup();
+        Self { points }
+    }
+
+    /// Create a schedule from a recorded trace, e.g. log output of
+    /// a staging system.
+    ///
+    /// Every line containing a `polls=<n>` field is a cancellation which
+    /// happened after `n` polls. All other lines are ignored. This matches
+    /// the output of `tracing` events like
+    /// `info!(polls = n, "request cancelled")`.
+    pub fn from_trace(trace: &str) -> Result<Self, ScheduleError> {
+        let mut points = Vec::new();
+        for (index, line) in trace.lines().enumerate() {
+            for field in line.split_whitespace() {
+                if let Some(value) = field.strip_prefix("polls=") {
+                    let point = value.parse().map_err(|_| ScheduleError {
+                        line: index + 1,
+                        value: value.into(),
+                    })?;
+                    points.push(point);
+                }
+            }
+        }
+        Ok(Self::from_points(points))
+    }
+
+    /// The sorted abort points of this schedule.
+    pub fn points(&self) -> &[usize] {
+        &self.points
+    }
+}
+
+/// This error is returned by `Schedule::from_trace` if a `polls` field
+/// does not contain a valid number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduleError {
+    /// Line number (starting at 1) of the invalid field.
+    pub line: usize,
+    /// Value of the invalid field.
+    pub value: String,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid polls field in line {}: {:?}", self.line, self.value)
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+thread_local! {
+    static TRACE: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+/// Trace of the current `Sweep` iteration. The trace is shared with the
+/// wakers handed out to the future which may be woken on other threads.
+pub(crate) type Trace = Arc<Mutex<Vec<TraceEvent>>>;
+
+/// Add an event to the trace of the current `Sweep` iteration, if any.
+pub(crate) fn trace_event(event: TraceEvent) {
+    TRACE.with(|trace| {
+        if let Some(trace) = trace.borrow().as_ref() {
+            trace.lock().unwrap().push(event);
+        }
+    });
+}
+
+/// Record all events of the current thread in `trace` while `f` is
+/// running.
+fn with_trace<R>(trace: &Trace, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Trace>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            TRACE.with(|trace| *trace.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(TRACE.with(|current| current.replace(Some(trace.clone()))));
+    f()
+}
+
+/// Create a setup function for a `Sweep` which restores the state from a
+/// snapshot instead of building it from scratch.
+///
+/// The first call runs `setup` and saves a snapshot of the fresh state via
+/// `save`. All following calls create the state via `restore` from that
+/// snapshot. This makes exhaustive sweeps over expensive setups like
+/// in-memory databases much faster. `restore` must produce a state which
+/// is equivalent to the one created by `setup`.
+pub fn snapshot<S, T, Setup, Save, Restore>(setup: Setup, save: Save, mut restore: Restore) -> impl FnMut() -> S
+where
+    Setup: FnOnce() -> S,
+    Save: FnOnce(&S) -> T,
+    Restore: FnMut(&T) -> S,
+{
+    let mut init = Some((setup, save));
+    let mut snapshot = None;
+    move || match &snapshot {
+        Some(snapshot) => restore(snapshot),
+        None => {
+            let (setup, save) = init.take().unwrap();
+            let state = setup();
+            snapshot = Some(save(&state));
+            state
+        }
+    }
+}
+
+/// Run a `Sweep` in a doctest and panic with a single line message if a
+/// check failed.
+///
+/// The sweep runs on the current thread without an async runtime so a
+/// cancel-safety example in the API docs of a library takes only a few
+/// lines. A custom `Sweep` can be passed as the first argument.
+///
+/// ```rust
+/// use std::cell::Cell;
+///
+/// use futures_test_abort::{after, doctest_sweep};
+///
+/// async fn increment(counter: &Cell<u32>) {
+///     after((), 2).await;
+///     counter.set(counter.get() + 1);
+/// }
+///
+/// doctest_sweep! {
+///     setup: || Cell::new(0),
+///     future: increment,
+///     check: |counter| assert!(counter.get() <= 1),
+/// }
+/// ```
+#[macro_export]
+macro_rules! doctest_sweep {
+    (setup: $setup:expr, future: $make:expr, check: $check:expr $(,)?) => {
+        $crate::__doctest_sweep($crate::Sweep::new(), $setup, $make, $check);
+    };
+    (sweep: $sweep:expr, setup: $setup:expr, future: $make:expr, check: $check:expr $(,)?) => {
+        $crate::__doctest_sweep($sweep, $setup, $make, $check);
+    };
+}
+
+#[doc(hidden)]
+pub fn __doctest_sweep<S, Setup, Make, Check, R>(sweep: Sweep, setup: Setup, make: Make, check: Check)
+where
+    Setup: FnMut() -> S,
+    Make: for<'a> MakeFuture<'a, S>,
+    Check: FnMut(&S) -> R,
+    R: CheckResult,
+{
+    let report = block_on(sweep.report(setup, make, check));
+    if let Some(failure) = report.compact_failure() {
+        panic!("{}", failure);
+    }
+}
+
+/// Run the test generated by `abort_test` for the given abort point or to
+/// completion if `point` is `None`.
+#[doc(hidden)]
+pub fn __abort_test<S, Setup, Make, Check, R>(
+    name: &str,
+    setup: Setup,
+    make: Make,
+    check: Check,
+    max_polls: usize,
+    point: Option<usize>,
+) where
+    Setup: FnMut() -> S,
+    Make: for<'a> MakeFuture<'a, S>,
+    Check: FnMut(&S) -> R,
+    R: CheckResult,
+{
+    let sweep = Sweep::new()
+        .name(name)
+        .max_polls(max_polls)
+        .schedule(Schedule::from_points(point));
+    __doctest_sweep(sweep, setup, make, check);
+}
+
+/// Reactors with which a waker was registered in a poll whose waker was
+/// later woken as a stale waker.
+fn stale_reactors(trace: &[TraceEvent]) -> Vec<String> {
+    let mut poll = 0;
+    let mut registered: Vec<(usize, &str)> = Vec::new();
+    let mut reactors: Vec<String> = Vec::new();
+    for event in trace {
+        match event {
+            TraceEvent::Poll(n) => poll = *n,
+            TraceEvent::Registered(reactor) => registered.push((poll, reactor)),
+            TraceEvent::StaleWake(n) => {
+                for (_, reactor) in registered.iter().filter(|(poll, _)| poll == n) {
+                    if !reactors.iter().any(|r| r == reactor) {
+                        reactors.push(reactor.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    reactors
+}
+
+/// Leaf future which registers wakers with a custom reactor, e.g. a timer
+/// wheel or an IO driver.
+///
+/// Leaf futures report registering and deregistering their waker via
+/// `registered` and `deregistered` or the `leaf_registered!` and
+/// `leaf_deregistered!` macros. A `Sweep` fails an abort point at which a
+/// waker is still registered after the future was dropped and attributes
+/// stale wakes to the reactor the stale waker was registered with.
+/// Outside of a `Sweep` nothing is recorded.
+pub trait InstrumentedLeaf {
+    /// Name of the reactor the leaf registers its waker with.
+    fn reactor(&self) -> &'static str;
+
+    /// Record that the waker was registered with the reactor.
+    fn registered(&self) {
+        __leaf_event(self.reactor(), true);
+    }
+
+    /// Record that the waker was deregistered from the reactor.
+    fn deregistered(&self) {
+        __leaf_event(self.reactor(), false);
+    }
+}
+
+#[doc(hidden)]
+pub fn __leaf_event(reactor: &str, registered: bool) {
+    trace_event(if registered {
+        TraceEvent::Registered(reactor.into())
+    } else {
+        TraceEvent::Deregistered(reactor.into())
+    });
+}
+
+/// Record that a leaf future registered its waker with the named reactor.
+/// See `InstrumentedLeaf`.
+#[macro_export]
+macro_rules! leaf_registered {
+    ($reactor:expr) => {
+        $crate::__leaf_event($reactor, true)
+    };
+}
+
+/// Record that a leaf future deregistered its waker from the named
+/// reactor. See `InstrumentedLeaf`.
+#[macro_export]
+macro_rules! leaf_deregistered {
+    ($reactor:expr) => {
+        $crate::__leaf_event($reactor, false)
+    };
+}
+
+/// Abort the futures created by `run` at poll 0, 1, 2, … until one
+/// completes. Every iteration gets fresh state from `setup` which is
+/// passed to `teardown_check` after the future was aborted and dropped
+/// again before the next iteration, so no abort point sees state left
+/// behind by another one. Panics like `Sweep::run`.
+///
+/// This is `Sweep::run` with the default settings.
+pub async fn abort_all_points<S, Setup, Run, Check, R>(setup: Setup, run: Run, teardown_check: Check) -> Report
+where
+    Setup: FnMut() -> S,
+    Run: for<'a> MakeFuture<'a, S>,
+    Check: FnMut(&S) -> R,
+    R: CheckResult,
+{
+    Sweep::new().run(setup, run, teardown_check).await
+}
+
+/// Abort the futures created by `factory` at poll 0, 1, 2, … until one
+/// completes and call `invariant` after every run. Panics if the invariant
+/// failed or no future completed within the poll limit of the current
+/// profile.
+///
+/// Unlike `Sweep` there is no per-iteration state, so the futures may
+/// borrow from the surrounding scope instead. Otherwise this is
+/// `Sweep::run` with the default settings. Use `Sweep` for all other
+/// options.
+pub async fn abort_sweep<F, Fut, Check, R>(factory: F, mut invariant: Check) -> Report
+where
+    F: FnMut() -> Fut,
+    Fut: Future,
+    Check: FnMut() -> R,
+    R: CheckResult,
+{
+    Sweep::new().run(PhantomData::default, Factory(factory), |_| invariant()).await
+}
+
+/// Factory of `abort_sweep` calling a closure which takes no state. The
+/// state is a `PhantomData` of the future so the future may borrow from
+/// the scope of the closure.
+struct Factory<F>(F);
+
+impl<'a, F, Fut> MakeFuture<'a, PhantomData<Fut>> for Factory<F>
+where
+    F: FnMut() -> Fut,
+    Fut: Future,
+{
+    type Future = Fut;
+
+    fn make(&mut self, _: &'a PhantomData<Fut>) -> Self::Future {
+        (self.0)()
+    }
+}
+
+/// Factory of `Sweep::report_spurious_polls` wrapping every future in
+/// `NoopPolls`.
+struct Spurious<'m, M>(usize, &'m mut M);
+
+impl<'a, S, M> MakeFuture<'a, S> for Spurious<'_, M>
+where
+    M: MakeFuture<'a, S>,
+{
+    type Future = NoopPolls<M::Future>;
+
+    fn make(&mut self, state: &'a S) -> Self::Future {
+        NoopPolls {
+            extra_polls: self.0,
+            future: Pinned::new(self.1.make(state)),
+        }
+    }
+}
+
+/// Future which polls the inner future again up to `extra_polls` times
+/// whenever it returns `Poll::Pending`.
+struct NoopPolls<T> {
+    extra_polls: usize,
+    future: Pinned<T>,
+}
+
+impl<T> Future for NoopPolls<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        for _ in 0..me.extra_polls {
+            if let Poll::Ready(output) = unsafe { me.future.as_pin_mut() }.poll(cx) {
+                return Poll::Ready(output);
+            }
+        }
+        unsafe { me.future.as_pin_mut() }.poll(cx)
+    }
+}
+
+/// Drop `future` without ever polling it and call `invariant`
+/// afterwards. Panics if the invariant failed.
+///
+/// Constructing a future and dropping it before it is scheduled, e.g.
+/// a request which is shed by a load balancer, is distinct from aborting
+/// it after some polls: only the constructor and the `Drop` impl run.
+/// The returned report has the outcome `Outcome::NeverPolled`. See
+/// `Sweep::abort_before_first_poll` to time it with a custom `Clock`.
+pub fn abort_before_first_poll<Fut, Check, R>(future: Fut, invariant: Check) -> PointReport
+where
+    Fut: Future,
+    Check: FnOnce() -> R,
+    R: CheckResult,
+{
+    Sweep::new().abort_before_first_poll(future, invariant)
+}
+
+/// Record that a fault was injected into the code under test, e.g. a
+/// severed connection. Faults are part of the explanation of a failed
+/// check. Outside of a `Sweep` this function does nothing.
+pub fn fault(description: impl Into<String>) {
+    trace_event(TraceEvent::Fault(description.into()));
+}
+
+/// Tag the following abort points of the code under test, e.g. with
+/// `"critical"` for code handling money. Tags are listed by
+/// `PointReport::tags` and `Report::by_tag` and can be targeted via
+/// `Sweep::only_tagged`. Outside of a `Sweep` this function does nothing.
+pub fn tag(name: &'static str) {
+    trace_event(TraceEvent::Tag(name.into()));
+}
+
+/// Poll in which `tag` was first recorded.
+fn tagged_poll(trace: &[TraceEvent], tag: &str) -> Option<usize> {
+    let mut poll = 0;
+    for event in trace {
+        match event {
+            TraceEvent::Poll(n) => poll = *n,
+            TraceEvent::Tag(name) if name == tag => return Some(poll),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Record that a resource was acquired and return a token which records
+/// that it was released when dropped.
+///
+/// The explanation of a failed check lists all tracked resources which
+/// were not released. Outside of a `Sweep` nothing is recorded.
+pub fn track(name: &'static str) -> Tracked {
+    trace_event(TraceEvent::Tracked(name.into()));
+    Tracked { name }
+}
+
+/// Token returned by `track`.
+#[must_use]
+#[derive(Debug)]
+pub struct Tracked {
+    name: &'static str,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        trace_event(TraceEvent::Dropped(self.name.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::future::{poll_fn, Future};
+    use std::pin::{pin, Pin};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use futures_core::Stream;
+
+    use super::{
+        abort_all_points, panic_message, Clock, DropTiming, InstrumentedLeaf, ManualClock, Profile, Schedule, Sweep,
+    };
+    use crate::{after, label, never, AbortReason, Outcome, ScopedCounter, Settled};
+    use crate::test_util::{count_to_three, count_unsafe, enter_counter, enter_repeatedly, labeled_loop, Counter};
+
+    #[tokio::test]
+    async fn sweep_borrowed_state() {
+        let mut iterations = 0;
+        let report = Sweep::new()
+            .run(
+                || Counter { count: Cell::new(0), started: Cell::new(0) },
+                count_to_three,
+                |counter| {
+                    assert_eq!(counter.started.get(), if iterations == 0 { 0 } else { 1 });
+                    assert_eq!(counter.count.get(), iterations.min(3));
+                    iterations += 1;
+                },
+            )
+            .await;
+        assert_eq!(report.num_polls, Some(4));
+        assert_eq!(report.abort_points().count(), 4);
+        assert_eq!(iterations, 5);
+    }
+
+    #[tokio::test]
+    async fn sweep_stream() {
+        let sweep = Sweep::new();
+        let started = Cell::new(0);
+        let mut points = pin!(sweep.stream(
+            || {
+                started.set(started.get() + 1);
+                Counter { count: Cell::new(0), started: Cell::new(0) }
+            },
+            count_to_three,
+            |counter| assert_eq!(counter.started.get(), 1),
+        ));
+        let first = poll_fn(|cx| points.as_mut().poll_next(cx)).await.unwrap();
+        assert_eq!(first.max_polls, 0);
+        assert!(first.failure.is_some());
+        assert_eq!(started.get(), 1);
+        let second = poll_fn(|cx| points.as_mut().poll_next(cx)).await.unwrap();
+        assert_eq!(second.max_polls, 1);
+        assert!(second.failure.is_none());
+        // the remaining iterations only run on demand
+        assert_eq!(started.get(), 2);
+        let mut rest = Vec::new();
+        while let Some(point) = poll_fn(|cx| points.as_mut().poll_next(cx)).await {
+            rest.push(point);
+        }
+        assert_eq!(rest.len(), 3);
+        assert!(rest.last().unwrap().completed);
+    }
+
+    #[tokio::test]
+    async fn abort_all_points_resets_state() {
+        let mut checks = 0;
+        let report = abort_all_points(
+            || Counter { count: Cell::new(0), started: Cell::new(0) },
+            count_to_three,
+            |counter| {
+                assert!(counter.started.get() <= 1);
+                assert!(counter.count.get() <= 3);
+                checks += 1;
+            },
+        )
+        .await;
+        assert_eq!(report.points.len(), 5);
+        assert_eq!(checks, 5);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "did not complete within 10 polls")]
+    async fn sweep_max_polls() {
+        Sweep::new()
+            .max_polls(10)
+            .run(|| (), |_: &()| never(), |_| {})
+            .await;
+    }
+
+    #[tokio::test]
+    async fn sweep_schedule_from_trace() {
+        let trace = "INFO request cancelled polls=2 path=/\nINFO request done\nINFO request cancelled polls=0";
+        let schedule = Schedule::from_trace(trace).unwrap();
+        assert_eq!(schedule.points(), &[0, 2]);
+        let report = Sweep::new()
+            .schedule(schedule)
+            .run(
+                || Counter { count: Cell::new(0), started: Cell::new(0) },
+                count_to_three,
+                |_| {},
+            )
+            .await;
+        let points: Vec<usize> = report.abort_points().map(|point| point.max_polls).collect();
+        assert_eq!(points, vec![0, 2]);
+        assert_eq!(report.num_polls, Some(4));
+        assert_eq!(report.summary().coverage, Some(50.0));
+        assert_eq!(Schedule::from_trace("polls=x").unwrap_err().line, 1);
+    }
+
+    async fn tagged_transfer(counter: &Counter) {
+        after((), 1).await;
+        crate::tag("critical");
+        counter.count.set(counter.count.get() + 1);
+        after((), 1).await;
+        counter.count.set(counter.count.get() - 1);
+        after((), 1).await;
+    }
+
+    #[derive(Default)]
+    struct Payments {
+        idempotent: bool,
+        rows: RefCell<Vec<&'static str>>,
+    }
+
+    /// Inserts the payment and then confirms it. Unless the payments are
+    /// idempotent a retry inserts the payment again.
+    async fn pay(payments: &Payments) {
+        after((), 1).await;
+        if !payments.idempotent || !payments.rows.borrow().contains(&"payment") {
+            payments.rows.borrow_mut().push("payment");
+        }
+        after((), 1).await;
+    }
+
+    #[tokio::test]
+    async fn sweep_retries() {
+        let check = |payments: &Payments| assert_eq!(*payments.rows.borrow(), ["payment"]);
+        let report = Sweep::new().report_retries(3, Payments::default, pay, check).await;
+        assert_eq!(report.num_polls, Some(3));
+        let failed: Vec<_> = report.points.iter().filter(|point| point.failure.is_some()).map(|point| point.max_polls).collect();
+        assert_eq!(failed, [2]);
+        let idempotent = || Payments { idempotent: true, ..Payments::default() };
+        Sweep::new().run_retries(3, idempotent, pay, check).await;
+        let never_done = Sweep::new().max_polls(4).report_retries(2, || (), |_: &()| never(), |_: &()| ()).await;
+        assert_eq!(never_done.points.len(), 5);
+        assert_eq!(never_done.points[0].failure.as_deref(), Some("retry did not complete within 4 polls"));
+        let timeouts = Sweep::new().reason(AbortReason::Timeout).report_retries(2, Payments::default, pay, check).await;
+        assert_eq!(timeouts.points[1].reason.as_deref(), Some("timeout"));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Sweep::drop_timing is not supported by Sweep::report_retries")]
+    async fn sweep_retries_unsupported() {
+        let sweep = Sweep::new().drop_timing(DropTiming::Never);
+        sweep.report_retries(2, || (), |_: &()| after((), 1), |_: &()| ()).await;
+    }
+
+    #[tokio::test]
+    async fn sweep_spurious_polls() {
+        async fn transfer(accounts: &RefCell<(i32, i32)>) {
+            accounts.borrow_mut().0 -= 1;
+            after((), 1).await;
+            accounts.borrow_mut().1 += 1;
+        }
+        async fn transfer_at_once(accounts: &RefCell<(i32, i32)>) {
+            after((), 1).await;
+            let mut accounts = accounts.borrow_mut();
+            accounts.0 -= 1;
+            accounts.1 += 1;
+        }
+        let setup = || RefCell::new((1, 0));
+        let check = |accounts: &RefCell<(i32, i32)>| {
+            let (from, to) = *accounts.borrow();
+            assert_eq!(from + to, 1);
+        };
+        let reports = Sweep::new().report_spurious_polls(2, setup, transfer, check).await;
+        assert_eq!(reports.sensitive(), [1]);
+        assert!(!reports.is_safe());
+        assert_eq!(reports.spurious.num_polls, Some(1));
+        let panic = std::panic::catch_unwind(|| reports.assert_safe()).unwrap_err();
+        assert!(panic_message(&*panic).starts_with("poll-count sensitive at abort point 1: check failed"));
+        let reports = Sweep::new().run_spurious_polls(2, setup, transfer_at_once, check).await;
+        assert!(reports.sensitive().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sweep_hooks() {
+        let db = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let teardowns = Arc::new(AtomicUsize::new(0));
+        let insert = |db: &Arc<std::sync::Mutex<Vec<u32>>>| {
+            let db = db.clone();
+            async move {
+                db.lock().unwrap().push(1);
+                after((), 1).await;
+            }
+        };
+        let check = |db: &Arc<std::sync::Mutex<Vec<u32>>>| {
+            let rows = db.lock().unwrap().len();
+            assert!(rows <= 1, "{} rows", rows);
+        };
+        let setup = {
+            let db = db.clone();
+            move || db.clone()
+        };
+        let report = Sweep::new().report(setup.clone(), insert, check).await;
+        assert!(!report.is_safe());
+        let report = Sweep::new()
+            .setup({
+                let db = db.clone();
+                move || {
+                    let db = db.clone();
+                    async move {
+                        after((), 2).await;
+                        db.lock().unwrap().clear();
+                    }
+                }
+            })
+            .teardown({
+                let teardowns = teardowns.clone();
+                move || {
+                    teardowns.fetch_add(1, Ordering::SeqCst);
+                    async {}
+                }
+            })
+            .run(setup.clone(), insert, check)
+            .await;
+        assert_eq!(teardowns.load(Ordering::SeqCst), report.points.len());
+        let report = Sweep::new().teardown(never).hook_max_polls(3).report(setup, insert, |_: &_| ()).await;
+        assert_eq!(report.points[0].failure.as_deref(), Some("teardown hook did not complete within 3 polls"));
+    }
+
+    #[tokio::test]
+    async fn sweep_tags() {
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let check = |counter: &Counter| assert_eq!(counter.count.get(), 0);
+        let report = Sweep::new().report(setup, tagged_transfer, check).await;
+        let tags: Vec<_> = report.points.iter().map(|point| point.tags()).collect();
+        assert_eq!(tags, [vec![], vec![], vec!["critical"], vec!["critical"], vec!["critical"]]);
+        let by_tag = report.by_tag();
+        assert_eq!(by_tag.len(), 1);
+        assert_eq!((by_tag[0].tag.as_str(), by_tag[0].num_abort_points, by_tag[0].num_unsafe), ("critical", 2, 1));
+        let report = Sweep::new().only_tagged("critical").report(setup, tagged_transfer, check).await;
+        let points: Vec<_> = report.abort_points().map(|point| point.max_polls).collect();
+        assert_eq!(points, [2, 3]);
+        let report = Sweep::new().only_tagged("missing").report(setup, tagged_transfer, check).await;
+        assert_eq!(report.points.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sweep_label_target() {
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let report = Sweep::new().abort_after_label("loop").run(setup, labeled_loop, |_| {}).await;
+        let points: Vec<_> = report
+            .abort_points()
+            .map(|point| (point.max_polls, point.last_label.as_deref()))
+            .collect();
+        assert_eq!(points, vec![(3, Some("loop"))]);
+        let report = Sweep::new().abort_after_every_label("loop").run(setup, labeled_loop, |_| {}).await;
+        let points: Vec<_> = report.abort_points().map(|point| point.max_polls).collect();
+        assert_eq!(points, vec![3, 4, 5]);
+        assert_eq!(report.num_polls, Some(6));
+        let report = Sweep::new().abort_at_iteration("loop", 2).run(setup, labeled_loop, |_| {}).await;
+        let points: Vec<_> = report.abort_points().map(|point| point.max_polls).collect();
+        assert_eq!(points, vec![4]);
+    }
+
+    #[cfg_attr(miri, ignore = "spawns a subprocess")]
+    #[tokio::test]
+    async fn sweep_subprocess_crash() {
+        let report = Sweep::new()
+            .subprocess("harness::tests::sweep_subprocess_crash")
+            .report(
+                || Counter { count: Cell::new(0), started: Cell::new(0) },
+                count_unsafe,
+                |counter| {
+                    if counter.count.get() != 0 {
+                        std::process::abort();
+                    }
+                },
+            )
+            .await;
+        let failures: Vec<_> = report.points.iter().map(|point| point.failure.as_deref()).collect();
+        assert_eq!(failures.len(), 4);
+        assert_eq!(failures[0], None);
+        assert!(failures[1].unwrap().starts_with("process crashed"));
+        assert!(failures[2].unwrap().starts_with("process crashed"));
+        assert_eq!(failures[3], None);
+        assert_eq!(report.num_polls, Some(3));
+        assert_eq!(report.summary().num_unsafe, 2);
+    }
+
+    #[cfg_attr(miri, ignore = "spawns a subprocess")]
+    #[tokio::test]
+    async fn sweep_isolate() {
+        let report = Sweep::new()
+            .isolate("harness::tests::sweep_isolate")
+            .report(
+                || Counter { count: Cell::new(0), started: Cell::new(0) },
+                count_unsafe,
+                |counter| {
+                    if counter.count.get() != 0 {
+                        std::process::abort();
+                    }
+                    // Every iteration runs in a fresh process.
+                    static ITERATIONS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+                    assert_eq!(ITERATIONS.fetch_add(1, std::sync::atomic::Ordering::Relaxed), 0);
+                },
+            )
+            .await;
+        let crashed: Vec<_> = report.points.iter().map(|point| point.crashed).collect();
+        assert_eq!(crashed, vec![false, true, true, false]);
+        assert!(report.points.iter().all(|point| point.crashed || point.is_safe()));
+        assert_eq!(report.num_polls, Some(3));
+    }
+
+    #[tokio::test]
+    async fn sweep_time_budget() {
+        let clock = ManualClock::new();
+        let report = Sweep::new()
+            .profile(Profile::Quick)
+            .clock(clock.clone())
+            .run(
+                || Counter { count: Cell::new(0), started: Cell::new(0) },
+                count_to_three,
+                |_| clock.advance(Duration::from_millis(600)),
+            )
+            .await;
+        let points: Vec<_> = report.points.iter().map(|point| point.max_polls).collect();
+        assert_eq!(points, vec![0, 1, 100]);
+        assert_eq!(report.num_polls, Some(4));
+        assert_eq!(report.summary().coverage, Some(50.0));
+    }
+
+    #[tokio::test]
+    async fn sweep_manual_clock() {
+        let clock = ManualClock::new();
+        let report = Sweep::new()
+            .clock(clock.clone())
+            .run(
+                || Counter { count: Cell::new(0), started: Cell::new(0) },
+                count_to_three,
+                |_| clock.advance(Duration::from_millis(10)),
+            )
+            .await;
+        assert!(report.points.iter().all(|point| point.elapsed == Duration::from_millis(10)));
+    }
+
+    async fn leak_tracked(count: &Cell<usize>) {
+        let _connection = crate::track("connection");
+        label("connected");
+        count.set(count.get() + 1);
+        after((), 1).await;
+        std::mem::forget(crate::track("lock"));
+        count.set(count.get() - 1);
+    }
+
+    #[tokio::test]
+    async fn sweep_explanation() {
+        let report = Sweep::new()
+            .report(Cell::default, leak_tracked, |count| assert_eq!(count.get(), 0))
+            .await;
+        let point = report.points.iter().find(|point| !point.is_safe()).unwrap();
+        assert_eq!(point.max_polls, 1);
+        assert_eq!(
+            point.explanation(),
+            "poll 0\n  tracked connection\n  label connected\n  wake\naborted\n  dropped connection\n"
+        );
+        assert_eq!(point.outcome(), Outcome::Aborted);
+        let last = report.points.last().unwrap();
+        assert_eq!(last.outcome(), Outcome::Completed);
+        assert_eq!(last.not_dropped(), ["lock"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "check failed for a future which was never polled: dropped")]
+    fn abort_before_first_poll() {
+        let count = Cell::new(0);
+        let point = crate::abort_before_first_poll(leak_tracked(&count), || assert_eq!(count.get(), 0));
+        assert_eq!(point.outcome(), Outcome::NeverPolled);
+        assert_eq!(point.explanation(), "never polled\naborted\n");
+        let dropped = Cell::new(false);
+        let guard = crate::sync::Guard::new((), |_| dropped.set(true));
+        crate::abort_before_first_poll(async move { drop(guard) }, || assert!(!dropped.get(), "dropped"));
+    }
+
+    #[test]
+    fn sweep_abort_before_first_poll() {
+        let clock = ManualClock::new();
+        let sweep = Sweep::new().clock(clock.clone());
+        let point = sweep.abort_before_first_poll(after((), 1), || clock.advance(Duration::from_secs(1)));
+        assert!(point.never_polled);
+        assert_eq!(point.outcome(), Outcome::NeverPolled);
+        assert_eq!(point.elapsed, Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn sweep_capacity() {
+        let make = |_: &()| after((), 3);
+        let report = Sweep::new().points_capacity(8).trace_capacity(16).report(|| (), make, |_| {}).await;
+        let expected = Sweep::new().report(|| (), make, |_| {}).await;
+        let traces = |report: &crate::Report| report.points.iter().map(|p| p.trace.clone()).collect::<Vec<_>>();
+        assert_eq!(traces(&report), traces(&expected));
+        assert_eq!(report.points[2].trace.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn sweep_drop_timing() {
+        let report = Sweep::new()
+            .drop_timing(DropTiming::AfterYields(2))
+            .report(ScopedCounter::new, enter_counter, ScopedCounter::assert_settled)
+            .await;
+        let failure = report.points[1].failure.as_deref().unwrap();
+        assert!(failure.starts_with("while the aborted future was held: "));
+        assert!(report.points[0].is_safe());
+        assert!(Sweep::new()
+            .report(ScopedCounter::new, enter_counter, ScopedCounter::assert_settled)
+            .await
+            .is_safe());
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let report = Sweep::new()
+            .clock(clock.clone())
+            .drop_timing(DropTiming::AfterDuration(Duration::from_secs(1)))
+            .report(ScopedCounter::new, enter_counter, ScopedCounter::assert_settled)
+            .await;
+        assert!(report.points[1].failure.as_deref().unwrap().starts_with("while the aborted future was held: "));
+        assert_eq!(clock.now() - start, Duration::from_secs(report.points.len() as u64 - 1));
+    }
+
+    struct Sleep {
+        registered: bool,
+        deregister_on_drop: bool,
+    }
+
+    impl InstrumentedLeaf for Sleep {
+        fn reactor(&self) -> &'static str {
+            "timer"
+        }
+    }
+
+    impl Future for Sleep {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.registered {
+                self.registered = false;
+                self.deregistered();
+                return Poll::Ready(());
+            }
+            self.registered = true;
+            self.registered();
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Sleep {
+        fn drop(&mut self) {
+            if self.registered && self.deregister_on_drop {
+                crate::leaf_deregistered!("timer");
+            }
+        }
+    }
+
+    async fn sleep(deregister_on_drop: &bool) {
+        Sleep {
+            registered: false,
+            deregister_on_drop: *deregister_on_drop,
+        }
+        .await
+    }
+
+    #[tokio::test]
+    async fn instrumented_leaf() {
+        Sweep::new().run(|| true, sleep, |_| ()).await;
+        let report = Sweep::new().report(|| false, sleep, |_| ()).await;
+        assert_eq!(
+            report.points[1].failure.as_deref(),
+            Some("waker still registered with timer after the future was dropped")
+        );
+        assert!(report.points[1].explanation().ends_with("still registered: timer\n"));
+        assert!(report.points[2].is_safe());
+    }
+
+    #[tokio::test]
+    async fn abort_sweep_borrowing() {
+        let counter = ScopedCounter::new();
+        let report = crate::abort_sweep(|| enter_repeatedly(&counter), || assert_eq!(counter.get(), 0)).await;
+        assert_eq!(report.points.len(), 7);
+        assert_eq!(report.num_polls, Some(6));
+        assert_eq!(report.points[1].trace[0], crate::TraceEvent::Poll(0));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "check failed at abort point 1")]
+    async fn abort_sweep_unsafe() {
+        let counter = Cell::new(0);
+        crate::abort_sweep(|| increment_twice(&counter), || assert_eq!(counter.replace(0) % 2, 0)).await;
+    }
+
+    async fn increment_twice(counter: &Cell<u32>) {
+        counter.set(counter.get() + 1);
+        after((), 1).await;
+        counter.set(counter.get() + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "not abort-safe: check failed at abort point 1: half done")]
+    fn doctest_sweep() {
+        crate::doctest_sweep! {
+            setup: || Cell::new(0),
+            future: increment_twice,
+            check: |_| (),
+        }
+        crate::doctest_sweep! {
+            sweep: Sweep::new().max_polls(10),
+            setup: || Cell::new(0),
+            future: increment_twice,
+            check: |counter| assert!(counter.get() != 1, "half done"),
+        };
+    }
+
+    #[cfg(feature = "macros")]
+    #[crate::abort_test(max_polls = 3)]
+    async fn abort_test_yields_twice() {
+        after((), 2).await;
+    }
+
+    #[cfg(feature = "macros")]
+    #[crate::abort_test(max_polls = 3, setup = Cell::default, check = at_most_once)]
+    async fn abort_test_increments_once(counter: &Cell<u32>) {
+        after((), 1).await;
+        counter.set(counter.get() + 1);
+        after((), 1).await;
+    }
+
+    #[cfg(feature = "macros")]
+    fn at_most_once(counter: &Cell<u32>) {
+        assert!(counter.get() <= 1);
+    }
+
+    #[cfg(feature = "macros")]
+    thread_local! {
+        static STARTED: Cell<bool> = const { Cell::new(false) };
+    }
+
+    #[cfg(feature = "macros")]
+    #[crate::abort_test(max_polls = 2, check = not_started)]
+    async fn abort_test_global_check() {
+        STARTED.with(|started| started.set(true));
+        let _reset = crate::acquire(|| (), |()| STARTED.with(|started| started.set(false)));
+        after((), 1).await;
+    }
+
+    #[cfg(feature = "macros")]
+    fn not_started() {
+        assert!(!STARTED.with(Cell::get));
+    }
+
+    async fn push_item(items: &RefCell<Vec<u32>>) {
+        after((), 1).await;
+        items.borrow_mut().push(3);
+    }
+
+    #[tokio::test]
+    async fn sweep_snapshot() {
+        let setups = Cell::new(0);
+        let setup = || {
+            setups.set(setups.get() + 1);
+            RefCell::new(vec![1, 2])
+        };
+        let restore = |items: &Vec<u32>| RefCell::new(items.clone());
+        let report = Sweep::new()
+            .run(crate::snapshot(setup, |items| items.borrow().clone(), restore), push_item, |items| {
+                assert!(items.borrow().starts_with(&[1, 2]))
+            })
+            .await;
+        assert_eq!(report.points.len(), 3);
+        assert_eq!(setups.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn sweep_deduplicated() {
+        let report = Sweep::new()
+            .run_deduplicated(ScopedCounter::new, enter_repeatedly, ScopedCounter::assert_settled, |counter| {
+                counter.get() as u64
+            })
+            .await;
+        let tested: Vec<usize> = report.points.iter().map(|point| point.max_polls).collect();
+        assert_eq!(tested, [0, 1, 6]);
+        assert_eq!(report.skipped.len(), 4);
+        assert_eq!(report.skipped[0], crate::Skipped::new(2, 1));
+        assert_eq!(report.summary().coverage, Some(100.0));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "check failed at abort point 1 (future leaked)")]
+    async fn sweep_leak() {
+        Sweep::new()
+            .drop_timing(DropTiming::Never)
+            .run(ScopedCounter::new, enter_counter, ScopedCounter::assert_settled)
+            .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "check failed at abort point 1 of ~2 expected polls")]
+    async fn sweep_expected_polls() {
+        Sweep::new()
+            .expected_polls(2)
+            .drop_timing(DropTiming::Never)
+            .run(ScopedCounter::new, enter_counter, ScopedCounter::assert_settled)
+            .await;
+    }
+}
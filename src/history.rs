@@ -0,0 +1,267 @@
+//! Linearizability checking of operation histories.
+//!
+//! When several instances of a future operate on shared state concurrently
+//! and some of them are aborted, it is not enough to check the state after
+//! each instance. Instead every operation is recorded in a `History` and
+//! the history is checked against a `Sequential` specification of the
+//! shared state: there must be a sequential execution of the operations
+//! which respects their real-time order, produces the recorded results and
+//! ends in the observed final state. Aborted operations may or may not have
+//! taken effect.
+//!
+//! ```rust
+//! use futures_test_abort::history::{History, Sequential};
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! struct Counter(u32);
+//!
+//! impl Sequential for Counter {
+//!     type Op = ();
+//!     type Ret = u32;
+//!
+//!     fn apply(&mut self, _op: &()) -> u32 {
+//!         self.0 += 1;
+//!         self.0
+//!     }
+//! }
+//!
+//! let history = History::new();
+//! let a = history.begin(());
+//! let b = history.begin(());
+//! a.commit(2);
+//! drop(b);
+//! assert!(history.check(Counter(0), &Counter(2)).is_ok());
+//! assert!(history.check(Counter(0), &Counter(1)).is_err());
+//! ```
+
+use std::fmt;
+use std::sync::Mutex;
+
+/// Sequential specification of shared state.
+pub trait Sequential: Clone + PartialEq + fmt::Debug {
+    /// Operation which can be applied to the state.
+    type Op: fmt::Debug;
+    /// Result of an operation.
+    type Ret: PartialEq + fmt::Debug;
+
+    /// Apply the operation and return its result.
+    fn apply(&mut self, op: &Self::Op) -> Self::Ret;
+}
+
+/// Event in a `History`. Operations are identified by their index in the
+/// order they were begun.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HistoryEvent<Op, Ret> {
+    /// An operation was begun.
+    Begin {
+        /// Id of the operation.
+        id: usize,
+        /// The operation.
+        op: Op,
+    },
+    /// An operation completed with the given result.
+    Commit {
+        /// Id of the operation.
+        id: usize,
+        /// Result of the operation.
+        ret: Ret,
+    },
+    /// An operation was dropped before it completed.
+    Abort {
+        /// Id of the operation.
+        id: usize,
+    },
+}
+
+/// Concurrent history of operations on shared state.
+#[derive(Debug)]
+pub struct History<Op, Ret> {
+    events: Mutex<Vec<HistoryEvent<Op, Ret>>>,
+}
+
+impl<Op, Ret> History<Op, Ret>
+where
+    Op: Clone,
+    Ret: Clone,
+{
+    /// Create an empty history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the begin of an operation. The returned guard records the
+    /// abort of the operation unless it is committed.
+    pub fn begin(&self, op: Op) -> Operation<'_, Op, Ret> {
+        let mut events = self.events.lock().unwrap();
+        let id = events.iter().filter(|e| matches!(e, HistoryEvent::Begin { .. })).count();
+        events.push(HistoryEvent::Begin { id, op });
+        Operation {
+            history: self,
+            id,
+            done: false,
+        }
+    }
+
+    /// All recorded events.
+    pub fn events(&self) -> Vec<HistoryEvent<Op, Ret>> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Check that the history is linearizable with respect to the
+    /// specification starting at `initial` and ending at `observed`.
+    /// Returns the ids of the operations which took effect in the order
+    /// of a valid sequential execution.
+    pub fn check<S>(&self, initial: S, observed: &S) -> Result<Vec<usize>, String>
+    where
+        S: Sequential<Op = Op, Ret = Ret>,
+        Op: fmt::Debug,
+        Ret: PartialEq + fmt::Debug,
+    {
+        let events = self.events();
+        let mut ops: Vec<Entry<'_, Op, Ret>> = Vec::new();
+        for (index, event) in events.iter().enumerate() {
+            match event {
+                HistoryEvent::Begin { op, .. } => ops.push(Entry {
+                    op,
+                    begin: index,
+                    end: usize::MAX,
+                    ret: None,
+                }),
+                HistoryEvent::Commit { id, ret } => {
+                    ops[*id].end = index;
+                    ops[*id].ret = Some(ret);
+                }
+                HistoryEvent::Abort { id } => ops[*id].end = index,
+            }
+        }
+        let mut done = vec![false; ops.len()];
+        let mut order = Vec::new();
+        if linearize(&ops, &mut done, &mut order, initial, observed) {
+            Ok(order)
+        } else {
+            Err(format!(
+                "history is not linearizable ending in state {:?}: {:?}",
+                observed, events
+            ))
+        }
+    }
+}
+
+impl<Op, Ret> Default for History<Op, Ret> {
+    fn default() -> Self {
+        Self {
+            events: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// Operation returned by `History::begin`.
+#[must_use]
+#[derive(Debug)]
+pub struct Operation<'a, Op, Ret> {
+    history: &'a History<Op, Ret>,
+    id: usize,
+    done: bool,
+}
+
+impl<Op, Ret> Operation<'_, Op, Ret> {
+    /// Record the completion of the operation with the given result.
+    pub fn commit(mut self, ret: Ret) {
+        self.done = true;
+        self.history.events.lock().unwrap().push(HistoryEvent::Commit { id: self.id, ret });
+    }
+}
+
+impl<Op, Ret> Drop for Operation<'_, Op, Ret> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.history.events.lock().unwrap().push(HistoryEvent::Abort { id: self.id });
+        }
+    }
+}
+
+struct Entry<'a, Op, Ret> {
+    op: &'a Op,
+    begin: usize,
+    end: usize,
+    /// `None` if the operation was aborted or is still running.
+    ret: Option<&'a Ret>,
+}
+
+/// Depth first search for a sequential execution. Aborted operations are
+/// either applied like committed ones or skipped.
+fn linearize<S>(
+    ops: &[Entry<'_, S::Op, S::Ret>],
+    done: &mut [bool],
+    order: &mut Vec<usize>,
+    state: S,
+    observed: &S,
+) -> bool
+where
+    S: Sequential,
+{
+    if done.iter().all(|done| *done) {
+        return state == *observed;
+    }
+    for (id, entry) in ops.iter().enumerate() {
+        if done[id] {
+            continue;
+        }
+        // Operations which ended before this one began must come first.
+        let blocked = ops
+            .iter()
+            .enumerate()
+            .any(|(other, o)| !done[other] && other != id && o.end < entry.begin);
+        done[id] = true;
+        if !blocked {
+            let mut next = state.clone();
+            let ret = next.apply(entry.op);
+            if entry.ret.is_none_or(|expected| *expected == ret) {
+                order.push(id);
+                if linearize(ops, done, order, next, observed) {
+                    return true;
+                }
+                order.pop();
+            }
+        }
+        if entry.ret.is_none() && linearize(ops, done, order, state.clone(), observed) {
+            return true;
+        }
+        done[id] = false;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{History, Sequential};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Register(u32);
+
+    impl Sequential for Register {
+        type Op = u32;
+        type Ret = u32;
+
+        fn apply(&mut self, op: &u32) -> u32 {
+            std::mem::replace(&mut self.0, *op)
+        }
+    }
+
+    #[test]
+    fn history_linearizable() {
+        let history = History::new();
+        let a = history.begin(1);
+        let b = history.begin(2);
+        b.commit(0);
+        a.commit(2);
+        assert_eq!(history.check(Register(0), &Register(1)), Ok(vec![1, 0]));
+        assert!(history.check(Register(0), &Register(2)).is_err());
+        let history = History::new();
+        history.begin(1).commit(0);
+        drop(history.begin(2));
+        history.begin(3).commit(2);
+        assert_eq!(history.check(Register(0), &Register(3)), Ok(vec![0, 1, 2]));
+        assert!(history.check(Register(0), &Register(2)).is_err());
+    }
+}
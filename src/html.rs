@@ -0,0 +1,156 @@
+//! Standalone HTML report of several sweeps.
+//!
+//! Textual reports stop being readable at a few hundred abort points.
+//! `render` produces a single HTML file without external resources which
+//! shows one row per scenario and one colored cell per abort point, e.g.
+//! to be uploaded as a CI artifact. Clicking a cell expands the trace and
+//! failure of the abort point.
+//!
+//! ```rust,no_run
+//! use futures_test_abort::{after, html, Sweep};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let report = Sweep::new()
+//!     .name("after")
+//!     .report(|| (), |_: &()| after((), 2), |_| {})
+//!     .await;
+//! html::write("target/fta-report.html", &[report]).unwrap();
+//! # }
+//! ```
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::report::{Outcome, PointReport, Report};
+
+const STYLE: &str = "body{font-family:sans-serif}\
+table{border-collapse:collapse}\
+td,th{border:1px solid #ccc;padding:2px 4px;vertical-align:top;text-align:left}\
+td.safe{background:#c8e6c9}\
+td.unsafe{background:#ef9a9a}\
+td.completed{background:#bbdefb}\
+td.crashed{background:#b71c1c;color:#fff}\
+td.skipped{background:#eee}\
+summary{cursor:pointer}\
+pre{margin:4px 0;font-size:smaller}";
+
+/// Render the reports as a standalone HTML page.
+pub fn render(reports: &[Report]) -> String {
+    let columns = reports
+        .iter()
+        .flat_map(|report| {
+            let points = report.points.iter().map(|point| point.max_polls);
+            points.chain(report.skipped.iter().map(|skipped| skipped.max_polls))
+        })
+        .max()
+        .map_or(0, |max_polls| max_polls + 1);
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>abort matrix</title>\n");
+    let _ = writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE);
+    let unsafe_points: usize = reports.iter().map(|report| report.summary().num_unsafe).sum();
+    let _ = writeln!(
+        html,
+        "<h1>abort matrix</h1>\n<p>{} scenarios, {} unsafe abort points</p>",
+        reports.len(),
+        unsafe_points
+    );
+    html.push_str("<table>\n<tr><th>scenario</th>");
+    for column in 0..columns {
+        let _ = write!(html, "<th>{}</th>", column);
+    }
+    html.push_str("</tr>\n");
+    for (index, report) in reports.iter().enumerate() {
+        let name = match &report.name {
+            Some(name) => escape(name),
+            None => format!("scenario {}", index),
+        };
+        let _ = write!(html, "<tr><th>{}</th>", name);
+        for column in 0..columns {
+            if let Some(point) = report.points.iter().find(|point| point.max_polls == column) {
+                cell(&mut html, point);
+            } else if let Some(skipped) = report.skipped.iter().find(|skipped| skipped.max_polls == column) {
+                let _ = write!(html, "<td class=\"skipped\">same state as {}</td>", skipped.duplicate_of);
+            } else {
+                html.push_str("<td></td>");
+            }
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Render the reports via `render` and write them to `path`. Missing
+/// parent directories are created.
+pub fn write(path: impl AsRef<Path>, reports: &[Report]) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, render(reports))
+}
+
+fn cell(html: &mut String, point: &PointReport) {
+    let class = match point.outcome() {
+        Outcome::Crashed => "crashed",
+        _ if !point.is_safe() => "unsafe",
+        Outcome::Completed => "completed",
+        _ => "safe",
+    };
+    let mark = match class {
+        "crashed" => "!",
+        "unsafe" => "X",
+        "completed" => "C",
+        _ => ".",
+    };
+    let _ = write!(html, "<td class=\"{}\"><details><summary>{}</summary>", class, mark);
+    if let Some(failure) = &point.failure {
+        let _ = write!(html, "<pre>{}</pre>", escape(failure));
+    }
+    if let Some(label) = &point.last_label {
+        let _ = write!(html, "<pre>last label: {}</pre>", escape(label));
+    }
+    let trace: Vec<String> = point.trace.iter().map(ToString::to_string).collect();
+    let _ = write!(html, "<pre>{}</pre></details></td>", escape(&trace.join("\n")));
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::render;
+    use crate::Sweep;
+    use crate::test_util::{count_to_three, count_unsafe, Counter};
+
+    #[tokio::test]
+    async fn html_report() {
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let report = Sweep::new()
+            .name("<count>")
+            .report(setup, count_unsafe, |counter| assert_eq!(counter.count.get(), 0))
+            .await;
+        let html = render(&[report, Sweep::new().report(setup, count_to_three, |_| {}).await]);
+        assert!(html.contains("<th>&lt;count&gt;</th>"));
+        assert!(html.contains("<th>scenario 1</th>"));
+        assert_eq!(html.matches("<td class=\"unsafe\">").count(), 2);
+        assert_eq!(html.matches("<td class=\"completed\">").count(), 2);
+        assert!(html.contains("<pre>poll 0\nwake\naborted</pre>"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Invariants checked by a `Sweep` after every abort point.
+//!
+//! A check can simply panic, e.g. via `assert!`. Checks returning
+//! `Result<(), InvariantError>` instead are recorded with their context and
+//! data in `PointReport::invariant_errors`. `Invariants` combines several
+//! named checks so all failed invariants of an abort point are reported.
+//!
+//! Invariants of resources shared by several scenarios, e.g. a global
+//! connection count, can be registered once via `register_global`. They
+//! are checked after every iteration of every sweep, soak run and search
+//! on the current thread in addition to the check of the scenario, so a
+//! violation shows up in the report of the scenario and abort point
+//! which caused it.
+//!
+//! ```rust
+//! use futures_test_abort::{after, InvariantError, Invariants, ScopedCounter, Sweep};
+//!
+//! async fn enter(counter: &ScopedCounter) {
+//!     let _entered = counter.enter();
+//!     after((), 1).await;
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let mut invariants = Invariants::new().check_named("entered", |counter: &ScopedCounter| {
+//!     match counter.get() {
+//!         0 => Ok(()),
+//!         n => Err(InvariantError::new("counter was not left").with("count", n)),
+//!     }
+//! });
+//! Sweep::new()
+//!     .run(ScopedCounter::new, enter, |counter| invariants.check(counter))
+//!     .await;
+//! # }
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::harness::panic_message;
+
+/// Failed invariant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InvariantError {
+    /// Name of the invariant as passed to `Invariants::check_named`.
+    pub name: Option<String>,
+    /// Description of the violation.
+    pub message: String,
+    /// Context in which the violation was detected, outermost first.
+    pub context: Vec<String>,
+    /// Values relevant to the violation as key and `Debug` representation.
+    pub data: Vec<(String, String)>,
+}
+
+impl InvariantError {
+    /// Create an error with the given description.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            name: None,
+            message: message.into(),
+            context: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Add a context string.
+    pub fn context(mut self, context: impl Into<String>) -> Self {
+        self.context.push(context.into());
+        self
+    }
+
+    /// Attach a value to the error.
+    pub fn with(mut self, key: impl Into<String>, value: impl fmt::Debug) -> Self {
+        self.data.push((key.into(), format!("{:?}", value)));
+        self
+    }
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{}: ", name)?;
+        }
+        write!(f, "{}", self.message)?;
+        for context in &self.context {
+            write!(f, " ({})", context)?;
+        }
+        if !self.data.is_empty() {
+            let data: Vec<String> = self.data.iter().map(|(key, value)| format!("{}: {}", key, value)).collect();
+            write!(f, " {{{}}}", data.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvariantError {}
+
+/// Result of a check. Implemented for `()` (checks which panic),
+/// `Result<(), InvariantError>` and `Result<(), Vec<InvariantError>>`.
+pub trait CheckResult {
+    /// Convert into the list of failed invariants.
+    fn into_errors(self) -> Vec<InvariantError>;
+}
+
+impl CheckResult for () {
+    fn into_errors(self) -> Vec<InvariantError> {
+        Vec::new()
+    }
+}
+
+impl CheckResult for Result<(), InvariantError> {
+    fn into_errors(self) -> Vec<InvariantError> {
+        self.err().into_iter().collect()
+    }
+}
+
+impl CheckResult for Result<(), Vec<InvariantError>> {
+    fn into_errors(self) -> Vec<InvariantError> {
+        self.err().unwrap_or_default()
+    }
+}
+
+type NamedCheck<S> = (String, Box<dyn FnMut(&S) -> Vec<InvariantError>>);
+
+/// Set of named invariants which are all checked.
+pub struct Invariants<S> {
+    checks: Vec<NamedCheck<S>>,
+}
+
+impl<S> Invariants<S> {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a named invariant. The name is added to its errors.
+    pub fn check_named<R>(mut self, name: impl Into<String>, mut check: impl FnMut(&S) -> R + 'static) -> Self
+    where
+        R: CheckResult,
+    {
+        self.checks.push((name.into(), Box::new(move |state| check(state).into_errors())));
+        self
+    }
+
+    /// Check all invariants and return the errors of the failed ones.
+    pub fn check(&mut self, state: &S) -> Result<(), Vec<InvariantError>> {
+        let mut errors = Vec::new();
+        for (name, check) in &mut self.checks {
+            for mut error in check(state) {
+                error.name = Some(name.clone());
+                errors.push(error);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl<S> Default for Invariants<S> {
+    fn default() -> Self {
+        Self { checks: Vec::new() }
+    }
+}
+
+impl<S> fmt::Debug for Invariants<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.checks.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("Invariants").field("checks", &names).finish()
+    }
+}
+
+type GlobalCheck = (usize, String, Box<dyn FnMut() -> Vec<InvariantError>>);
+
+thread_local! {
+    static GLOBALS: RefCell<Vec<GlobalCheck>> = const { RefCell::new(Vec::new()) };
+    static NEXT_GLOBAL: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Guard returned by `register_global`. The invariant is unregistered
+/// when it is dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct GlobalInvariant {
+    id: usize,
+}
+
+impl Drop for GlobalInvariant {
+    fn drop(&mut self) {
+        let _ = GLOBALS.try_with(|globals| globals.borrow_mut().retain(|(id, _, _)| *id != self.id));
+    }
+}
+
+/// Register a named invariant which is checked after every iteration of
+/// all harnesses on the current thread until the returned guard is
+/// dropped. Its errors are added to the ones of the scenario's check with
+/// the name prefixed by `global `. The check must not register further
+/// invariants.
+pub fn register_global<R>(name: impl Into<String>, mut check: impl FnMut() -> R + 'static) -> GlobalInvariant
+where
+    R: CheckResult,
+{
+    let id = NEXT_GLOBAL.with(|next| next.replace(next.get() + 1));
+    let name = format!("global {}", name.into());
+    GLOBALS.with(|globals| {
+        globals
+            .borrow_mut()
+            .push((id, name, Box::new(move || check().into_errors())))
+    });
+    GlobalInvariant { id }
+}
+
+/// Check all global invariants of the current thread.
+fn check_globals() -> Vec<InvariantError> {
+    GLOBALS.with(|globals| {
+        let mut errors = Vec::new();
+        for (_, name, check) in globals.borrow_mut().iter_mut() {
+            let failed = match panic::catch_unwind(AssertUnwindSafe(&mut *check)) {
+                Ok(failed) => failed,
+                Err(payload) => vec![InvariantError::new(panic_message(&*payload))],
+            };
+            for mut error in failed {
+                error.name = Some(name.clone());
+                errors.push(error);
+            }
+        }
+        errors
+    })
+}
+
+/// Run the check and the global invariants and return the failure
+/// message, if any, together with the structured errors. Panics are
+/// caught and turned into a failure message.
+pub(crate) fn evaluate<S, R>(check: &mut impl FnMut(&S) -> R, state: &S) -> (Option<String>, Vec<InvariantError>)
+where
+    R: CheckResult,
+{
+    let (panicked, mut errors) = match panic::catch_unwind(AssertUnwindSafe(|| check(state).into_errors())) {
+        Err(payload) => (Some(panic_message(&*payload)), Vec::new()),
+        Ok(errors) => (None, errors),
+    };
+    let globals = check_globals();
+    let mut messages: Vec<String> = panicked.into_iter().collect();
+    messages.extend(errors.iter().chain(&globals).map(ToString::to_string));
+    errors.extend(globals);
+    if messages.is_empty() {
+        (None, errors)
+    } else {
+        (Some(messages.join("; ")), errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::{after, DropTiming, InvariantError, Invariants, ScopedCounter, Sweep};
+    use crate::test_util::{count_to_three, enter_counter, Counter};
+
+    #[tokio::test]
+    async fn global_invariants() {
+        use std::rc::Rc;
+        async fn connect(open: &Rc<Cell<usize>>) {
+            open.set(open.get() + 1);
+            after((), 1).await;
+            open.set(open.get() - 1);
+        }
+        let open = Rc::new(Cell::new(0));
+        let guard = crate::register_global("connections", {
+            let open = open.clone();
+            move || match open.replace(0) {
+                0 => Ok(()),
+                n => Err(InvariantError::new("connections left open").with("open", n)),
+            }
+        });
+        let report = Sweep::new().name("connect").report(|| open.clone(), connect, |_| {}).await;
+        let unsafe_points: Vec<_> = report.points.iter().filter(|point| !point.is_safe()).collect();
+        assert_eq!(unsafe_points.len(), 1);
+        assert_eq!(unsafe_points[0].max_polls, 1);
+        assert_eq!(
+            unsafe_points[0].failure.as_deref(),
+            Some("global connections: connections left open {open: 1}")
+        );
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        assert!(Sweep::new().report(setup, count_to_three, |_| {}).await.is_safe());
+        drop(guard);
+        assert!(Sweep::new().report(|| open.clone(), connect, |_| {}).await.is_safe());
+    }
+
+    #[tokio::test]
+    async fn sweep_invariants() {
+        let mut invariants = Invariants::new()
+            .check_named("entered", |counter: &ScopedCounter| match counter.get() {
+                0 => Ok(()),
+                n => Err(InvariantError::new("counter was not left").context("after abort").with("count", n)),
+            })
+            .check_named("limit", |counter: &ScopedCounter| assert!(counter.get() < 2));
+        let report = Sweep::new()
+            .drop_timing(DropTiming::Never)
+            .report(ScopedCounter::new, enter_counter, |counter| invariants.check(counter))
+            .await;
+        let point = &report.points[1];
+        assert_eq!(point.failure.as_deref(), Some("entered: counter was not left (after abort) {count: 1}"));
+        assert_eq!(point.invariant_errors[0].name.as_deref(), Some("entered"));
+        assert_eq!(point.invariant_errors[0].data, [("count".to_string(), "1".to_string())]);
+        assert!(report.points[0].invariant_errors.is_empty());
+    }
+}
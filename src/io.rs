@@ -0,0 +1,584 @@
+//! In-memory I/O which can be severed at a chosen point.
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io;
+use std::mem;
+#[cfg(feature = "tokio-io")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[cfg(feature = "tokio-io")]
+use crate::future::Pinned;
+use crate::harness::fault;
+use crate::rng;
+
+/// Point at which a pipe created by `pipe_with` is severed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cut {
+    /// The pipe is only severed by calling `PipeHandle::sever`.
+    Never,
+    /// The pipe is severed after the given number of bytes has been
+    /// written in total by both ends.
+    AfterBytes(usize),
+    /// The pipe is severed after the given number of `poll_read` and
+    /// `poll_write` calls has been made in total by both ends.
+    AfterPolls(usize),
+}
+
+#[derive(Debug)]
+struct PipeState {
+    cut: Cut,
+    capacity: usize,
+    severed: bool,
+    num_bytes: usize,
+    num_polls: usize,
+    buffers: [VecDeque<u8>; 2],
+    read_wakers: [Option<Waker>; 2],
+    write_wakers: [Option<Waker>; 2],
+    open: [bool; 2],
+    shutdown: [bool; 2],
+    max_chunk: Option<usize>,
+    woken: Vec<Waker>,
+}
+
+impl PipeState {
+    fn poll(&mut self) {
+        if let Cut::AfterPolls(max_polls) = self.cut {
+            if self.num_polls >= max_polls {
+                self.sever();
+            }
+        }
+        self.num_polls += 1;
+    }
+
+    fn sever(&mut self) {
+        if !self.severed {
+            fault("pipe severed");
+        }
+        self.severed = true;
+        self.wake_all();
+    }
+
+    fn wake(&mut self, waker: Option<Waker>) {
+        self.woken.extend(waker);
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.read_wakers.iter_mut().chain(self.write_wakers.iter_mut()) {
+            self.woken.extend(waker.take());
+        }
+    }
+}
+
+/// Run `f` on the locked state of a pipe and wake the wakers it collected
+/// once the lock is released. A waker may poll the other end right away.
+fn locked<R>(state: &Mutex<PipeState>, f: impl FnOnce(&mut PipeState) -> R) -> R {
+    let mut state = state.lock().unwrap();
+    let result = f(&mut state);
+    let woken = mem::take(&mut state.woken);
+    drop(state);
+    for waker in woken {
+        waker.wake();
+    }
+    result
+}
+
+/// One end of an in-memory pipe created by `pipe` or `pipe_with`.
+///
+/// Everything written to one end can be read from the other end. Once the
+/// pipe is severed both ends see disconnects: data which was written
+/// before the cut can still be read, after that reads fail with
+/// `ConnectionReset` and writes fail with `BrokenPipe`.
+#[derive(Debug)]
+pub struct PipeEnd {
+    side: usize,
+    state: Arc<Mutex<PipeState>>,
+}
+
+impl PipeEnd {
+    /// Get a handle to the pipe which can be used to sever it and to
+    /// check that both ends were cleaned up.
+    pub fn handle(&self) -> PipeHandle {
+        PipeHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Attempt to read data from the other end into `buf`.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let side = self.side;
+        locked(&self.state, |state| {
+            state.poll();
+            let peer = 1 - side;
+            if !state.buffers[peer].is_empty() {
+                let len = buf.len().min(state.buffers[peer].len());
+                for (dst, src) in buf.iter_mut().zip(state.buffers[peer].drain(..len)) {
+                    *dst = src;
+                }
+                let waker = state.write_wakers[peer].take();
+                state.wake(waker);
+                return Poll::Ready(Ok(len));
+            }
+            if state.severed {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            if !state.open[peer] || state.shutdown[peer] {
+                return Poll::Ready(Ok(0));
+            }
+            state.read_wakers[side] = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+
+    /// Attempt to write data from `buf` to the other end.
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let side = self.side;
+        locked(&self.state, |state| {
+            state.poll();
+            let peer = 1 - side;
+            if state.severed || !state.open[peer] || state.shutdown[side] {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            let mut len = buf.len().min(state.capacity - state.buffers[side].len());
+            if let Cut::AfterBytes(max_bytes) = state.cut {
+                len = len.min(max_bytes - state.num_bytes);
+            }
+            if let Some(max_chunk) = state.max_chunk {
+                let chunk = rng::with_substream("io", |rng| rng.below(max_chunk) + 1).unwrap_or(max_chunk);
+                len = len.min(chunk);
+            }
+            if len == 0 && !buf.is_empty() {
+                state.write_wakers[side] = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            state.buffers[side].extend(&buf[..len]);
+            state.num_bytes += len;
+            let waker = state.read_wakers[peer].take();
+            state.wake(waker);
+            if state.cut == Cut::AfterBytes(state.num_bytes) {
+                state.sever();
+            }
+            Poll::Ready(Ok(len))
+        })
+    }
+
+    /// Shut down the writing side of this end. The other end reads EOF
+    /// once it has consumed all remaining data.
+    pub fn shutdown(&mut self) {
+        let side = self.side;
+        locked(&self.state, |state| {
+            state.shutdown[side] = true;
+            let waker = state.read_wakers[1 - side].take();
+            state.wake(waker);
+        })
+    }
+
+    /// Read data from the other end into `buf`.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    /// Write data from `buf` to the other end.
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_write(cx, buf)).await
+    }
+
+    /// Write all of `buf` to the other end.
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let len = self.write(buf).await?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let side = self.side;
+        locked(&self.state, |state| {
+            state.open[side] = false;
+            state.wake_all();
+        })
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl tokio::io::AsyncRead for PipeEnd {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        PipeEnd::poll_read(self.get_mut(), cx, buf)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl tokio::io::AsyncWrite for PipeEnd {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        PipeEnd::poll_write(self.get_mut(), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().shutdown();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Wrapper for an `AsyncRead` which limits the number of `poll_read`
+/// calls before it simulates an aborted connection.
+///
+/// Once the limit is reached every read fails with `ConnectionReset` and
+/// a fault is recorded. Writes are passed on unchanged so the wrapper can
+/// be used with duplex streams.
+#[cfg(feature = "tokio-io")]
+#[derive(Debug)]
+pub struct AbortRead<T> {
+    num_polls: usize,
+    max_polls: usize,
+    io: Pinned<T>,
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> AbortRead<T> {
+    /// Number of `poll_read` calls so far including the failed ones.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+
+    /// Returns `true` once the limit was reached.
+    pub fn is_aborted(&self) -> bool {
+        self.num_polls > self.max_polls
+    }
+
+    /// Consume the wrapper and return the inner reader.
+    pub fn into_inner(self) -> T {
+        self.io.into_inner()
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> tokio::io::AsyncRead for AbortRead<T>
+where
+    T: tokio::io::AsyncRead,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        // Safety: we never move `self.io`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        me.num_polls += 1;
+        if me.is_aborted() {
+            if me.num_polls == me.max_polls + 1 {
+                fault("connection aborted");
+            }
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        unsafe { me.io.as_pin_mut() }.poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> tokio::io::AsyncWrite for AbortRead<T>
+where
+    T: tokio::io::AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // Safety: we never move `self.io`
+        unsafe { Pin::into_inner_unchecked(self).io.as_pin_mut() }.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Safety: we never move `self.io`
+        unsafe { Pin::into_inner_unchecked(self).io.as_pin_mut() }.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Safety: we never move `self.io`
+        unsafe { Pin::into_inner_unchecked(self).io.as_pin_mut() }.poll_shutdown(cx)
+    }
+}
+
+/// Create an `AbortRead` wrapper which fails all reads after `max_polls`
+/// calls of `poll_read`.
+#[cfg(feature = "tokio-io")]
+pub fn abort_read<T>(io: T, max_polls: usize) -> AbortRead<T>
+where
+    T: tokio::io::AsyncRead,
+{
+    AbortRead {
+        num_polls: 0,
+        max_polls,
+        io: Pinned::new(io),
+    }
+}
+
+/// Wrapper for an `AsyncWrite` which limits the number of `poll_write`
+/// calls before it simulates an aborted connection.
+///
+/// Once the limit is reached every write, flush and shutdown fails with
+/// `BrokenPipe` and a fault is recorded. Reads are passed on unchanged so
+/// the wrapper can be used with duplex streams.
+#[cfg(feature = "tokio-io")]
+#[derive(Debug)]
+pub struct AbortWrite<T> {
+    num_polls: usize,
+    max_polls: usize,
+    io: Pinned<T>,
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> AbortWrite<T> {
+    /// Number of `poll_write` calls so far including the failed ones.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+
+    /// Returns `true` once the limit was reached.
+    pub fn is_aborted(&self) -> bool {
+        self.num_polls > self.max_polls
+    }
+
+    /// Consume the wrapper and return the inner writer.
+    pub fn into_inner(self) -> T {
+        self.io.into_inner()
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> tokio::io::AsyncRead for AbortWrite<T>
+where
+    T: tokio::io::AsyncRead,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        // Safety: we never move `self.io`
+        unsafe { Pin::into_inner_unchecked(self).io.as_pin_mut() }.poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl<T> tokio::io::AsyncWrite for AbortWrite<T>
+where
+    T: tokio::io::AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // Safety: we never move `self.io`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        me.num_polls += 1;
+        if me.is_aborted() {
+            if me.num_polls == me.max_polls + 1 {
+                fault("connection aborted");
+            }
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        unsafe { me.io.as_pin_mut() }.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.is_aborted() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        // Safety: we never move `self.io`
+        unsafe { Pin::into_inner_unchecked(self).io.as_pin_mut() }.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.is_aborted() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        // Safety: we never move `self.io`
+        unsafe { Pin::into_inner_unchecked(self).io.as_pin_mut() }.poll_shutdown(cx)
+    }
+}
+
+/// Create an `AbortWrite` wrapper which fails all writes after
+/// `max_polls` calls of `poll_write`.
+#[cfg(feature = "tokio-io")]
+pub fn abort_write<T>(io: T, max_polls: usize) -> AbortWrite<T>
+where
+    T: tokio::io::AsyncWrite,
+{
+    AbortWrite {
+        num_polls: 0,
+        max_polls,
+        io: Pinned::new(io),
+    }
+}
+
+/// Handle to an in-memory pipe.
+#[derive(Clone, Debug)]
+pub struct PipeHandle {
+    state: Arc<Mutex<PipeState>>,
+}
+
+impl PipeHandle {
+    /// Sever the pipe causing both ends to see a disconnect.
+    pub fn sever(&self) {
+        locked(&self.state, PipeState::sever);
+    }
+
+    /// Returns `true` if the pipe has been severed.
+    pub fn is_severed(&self) -> bool {
+        self.state.lock().unwrap().severed
+    }
+
+    /// Number of bytes written by both ends.
+    pub fn num_bytes(&self) -> usize {
+        self.state.lock().unwrap().num_bytes
+    }
+
+    /// Number of `poll_read` and `poll_write` calls made by both ends.
+    pub fn num_polls(&self) -> usize {
+        self.state.lock().unwrap().num_polls
+    }
+
+    /// Number of ends which have not been dropped, yet. This is `0` once
+    /// both peers have cleaned up their end of the pipe.
+    pub fn open_ends(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.open.iter().filter(|open| **open).count()
+    }
+}
+
+/// Create an in-memory pipe which is never severed on its own.
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    pipe_with(Cut::Never)
+}
+
+/// Create an in-memory pipe which is severed at the given point.
+pub fn pipe_with(cut: Cut) -> (PipeEnd, PipeEnd) {
+    new_pipe(cut, None)
+}
+
+/// Create an in-memory pipe which accepts at most `max_chunk` bytes per
+/// write. In a seeded `Sweep` the size of every chunk is drawn from the
+/// `io` substream so partial writes happen at random but reproducible
+/// sizes.
+pub fn pipe_chunked(cut: Cut, max_chunk: usize) -> (PipeEnd, PipeEnd) {
+    assert!(max_chunk > 0, "max_chunk must not be 0");
+    new_pipe(cut, Some(max_chunk))
+}
+
+fn new_pipe(cut: Cut, max_chunk: Option<usize>) -> (PipeEnd, PipeEnd) {
+    let state = Arc::new(Mutex::new(PipeState {
+        cut,
+        capacity: 8192,
+        severed: cut == Cut::AfterBytes(0),
+        num_bytes: 0,
+        num_polls: 0,
+        buffers: [VecDeque::new(), VecDeque::new()],
+        read_wakers: [None, None],
+        write_wakers: [None, None],
+        open: [true, true],
+        shutdown: [false, false],
+        max_chunk,
+        woken: Vec::new(),
+    }));
+    (
+        PipeEnd {
+            side: 0,
+            state: state.clone(),
+        },
+        PipeEnd { side: 1, state },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Wake, Waker};
+
+    use super::{pipe, pipe_with, Cut, PipeHandle};
+
+    #[tokio::test]
+    async fn pipe_roundtrip() {
+        let (mut client, mut server) = pipe();
+        let handle = client.handle();
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown();
+        let mut buf = [0u8; 8];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+        drop(client);
+        assert_eq!(handle.open_ends(), 1);
+        drop(server);
+        assert_eq!(handle.open_ends(), 0);
+    }
+
+    struct PollsOnWake(PipeHandle, AtomicUsize);
+
+    impl Wake for PollsOnWake {
+        fn wake(self: Arc<Self>) {
+            self.1.store(self.0.num_polls(), Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn pipe_wakes_unlocked() {
+        let (mut client, mut server) = pipe();
+        let wake = Arc::new(PollsOnWake(client.handle(), AtomicUsize::new(0)));
+        let mut buf = [0u8; 4];
+        let waker = Waker::from(wake.clone());
+        assert!(server.poll_read(&mut Context::from_waker(&waker), &mut buf).is_pending());
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(wake.1.load(Ordering::Relaxed), 2);
+    }
+
+    #[cfg(feature = "tokio-io")]
+    #[tokio::test]
+    async fn abort_read_write() {
+        use std::future::poll_fn;
+        use std::pin::Pin;
+
+        use tokio::io::{AsyncRead, AsyncWrite};
+
+        let (client, server) = pipe();
+        let mut client = crate::abort_write(client, 1);
+        let mut server = crate::abort_read(server, 1);
+        let mut buf = [0; 4];
+        let written = poll_fn(|cx| Pin::new(&mut client).poll_write(cx, b"ping")).await;
+        assert_eq!(written.unwrap(), 4);
+        let written = poll_fn(|cx| Pin::new(&mut client).poll_write(cx, b"ping")).await;
+        assert_eq!(written.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+        assert!(client.is_aborted());
+        let read = poll_fn(|cx| Pin::new(&mut server).poll_read(cx, &mut buf)).await;
+        assert_eq!(read.unwrap(), 4);
+        let read = poll_fn(|cx| Pin::new(&mut server).poll_read(cx, &mut buf)).await;
+        assert_eq!(read.unwrap_err().kind(), std::io::ErrorKind::ConnectionReset);
+        assert_eq!(server.num_polls(), 2);
+    }
+
+    #[tokio::test]
+    async fn pipe_cut_after_bytes() {
+        let (mut client, mut server) = pipe_with(Cut::AfterBytes(3));
+        let handle = client.handle();
+        let (written, read) = tokio::join!(
+            async move { client.write_all(b"hello").await },
+            async move {
+                let mut received = Vec::new();
+                let mut buf = [0u8; 8];
+                loop {
+                    match server.read(&mut buf).await {
+                        Ok(len) => received.extend_from_slice(&buf[..len]),
+                        Err(e) => return (received, e.kind()),
+                    }
+                }
+            },
+        );
+        assert_eq!(written.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+        assert_eq!(read, (b"hel".to_vec(), std::io::ErrorKind::ConnectionReset));
+        assert!(handle.is_severed());
+        assert_eq!(handle.open_ends(), 0);
+    }
+
+    #[tokio::test]
+    async fn pipe_cut_after_polls() {
+        let (mut client, _server) = pipe_with(Cut::AfterPolls(2));
+        assert!(client.write(b"a").await.is_ok());
+        assert!(client.write(b"b").await.is_ok());
+        assert!(client.write(b"c").await.is_err());
+    }
+}
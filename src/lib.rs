@@ -146,157 +146,102 @@
 //!
 //! at your option.
 #![warn(missing_docs)]
-
-use std::future::Future;
-use std::pin::Pin;
-use std::task::{Context, Poll};
-
-/// This error is returned when an `AbortN` future resolves
-/// aborting the inner future.
-#[derive(Debug)]
-pub struct Aborted {
-    /// Number of polls that were made before aborting the future.
-    pub num_polls: usize
-}
-
-/// Wrapper for a `Future` which limits the times it can be polled.
-pub struct Abort<T>
-where
-    T: Future
-{
-    num_polls: usize,
-    max_polls: usize,
-    future: T,
-}
-
-impl<T> Future for Abort<T>
-where
-    T: Future,
-{
-    type Output = Result<T::Output, Aborted>;
-    
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.num_polls >= self.max_polls {
-            return Poll::Ready(Err(Aborted {
-                num_polls: self.num_polls
-            }));
-        }
-        // Safety: we never move `self.num_polls` or `self.future`
-        unsafe {
-            let me = Pin::into_inner_unchecked(self);
-            me.num_polls += 1;
-            let future = Pin::new_unchecked(&mut me.future);
-            match future.poll(cx) {
-                Poll::Ready(v) => Poll::Ready(Ok(v)),
-                Poll::Pending => Poll::Pending
-            }
-        }
-    }
-}
-
-/// Create a `Abort` future wrapper which limits the times a future
-/// can be polled before it returns `Err(Aborted(max_polls))`. If the
-/// future is ready before reaching `max_polls` `Ok(T)` is returned
-/// instead.
-pub fn abort<T>(future: T, max_polls: usize) -> Abort<T>
-where
-    T: Future,
-{
-    Abort {
-        num_polls: 0,
-        max_polls,
-        future,
-    }
-}
-
-/// A future that never resolves but schedules itself to be continuously
-/// polled.
-pub struct Never;
-
-impl Future for Never {
-    type Output = ();
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        cx.waker().wake_by_ref();
-        Poll::Pending
-    }
-}
-
-/// Create a `Never` future which never resolves but schedules itself
-/// to be woken all the time.
-pub fn never() -> Never {
-    Never
-}
-
-/// A future that is ready after a given number of polls.
-pub struct After<T> {
-    value: Option<T>,
-    num_polls: usize,
-    max_polls: usize,
-}
-
-impl<T> Future for After<T> {
-    type Output = T;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let ready = self.num_polls >= self.max_polls;
-        // Safety: we never move `self.num_polls`
-        unsafe {
-            let me = Pin::into_inner_unchecked(self);
-            if ready {
-                let value = me.value.take().unwrap();
-                return Poll::Ready(value);
-            }
-            me.num_polls += 1;
-        }
-        cx.waker().wake_by_ref();
-        Poll::Pending
-    }
-}
-
-/// Create future that is ready after a given number of polls.
-pub fn after<T>(value: T, max_polls: usize) -> After<T> {
-    After {
-        value: Some(value),
-        num_polls: 0,
-        max_polls,
-    }
-}
-
-
+#![cfg_attr(feature = "coroutine", feature(coroutine_trait, coroutines, stmt_expr_attributes))]
+
+#[cfg(all(test, feature = "macros"))]
+extern crate self as futures_test_abort;
+
+pub mod actor;
+pub mod adversary;
+#[cfg(feature = "baseline")]
+pub mod baseline;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod channel;
+pub mod combinator;
+pub mod conformance;
+pub mod drops;
+#[cfg(feature = "console")]
+mod console;
+#[cfg(feature = "coroutine")]
+pub mod coroutine;
+pub mod examples;
+pub mod executor;
+pub mod fairness;
+pub mod fanout;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+pub mod future;
+pub mod harness;
+pub mod history;
+#[cfg(feature = "html")]
+pub mod html;
+pub mod invariant;
+pub mod io;
+#[cfg(feature = "tokio-local")]
+pub mod local;
+pub mod model;
+pub mod registry;
+pub mod report;
+pub mod rng;
+pub mod scope;
+#[cfg(feature = "sink")]
+pub mod sink;
+pub mod soak;
+pub mod stream;
+pub mod sync;
 #[cfg(test)]
-mod tests {
-    use crate::{abort, after, never};
-
-    #[tokio::test]
-    async fn abort_n_0_err() {
-        assert!(abort(async { 42 }, 0).await.is_err());
-    }
-
-    #[tokio::test]
-    async fn abort_n_1_ok() {
-        let result = abort(async { 42usize }, 1).await;
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 42usize);
-    }
-
-    #[tokio::test]
-    async fn abort_n_err() {
-        for max_polls in 0..100 {
-            let result = abort(async { never().await }, max_polls).await;
-            assert!(result.is_err());
-            assert_eq!(result.unwrap_err().num_polls, max_polls);
-        }
-    }
-
-    #[tokio::test]
-    async fn abort_n_ok() {
-        for max_polls in 0..100 {
-            let result = abort(async { after(max_polls, max_polls).await }, max_polls+1).await;
-            assert!(result.is_ok());
-            assert_eq!(result.unwrap(), max_polls);
-        }
-    }
-
+mod test_util;
+pub mod timeout;
+#[cfg(feature = "tower")]
+pub mod tower;
+mod watchdog;
+
+pub use drops::{DropCounter, DropProbe, DropSpy, DropToken};
+pub use executor::{block_on, Stepper};
+pub use future::{
+    abort, abort_after_wakes, abort_async_drop, abort_at_checkpoint, abort_poll_fn, abort_random, abort_reason,
+    abort_when, abort_with_handle, abort_with_opts, abort_with_output, abort_with_policy, after, after_async,
+    after_fn, checkpoint, count_polls, label, labeled, migrate, never, never_silent, or_output, panic_after,
+    spurious_wakes, spy_wakers, try_abort, Abort, AbortAsyncDrop, AbortExt, AbortHandle, AbortOpts, AbortReason,
+    AbortWhen, Abortable, Aborted, After, AfterAsync, AfterFn, AsyncDrop, AsyncDropAborted, Checkpoint,
+    CountPolls, Counting, Instrumented, Label, Labeled, Migrate, Never, NeverSilent, OrOutput, PanicAfter,
+    Policy, Probe, SpuriousWakes, SpyWakers, Suspension, WakerHooks, WakerLayer, WakerSpy,
+};
+#[doc(hidden)]
+pub use future::__loop_iter;
+#[doc(hidden)]
+pub use harness::{__abort_test, __doctest_sweep, __leaf_event};
+#[cfg(feature = "tokio-time")]
+pub use harness::TokioClock;
+pub use harness::{
+    abort_all_points, abort_before_first_poll, abort_sweep, fault, snapshot, tag, track, Clock, DropTiming,
+    InstrumentedLeaf, Iterations, MakeFuture, ManualClock, Profile, ProfileSettings, Schedule, ScheduleError, Sweep,
+    SystemClock, Tracked,
+};
+#[cfg(feature = "macros")]
+pub use futures_test_abort_macros::abort_test;
+pub use invariant::{register_global, GlobalInvariant, InvariantError, Invariants};
+#[cfg(feature = "tokio-io")]
+pub use io::{abort_read, abort_write, AbortRead, AbortWrite};
+pub use io::{pipe, pipe_chunked, pipe_with, Cut, PipeEnd, PipeHandle};
+pub use report::{
+    Outcome, Phase, PointReport, Report, Skipped, SpuriousPolls, Summary, TagSummary, TimeoutMatrix, TimeoutRow,
+    TraceEvent,
+};
+pub use scope::{with_defaults, Defaults, WithDefaults};
+pub use stream::{abort as abort_stream, Abort as AbortStream};
+pub use sync::{acquire, CounterGuard, Guard, ScopedCounter, ScopedSet, SetGuard, Settled};
+
+/// The most commonly used items of this crate.
+///
+/// ```rust
+/// use futures_test_abort::prelude::*;
+/// ```
+pub mod prelude {
+    pub use crate::future::{abort, after, label, labeled, never, AbortExt};
+    pub use crate::harness::{MakeFuture, Sweep};
+    pub use crate::loop_iter;
+    pub use crate::report::Report;
+    pub use crate::sync::{acquire, Settled};
 }
-
@@ -0,0 +1,261 @@
+//! Tracking of tasks spawned via `spawn_local`.
+//!
+//! Handlers of `!Send` servers hand work off to a `LocalSet` via
+//! `spawn_local`. If such a handler is aborted the spawned tasks keep
+//! running and may wait forever for the handler. `LocalTasks` spawns the
+//! tasks on the current `LocalSet` and remembers where they were spawned,
+//! so the tasks which neither completed nor were cancelled before the set
+//! is dropped can be reported.
+//!
+//! ```rust
+//! use futures_test_abort::{abort, after, local::LocalTasks};
+//! use tokio::task::LocalSet;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let tasks = LocalTasks::new();
+//! let local = LocalSet::new();
+//! local
+//!     .run_until(async {
+//!         let handler = async {
+//!             tasks.spawn_local_named("audit", after((), 2));
+//!             after((), 1).await;
+//!         };
+//!         abort(handler, 1).await.unwrap_err();
+//!         tasks.drain(10).await.unwrap();
+//!     })
+//!     .await;
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use tokio::task::JoinHandle;
+
+use crate::future::{after, Pinned};
+use crate::sync::Settled;
+
+/// Task spawned via `LocalTasks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalTask {
+    /// Name given to `LocalTasks::spawn_local_named`.
+    pub name: Option<&'static str>,
+    /// Location of the `spawn_local` call.
+    pub location: &'static Location<'static>,
+}
+
+impl fmt::Display for LocalTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "{} ({})", name, self.location),
+            None => write!(f, "{}", self.location),
+        }
+    }
+}
+
+/// Tracker of the tasks spawned on the current `LocalSet`.
+///
+/// Clones share the tracked tasks.
+#[derive(Clone, Default)]
+pub struct LocalTasks {
+    tasks: Rc<RefCell<Tasks>>,
+}
+
+#[derive(Default)]
+struct Tasks {
+    next_id: usize,
+    alive: BTreeMap<usize, LocalTask>,
+    completed: usize,
+    cancelled: usize,
+}
+
+impl LocalTasks {
+    /// Create a tracker without tasks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawn `future` on the current `LocalSet` like
+    /// `tokio::task::spawn_local` and track it.
+    #[track_caller]
+    pub fn spawn_local<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn(None, Location::caller(), future)
+    }
+
+    /// Spawn and track `future` like `spawn_local`. The name is reported
+    /// together with the spawn site if the task survives.
+    #[track_caller]
+    pub fn spawn_local_named<F>(&self, name: &'static str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn(Some(name), Location::caller(), future)
+    }
+
+    fn spawn<F>(
+        &self,
+        name: Option<&'static str>,
+        location: &'static Location<'static>,
+        future: F,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let id = {
+            let mut tasks = self.tasks.borrow_mut();
+            let id = tasks.next_id;
+            tasks.next_id += 1;
+            tasks.alive.insert(id, LocalTask { name, location });
+            id
+        };
+        tokio::task::spawn_local(Tracked {
+            id,
+            tasks: self.tasks.clone(),
+            future: Pinned::new(future),
+        })
+    }
+
+    /// Tasks which neither completed nor were cancelled, in the order
+    /// they were spawned.
+    pub fn survivors(&self) -> Vec<LocalTask> {
+        self.tasks.borrow().alive.values().cloned().collect()
+    }
+
+    /// Number of tasks which ran to completion.
+    pub fn completed(&self) -> usize {
+        self.tasks.borrow().completed
+    }
+
+    /// Number of tasks which were dropped before they completed.
+    pub fn cancelled(&self) -> usize {
+        self.tasks.borrow().cancelled
+    }
+
+    /// Returns an error listing the surviving tasks with their spawn
+    /// sites unless all tasks completed or were cancelled.
+    pub fn check(&self) -> Result<(), String> {
+        let survivors = self.survivors();
+        if survivors.is_empty() {
+            return Ok(());
+        }
+        let survivors: Vec<String> = survivors.iter().map(ToString::to_string).collect();
+        Err(format!(
+            "{} local task(s) survived: {}",
+            survivors.len(),
+            survivors.join(", ")
+        ))
+    }
+
+    /// Let the `LocalSet` run the tracked tasks for up to `max_yields`
+    /// yields of the calling task and check them afterwards. Await this
+    /// inside `LocalSet::run_until` before the set is dropped.
+    pub async fn drain(&self, max_yields: usize) -> Result<(), String> {
+        for _ in 0..max_yields {
+            if self.is_settled() {
+                break;
+            }
+            after((), 1).await;
+        }
+        self.check()
+    }
+}
+
+impl Settled for LocalTasks {
+    fn is_settled(&self) -> bool {
+        self.tasks.borrow().alive.is_empty()
+    }
+}
+
+impl fmt::Debug for LocalTasks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tasks = self.tasks.borrow();
+        f.debug_struct("LocalTasks")
+            .field("survivors", &tasks.alive.values().map(ToString::to_string).collect::<Vec<_>>())
+            .field("completed", &tasks.completed)
+            .field("cancelled", &tasks.cancelled)
+            .finish()
+    }
+}
+
+/// Future of a task spawned via `LocalTasks`.
+struct Tracked<F> {
+    id: usize,
+    tasks: Rc<RefCell<Tasks>>,
+    future: Pinned<F>,
+}
+
+impl<F> Future for Tracked<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let result = unsafe { me.future.as_pin_mut() }.poll(cx);
+        if result.is_ready() {
+            let mut tasks = me.tasks.borrow_mut();
+            if tasks.alive.remove(&me.id).is_some() {
+                tasks.completed += 1;
+            }
+        }
+        result
+    }
+}
+
+impl<F> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        let mut tasks = self.tasks.borrow_mut();
+        if tasks.alive.remove(&self.id).is_some() {
+            tasks.cancelled += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalTasks;
+    use crate::{abort, after, never, Settled};
+
+    #[tokio::test]
+    async fn local_task_survivors() {
+        let tasks = LocalTasks::new();
+        let local = tokio::task::LocalSet::new();
+        let (flush, forgotten) = local
+            .run_until(async {
+                let handler = async {
+                    tasks.spawn_local_named("flush", after((), 2));
+                    tasks.spawn_local(never());
+                    after((), 1).await;
+                    tasks.spawn_local_named("unreachable", never());
+                };
+                abort(handler, 1).await.unwrap_err();
+                let error = tasks.drain(10).await.unwrap_err();
+                (tasks.completed(), error)
+            })
+            .await;
+        assert_eq!(flush, 1);
+        let survivors = tasks.survivors();
+        assert_eq!(survivors.len(), 1);
+        assert_eq!(survivors[0].name, None);
+        assert_eq!(forgotten, format!("1 local task(s) survived: {}", survivors[0].location));
+        assert!(!tasks.is_settled());
+        drop(local);
+        assert_eq!(tasks.cancelled(), 1);
+        tasks.assert_settled();
+    }
+}
@@ -0,0 +1,234 @@
+//! Model based checking of state machines.
+//!
+//! A `Model` describes the valid states of the code under test and the
+//! transitions between them. The code under test marks every transition
+//! via `Model::begin` and `Transition::commit`. After every abort the
+//! check compares the real state with the model: it must be a valid state
+//! and must match the state of the last committed transition. A transition
+//! which was interrupted by an abort must therefore leave the real state
+//! unchanged.
+//!
+//! ```rust
+//! use std::cell::Cell;
+//!
+//! use futures_test_abort::model::Model;
+//! use futures_test_abort::{after, Sweep};
+//!
+//! #[derive(Clone, Copy, Debug, PartialEq)]
+//! enum Conn {
+//!     Idle,
+//!     Connected,
+//! }
+//!
+//! struct State {
+//!     conn: Cell<Conn>,
+//!     model: Model<Conn>,
+//! }
+//!
+//! async fn connect(state: &State) {
+//!     let transition = state.model.begin(Conn::Connected);
+//!     after((), 2).await;
+//!     state.conn.set(Conn::Connected);
+//!     transition.commit();
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! Sweep::new()
+//!     .run(
+//!         || State {
+//!             conn: Cell::new(Conn::Idle),
+//!             model: Model::new(Conn::Idle).transition(Conn::Idle, Conn::Connected),
+//!         },
+//!         connect,
+//!         |state| state.model.assert_state(&state.conn.get()),
+//!     )
+//!     .await;
+//! # }
+//! ```
+
+use std::fmt;
+use std::sync::Mutex;
+
+/// Model of a state machine.
+#[derive(Debug)]
+pub struct Model<M> {
+    states: Vec<M>,
+    transitions: Vec<(M, M)>,
+    current: Mutex<ModelState<M>>,
+}
+
+#[derive(Debug)]
+struct ModelState<M> {
+    state: M,
+    /// Last transition which was begun but dropped without being committed.
+    interrupted: Option<(M, M)>,
+}
+
+impl<M> Model<M>
+where
+    M: Clone + PartialEq + fmt::Debug,
+{
+    /// Create a model with the given initial state.
+    pub fn new(initial: M) -> Self {
+        Self {
+            states: vec![initial.clone()],
+            transitions: Vec::new(),
+            current: Mutex::new(ModelState {
+                state: initial,
+                interrupted: None,
+            }),
+        }
+    }
+
+    /// Add a valid state without any transitions.
+    pub fn state(mut self, state: M) -> Self {
+        if !self.states.contains(&state) {
+            self.states.push(state);
+        }
+        self
+    }
+
+    /// Add a valid transition. Both states are added as valid states.
+    pub fn transition(mut self, from: M, to: M) -> Self {
+        self = self.state(from.clone()).state(to.clone());
+        self.transitions.push((from, to));
+        self
+    }
+
+    /// Current state of the model.
+    pub fn current(&self) -> M {
+        self.current.lock().unwrap().state.clone()
+    }
+
+    /// Begin a transition from the current state to `to`. Panics if the
+    /// model has no such transition.
+    pub fn begin(&self, to: M) -> Transition<'_, M> {
+        let from = self.current();
+        assert!(
+            self.transitions.contains(&(from.clone(), to.clone())),
+            "invalid transition {:?} -> {:?}",
+            from,
+            to
+        );
+        Transition {
+            model: self,
+            from,
+            to: Some(to),
+        }
+    }
+
+    /// Compare the real state with the model. Returns a description of the
+    /// mismatch if the real state is not a valid state or does not match
+    /// the state of the model.
+    pub fn check(&self, real: &M) -> Result<(), String> {
+        let current = self.current.lock().unwrap();
+        if !self.states.contains(real) {
+            return Err(format!("{:?} is not a valid state of the model", real));
+        }
+        if *real != current.state {
+            let mut msg = format!("state is {:?} but the model is in state {:?}", real, current.state);
+            if let Some((from, to)) = &current.interrupted {
+                msg.push_str(&format!(" after transition {:?} -> {:?} was interrupted", from, to));
+            }
+            return Err(msg);
+        }
+        Ok(())
+    }
+
+    /// Panic unless `check` succeeds.
+    pub fn assert_state(&self, real: &M) {
+        if let Err(msg) = self.check(real) {
+            panic!("{}", msg);
+        }
+    }
+}
+
+/// Transition returned by `Model::begin`. The transition is interrupted
+/// unless it is committed before being dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct Transition<'a, M>
+where
+    M: Clone + PartialEq + fmt::Debug,
+{
+    model: &'a Model<M>,
+    from: M,
+    to: Option<M>,
+}
+
+impl<M> Transition<'_, M>
+where
+    M: Clone + PartialEq + fmt::Debug,
+{
+    /// Complete the transition. The model moves to the target state.
+    pub fn commit(mut self) {
+        let to = self.to.take().unwrap();
+        let mut current = self.model.current.lock().unwrap();
+        current.state = to;
+        current.interrupted = None;
+    }
+}
+
+impl<M> Drop for Transition<'_, M>
+where
+    M: Clone + PartialEq + fmt::Debug,
+{
+    fn drop(&mut self) {
+        if let Some(to) = self.to.take() {
+            self.model.current.lock().unwrap().interrupted = Some((self.from.clone(), to));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::Model;
+    use crate::{after, Sweep};
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Conn {
+        Idle,
+        Connecting,
+        Connected,
+    }
+
+    struct Machine {
+        conn: Cell<Conn>,
+        model: Model<Conn>,
+    }
+
+    fn machine() -> Machine {
+        Machine {
+            conn: Cell::new(Conn::Idle),
+            model: Model::new(Conn::Idle).transition(Conn::Idle, Conn::Connected),
+        }
+    }
+
+    async fn connect_unsafe(machine: &Machine) {
+        let transition = machine.model.begin(Conn::Connected);
+        machine.conn.set(Conn::Connecting);
+        after((), 1).await;
+        machine.conn.set(Conn::Connected);
+        transition.commit();
+    }
+
+    #[tokio::test]
+    async fn sweep_model() {
+        let report = Sweep::new()
+            .report(machine, connect_unsafe, |machine| machine.model.assert_state(&machine.conn.get()))
+            .await;
+        let failure = report.points[1].failure.as_deref().unwrap();
+        assert_eq!(failure, "Connecting is not a valid state of the model");
+        assert!(report.points[2].is_safe());
+        let machine = machine();
+        drop(machine.model.begin(Conn::Connected));
+        machine.conn.set(Conn::Connected);
+        assert_eq!(
+            machine.model.check(&Conn::Connected).unwrap_err(),
+            "state is Connected but the model is in state Idle after transition Idle -> Connected was interrupted"
+        );
+    }
+}
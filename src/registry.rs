@@ -0,0 +1,81 @@
+//! Opt-in registry of the reports of all sweeps in a test binary.
+//!
+//! The output of a single test makes it hard to see the overall
+//! cancel-safety coverage of a crate. Once the registry is enabled via
+//! `enable` or the `FTA_REGISTRY` environment variable every `Sweep` and
+//! `actor::Mailbox` records its report here. Call `print_summary` from a
+//! final test to print a consolidated summary. Tests run in parallel by
+//! default, so the summary only covers the sweeps which finished so far.
+
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::report::{Report, Summary};
+
+/// Name of the environment variable enabling the registry if it is set to
+/// a value other than `0`.
+pub const ENABLE_VAR: &str = "FTA_REGISTRY";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REPORTS: Mutex<Vec<Report>> = Mutex::new(Vec::new());
+
+/// Enable the registry for the rest of the process.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns `true` if the registry is enabled.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst) || env::var(ENABLE_VAR).is_ok_and(|value| value != "0")
+}
+
+/// Record a report if the registry is enabled.
+pub(crate) fn record(report: &Report) {
+    if is_enabled() {
+        REPORTS.lock().unwrap_or_else(|e| e.into_inner()).push(report.clone());
+    }
+}
+
+/// All reports recorded so far.
+pub fn reports() -> Vec<Report> {
+    REPORTS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Summary of all reports recorded so far.
+pub fn summary() -> Summary {
+    Summary::from_reports(&reports())
+}
+
+/// Print the summary of all reports recorded so far followed by one line
+/// for every sweep with unsafe abort points.
+pub fn print_summary() {
+    let reports = reports();
+    println!("cancel-safety summary of {} sweeps: {}", reports.len(), Summary::from_reports(&reports));
+    for report in &reports {
+        let summary = report.summary();
+        if summary.num_unsafe > 0 {
+            println!("  {}: {}", report.name.as_deref().unwrap_or("unnamed sweep"), summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enable, reports, summary};
+    use crate::{ScopedCounter, Sweep};
+    use crate::test_util::enter_repeatedly;
+
+    #[tokio::test]
+    async fn registry_summary() {
+        enable();
+        Sweep::new()
+            .name("registry_summary")
+            .run(ScopedCounter::new, enter_repeatedly, |_| ())
+            .await;
+        let reports = reports();
+        let report = reports.iter().find(|report| report.name.as_deref() == Some("registry_summary")).unwrap();
+        assert_eq!(report.summary().num_unsafe, 0);
+        assert!(summary().num_abort_points >= report.summary().num_abort_points);
+    }
+}
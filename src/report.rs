@@ -0,0 +1,784 @@
+//! Result, report and statistics types of a `Sweep`.
+//!
+//! These types only contain plain data and are independent of the wrappers
+//! which produce them so external tools can depend on them. With the
+//! `serde` feature enabled they implement `Serialize` and `Deserialize`.
+//! The reports are `#[non_exhaustive]` so fields and variants can be
+//! added without breaking those tools.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::harness::{decode_field, encode_field};
+use crate::invariant::InvariantError;
+
+/// Outcome of a single iteration of a `Sweep`.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct PointReport {
+    /// Number of polls the future was allowed to make.
+    pub max_polls: usize,
+    /// `true` if the future completed instead of being aborted.
+    pub completed: bool,
+    /// `true` if the future was dropped without being polled, i.e. at
+    /// abort point `0` or via `abort_before_first_poll`.
+    pub never_polled: bool,
+    /// Message of the failed check or `None` if the check passed.
+    pub failure: Option<String>,
+    /// `true` if the process running the iteration crashed. This can only
+    /// happen when running the sweep in a subprocess.
+    pub crashed: bool,
+    /// `true` if the aborted future was leaked instead of dropped. See
+    /// `DropTiming::Never`.
+    pub leaked: bool,
+    /// Time it took to run the iteration including the cleanup of the
+    /// future and the check.
+    pub elapsed: Duration,
+    /// Last label reached by the future before it was aborted or completed.
+    pub last_label: Option<String>,
+    /// Events recorded while the future was polled and dropped. If the
+    /// process crashed the trace ends with the last event before the
+    /// crash.
+    pub trace: Vec<TraceEvent>,
+    /// Structured errors of the failed invariants. This is empty for
+    /// checks which panic and for iterations run in a subprocess.
+    pub invariant_errors: Vec<InvariantError>,
+    /// Logical async backtrace of the `Labeled` futures (outermost first)
+    /// in which the future was suspended when it was aborted.
+    pub backtrace: Vec<String>,
+    /// Reason the future was aborted for or `None` if it completed. See
+    /// `AbortReason`.
+    pub reason: Option<String>,
+}
+
+impl PointReport {
+    /// Returns `true` if the check passed.
+    pub fn is_safe(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Outcome of the iteration.
+    pub fn outcome(&self) -> Outcome {
+        if self.crashed {
+            Outcome::Crashed
+        } else if self.completed {
+            Outcome::Completed
+        } else if self.never_polled {
+            Outcome::NeverPolled
+        } else {
+            Outcome::Aborted
+        }
+    }
+
+    /// Names of the resources passed to `track` which were not released
+    /// after the future was dropped.
+    pub fn not_dropped(&self) -> Vec<&str> {
+        let mut alive: Vec<&str> = Vec::new();
+        for event in &self.trace {
+            match event {
+                TraceEvent::Tracked(name) => alive.push(name),
+                TraceEvent::Dropped(name) => {
+                    if let Some(index) = alive.iter().rposition(|alive| alive == name) {
+                        alive.remove(index);
+                    }
+                }
+                _ => {}
+            }
+        }
+        alive
+    }
+
+    /// Tags recorded via `tag` before the future was aborted or completed,
+    /// in the order they were first recorded.
+    pub fn tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = Vec::new();
+        for event in &self.trace {
+            match event {
+                TraceEvent::Tag(name) if !tags.contains(&name.as_str()) => tags.push(name),
+                TraceEvent::Aborted | TraceEvent::Completed => break,
+                _ => {}
+            }
+        }
+        tags
+    }
+
+    /// Reactors with which a leaf future registered a waker which was not
+    /// deregistered again. See `InstrumentedLeaf`.
+    pub fn still_registered(&self) -> Vec<&str> {
+        let mut registered: Vec<&str> = Vec::new();
+        for event in &self.trace {
+            match event {
+                TraceEvent::Registered(reactor) => registered.push(reactor),
+                TraceEvent::Deregistered(reactor) => {
+                    if let Some(index) = registered.iter().rposition(|registered| registered == reactor) {
+                        registered.remove(index);
+                    }
+                }
+                _ => {}
+            }
+        }
+        registered
+    }
+
+    /// Human readable description of what happened in this iteration:
+    /// one line per recorded event followed by the tracked resources
+    /// which were not released and the reactors wakers are still
+    /// registered with.
+    pub fn explanation(&self) -> String {
+        let mut explanation = String::new();
+        if self.outcome() == Outcome::NeverPolled {
+            explanation.push_str("never polled\n");
+        }
+        for event in &self.trace {
+            let indent = match event {
+                TraceEvent::Poll(_) | TraceEvent::Aborted | TraceEvent::Completed => "",
+                _ => "  ",
+            };
+            explanation.push_str(&format!("{}{}\n", indent, event));
+        }
+        if !self.backtrace.is_empty() {
+            explanation.push_str("suspended at:\n");
+            for frame in &self.backtrace {
+                explanation.push_str(&format!("  {}\n", frame));
+            }
+        }
+        let not_dropped = self.not_dropped();
+        if !not_dropped.is_empty() {
+            explanation.push_str(&format!("not dropped: {}\n", not_dropped.join(", ")));
+        }
+        let still_registered = self.still_registered();
+        if !still_registered.is_empty() {
+            explanation.push_str(&format!("still registered: {}\n", still_registered.join(", ")));
+        }
+        explanation
+    }
+}
+
+/// Outcome of a single iteration of a `Sweep` regardless of the check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum Outcome {
+    /// The future completed.
+    Completed,
+    /// The future was aborted after at least one poll.
+    Aborted,
+    /// The future was dropped without being polled, i.e. at abort point
+    /// `0` or via `abort_before_first_poll`.
+    NeverPolled,
+    /// The process running the iteration crashed.
+    Crashed,
+}
+
+/// Event recorded during an iteration of a `Sweep`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum TraceEvent {
+    /// The future is polled for the n-th time (starting at `0`).
+    Poll(usize),
+    /// The future reached a `label` or `loop_iter!`.
+    Label(String),
+    /// The waker passed to the future was woken.
+    Wake,
+    /// The waker passed to the future in the given poll was woken after
+    /// a fresh waker was passed in a later poll. See `Sweep::migrate`.
+    StaleWake(usize),
+    /// A fault was injected, e.g. via `fault` or a severed `pipe`.
+    Fault(String),
+    /// The code under test tagged the following abort points via `tag`.
+    Tag(String),
+    /// A resource was acquired via `track`.
+    Tracked(String),
+    /// A resource acquired via `track` was released.
+    Dropped(String),
+    /// A leaf future registered a waker with the named reactor. See
+    /// `InstrumentedLeaf`.
+    Registered(String),
+    /// A leaf future deregistered its waker from the named reactor.
+    Deregistered(String),
+    /// The future was aborted and is about to be dropped.
+    Aborted,
+    /// The future completed and is about to be dropped.
+    Completed,
+    /// The future panicked with the given message and is about to be
+    /// dropped. This ends the trace instead of `Aborted` or `Completed`.
+    Panicked(String),
+}
+
+impl TraceEvent {
+    /// Encode the event as kind and field of the subprocess protocol.
+    pub(crate) fn encode(&self) -> String {
+        let (kind, field) = match self {
+            Self::Poll(n) => ("poll", Some(n.to_string())),
+            Self::Label(name) => ("label", Some(name.clone())),
+            Self::Wake => ("wake", None),
+            Self::StaleWake(n) => ("stale-wake", Some(n.to_string())),
+            Self::Fault(description) => ("fault", Some(description.clone())),
+            Self::Tag(name) => ("tag", Some(name.clone())),
+            Self::Tracked(name) => ("tracked", Some(name.clone())),
+            Self::Dropped(name) => ("dropped", Some(name.clone())),
+            Self::Registered(reactor) => ("registered", Some(reactor.clone())),
+            Self::Deregistered(reactor) => ("deregistered", Some(reactor.clone())),
+            Self::Aborted => ("aborted", None),
+            Self::Completed => ("completed", None),
+            Self::Panicked(message) => ("panicked", Some(message.clone())),
+        };
+        format!("{} {}", kind, encode_field(field.as_deref()))
+    }
+
+    pub(crate) fn decode(kind: &str, field: &str) -> Option<Self> {
+        let field = decode_field(field);
+        Some(match kind {
+            "poll" => Self::Poll(field?.parse().ok()?),
+            "label" => Self::Label(field?),
+            "wake" => Self::Wake,
+            "stale-wake" => Self::StaleWake(field?.parse().ok()?),
+            "fault" => Self::Fault(field?),
+            "tag" => Self::Tag(field?),
+            "tracked" => Self::Tracked(field?),
+            "dropped" => Self::Dropped(field?),
+            "registered" => Self::Registered(field?),
+            "deregistered" => Self::Deregistered(field?),
+            "aborted" => Self::Aborted,
+            "completed" => Self::Completed,
+            "panicked" => Self::Panicked(field?),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Poll(n) => write!(f, "poll {}", n),
+            Self::Label(name) => write!(f, "label {}", name),
+            Self::Wake => write!(f, "wake"),
+            Self::StaleWake(n) => write!(f, "stale wake of poll {}", n),
+            Self::Fault(description) => write!(f, "fault: {}", description),
+            Self::Tag(name) => write!(f, "tag {}", name),
+            Self::Tracked(name) => write!(f, "tracked {}", name),
+            Self::Dropped(name) => write!(f, "dropped {}", name),
+            Self::Registered(reactor) => write!(f, "registered waker with {}", reactor),
+            Self::Deregistered(reactor) => write!(f, "deregistered waker from {}", reactor),
+            Self::Aborted => write!(f, "aborted"),
+            Self::Completed => write!(f, "completed"),
+            Self::Panicked(message) => write!(f, "panicked: {}", message),
+        }
+    }
+}
+
+/// Report of a `Sweep`.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Report {
+    /// Name of the scenario.
+    pub name: Option<String>,
+    /// Maximum number of polls used by the sweep.
+    pub max_polls: usize,
+    /// Number of polls the future needed to complete or `None` if it did
+    /// not complete within `max_polls`.
+    pub num_polls: Option<usize>,
+    /// Number of polls the future was expected to need as set via
+    /// `Sweep::expected_polls`.
+    pub expected_polls: Option<usize>,
+    /// Seed the randomness of the sweep was derived from.
+    pub seed: Option<u64>,
+    /// One entry per iteration. The last entry is the iteration in
+    /// which the future completed.
+    pub points: Vec<PointReport>,
+    /// Abort points which were skipped by `Sweep::report_deduplicated`.
+    pub skipped: Vec<Skipped>,
+    /// Number of polls the future needed in the previous run according
+    /// to the cache. See `Sweep::cache`.
+    pub previous_polls: Option<usize>,
+}
+
+/// Abort points of a `Report` carrying a tag. See `Report::by_tag`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct TagSummary {
+    /// Name of the tag.
+    pub tag: String,
+    /// Number of abort points carrying the tag.
+    pub num_abort_points: usize,
+    /// Number of those abort points for which the check failed.
+    pub num_unsafe: usize,
+}
+
+impl TagSummary {
+    fn new(tag: String) -> Self {
+        Self {
+            tag,
+            num_abort_points: 0,
+            num_unsafe: 0,
+        }
+    }
+}
+
+/// Abort point which was not tested because the state had the same hash
+/// as at an abort point which was already tested.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Skipped {
+    /// The skipped abort point.
+    pub max_polls: usize,
+    /// Tested abort point with the same state hash.
+    pub duplicate_of: usize,
+}
+
+impl Skipped {
+    pub(crate) fn new(max_polls: usize, duplicate_of: usize) -> Self {
+        Self { max_polls, duplicate_of }
+    }
+}
+
+impl Report {
+    /// Iterate over the aborted iterations.
+    pub fn abort_points(&self) -> impl Iterator<Item = &PointReport> {
+        self.points.iter().filter(|point| !point.completed)
+    }
+
+    /// Returns `true` if the future completed and all checks passed.
+    pub fn is_safe(&self) -> bool {
+        self.num_polls.is_some() && self.points.iter().all(PointReport::is_safe)
+    }
+
+    /// Describe how the number of polls changed since the previous run or
+    /// return `None` if it did not change or is unknown. This is meant to
+    /// be informational, e.g. in CI logs.
+    pub fn poll_count_change(&self) -> Option<String> {
+        let (previous, current) = (self.previous_polls?, self.num_polls?);
+        if previous == current {
+            return None;
+        }
+        Some(format!(
+            "{}: {} -> {} polls",
+            self.name.as_deref().unwrap_or("sweep"),
+            previous,
+            current
+        ))
+    }
+
+    /// Number of tested and unsafe abort points per tag recorded via
+    /// `tag`, in the order the tags were first recorded.
+    pub fn by_tag(&self) -> Vec<TagSummary> {
+        let mut summaries: Vec<TagSummary> = Vec::new();
+        for point in self.abort_points() {
+            for tag in point.tags() {
+                let index = match summaries.iter().position(|summary| summary.tag == tag) {
+                    Some(index) => index,
+                    None => {
+                        summaries.push(TagSummary::new(tag.to_string()));
+                        summaries.len() - 1
+                    }
+                };
+                summaries[index].num_abort_points += 1;
+                summaries[index].num_unsafe += !point.is_safe() as usize;
+            }
+        }
+        summaries
+    }
+
+    /// Compute the summary of this report.
+    pub fn summary(&self) -> Summary {
+        Summary::from_reports(Some(self))
+    }
+
+    /// Panic with a descriptive message unless the report `is_safe`.
+    pub fn assert_safe(&self) {
+        if let Some(point) = self.points.iter().find(|point| !point.is_safe()) {
+            let expected = match self.expected_polls {
+                Some(expected_polls) => format!(" of ~{} expected polls", expected_polls),
+                None => String::new(),
+            };
+            panic!(
+                "check failed at abort point {}{}{}: {}\n{}{}\nexplanation:\n{}",
+                point.max_polls,
+                expected,
+                if point.leaked { " (future leaked)" } else { "" },
+                point.failure.as_deref().unwrap_or_default(),
+                self.summary(),
+                self.seed.map_or_else(String::new, |seed| format!("\nseed: {}", seed)),
+                point.explanation()
+            );
+        }
+        if self.num_polls.is_none() {
+            panic!("future did not complete within {} polls", self.max_polls);
+        }
+    }
+
+    /// Single line description of the first failure or `None` if all
+    /// checks passed and the future completed. Unlike `assert_safe` this
+    /// omits the summary and explanation, which keeps panic messages of
+    /// doctests short.
+    pub fn compact_failure(&self) -> Option<String> {
+        if let Some(point) = self.points.iter().find(|point| !point.is_safe()) {
+            let label = match &point.last_label {
+                Some(label) => format!(" after label {:?}", label),
+                None => String::new(),
+            };
+            return Some(format!(
+                "not abort-safe: check failed at abort point {}{}: {}",
+                point.max_polls,
+                label,
+                point.failure.as_deref().unwrap_or_default()
+            ));
+        }
+        match self.num_polls {
+            Some(_) => None,
+            None => Some(format!("future did not complete within {} polls", self.max_polls)),
+        }
+    }
+}
+
+/// Reports of `Sweep::report_timeouts`, one per timeout poll, or of
+/// `Sweep::report_tokio_timeouts`, one per poll before which the clock
+/// was advanced.
+///
+/// The `Display` implementation renders the matrix with one line per
+/// timeout poll and one column per abort point: `.` for a safe abort
+/// point, `C` for the safe completion and `X` for a failed check.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct TimeoutMatrix {
+    /// The row without a timeout firing followed by one row per timeout
+    /// poll.
+    pub rows: Vec<TimeoutRow>,
+}
+
+/// Sweep of the abort points for a single timeout poll.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct TimeoutRow {
+    /// Timeout poll at which the timeout fired or `None` if no timeout
+    /// fired. For `Sweep::report_tokio_timeouts` this is the poll of the
+    /// future before which the clock was advanced.
+    pub fire_at: Option<usize>,
+    /// Report of the sweep.
+    pub report: Report,
+}
+
+impl TimeoutMatrix {
+    pub(crate) fn new(rows: Vec<TimeoutRow>) -> Self {
+        Self { rows }
+    }
+}
+
+impl TimeoutRow {
+    pub(crate) fn new(fire_at: Option<usize>, report: Report) -> Self {
+        Self { fire_at, report }
+    }
+}
+
+impl TimeoutMatrix {
+    /// Returns `true` if all sweeps are safe.
+    pub fn is_safe(&self) -> bool {
+        self.rows.iter().all(|row| row.report.is_safe())
+    }
+
+    /// Panic with a descriptive message unless the matrix `is_safe`.
+    pub fn assert_safe(&self) {
+        for row in &self.rows {
+            if row.report.is_safe() {
+                continue;
+            }
+            let timeout = match row.fire_at {
+                Some(poll) => format!("timeout fired at timeout poll {}", poll),
+                None => "no timeout fired".into(),
+            };
+            let failure = row.report.compact_failure().unwrap_or_default();
+            panic!("{} ({})\n{}", failure, timeout, self);
+        }
+    }
+}
+
+impl fmt::Display for TimeoutMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "timeout poll / abort points")?;
+        for row in &self.rows {
+            let fire_at = row.fire_at.map_or_else(|| "never".into(), |poll| poll.to_string());
+            let cells: String = row
+                .report
+                .points
+                .iter()
+                .map(|point| match (point.is_safe(), point.completed) {
+                    (false, _) => 'X',
+                    (true, true) => 'C',
+                    (true, false) => '.',
+                })
+                .collect();
+            writeln!(f, "{:>5} {}", fire_at, cells)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports of `Sweep::report_spurious_polls`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct SpuriousPolls {
+    /// Number of spurious polls inserted after every pending poll.
+    pub extra_polls: usize,
+    /// Report of the sweep without spurious polls.
+    pub plain: Report,
+    /// Report of the sweep with spurious polls.
+    pub spurious: Report,
+}
+
+impl SpuriousPolls {
+    pub(crate) fn new(extra_polls: usize, plain: Report, spurious: Report) -> Self {
+        Self {
+            extra_polls,
+            plain,
+            spurious,
+        }
+    }
+}
+
+impl SpuriousPolls {
+    /// Abort points at which the check passed in one sweep and failed in
+    /// the other. Such a divergence means the outcome depends on how
+    /// often the future is polled rather than on where it awaits.
+    pub fn sensitive(&self) -> Vec<usize> {
+        self.plain
+            .points
+            .iter()
+            .filter(|point| {
+                self.spurious
+                    .points
+                    .iter()
+                    .find(|spurious| spurious.max_polls == point.max_polls)
+                    .is_some_and(|spurious| spurious.is_safe() != point.is_safe())
+            })
+            .map(|point| point.max_polls)
+            .collect()
+    }
+
+    /// Returns `true` if the sweep without spurious polls is safe and no
+    /// abort point is `sensitive`.
+    pub fn is_safe(&self) -> bool {
+        self.plain.is_safe() && self.sensitive().is_empty()
+    }
+
+    /// Panic with a descriptive message unless the reports are safe.
+    /// Divergent abort points are reported before failed checks.
+    pub fn assert_safe(&self) {
+        if let Some(&max_polls) = self.sensitive().first() {
+            let outcome = |report: &Report| {
+                let point = report.points.iter().find(|point| point.max_polls == max_polls);
+                match point.and_then(|point| point.failure.as_deref()) {
+                    Some(failure) => format!("failed: {}", failure),
+                    None => "passed".into(),
+                }
+            };
+            panic!(
+                "poll-count sensitive at abort point {}: check {} without spurious polls but {} with {} spurious \
+                 polls after every pending poll",
+                max_polls,
+                outcome(&self.plain),
+                outcome(&self.spurious),
+                self.extra_polls
+            );
+        }
+        self.plain.assert_safe();
+    }
+}
+
+/// Range of consecutive unsafe abort points.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Phase {
+    /// Name of the scenario the phase belongs to.
+    pub name: Option<String>,
+    /// First unsafe abort point.
+    pub start: usize,
+    /// Last unsafe abort point (inclusive).
+    pub end: usize,
+}
+
+impl Phase {
+    /// Phase of the scenario `name` starting and ending at `start`.
+    fn new(name: Option<String>, start: usize) -> Self {
+        Self { name, start, end: start }
+    }
+}
+
+/// Summary of one or more sweep reports.
+///
+/// The `Display` implementation renders a single line suitable for PR
+/// comments while `to_json` renders a stable machine readable document.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Summary {
+    /// Number of abort points which were tested.
+    pub num_abort_points: usize,
+    /// Number of abort points for which the check passed.
+    pub num_safe: usize,
+    /// Number of abort points for which the check failed.
+    pub num_unsafe: usize,
+    /// Number of abort points which were skipped because their state
+    /// duplicated a tested abort point.
+    pub num_skipped: usize,
+    /// Percentage of abort points which were tested or skipped as
+    /// duplicates or `None` if
+    /// a future did not complete and the number of abort points is unknown.
+    pub coverage: Option<f64>,
+    /// Longest range of consecutive unsafe abort points.
+    pub worst_phase: Option<Phase>,
+}
+
+impl Summary {
+    /// Version of the schema used by `to_json`.
+    pub const SCHEMA_VERSION: u32 = 2;
+
+    /// Compute the summary of multiple reports, e.g. all scenarios of
+    /// a test campaign.
+    pub fn from_reports<'a>(reports: impl IntoIterator<Item = &'a Report>) -> Self {
+        let mut summary = Self {
+            coverage: Some(100.0),
+            ..Self::default()
+        };
+        let mut total = 0;
+        for report in reports {
+            let mut phase: Option<Phase> = None;
+            for point in report.abort_points() {
+                summary.num_abort_points += 1;
+                if point.is_safe() {
+                    summary.num_safe += 1;
+                    phase = None;
+                    continue;
+                }
+                summary.num_unsafe += 1;
+                let phase = phase.get_or_insert_with(|| Phase::new(report.name.clone(), point.max_polls));
+                phase.end = point.max_polls;
+                let worst = summary.worst_phase.as_ref();
+                if worst.is_none_or(|worst| worst.end - worst.start < phase.end - phase.start) {
+                    summary.worst_phase = Some(phase.clone());
+                }
+            }
+            summary.num_skipped += report.skipped.len();
+            match report.num_polls {
+                Some(num_polls) => total += num_polls,
+                None => summary.coverage = None,
+            }
+        }
+        if let Some(coverage) = summary.coverage.as_mut() {
+            if total > 0 {
+                *coverage = 100.0 * (summary.num_abort_points + summary.num_skipped) as f64 / total as f64;
+            }
+        }
+        summary
+    }
+
+    /// Render the summary as JSON. The schema is stable and versioned
+    /// via the `version` field.
+    pub fn to_json(&self) -> String {
+        let coverage = match self.coverage {
+            Some(coverage) => format!("{:.1}", coverage),
+            None => "null".into(),
+        };
+        let worst_phase = match &self.worst_phase {
+            Some(phase) => format!(
+                "{{\"name\":{},\"start\":{},\"end\":{}}}",
+                phase.name.as_deref().map_or_else(|| "null".into(), json_string),
+                phase.start,
+                phase.end
+            ),
+            None => "null".into(),
+        };
+        format!(
+            "{{\"version\":{},\"abort_points\":{},\"safe\":{},\"unsafe\":{},\"skipped\":{},\"coverage\":{},\"worst_phase\":{}}}",
+            Self::SCHEMA_VERSION,
+            self.num_abort_points,
+            self.num_safe,
+            self.num_unsafe,
+            self.num_skipped,
+            coverage,
+            worst_phase
+        )
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} abort points, {} safe, {} unsafe",
+            self.num_abort_points, self.num_safe, self.num_unsafe
+        )?;
+        if self.num_skipped > 0 {
+            write!(f, ", {} skipped as duplicate states", self.num_skipped)?;
+        }
+        match self.coverage {
+            Some(coverage) => write!(f, ", {:.1}% coverage", coverage)?,
+            None => write!(f, ", unknown coverage")?,
+        }
+        if let Some(phase) = &self.worst_phase {
+            write!(f, ", worst phase: ")?;
+            if let Some(name) = &phase.name {
+                write!(f, "{} ", name)?;
+            }
+            write!(f, "polls {}..={}", phase.start, phase.end)?;
+        }
+        Ok(())
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::Sweep;
+    use crate::test_util::{count_unsafe, Counter};
+
+    #[tokio::test]
+    async fn sweep_summary() {
+        let report = Sweep::new()
+            .name("count")
+            .report(
+                || Counter { count: Cell::new(0), started: Cell::new(0) },
+                count_unsafe,
+                |counter| assert_eq!(counter.count.get(), 0),
+            )
+            .await;
+        assert!(!report.is_safe());
+        let summary = report.summary();
+        assert_eq!(summary.num_abort_points, 3);
+        assert_eq!(summary.num_safe, 1);
+        assert_eq!(summary.num_unsafe, 2);
+        assert_eq!(summary.coverage, Some(100.0));
+        assert_eq!(
+            summary.to_string(),
+            "3 abort points, 1 safe, 2 unsafe, 100.0% coverage, worst phase: count polls 1..=2"
+        );
+        assert_eq!(
+            summary.to_json(),
+            r#"{"version":2,"abort_points":3,"safe":1,"unsafe":2,"skipped":0,"coverage":100.0,"worst_phase":{"name":"count","start":1,"end":2}}"#
+        );
+    }
+}
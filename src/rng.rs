@@ -0,0 +1,320 @@
+//! Seeded randomness shared by all modules of this crate.
+//!
+//! A `Sweep` with a seed (see `Sweep::seed`) derives all randomness from
+//! that single seed. Every feature draws from its own named substream so
+//! enabling one feature does not change the random decisions of another
+//! and one seed reproduces the whole scenario. The substreams restart for
+//! every abort point so every iteration sees the same random decisions.
+//!
+//! The substreams are `Rng`s by default. Any other `RandomSource` can be
+//! plugged in via `RandomSources`, e.g. a `ByteSource` replaying the input
+//! of a fuzzer or, with the `rand` feature, any generator of the `rand`
+//! crate wrapped in `FromRand`.
+//!
+//! ```rust
+//! use futures_test_abort::rng::{ByteSource, RandomSource, RandomSources};
+//!
+//! let input = vec![3, 0, 0, 0, 0, 0, 0, 0];
+//! let sources = RandomSources::new(move |_name| ByteSource::new(input.clone()));
+//! assert_eq!(sources.substream("io").next_u64(), 3);
+//! assert_eq!(sources.substream("io").below(2), 1);
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+
+thread_local! {
+    static STREAMS: RefCell<Option<Streams>> = const { RefCell::new(None) };
+}
+
+/// Small and fast pseudo random number generator (SplitMix64). It is not
+/// suitable for cryptographic purposes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator from a seed.
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Create the generator of the named substream of `seed`.
+    pub fn substream(seed: u64, name: &str) -> Self {
+        // FNV-1a hash of the name mixed into the seed
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in name.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        let mut rng = Self::new(seed ^ hash);
+        Self::new(rng.next_u64())
+    }
+
+    /// Next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in `0..n`. Panics if `n` is `0`.
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0, "empty range");
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Source of random numbers used by the randomized features of this
+/// crate.
+pub trait RandomSource {
+    /// Next random number.
+    fn next_u64(&mut self) -> u64;
+
+    /// Random number in `0..n`. Panics if `n` is `0`.
+    fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0, "empty range");
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+impl RandomSource for Rng {
+    fn next_u64(&mut self) -> u64 {
+        Rng::next_u64(self)
+    }
+}
+
+/// Source replaying the given bytes, e.g. the input of a fuzzer. Every
+/// number is made of the next 8 bytes in little endian order. Missing
+/// bytes are `0`, so an exhausted source always returns `0`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteSource {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl ByteSource {
+    /// Create a source replaying `bytes`.
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self {
+            bytes: bytes.into(),
+            position: 0,
+        }
+    }
+}
+
+impl RandomSource for ByteSource {
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        let rest = self.bytes.get(self.position..).unwrap_or_default();
+        let len = rest.len().min(8);
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.position += len;
+        u64::from_le_bytes(buf)
+    }
+}
+
+/// Adapter using a generator of the `rand` crate as `RandomSource`.
+#[cfg(feature = "rand")]
+#[derive(Clone, Debug)]
+pub struct FromRand<R>(pub R);
+
+#[cfg(feature = "rand")]
+impl<R> RandomSource for FromRand<R>
+where
+    R: rand_core::RngCore,
+{
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+}
+
+type BoxedSource = Box<dyn RandomSource + Send>;
+
+/// Factory of the named substreams. See the module documentation.
+///
+/// The factory is called once per substream and iteration, so it must
+/// return a source in the same state every time to make the iterations
+/// see the same random decisions.
+#[derive(Clone)]
+pub struct RandomSources(Arc<dyn Fn(&str) -> BoxedSource + Send + Sync>);
+
+impl RandomSources {
+    /// Create the factory from a function returning the source of the
+    /// named substream.
+    pub fn new<F, R>(factory: F) -> Self
+    where
+        F: Fn(&str) -> R + Send + Sync + 'static,
+        R: RandomSource + Send + 'static,
+    {
+        Self(Arc::new(move |name| Box::new(factory(name))))
+    }
+
+    /// Factory of the `Rng` substreams of `seed`. This is what a seed
+    /// given to `Sweep::seed` and the like uses.
+    pub fn seeded(seed: u64) -> Self {
+        Self::new(move |name| Rng::substream(seed, name))
+    }
+
+    /// Create the source of the named substream.
+    pub fn substream(&self, name: &str) -> BoxedSource {
+        self.0(name)
+    }
+}
+
+impl fmt::Debug for RandomSources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RandomSources").finish_non_exhaustive()
+    }
+}
+
+/// Substreams of the current iteration.
+pub(crate) struct Streams {
+    sources: RandomSources,
+    streams: Vec<(String, BoxedSource)>,
+}
+
+impl Streams {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self::from_sources(RandomSources::seeded(seed))
+    }
+
+    pub(crate) fn from_sources(sources: RandomSources) -> Self {
+        Self {
+            sources,
+            streams: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for Streams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.streams.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("Streams").field("streams", &names).finish()
+    }
+}
+
+/// Make `streams` the substreams of the current thread while `f` is
+/// running.
+pub(crate) fn enter<R>(streams: &mut Option<Streams>, f: impl FnOnce() -> R) -> R {
+    struct Restore<'a>(&'a mut Option<Streams>, Option<Streams>);
+    impl Drop for Restore<'_> {
+        fn drop(&mut self) {
+            *self.0 = STREAMS.with(|current| current.replace(self.1.take()));
+        }
+    }
+    let previous = STREAMS.with(|current| current.replace(streams.take()));
+    let _restore = Restore(streams, previous);
+    f()
+}
+
+/// Call `f` with the named substream of the current `Sweep` iteration.
+/// Returns `None` if neither a seed nor random sources are set.
+pub fn with_substream<R>(name: &str, f: impl FnOnce(&mut dyn RandomSource) -> R) -> Option<R> {
+    STREAMS.with(|streams| {
+        let mut streams = streams.borrow_mut();
+        let streams = streams.as_mut()?;
+        let index = match streams.streams.iter().position(|(n, _)| n == name) {
+            Some(index) => index,
+            None => {
+                let rng = streams.sources.substream(name);
+                streams.streams.push((name.into(), rng));
+                streams.streams.len() - 1
+            }
+        };
+        Some(f(&mut *streams.streams[index].1))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::{ByteSource, RandomSources, Rng};
+    use crate::{after, pipe_chunked, Cut, Sweep};
+
+    async fn write_chunked(chunks: &RefCell<Vec<usize>>) {
+        let (mut client, _server) = pipe_chunked(Cut::Never, 4);
+        let mut buf = &b"0123456789abcdef"[..];
+        while !buf.is_empty() {
+            let len = client.write(buf).await.unwrap();
+            chunks.borrow_mut().push(len);
+            buf = &buf[len..];
+        }
+    }
+
+    async fn seeded_chunks(seed: u64) -> Vec<Vec<usize>> {
+        let mut chunks = Vec::new();
+        Sweep::new()
+            .seed(seed)
+            .run(RefCell::default, write_chunked, |state| chunks.push(state.borrow().clone()))
+            .await;
+        chunks
+    }
+
+    #[tokio::test]
+    async fn sweep_seed() {
+        let chunks = seeded_chunks(42).await;
+        let last = chunks.last().unwrap();
+        assert_eq!(last.iter().sum::<usize>(), 16);
+        assert!(last.iter().all(|len| (1..=4).contains(len)));
+        // every iteration sees the same random decisions
+        assert!(chunks.iter().all(|c| last.starts_with(c)));
+        assert_eq!(seeded_chunks(42).await, chunks);
+        assert_eq!(Rng::substream(42, "io"), Rng::substream(42, "io"));
+        assert_ne!(Rng::substream(42, "io").next_u64(), Rng::substream(42, "other").next_u64());
+    }
+
+    #[tokio::test]
+    async fn sweep_random_sources() {
+        use crate::executor::{Executor, Order};
+        let input: Vec<u8> = [3u64, 1].iter().flat_map(|n| n.to_le_bytes()).collect();
+        let sources = RandomSources::new(move |_name| ByteSource::new(input.clone()));
+        let mut chunks = Vec::new();
+        Sweep::new()
+            .random_sources(sources.clone())
+            .run(RefCell::default, write_chunked, |state| chunks.push(state.borrow().clone()))
+            .await;
+        // exhausted input draws `0`, i.e. chunks of a single byte
+        assert_eq!(chunks.last().unwrap(), &[4, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        let run = || {
+            let mut executor = Executor::new(Order::Random(7)).random_sources(sources.clone());
+            for _ in 0..3 {
+                executor.spawn(after((), 2));
+            }
+            executor.run().polled
+        };
+        assert_eq!(run()[..2], [0, 1]);
+        assert_eq!(run(), run());
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn rand_adapter() {
+        use super::{FromRand, RandomSource};
+        struct Counter(u64);
+        impl rand_core::RngCore for Counter {
+            fn next_u32(&mut self) -> u32 {
+                self.next_u64() as u32
+            }
+            fn next_u64(&mut self) -> u64 {
+                self.0 += 1;
+                self.0
+            }
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                rand_core::impls::fill_bytes_via_next(self, dest)
+            }
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+                self.fill_bytes(dest);
+                Ok(())
+            }
+        }
+        let mut source = FromRand(Counter(0));
+        assert_eq!(source.next_u64(), 1);
+        assert_eq!(source.below(2), 0);
+    }
+}
@@ -0,0 +1,160 @@
+//! Scoped defaults of the wrappers of this crate.
+//!
+//! Helpers which create `Abort` wrappers deep inside the code under test
+//! usually do not take options. `with_defaults` changes the defaults of
+//! all wrappers created while the wrapped future is polled, so a test can
+//! declare them once instead of passing options through every call.
+//!
+//! ```rust
+//! use futures_test_abort::{abort, with_defaults, Defaults};
+//!
+//! # async fn example() {
+//! let defaults = Defaults {
+//!     count_pending_only: true,
+//!     seed: Some(42),
+//!     ..Defaults::current()
+//! };
+//! with_defaults(defaults, async {
+//!     // This wrapper only counts polls after a wake.
+//!     let _ = abort(async {}, 3).await;
+//! })
+//! .await;
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::future::{AbortReason, Pinned};
+use crate::rng::{self, Streams};
+
+thread_local! {
+    static DEFAULTS: RefCell<Option<Defaults>> = const { RefCell::new(None) };
+}
+
+/// Defaults of the wrappers created inside a `with_defaults` scope.
+///
+/// New defaults are added as fields, so create them with
+/// `..Defaults::current()` or `..Defaults::default()` to stay compatible
+/// with future releases.
+#[derive(Clone, Debug, Default)]
+pub struct Defaults {
+    /// Default of `AbortOpts::count_pending_only`.
+    pub count_pending_only: bool,
+    /// Default of `AbortOpts::grace_polls`.
+    pub grace_polls: usize,
+    /// Default of `AbortOpts::reason`.
+    pub reason: AbortReason,
+    /// Seed of the randomized helpers, e.g. `pipe_chunked`. It replaces
+    /// the seed of the enclosing `Sweep` while the scope is polled. The
+    /// substreams continue across polls of the scope.
+    pub seed: Option<u64>,
+}
+
+impl Defaults {
+    /// Defaults of the innermost enclosing scope or `Defaults::default()`
+    /// outside of any scope.
+    pub fn current() -> Self {
+        DEFAULTS.with(|defaults| defaults.borrow().clone()).unwrap_or_default()
+    }
+}
+
+/// Wrapper which makes its defaults the current ones while the inner
+/// future is polled or dropped.
+pub struct WithDefaults<T> {
+    defaults: Defaults,
+    streams: Option<Streams>,
+    future: ManuallyDrop<Pinned<T>>,
+}
+
+impl<T> Future for WithDefaults<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let future = unsafe { me.future.as_pin_mut() };
+        enter(&me.defaults, &mut me.streams, || future.poll(cx))
+    }
+}
+
+impl<T> Drop for WithDefaults<T> {
+    fn drop(&mut self) {
+        // Cleanup code of the inner future sees the same defaults as the
+        // code running on poll.
+        let future = &mut self.future;
+        // Safety: the inner future is never used again after this.
+        enter(&self.defaults, &mut self.streams, || unsafe { ManuallyDrop::drop(future) });
+    }
+}
+
+/// Make `defaults` the current ones while `f` is running.
+fn enter<R>(defaults: &Defaults, streams: &mut Option<Streams>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Defaults>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            DEFAULTS.with(|defaults| *defaults.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(DEFAULTS.with(|current| current.replace(Some(defaults.clone()))));
+    if streams.is_some() {
+        rng::enter(streams, f)
+    } else {
+        f()
+    }
+}
+
+/// Create a `WithDefaults` future wrapper. All wrappers of this crate
+/// which are created while `future` is polled use `defaults` unless they
+/// are configured explicitly.
+pub fn with_defaults<T>(defaults: Defaults, future: T) -> WithDefaults<T>
+where
+    T: Future,
+{
+    WithDefaults {
+        streams: defaults.seed.map(Streams::new),
+        defaults,
+        future: ManuallyDrop::new(Pinned::new(future)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Defaults;
+    use crate::{abort, never, AbortOpts, AbortReason};
+    use crate::rng::Rng;
+
+    #[tokio::test]
+    async fn with_defaults() {
+        let defaults = Defaults {
+            count_pending_only: true,
+            seed: Some(7),
+            ..Defaults::current()
+        };
+        let draw = || crate::rng::with_substream("test", |rng| rng.next_u64());
+        let scoped = super::with_defaults(defaults, async {
+            assert!(AbortOpts::default().count_pending_only);
+            let nested = Defaults {
+                reason: AbortReason::Timeout,
+                ..Defaults::current()
+            };
+            super::with_defaults(nested, async {
+                let aborted = abort(never(), 0).await.unwrap_err();
+                assert!(matches!(aborted.reason, AbortReason::Timeout));
+                assert!(AbortOpts::default().count_pending_only);
+            })
+            .await;
+            assert!(matches!(AbortOpts::default().reason, AbortReason::Dropped));
+            draw()
+        });
+        assert_eq!(scoped.await, Some(Rng::substream(7, "test").next_u64()));
+        assert!(!AbortOpts::default().count_pending_only);
+        assert_eq!(draw(), None);
+    }
+}
@@ -0,0 +1,245 @@
+//! Wrapper which aborts sinks.
+//!
+//! A sink is driven by several methods and cancellation bugs often hide
+//! between them, e.g. an item accepted by `start_send` which is lost if
+//! the following `poll_flush` never completes. `AbortSink` counts the
+//! calls of all methods and fails the first call after the limit, so every
+//! step of the protocol can be aborted.
+//!
+//! ```rust
+//! use std::future::poll_fn;
+//! use std::pin::Pin;
+//!
+//! use futures_sink::Sink;
+//! use futures_test_abort::sink::{abort_sink, SinkError, SinkMethod};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let mut sink = abort_sink(Vec::new(), 2);
+//! poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx)).await.unwrap();
+//! Pin::new(&mut sink).start_send(1).unwrap();
+//! match poll_fn(|cx| Pin::new(&mut sink).poll_flush(cx)).await {
+//!     Err(SinkError::Aborted(aborted)) => assert_eq!(aborted.method, SinkMethod::PollFlush),
+//!     other => panic!("unexpected result: {:?}", other),
+//! }
+//! assert_eq!(sink.into_inner(), [1]);
+//! # }
+//! ```
+
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_sink::Sink;
+
+use crate::future::{Aborted, Pinned};
+use crate::harness::fault;
+use crate::scope::Defaults;
+
+/// Method of a `Sink`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SinkMethod {
+    /// `Sink::poll_ready`
+    PollReady,
+    /// `Sink::start_send`
+    StartSend,
+    /// `Sink::poll_flush`
+    PollFlush,
+    /// `Sink::poll_close`
+    PollClose,
+}
+
+impl fmt::Display for SinkMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::PollReady => "poll_ready",
+            Self::StartSend => "start_send",
+            Self::PollFlush => "poll_flush",
+            Self::PollClose => "poll_close",
+        })
+    }
+}
+
+/// Abort of an `AbortSink`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SinkAborted {
+    /// Method whose call reached the limit. Calls after the abort report
+    /// the method of the first failed call.
+    pub method: SinkMethod,
+    /// Details of the abort. `Aborted::num_polls` is the number of calls
+    /// which were passed on to the inner sink.
+    pub aborted: Aborted,
+}
+
+impl fmt::Display for SinkAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sink aborted in {} after {} calls", self.method, self.aborted.num_polls)
+    }
+}
+
+impl std::error::Error for SinkAborted {}
+
+/// Error of an `AbortSink`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SinkError<E> {
+    /// The sink was aborted.
+    Aborted(SinkAborted),
+    /// Error of the inner sink.
+    Inner(E),
+}
+
+impl<E> fmt::Display for SinkError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Aborted(aborted) => aborted.fmt(f),
+            Self::Inner(error) => error.fmt(f),
+        }
+    }
+}
+
+impl<E> std::error::Error for SinkError<E> where E: fmt::Debug + fmt::Display {}
+
+/// Wrapper for a `Sink` which limits the calls of its methods.
+///
+/// The calls of `poll_ready`, `start_send`, `poll_flush` and `poll_close`
+/// are counted together. Once the limit is reached every call fails with
+/// `SinkError::Aborted` without reaching the inner sink.
+pub struct AbortSink<T> {
+    num_calls: usize,
+    max_calls: usize,
+    aborted_in: Option<SinkMethod>,
+    sink: Pinned<T>,
+}
+
+impl<T> AbortSink<T> {
+    /// Number of calls passed on to the inner sink.
+    pub fn num_calls(&self) -> usize {
+        self.num_calls
+    }
+
+    /// Method whose call was aborted first or `None` if the limit was
+    /// not reached yet.
+    pub fn aborted_in(&self) -> Option<SinkMethod> {
+        self.aborted_in
+    }
+
+    /// Consume the wrapper and return the inner sink.
+    pub fn into_inner(self) -> T {
+        self.sink.into_inner()
+    }
+
+    /// Count a call of `method` unless the limit is reached. Returns the
+    /// abort otherwise.
+    fn count(self: Pin<&mut Self>, method: SinkMethod) -> Option<SinkAborted> {
+        // Safety: we never move `self.sink`
+        let me = unsafe { self.get_unchecked_mut() };
+        if me.num_calls < me.max_calls {
+            me.num_calls += 1;
+            return None;
+        }
+        let method = *me.aborted_in.get_or_insert_with(|| {
+            fault(format!("sink aborted at {}", method));
+            method
+        });
+        Some(SinkAborted {
+            method,
+            aborted: Aborted {
+                num_polls: me.num_calls,
+                reason: Defaults::current().reason,
+                ..Aborted::default()
+            },
+        })
+    }
+
+    fn sink(self: Pin<&mut Self>) -> Pin<&mut T> {
+        // Safety: we never move `self.sink`
+        unsafe { self.get_unchecked_mut().sink.as_pin_mut() }
+    }
+}
+
+impl<T, Item> Sink<Item> for AbortSink<T>
+where
+    T: Sink<Item>,
+{
+    type Error = SinkError<T::Error>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(aborted) = self.as_mut().count(SinkMethod::PollReady) {
+            return Poll::Ready(Err(SinkError::Aborted(aborted)));
+        }
+        self.sink().poll_ready(cx).map_err(SinkError::Inner)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        if let Some(aborted) = self.as_mut().count(SinkMethod::StartSend) {
+            return Err(SinkError::Aborted(aborted));
+        }
+        self.sink().start_send(item).map_err(SinkError::Inner)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(aborted) = self.as_mut().count(SinkMethod::PollFlush) {
+            return Poll::Ready(Err(SinkError::Aborted(aborted)));
+        }
+        self.sink().poll_flush(cx).map_err(SinkError::Inner)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(aborted) = self.as_mut().count(SinkMethod::PollClose) {
+            return Poll::Ready(Err(SinkError::Aborted(aborted)));
+        }
+        self.sink().poll_close(cx).map_err(SinkError::Inner)
+    }
+}
+
+/// Create an `AbortSink` wrapper which fails all calls after `max_calls`
+/// calls of the sink methods.
+pub fn abort_sink<T>(sink: T, max_calls: usize) -> AbortSink<T> {
+    AbortSink {
+        num_calls: 0,
+        max_calls,
+        aborted_in: None,
+        sink: Pinned::new(sink),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::pin::Pin;
+
+    use super::{abort_sink, SinkError, SinkMethod};
+
+    #[tokio::test]
+    async fn abort_sink_methods() {
+        use futures_sink::Sink;
+
+
+        async fn send<S: Sink<u8> + Unpin>(sink: &mut S, item: u8) -> Result<(), S::Error> {
+            poll_fn(|cx| Pin::new(&mut *sink).poll_ready(cx)).await?;
+            Pin::new(&mut *sink).start_send(item)?;
+            poll_fn(|cx| Pin::new(&mut *sink).poll_flush(cx)).await
+        }
+
+        let methods = [SinkMethod::PollReady, SinkMethod::StartSend, SinkMethod::PollFlush];
+        for (max_calls, method) in (3..6).zip(methods) {
+            let mut sink = abort_sink(Vec::new(), max_calls);
+            send(&mut sink, 1).await.unwrap();
+            let aborted = match send(&mut sink, 2).await {
+                Err(SinkError::Aborted(aborted)) => aborted,
+                other => panic!("unexpected result: {:?}", other),
+            };
+            assert_eq!((aborted.method, aborted.aborted.num_polls), (method, max_calls));
+            assert!(poll_fn(|cx| Pin::new(&mut sink).poll_close(cx)).await.is_err());
+            assert_eq!(sink.aborted_in(), Some(method));
+            let sent = if method == SinkMethod::PollFlush { vec![1, 2] } else { vec![1] };
+            assert_eq!(sink.into_inner(), sent);
+        }
+        let mut sink = abort_sink(Vec::<u8>::new(), 0);
+        let error = poll_fn(|cx| Sink::<u8>::poll_close(Pin::new(&mut sink), cx)).await.unwrap_err();
+        assert_eq!(error.to_string(), "sink aborted in poll_close after 0 calls");
+    }
+}
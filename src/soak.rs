@@ -0,0 +1,407 @@
+//! Soak runs of a mix of scenarios sharing one state.
+//!
+//! Real services rarely fail under a homogeneous workload. Short hot-path
+//! futures interleave with long streaming ones and both are cancelled at
+//! different rates. `Soak` keeps a number of futures running on the
+//! current thread, picks the scenario of every new future by weight and
+//! aborts every scenario at its own rate. The check runs whenever a future
+//! completed or was aborted and a failure is attributed to the mix of
+//! scenarios which was running at that moment.
+//!
+//! ```rust
+//! use std::cell::Cell;
+//!
+//! use futures_test_abort::after;
+//! use futures_test_abort::soak::Soak;
+//!
+//! async fn short(open: &Cell<u32>) {
+//!     open.set(open.get() + 1);
+//!     after((), 1).await;
+//!     open.set(open.get() - 1);
+//! }
+//!
+//! async fn long(open: &Cell<u32>) {
+//!     for _ in 0..10 {
+//!         after((), 1).await;
+//!     }
+//!     let _ = open;
+//! }
+//!
+//! let report = Soak::new()
+//!     .seed(42)
+//!     .scenario("short", 10, 0.1, short)
+//!     .scenario("long", 1, 0.05, long)
+//!     .report(|| Cell::new(0), |open| assert!(open.get() <= 4));
+//! // aborting `short` leaks the counter eventually
+//! assert!(!report.is_safe());
+//! assert!(report.failure.unwrap().mix.contains(&"short"));
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Waker};
+
+use crate::executor::Flag;
+use crate::harness::MakeFuture;
+use crate::invariant::{self, CheckResult};
+use crate::rng::{RandomSource, RandomSources};
+
+/// Factory of the futures of a scenario with the output erased.
+trait Start<S> {
+    fn start<'a>(&mut self, state: &'a S) -> Pin<Box<dyn Future<Output = ()> + 'a>>;
+}
+
+struct Erased<M>(M);
+
+impl<S, M> Start<S> for Erased<M>
+where
+    M: for<'a> MakeFuture<'a, S>,
+{
+    fn start<'a>(&mut self, state: &'a S) -> Pin<Box<dyn Future<Output = ()> + 'a>> {
+        let future = self.0.make(state);
+        Box::pin(async move {
+            future.await;
+        })
+    }
+}
+
+struct Scenario<'s, S> {
+    name: &'static str,
+    weight: usize,
+    abort_rate: f64,
+    make: Box<dyn Start<S> + 's>,
+}
+
+/// Soak run of a weighted mix of scenarios.
+pub struct Soak<'s, S> {
+    seed: u64,
+    random_sources: Option<RandomSources>,
+    iterations: usize,
+    concurrency: usize,
+    max_polls: usize,
+    scenarios: Vec<Scenario<'s, S>>,
+}
+
+/// Number of futures of a scenario by outcome.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScenarioStats {
+    /// Name of the scenario.
+    pub name: &'static str,
+    /// Futures which were started.
+    pub started: usize,
+    /// Futures which completed.
+    pub completed: usize,
+    /// Futures which were aborted.
+    pub aborted: usize,
+}
+
+/// Failed check of a `Soak` run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SoakFailure {
+    /// Number of futures started before the check failed.
+    pub iteration: usize,
+    /// Scenario of the future which finished right before the check.
+    pub scenario: &'static str,
+    /// `true` if that future was aborted instead of completed.
+    pub aborted: bool,
+    /// Scenarios of all futures which were running at that moment
+    /// including the finished one, sorted by name.
+    pub mix: Vec<&'static str>,
+    /// Message of the failed check.
+    pub message: String,
+}
+
+impl fmt::Display for SoakFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "check failed after {} {} in iteration {} with the mix [{}]: {}",
+            if self.aborted { "aborting" } else { "completing" },
+            self.scenario,
+            self.iteration,
+            self.mix.join(", "),
+            self.message
+        )
+    }
+}
+
+/// Result of a `Soak` run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SoakReport {
+    /// Seed the scenario picks and aborts were derived from.
+    pub seed: u64,
+    /// Statistics of every scenario in the order they were added.
+    pub scenarios: Vec<ScenarioStats>,
+    /// First failure. The run stops at the first failure as the state
+    /// may be broken afterwards.
+    pub failure: Option<SoakFailure>,
+}
+
+impl SoakReport {
+    /// Returns `true` if all checks passed.
+    pub fn is_safe(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Panic with the failure and the seed unless `is_safe`.
+    pub fn assert_safe(&self) {
+        if let Some(failure) = &self.failure {
+            panic!("{}\nseed: {}", failure, self.seed);
+        }
+    }
+}
+
+/// Future of a running scenario.
+struct Running<'a> {
+    scenario: usize,
+    future: Pin<Box<dyn Future<Output = ()> + 'a>>,
+    flag: Arc<Flag>,
+    num_polls: usize,
+}
+
+impl<'s, S> Soak<'s, S> {
+    /// Create a soak run of 1000 futures with 4 of them running at a time.
+    pub fn new() -> Self {
+        Self {
+            seed: 0,
+            random_sources: None,
+            iterations: 1000,
+            concurrency: 4,
+            max_polls: 10_000,
+            scenarios: Vec::new(),
+        }
+    }
+
+    /// Set the seed of the scenario picks and aborts.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Draw the scenario picks and aborts from the given sources instead
+    /// of the `Rng` substreams of the seed. The seed is still reported.
+    pub fn random_sources(mut self, random_sources: RandomSources) -> Self {
+        self.random_sources = Some(random_sources);
+        self
+    }
+
+    /// Set the total number of futures to start.
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Set the number of futures running at the same time.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        assert!(concurrency > 0, "at least one future must run");
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Set the number of polls after which a single future which was not
+    /// aborted must be done.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Add a scenario. New futures are picked with a probability
+    /// proportional to `weight` and aborted before every poll with the
+    /// probability `abort_rate`.
+    pub fn scenario<M>(mut self, name: &'static str, weight: usize, abort_rate: f64, make: M) -> Self
+    where
+        M: for<'a> MakeFuture<'a, S> + 's,
+    {
+        self.scenarios.push(Scenario {
+            name,
+            weight,
+            abort_rate,
+            make: Box::new(Erased(make)),
+        });
+        self
+    }
+
+    /// Run the soak and panic if a check failed.
+    pub fn run<Setup, Check, R>(&mut self, setup: Setup, check: Check) -> SoakReport
+    where
+        Setup: FnOnce() -> S,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let report = self.report(setup, check);
+        report.assert_safe();
+        report
+    }
+
+    /// Run the soak and return the report. Futures which are never woken
+    /// again or exceed `max_polls` are reported as failure, too.
+    pub fn report<Setup, Check, R>(&mut self, setup: Setup, mut check: Check) -> SoakReport
+    where
+        Setup: FnOnce() -> S,
+        Check: FnMut(&S) -> R,
+        R: CheckResult,
+    {
+        let total_weight: usize = self.scenarios.iter().map(|scenario| scenario.weight).sum();
+        assert!(total_weight > 0, "no scenario with a weight above zero");
+        let mut report = SoakReport {
+            seed: self.seed,
+            scenarios: self
+                .scenarios
+                .iter()
+                .map(|scenario| ScenarioStats {
+                    name: scenario.name,
+                    ..ScenarioStats::default()
+                })
+                .collect(),
+            failure: None,
+        };
+        let sources = self.random_sources.clone().unwrap_or_else(|| RandomSources::seeded(self.seed));
+        let mut picks = sources.substream("soak-pick");
+        let mut aborts = sources.substream("soak-abort");
+        let state = setup();
+        let mut running: Vec<Running<'_>> = Vec::with_capacity(self.concurrency);
+        let mut started = 0;
+        loop {
+            while running.len() < self.concurrency && started < self.iterations {
+                let mut pick = picks.below(total_weight);
+                let index = self
+                    .scenarios
+                    .iter()
+                    .position(|scenario| {
+                        let hit = pick < scenario.weight;
+                        pick = pick.saturating_sub(scenario.weight);
+                        hit
+                    })
+                    .unwrap();
+                running.push(Running {
+                    scenario: index,
+                    future: self.scenarios[index].make.start(&state),
+                    flag: Arc::new(Flag(AtomicBool::new(true))),
+                    num_polls: 0,
+                });
+                report.scenarios[index].started += 1;
+                started += 1;
+            }
+            if running.is_empty() {
+                return report;
+            }
+            let woken = running.iter().position(|task| task.flag.0.swap(false, Ordering::SeqCst));
+            let Some(i) = woken else {
+                let message = format!("futures of the scenarios [{}] are never woken", self.mix(&running).join(", "));
+                report.failure = Some(self.failure(started, &running, 0, false, message));
+                return report;
+            };
+            // Move the woken future to the back so the others get a turn.
+            let task = running.remove(i);
+            running.push(task);
+            let last = running.len() - 1;
+            let scenario = running[last].scenario;
+            let aborted = unit(&mut *aborts) < self.scenarios[scenario].abort_rate;
+            let done = aborted || {
+                let task = &mut running[last];
+                if task.num_polls == self.max_polls {
+                    let message = format!("future did not complete within {} polls", self.max_polls);
+                    report.failure = Some(self.failure(started, &running, last, false, message));
+                    return report;
+                }
+                task.num_polls += 1;
+                let waker = Waker::from(task.flag.clone());
+                task.future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready()
+            };
+            if !done {
+                continue;
+            }
+            if aborted {
+                report.scenarios[scenario].aborted += 1;
+            } else {
+                report.scenarios[scenario].completed += 1;
+            }
+            let mix = self.mix(&running);
+            running.pop();
+            let (failure, _) = invariant::evaluate(&mut check, &state);
+            if let Some(message) = failure {
+                report.failure = Some(SoakFailure {
+                    iteration: started,
+                    scenario: self.scenarios[scenario].name,
+                    aborted,
+                    mix,
+                    message,
+                });
+                return report;
+            }
+        }
+    }
+
+    fn mix(&self, running: &[Running<'_>]) -> Vec<&'static str> {
+        let mut mix: Vec<&'static str> = running.iter().map(|task| self.scenarios[task.scenario].name).collect();
+        mix.sort_unstable();
+        mix
+    }
+
+    fn failure(
+        &self,
+        iteration: usize,
+        running: &[Running<'_>],
+        index: usize,
+        aborted: bool,
+        message: String,
+    ) -> SoakFailure {
+        SoakFailure {
+            iteration,
+            scenario: self.scenarios[running[index].scenario].name,
+            aborted,
+            mix: self.mix(running),
+            message,
+        }
+    }
+}
+
+impl<S> Default for Soak<'_, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Random number in `0.0..1.0`.
+fn unit(rng: &mut dyn RandomSource) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::Soak;
+    use crate::after;
+    use crate::test_util::{count_to_three, Counter};
+
+    #[test]
+    fn soak_mix() {
+        async fn short(counter: &Counter) {
+            counter.count.set(counter.count.get() + 1);
+            after((), 1).await;
+            counter.count.set(counter.count.get() - 1);
+        }
+        let setup = || Counter { count: Cell::new(0), started: Cell::new(0) };
+        let report = Soak::new()
+            .seed(7)
+            .iterations(200)
+            .scenario("short", 4, 0.0, short)
+            .scenario("long", 1, 0.1, count_to_three)
+            .run(setup, |_| {});
+        assert_eq!(report.scenarios[0].aborted, 0);
+        assert_eq!(report.scenarios.iter().map(|stats| stats.started).sum::<usize>(), 200);
+        assert!(report.scenarios[1].aborted > 0);
+        let report = Soak::new()
+            .seed(7)
+            .scenario("short", 4, 0.2, short)
+            .scenario("long", 1, 0.0, count_to_three)
+            .report(setup, |counter| assert!(counter.count.get() < 10));
+        let failure = report.failure.unwrap();
+        assert!(failure.mix.contains(&"short"));
+        assert!(failure.to_string().contains("with the mix ["));
+    }
+}
@@ -0,0 +1,618 @@
+//! Wrappers which abort streams.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+
+use crate::future::{__loop_iter, label, Aborted, Pinned};
+use crate::harness::{panic_message, MakeFuture};
+
+/// Label reached by `for_each` before waiting for the next item.
+pub const NEXT_LABEL: &str = "fta::stream::next";
+
+/// Label reached by `for_each` before running the loop body.
+pub const BODY_LABEL: &str = "fta::stream::body";
+
+/// Abort of a stream `Abort` or `BatchAbort` wrapper.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StreamAborted {
+    /// Number of items the inner stream yielded before it was aborted.
+    pub num_items: usize,
+    /// Number of polls by which the abort may be late. This is only
+    /// non-zero for `BatchAbort`.
+    pub tolerance: usize,
+    /// Details of the abort.
+    pub aborted: Aborted,
+}
+
+impl fmt::Display for StreamAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stream {} and {} items", self.aborted, self.num_items)?;
+        if self.tolerance > 0 {
+            write!(f, " (up to {} polls late)", self.tolerance)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StreamAborted {}
+
+/// Wrapper for a `Stream` which limits the times it can be polled.
+///
+/// Once the limit is reached the stream yields a single
+/// `Err(StreamAborted)` and ends. It is re-exported as `AbortStream` at
+/// the crate root.
+pub struct Abort<T>
+where
+    T: Stream,
+{
+    num_polls: usize,
+    num_items: usize,
+    max_polls: usize,
+    done: bool,
+    stream: Pinned<T>,
+}
+
+impl<T> Abort<T>
+where
+    T: Stream,
+{
+    /// Number of times the inner stream has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+
+    /// Number of items the inner stream yielded. Once the stream was
+    /// aborted this is the number of items yielded before the abort.
+    pub fn num_items(&self) -> usize {
+        self.num_items
+    }
+}
+
+impl<T> Stream for Abort<T>
+where
+    T: Stream,
+{
+    type Item = Result<T::Item, StreamAborted>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Safety: we never move `self.stream`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            if me.done {
+                return Poll::Ready(None);
+            }
+            if me.num_polls >= me.max_polls {
+                me.done = true;
+                return Poll::Ready(Some(Err(StreamAborted {
+                    num_items: me.num_items,
+                    tolerance: 0,
+                    aborted: Aborted {
+                        num_polls: me.num_polls,
+                        ..Aborted::default()
+                    },
+                })));
+            }
+            me.num_polls += 1;
+            match me.stream.as_pin_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    me.num_items += 1;
+                    Poll::Ready(Some(Ok(item)))
+                }
+                Poll::Ready(None) => {
+                    me.done = true;
+                    Poll::Ready(None)
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    /// The size hint of the inner stream. The lower bound is capped at `1`
+    /// because the stream may be aborted at any time and the upper bound
+    /// includes the `Err(Aborted)` item.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            return (0, Some(0));
+        }
+        let (lower, upper) = self.stream.get_ref().size_hint();
+        (lower.min(1), upper.and_then(|upper| upper.checked_add(1)))
+    }
+}
+
+/// Create a stream `Abort` wrapper which limits the times a stream can be
+/// polled before it yields `Err(StreamAborted)`. It is re-exported as
+/// `abort_stream` at the crate root.
+pub fn abort<T>(stream: T, max_polls: usize) -> Abort<T>
+where
+    T: Stream,
+{
+    Abort {
+        num_polls: 0,
+        num_items: 0,
+        max_polls,
+        done: false,
+        stream: Pinned::new(stream),
+    }
+}
+
+/// Wrapper for a `Stream` which works like `Abort` but only checks the
+/// limit every `batch` polls.
+///
+/// Every poll is still counted. Checking the limit in batches trades
+/// precision for throughput in tests polling streams millions of times:
+/// the stream is aborted at the first multiple of `batch` which is at
+/// least `max_polls`, i.e. up to `batch - 1` polls late. The imprecision
+/// is recorded in `StreamAborted::tolerance`. The limit of `Abort` is a
+/// single compare as well, so both wrappers cost about the same per poll
+/// (see the `poll_next` benches) until the check gets more expensive.
+pub struct BatchAbort<T>
+where
+    T: Stream,
+{
+    num_polls: usize,
+    num_items: usize,
+    max_polls: usize,
+    batch: usize,
+    /// Number of polls at which the limit is checked next.
+    next_check: usize,
+    done: bool,
+    stream: Pinned<T>,
+}
+
+impl<T> BatchAbort<T>
+where
+    T: Stream,
+{
+    /// Number of times the inner stream has been polled.
+    pub fn num_polls(&self) -> usize {
+        self.num_polls
+    }
+
+    /// Number of items the inner stream yielded.
+    pub fn num_items(&self) -> usize {
+        self.num_items
+    }
+}
+
+impl<T> Stream for BatchAbort<T>
+where
+    T: Stream,
+{
+    type Item = Result<T::Item, StreamAborted>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Safety: we never move `self.stream`
+        unsafe {
+            let me = Pin::into_inner_unchecked(self);
+            if me.done {
+                return Poll::Ready(None);
+            }
+            if me.num_polls == me.next_check {
+                me.next_check = me.num_polls.saturating_add(me.batch);
+                if me.num_polls >= me.max_polls {
+                    me.done = true;
+                    return Poll::Ready(Some(Err(StreamAborted {
+                        num_items: me.num_items,
+                        tolerance: me.batch - 1,
+                        aborted: Aborted {
+                            num_polls: me.num_polls,
+                            ..Aborted::default()
+                        },
+                    })));
+                }
+            }
+            me.num_polls += 1;
+            match me.stream.as_pin_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    me.num_items += 1;
+                    Poll::Ready(Some(Ok(item)))
+                }
+                Poll::Ready(None) => {
+                    me.done = true;
+                    Poll::Ready(None)
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            return (0, Some(0));
+        }
+        let (lower, upper) = self.stream.get_ref().size_hint();
+        (lower.min(1), upper.and_then(|upper| upper.checked_add(1)))
+    }
+}
+
+/// Create a `BatchAbort` stream wrapper which checks the limit only every
+/// `batch` polls. Panics if `batch` is `0`.
+pub fn abort_batched<T>(stream: T, max_polls: usize, batch: usize) -> BatchAbort<T>
+where
+    T: Stream,
+{
+    assert!(batch > 0, "batch must not be 0");
+    BatchAbort {
+        num_polls: 0,
+        num_items: 0,
+        max_polls,
+        batch,
+        next_check: 0,
+        done: false,
+        stream: Pinned::new(stream),
+    }
+}
+
+/// Consume a stream like `while let Some(item) = stream.next().await`.
+///
+/// Waiting for the next item is marked as loop iteration `NEXT_LABEL` and
+/// running the body is marked with `BODY_LABEL`. When run in a `Sweep`
+/// the `last_label` of every abort point tells whether the abort hit the
+/// stream or the loop body.
+pub async fn for_each<T, F, Fut>(stream: T, mut body: F)
+where
+    T: Stream,
+    F: FnMut(T::Item) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut stream = pin!(stream);
+    loop {
+        __loop_iter(NEXT_LABEL);
+        let item = match poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+            Some(item) => item,
+            None => break,
+        };
+        label(BODY_LABEL);
+        body(item).await;
+    }
+}
+
+/// Check of the convention that a stream is not polled again after it
+/// yielded `None`.
+///
+/// The `Stream` contract allows a stream to panic or misbehave when it is
+/// polled after `None`. A consumer which is aborted right after receiving
+/// `None` and is then restarted on the same stream easily violates this
+/// convention. `Termination` checks both perspectives: `check_producer`
+/// tells whether a stream tolerates being polled after `None` and
+/// `check_consumer` whether a consumer keeps away from an ended stream when
+/// it is aborted just before or after the terminal item.
+#[derive(Clone, Copy, Debug)]
+pub struct Termination {
+    max_polls: usize,
+}
+
+impl Termination {
+    /// Create a check which polls the stream or consumer at most `1000`
+    /// times.
+    pub fn new() -> Self {
+        Self { max_polls: 1000 }
+    }
+
+    /// Set the number of polls after which the stream or consumer must be
+    /// done.
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Check a stream created by `make` and panic on failure.
+    pub fn run_producer<T, Make>(&self, make: Make)
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+    {
+        if let Err(msg) = self.check_producer(make) {
+            panic!("{}", msg);
+        }
+    }
+
+    /// Check a stream created by `make`.
+    ///
+    /// The stream is polled until it yields `None` and then polled once
+    /// more. Panicking or yielding another item is reported. Then fresh
+    /// streams are dropped just before and after their terminal item which
+    /// must not panic either. Returns a description of the first failure.
+    pub fn check_producer<T, Make>(&self, mut make: Make) -> Result<(), String>
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+    {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut stream = Box::pin(make());
+        let mut last_item = 0;
+        let mut num_polls = 0;
+        loop {
+            if num_polls == self.max_polls {
+                return Err(format!("stream did not end within {} polls", self.max_polls));
+            }
+            num_polls += 1;
+            match stream.as_mut().poll_next(&mut cx) {
+                Poll::Ready(Some(_)) => last_item = num_polls,
+                Poll::Ready(None) => break,
+                Poll::Pending => {}
+            }
+        }
+        let poll_after_end = AssertUnwindSafe(|| matches!(stream.as_mut().poll_next(&mut cx), Poll::Ready(Some(_))));
+        match panic::catch_unwind(poll_after_end) {
+            Ok(true) => return Err(format!("stream yielded an item after None (poll {})", num_polls + 1)),
+            Ok(false) => {}
+            Err(payload) => {
+                let message = panic_message(&*payload);
+                return Err(format!("stream panicked when polled after None (poll {}): {}", num_polls + 1, message));
+            }
+        }
+        for abort_after in last_item.saturating_sub(1)..=num_polls {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut stream = Box::pin(make());
+                for _ in 0..abort_after {
+                    let _ = stream.as_mut().poll_next(&mut cx);
+                }
+            }));
+            if let Err(payload) = result {
+                let message = panic_message(&*payload);
+                return Err(format!("stream panicked when dropped after {} polls: {}", abort_after, message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a consumer of the stream created by `make` and panic on
+    /// failure.
+    pub fn run_consumer<T, Make, Consume>(&self, make: Make, consume: Consume)
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+        Consume: for<'a> MakeFuture<'a, Watched<T>>,
+    {
+        if let Err(msg) = self.check_consumer(make, consume) {
+            panic!("{}", msg);
+        }
+    }
+
+    /// Check a consumer of the stream created by `make`.
+    ///
+    /// The consumer is first run to completion to find the polls at which
+    /// it receives the last item and `None`. Then for every abort point
+    /// from just before the last item until just after `None` the consumer
+    /// is aborted and restarted on the same stream.
+    /// Polling the stream after it yielded `None` is reported. Returns a
+    /// description of the first failure.
+    pub fn check_consumer<T, Make, Consume>(&self, mut make: Make, mut consume: Consume) -> Result<(), String>
+    where
+        T: Stream,
+        Make: FnMut() -> T,
+        Consume: for<'a> MakeFuture<'a, Watched<T>>,
+    {
+        let mut cx = Context::from_waker(Waker::noop());
+        let watched = Watched::new(make());
+        let mut received = Vec::new();
+        {
+            let mut future = pin!(consume.make(&watched));
+            while future.as_mut().poll(&mut cx).is_pending() {
+                if received.len() == self.max_polls {
+                    return Err(format!("consumer did not complete within {} polls", self.max_polls));
+                }
+                received.push((watched.num_items.get(), watched.is_terminated()));
+            }
+            received.push((watched.num_items.get(), watched.is_terminated()));
+        }
+        if watched.polled_after_end.get() {
+            return Err("consumer polled the stream after None".into());
+        }
+        let num_items = watched.num_items.get();
+        let last_item = received.iter().position(|(n, _)| *n == num_items).unwrap_or(0);
+        let end = received.iter().position(|(_, ended)| *ended).unwrap_or(received.len() - 1);
+        for abort_after in last_item..=end + 1 {
+            let watched = Watched::new(make());
+            {
+                let mut future = pin!(consume.make(&watched));
+                if (0..abort_after).any(|_| future.as_mut().poll(&mut cx).is_ready()) {
+                    continue;
+                }
+            }
+            let mut future = pin!(consume.make(&watched));
+            let mut num_polls = 0;
+            while !watched.polled_after_end.get() && future.as_mut().poll(&mut cx).is_pending() {
+                num_polls += 1;
+                if num_polls == self.max_polls {
+                    break;
+                }
+            }
+            if watched.polled_after_end.get() {
+                return Err(format!(
+                    "consumer polled the stream after None when restarted after being aborted after {} polls",
+                    abort_after
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Termination {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stream shared by the consumers checked by `Termination::check_consumer`
+/// which records polls after `None`.
+#[derive(Debug)]
+pub struct Watched<T> {
+    stream: RefCell<Pin<Box<T>>>,
+    num_items: Cell<usize>,
+    terminated: Cell<bool>,
+    polled_after_end: Cell<bool>,
+}
+
+impl<T> Watched<T>
+where
+    T: Stream,
+{
+    fn new(stream: T) -> Self {
+        Self {
+            stream: RefCell::new(Box::pin(stream)),
+            num_items: Cell::new(0),
+            terminated: Cell::new(false),
+            polled_after_end: Cell::new(false),
+        }
+    }
+
+    /// Returns `true` if the stream yielded `None`.
+    pub fn is_terminated(&self) -> bool {
+        self.terminated.get()
+    }
+
+    /// Wait for the next item of the stream. The stream is not polled
+    /// again after it yielded `None` and the poll is recorded as a
+    /// violation.
+    pub async fn next(&self) -> Option<T::Item> {
+        poll_fn(|cx| {
+            if self.terminated.get() {
+                self.polled_after_end.set(true);
+                return Poll::Ready(None);
+            }
+            let item = self.stream.borrow_mut().as_mut().poll_next(cx);
+            match &item {
+                Poll::Ready(Some(_)) => self.num_items.set(self.num_items.get() + 1),
+                Poll::Ready(None) => self.terminated.set(true),
+                Poll::Pending => {}
+            }
+            item
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_core::Stream;
+
+    use super::{abort, abort_batched, for_each, BODY_LABEL, NEXT_LABEL, Termination, Watched};
+    use crate::{after, Sweep};
+    use crate::test_util::{next, Count};
+
+    struct Strict(usize);
+
+    impl Stream for Strict {
+        type Item = usize;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<usize>> {
+            assert!(self.0 <= 2, "polled after None");
+            self.0 += 1;
+            Poll::Ready(Some(self.0).filter(|n| *n <= 2))
+        }
+    }
+
+    async fn drain(stream: &Watched<Count>) {
+        while stream.next().await.is_some() {}
+        after((), 1).await;
+    }
+
+    async fn drain_once(stream: &Watched<Count>) {
+        while !stream.is_terminated() && stream.next().await.is_some() {}
+        after((), 1).await;
+    }
+
+    #[test]
+    fn stream_termination() {
+        let termination = Termination::new();
+        assert_eq!(termination.check_producer(|| Count(0)), Ok(()));
+        let msg = termination.check_producer(|| Strict(0)).unwrap_err();
+        assert_eq!(
+            msg,
+            "stream panicked when polled after None (poll 4): polled after None"
+        );
+        assert_eq!(termination.check_consumer(|| Count(0), drain_once), Ok(()));
+        let msg = termination.check_consumer(|| Count(0), drain).unwrap_err();
+        assert_eq!(
+            msg,
+            "consumer polled the stream after None when restarted after being aborted after 1 polls"
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_abort() {
+        let mut stream = abort(Count(0), 2);
+        assert_eq!(stream.size_hint(), (0, None));
+        assert_eq!(next(&mut stream).await.unwrap().unwrap(), 1);
+        assert_eq!(next(&mut stream).await.unwrap().unwrap(), 2);
+        let aborted = next(&mut stream).await.unwrap().unwrap_err();
+        assert_eq!((aborted.aborted.num_polls, aborted.num_items), (2, 2));
+        assert_eq!(aborted.to_string(), "stream aborted at 2 polls and 2 items");
+        assert!(next(&mut stream).await.is_none());
+        assert_eq!(stream.num_items(), 2);
+        let mut stream = crate::abort_stream(Count(0), 5);
+        assert!(next(&mut stream).await.unwrap().is_ok());
+        assert!(next(&mut stream).await.unwrap().is_ok());
+        assert!(next(&mut stream).await.is_none());
+        assert_eq!((stream.num_polls(), stream.num_items()), (3, 2));
+    }
+
+    struct Forever(usize);
+
+    impl Stream for Forever {
+        type Item = usize;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<usize>> {
+            self.0 += 1;
+            Poll::Ready(Some(self.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_abort_batched() {
+        let mut stream = abort_batched(Forever(0), 5, 4);
+        for n in 1..=8 {
+            assert_eq!(next(&mut stream).await.unwrap().unwrap(), n);
+        }
+        let aborted = next(&mut stream).await.unwrap().unwrap_err();
+        assert_eq!((aborted.aborted.num_polls, aborted.num_items, aborted.tolerance), (8, 8, 3));
+        assert_eq!(aborted.to_string(), "stream aborted at 8 polls and 8 items (up to 3 polls late)");
+        assert!(next(&mut stream).await.is_none());
+    }
+
+    /// Stream which is pending once before every item.
+    struct Slow(usize);
+
+    impl Stream for Slow {
+        type Item = usize;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<usize>> {
+            self.0 += 1;
+            if self.0 % 2 == 1 {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(Some(self.0 / 2).filter(|n| *n <= 2))
+        }
+    }
+
+    async fn sum_slow(sum: &Cell<usize>) {
+        for_each(Slow(0), |n| async move {
+            after((), 1).await;
+            sum.set(sum.get() + n);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stream_for_each() {
+        let report = Sweep::new().report(Cell::default, sum_slow, |_| {}).await;
+        let labels: Vec<_> = report.abort_points().map(|point| point.last_label.as_deref()).collect();
+        let next = Some(NEXT_LABEL);
+        let body = Some(BODY_LABEL);
+        assert_eq!(labels, [None, next, body, next, body, next]);
+    }
+}
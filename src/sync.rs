@@ -0,0 +1,323 @@
+//! Guards and state helpers which make state changes abort-safe.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Guard which passes its value to a cleanup closure when it is dropped.
+///
+/// This replaces the boilerplate guard struct and `Drop` implementation
+/// needed to make state changes abort-safe:
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// use futures_test_abort as fta;
+///
+/// async fn do_something(count: &AtomicUsize) {
+///     let _guard = fta::acquire(
+///         || count.fetch_add(1, Ordering::Relaxed),
+///         |_| {
+///             count.fetch_sub(1, Ordering::Relaxed);
+///         },
+///     );
+///     tokio::task::yield_now().await;
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let count = AtomicUsize::new(0);
+/// fta::abort(do_something(&count), 1).await.unwrap_err();
+/// assert_eq!(count.load(Ordering::Relaxed), 0);
+/// # }
+/// ```
+#[must_use]
+pub struct Guard<T, F>
+where
+    F: FnOnce(T),
+{
+    inner: Option<(T, F)>,
+}
+
+impl<T, F> Guard<T, F>
+where
+    F: FnOnce(T),
+{
+    /// Create a guard which calls `cleanup` with `value` when dropped.
+    pub fn new(value: T, cleanup: F) -> Self {
+        Self {
+            inner: Some((value, cleanup)),
+        }
+    }
+
+    /// Disarm the guard and return the value without calling the cleanup
+    /// closure, e.g. after a state change was committed.
+    pub fn into_inner(mut self) -> T {
+        self.inner.take().unwrap().0
+    }
+}
+
+impl<T, F> Deref for Guard<T, F>
+where
+    F: FnOnce(T),
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.as_ref().unwrap().0
+    }
+}
+
+impl<T, F> DerefMut for Guard<T, F>
+where
+    F: FnOnce(T),
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner.as_mut().unwrap().0
+    }
+}
+
+impl<T, F> Drop for Guard<T, F>
+where
+    F: FnOnce(T),
+{
+    fn drop(&mut self) {
+        if let Some((value, cleanup)) = self.inner.take() {
+            cleanup(value);
+        }
+    }
+}
+
+impl<T, F> fmt::Debug for Guard<T, F>
+where
+    T: fmt::Debug,
+    F: FnOnce(T),
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Guard").field("value", &self.inner.as_ref().map(|inner| &inner.0)).finish()
+    }
+}
+
+/// Call `acquire` and return a `Guard` which passes its result to
+/// `release` when dropped. This covers the increment/decrement,
+/// insert/remove and acquire/release patterns.
+pub fn acquire<T, F>(acquire: impl FnOnce() -> T, release: F) -> Guard<T, F>
+where
+    F: FnOnce(T),
+{
+    Guard::new(acquire(), release)
+}
+
+/// State which can tell whether all scoped changes have been rolled back.
+///
+/// This makes abort-safe state helpers checkable by a `Sweep`, e.g.
+/// `Sweep::new().run(ScopedCounter::new, make, ScopedCounter::assert_settled)`.
+pub trait Settled {
+    /// Returns `true` if no guard of this state is alive.
+    fn is_settled(&self) -> bool;
+
+    /// Panics unless `is_settled` returns `true`.
+    fn assert_settled(&self)
+    where
+        Self: fmt::Debug,
+    {
+        assert!(self.is_settled(), "state not settled: {:?}", self);
+    }
+}
+
+/// Counter whose guards decrement it again when they are dropped.
+#[derive(Debug, Default)]
+pub struct ScopedCounter {
+    count: AtomicUsize,
+}
+
+impl ScopedCounter {
+    /// Create a new counter starting at `0`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current value of the counter.
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Increment the counter and return a guard which decrements it when
+    /// dropped.
+    pub fn enter(&self) -> CounterGuard<'_> {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        CounterGuard { counter: self }
+    }
+}
+
+impl Settled for ScopedCounter {
+    fn is_settled(&self) -> bool {
+        self.get() == 0
+    }
+}
+
+/// Guard returned by `ScopedCounter::enter`.
+#[must_use]
+#[derive(Debug)]
+pub struct CounterGuard<'a> {
+    counter: &'a ScopedCounter,
+}
+
+impl Drop for CounterGuard<'_> {
+    fn drop(&mut self) {
+        self.counter.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Set whose guards remove the inserted value again when they are
+/// dropped.
+#[derive(Debug)]
+pub struct ScopedSet<T> {
+    values: Mutex<HashSet<T>>,
+}
+
+impl<T> ScopedSet<T>
+where
+    T: Clone + Eq + Hash,
+{
+    /// Create a new empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a value and return a guard which removes it when dropped.
+    /// Returns `None` if the value is already present.
+    pub fn insert(&self, value: T) -> Option<SetGuard<'_, T>> {
+        if !self.values.lock().unwrap().insert(value.clone()) {
+            return None;
+        }
+        Some(SetGuard { set: self, value })
+    }
+
+    /// Returns `true` if the set contains the value.
+    pub fn contains(&self, value: &T) -> bool {
+        self.values.lock().unwrap().contains(value)
+    }
+
+    /// Number of values in the set.
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().len()
+    }
+
+    /// Returns `true` if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for ScopedSet<T> {
+    fn default() -> Self {
+        Self {
+            values: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl<T> Settled for ScopedSet<T>
+where
+    T: Clone + Eq + Hash,
+{
+    fn is_settled(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+/// Guard returned by `ScopedSet::insert`.
+#[must_use]
+#[derive(Debug)]
+pub struct SetGuard<'a, T>
+where
+    T: Eq + Hash,
+{
+    set: &'a ScopedSet<T>,
+    value: T,
+}
+
+impl<T> SetGuard<'_, T>
+where
+    T: Eq + Hash,
+{
+    /// The inserted value.
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> Drop for SetGuard<'_, T>
+where
+    T: Eq + Hash,
+{
+    fn drop(&mut self) {
+        self.set.values.lock().unwrap().remove(&self.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{acquire, ScopedCounter, ScopedSet, Settled};
+    use crate::{abort, after, Sweep};
+
+    #[tokio::test]
+    async fn guard_cleanup() {
+        let set = std::sync::Mutex::new(std::collections::HashSet::new());
+        let insert = |key: usize| {
+            acquire(
+                || {
+                    set.lock().unwrap().insert(key);
+                    key
+                },
+                |key| {
+                    set.lock().unwrap().remove(&key);
+                },
+            )
+        };
+        let aborted = abort(
+            async {
+                let _guard = insert(1);
+                after((), 1).await;
+            },
+            1,
+        );
+        assert!(aborted.await.is_err());
+        assert!(set.lock().unwrap().is_empty());
+        let committed = insert(2);
+        assert_eq!(*committed, 2);
+        committed.into_inner();
+        assert!(set.lock().unwrap().contains(&2));
+    }
+
+    async fn enter_and_insert((counter, set): &(ScopedCounter, ScopedSet<&'static str>)) {
+        let _entered = counter.enter();
+        after((), 1).await;
+        let _inserted = set.insert("key").unwrap();
+        after((), 1).await;
+    }
+
+    #[tokio::test]
+    async fn scoped_state_settled() {
+        Sweep::new()
+            .run(
+                || (ScopedCounter::new(), ScopedSet::new()),
+                enter_and_insert,
+                |(counter, set)| {
+                    counter.assert_settled();
+                    set.assert_settled();
+                },
+            )
+            .await;
+        let set = ScopedSet::new();
+        let guard = set.insert(1).unwrap();
+        assert!(set.insert(1).is_none());
+        assert!(!set.is_settled());
+        drop(guard);
+        assert!(set.is_settled());
+    }
+}
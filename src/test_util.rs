@@ -0,0 +1,66 @@
+//! Helpers shared by the tests of several modules.
+
+use std::cell::Cell;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::{after, label, ScopedCounter};
+
+pub(crate) struct Counter {
+    pub(crate) count: Cell<usize>,
+    pub(crate) started: Cell<usize>,
+}
+
+pub(crate) async fn count_to_three(counter: &Counter) {
+    counter.started.set(counter.started.get() + 1);
+    for _ in 0..3 {
+        counter.count.set(counter.count.get() + 1);
+        after((), 1).await;
+    }
+}
+
+pub(crate) async fn count_unsafe(counter: &Counter) {
+    counter.count.set(counter.count.get() + 1);
+    after((), 2).await;
+    counter.count.set(counter.count.get() - 1);
+}
+
+pub(crate) async fn labeled_loop(counter: &Counter) {
+    label("start");
+    after((), 2).await;
+    for _ in 0..3 {
+        crate::loop_iter!("loop");
+        counter.count.set(counter.count.get() + 1);
+        after((), 1).await;
+    }
+}
+
+pub(crate) async fn enter_counter(counter: &ScopedCounter) {
+    let _entered = counter.enter();
+    after((), 1).await;
+}
+
+pub(crate) async fn enter_repeatedly(counter: &ScopedCounter) {
+    for _ in 0..5 {
+        let _entered = counter.enter();
+        after((), 1).await;
+    }
+}
+
+pub(crate) struct Count(pub(crate) usize);
+
+impl Stream for Count {
+    type Item = usize;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<usize>> {
+        self.0 += 1;
+        Poll::Ready(Some(self.0).filter(|n| *n <= 2))
+    }
+}
+
+pub(crate) async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+    poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+}
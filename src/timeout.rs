@@ -0,0 +1,443 @@
+//! Timeouts which fire at a chosen poll instead of after a duration.
+//!
+//! Code which wraps operations in `tokio::time::timeout` has two ways to
+//! be cancelled: its own timeout fires or the caller aborts it. Both run
+//! cleanup code and their interaction is a frequent source of double
+//! cleanup. There are two ways to sweep both dimensions:
+//!
+//! - Code which calls `timeout` of this module is swept by
+//!   `Sweep::report_timeouts`. The timeout fires at every timeout poll in
+//!   turn, counted across all `timeout` calls of the future. Outside of
+//!   that sweep `timeout` is a real timer, so it can be used in place of
+//!   `tokio::time::timeout` in all builds.
+//! - Code which calls `tokio::time::timeout` is swept by
+//!   `Sweep::report_tokio_timeouts` (feature `tokio-time`) on paused tokio
+//!   time. The sweep advances the clock before every poll of the future
+//!   in turn, which fires all timeouts which elapse by then.
+//!
+//! ```rust
+//! use std::cell::Cell;
+//! use std::time::Duration;
+//!
+//! use futures_test_abort::timeout::timeout;
+//! use futures_test_abort::{after, Sweep};
+//!
+//! async fn request(open: &Cell<u32>) {
+//!     open.set(open.get() + 1);
+//!     let _ = timeout(Duration::from_secs(1), after((), 2)).await;
+//!     open.set(open.get() - 1);
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let matrix = Sweep::new()
+//!     .report_timeouts(|| Cell::new(0), request, |open| assert!(open.get() <= 1))
+//!     .await;
+//! matrix.assert_safe();
+//! # }
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+#[cfg(feature = "tokio-time")]
+use std::pin::pin;
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(feature = "tokio-time")]
+use std::task::ready;
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::future::Pinned;
+use crate::harness::{trace_event, MakeFuture};
+use crate::report::TraceEvent;
+
+thread_local! {
+    static BUDGET: RefCell<Option<Budget>> = const { RefCell::new(None) };
+}
+
+/// Timeout polls of the current iteration.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Budget {
+    /// Timeout poll at which the timeout fires.
+    fire_at: Option<usize>,
+    /// Number of polls of all `Timeout` wrappers so far.
+    polls: usize,
+}
+
+/// This error is returned when a `Timeout` fired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed {
+    /// Duration passed to `timeout`.
+    pub duration: Duration,
+    /// Timeout poll at which the timeout fired. Within
+    /// `Sweep::report_timeouts` polls of all `Timeout` wrappers of an
+    /// iteration are counted together, so nested timeouts fire in turn.
+    /// Otherwise this is the poll of this wrapper at which the duration
+    /// had passed.
+    pub poll: usize,
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timeout of {:?} fired at timeout poll {}", self.duration, self.poll)
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Wrapper for a `Future` which fires when a `Sweep` decides so or once
+/// its duration passed. See `timeout`.
+pub struct Timeout<T> {
+    duration: Duration,
+    /// Number of polls of this wrapper outside of a sweep.
+    num_polls: usize,
+    /// Timer started by the first poll outside of a sweep.
+    timer: Option<Timer>,
+    future: Pinned<T>,
+}
+
+/// Timer of a `Timeout` outside of `Sweep::report_timeouts`.
+enum Timer {
+    /// Timer of the tokio runtime, which respects `tokio::time::pause`.
+    #[cfg(feature = "tokio-time")]
+    Tokio(tokio::time::Delay),
+    Thread(ThreadTimer),
+}
+
+impl Timer {
+    fn start(duration: Duration) -> Self {
+        #[cfg(feature = "tokio-time")]
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Timer::Tokio(tokio::time::delay_for(duration));
+        }
+        Timer::Thread(ThreadTimer::start(duration))
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self {
+            #[cfg(feature = "tokio-time")]
+            Timer::Tokio(delay) => Pin::new(delay).poll(cx),
+            Timer::Thread(timer) => timer.poll(cx),
+        }
+    }
+}
+
+/// Thread which wakes the last poll once the duration passed. Dropping
+/// the timer stops the thread and waits for it.
+struct ThreadTimer {
+    shared: Arc<(Mutex<TimerState>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct TimerState {
+    fired: bool,
+    stopped: bool,
+    waker: Option<Waker>,
+}
+
+impl ThreadTimer {
+    fn start(duration: Duration) -> Self {
+        let shared = Arc::new((Mutex::new(TimerState::default()), Condvar::new()));
+        let timer = shared.clone();
+        let thread = thread::spawn(move || {
+            let deadline = Instant::now() + duration;
+            let (state, stop) = &*timer;
+            let mut state = state.lock().unwrap();
+            while !state.stopped {
+                let now = Instant::now();
+                if now >= deadline {
+                    state.fired = true;
+                    let waker = state.waker.take();
+                    drop(state);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                    return;
+                }
+                state = stop.wait_timeout(state, deadline - now).unwrap().0;
+            }
+        });
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.shared.0.lock().unwrap();
+        if state.fired {
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for ThreadTimer {
+    fn drop(&mut self) {
+        self.shared.0.lock().unwrap().stopped = true;
+        self.shared.1.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<T> Future for Timeout<T>
+where
+    T: Future,
+{
+    type Output = Result<T::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fired = BUDGET.with(|budget| {
+            let mut budget = budget.borrow_mut();
+            let budget = budget.as_mut()?;
+            let poll = budget.polls;
+            budget.polls += 1;
+            Some((budget.fire_at == Some(poll)).then_some(poll))
+        });
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        if let Some(Some(poll)) = fired {
+            let elapsed = Elapsed {
+                duration: me.duration,
+                poll,
+            };
+            trace_event(TraceEvent::Fault(elapsed.to_string()));
+            return Poll::Ready(Err(elapsed));
+        }
+        if let Poll::Ready(output) = unsafe { me.future.as_pin_mut() }.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        if fired.is_some() {
+            return Poll::Pending;
+        }
+        let poll = me.num_polls;
+        me.num_polls += 1;
+        let duration = me.duration;
+        match me.timer.get_or_insert_with(|| Timer::start(duration)).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed { duration, poll })),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Create a `Timeout` future wrapper with the signature of
+/// `tokio::time::timeout`. Inside `Sweep::report_timeouts` the timeout
+/// fires at the timeout poll chosen by the sweep and the duration is only
+/// used for reporting. Otherwise it fires once the duration passed: on
+/// the timer of the tokio runtime with the `tokio-time` feature, which
+/// respects `tokio::time::pause`, and on a thread of its own outside of a
+/// tokio runtime or without the feature.
+pub fn timeout<T>(duration: Duration, future: T) -> Timeout<T>
+where
+    T: Future,
+{
+    Timeout {
+        duration,
+        num_polls: 0,
+        timer: None,
+        future: Pinned::new(future),
+    }
+}
+
+/// Wrapper which makes its budget the current one while the inner
+/// future is polled.
+pub(crate) struct Scoped<T> {
+    budget: Option<Budget>,
+    future: Pinned<T>,
+}
+
+impl<T> Scoped<T> {
+    /// Fire the timeout at `fire_at` while `future` is polled.
+    pub(crate) fn new(fire_at: Option<usize>, future: T) -> Self {
+        Self {
+            budget: Some(Budget { fire_at, polls: 0 }),
+            future: Pinned::new(future),
+        }
+    }
+}
+
+impl<T> Future for Scoped<T>
+where
+    T: Future,
+{
+    type Output = (T::Output, usize);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        struct Restore<'a>(&'a mut Option<Budget>, Option<Budget>);
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                *self.0 = BUDGET.with(|current| current.replace(self.1.take()));
+            }
+        }
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        let previous = BUDGET.with(|current| current.replace(me.budget.take()));
+        let restore = Restore(&mut me.budget, previous);
+        let result = unsafe { me.future.as_pin_mut() }.poll(cx);
+        drop(restore);
+        result.map(|output| (output, me.budget.map_or(0, |budget| budget.polls)))
+    }
+}
+
+/// Factory which restarts the timeout polls for every future it creates.
+pub(crate) struct Restart<'m, M>(pub(crate) &'m mut M);
+
+impl<'a, S, M> MakeFuture<'a, S> for Restart<'_, M>
+where
+    M: MakeFuture<'a, S>,
+{
+    type Future = M::Future;
+
+    fn make(&mut self, state: &'a S) -> Self::Future {
+        BUDGET.with(|budget| {
+            if let Some(budget) = budget.borrow_mut().as_mut() {
+                budget.polls = 0;
+            }
+        });
+        self.0.make(state)
+    }
+}
+
+/// Factory of `Sweep::report_tokio_timeouts` which advances the paused
+/// tokio clock by `duration` before poll `advance_at` of every future.
+#[cfg(feature = "tokio-time")]
+pub(crate) struct AdvanceAt<'m, M> {
+    pub(crate) advance_at: Option<usize>,
+    pub(crate) duration: Duration,
+    pub(crate) make: &'m mut M,
+}
+
+#[cfg(feature = "tokio-time")]
+impl<'a, S, M> MakeFuture<'a, S> for AdvanceAt<'_, M>
+where
+    M: MakeFuture<'a, S>,
+{
+    type Future = Advance<M::Future>;
+
+    fn make(&mut self, state: &'a S) -> Self::Future {
+        Advance {
+            advance_at: self.advance_at,
+            duration: self.duration,
+            num_polls: 0,
+            settle: None,
+            future: Pinned::new(self.make.make(state)),
+        }
+    }
+}
+
+/// Future which advances the paused tokio clock before poll `advance_at`
+/// of the inner future.
+#[cfg(feature = "tokio-time")]
+pub(crate) struct Advance<T> {
+    advance_at: Option<usize>,
+    duration: Duration,
+    num_polls: usize,
+    /// Timer which is awaited after advancing the clock. The runtime only
+    /// fires elapsed timers when it parks, so the inner future is polled
+    /// again once this timer fired too.
+    settle: Option<tokio::time::Delay>,
+    future: Pinned<T>,
+}
+
+#[cfg(feature = "tokio-time")]
+impl<T> Future for Advance<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        if me.advance_at == Some(me.num_polls) {
+            me.advance_at = None;
+            // `advance` moves the clock in its first poll and then yields.
+            // The yield must not wake the task or it is polled again before
+            // the runtime parks.
+            let _ = pin!(tokio::time::advance(me.duration)).poll(&mut Context::from_waker(Waker::noop()));
+            me.settle = Some(tokio::time::delay_for(Duration::from_millis(1)));
+        }
+        if let Some(settle) = &mut me.settle {
+            ready!(Pin::new(settle).poll(cx));
+            me.settle = None;
+        }
+        me.num_polls += 1;
+        unsafe { me.future.as_pin_mut() }.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use super::timeout;
+    use crate::{after, never, Sweep};
+
+    async fn cleanup_on_timeout(open: &Cell<i32>) {
+        open.set(open.get() + 1);
+        let guard = crate::sync::Guard::new((), |_| open.set(open.get() - 1));
+        if timeout(Duration::from_secs(1), after((), 2)).await.is_err() {
+            open.set(open.get() - 1);
+        }
+        after((), 1).await;
+        drop(guard);
+    }
+
+    #[tokio::test]
+    async fn sweep_timeouts() {
+        let matrix = Sweep::new()
+            .report_timeouts(Cell::default, cleanup_on_timeout, |open| assert_eq!(open.get(), 0))
+            .await;
+        assert!(!matrix.is_safe());
+        assert_eq!(matrix.rows.len(), 4);
+        assert_eq!(
+            matrix.to_string(),
+            "timeout poll / abort points\nnever ....C\n    0 .XX\n    1 ..XX\n    2 ...XX\n"
+        );
+        let fault = crate::TraceEvent::Fault("timeout of 1s fired at timeout poll 1".into());
+        assert!(matrix.rows[2].report.points[2].trace.contains(&fault));
+    }
+
+    #[tokio::test]
+    async fn timeout_outside_sweep() {
+        let elapsed = timeout(Duration::from_millis(10), never()).await.unwrap_err();
+        assert_eq!(elapsed.duration, Duration::from_millis(10));
+        assert_eq!(timeout(Duration::from_secs(60), after(1, 2)).await, Ok(1));
+    }
+
+    #[cfg(feature = "tokio-time")]
+    async fn cleanup_on_tokio_timeout(open: &Cell<i32>) {
+        open.set(open.get() + 1);
+        let guard = crate::sync::Guard::new((), |_| open.set(open.get() - 1));
+        if tokio::time::timeout(Duration::from_secs(1), after((), 2)).await.is_err() {
+            open.set(open.get() - 1);
+        }
+        after((), 1).await;
+        drop(guard);
+    }
+
+    #[cfg(feature = "tokio-time")]
+    #[tokio::test]
+    async fn sweep_tokio_timeouts() {
+        tokio::time::pause();
+        let matrix = Sweep::new()
+            .report_tokio_timeouts(Duration::from_secs(1), Cell::default, cleanup_on_tokio_timeout, |open| {
+                assert_eq!(open.get(), 0)
+            })
+            .await;
+        assert!(!matrix.is_safe());
+        assert_eq!(matrix.rows.len(), 5);
+        assert_eq!(
+            matrix.to_string(),
+            "timeout poll / abort points\nnever ....C\n    0 ......C\n    1 ....XX\n    2 ......C\n    3 ......C\n"
+        );
+    }
+}
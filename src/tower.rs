@@ -0,0 +1,540 @@
+//! Tower middleware which labels, aborts and checks request futures.
+//!
+//! A `Boundary` service calls `label` with its name whenever a request
+//! enters it and wraps the response future in `Labeled`. Inserting a
+//! boundary between all layers of a stack makes it possible to attribute
+//! abort points to layers via `Aborted::chain` and to abort at a chosen
+//! boundary via `Sweep::abort_after_label`. `LayerStack` builds such a
+//! stack like `tower::ServiceBuilder` does.
+//!
+//! Black-box integration tests, e.g. of an axum app behind a test server,
+//! cannot wrap the handler futures themselves. `Disconnects` provides a
+//! layer which runs registered checks as soon as a response future is
+//! dropped before it completed, i.e. when the client disconnected, and
+//! records the violations for inspection after the server shut down.
+//!
+//! `AbortLayer` simulates such disconnects: it aborts the response future
+//! of every call after a fixed number of polls.
+//!
+//! ```rust
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//!
+//! use futures_test_abort::tower::Disconnects;
+//!
+//! let connections = Arc::new(AtomicUsize::new(0));
+//! let disconnects = Disconnects::new().check_named("connections", {
+//!     let connections = connections.clone();
+//!     move || assert_eq!(connections.load(Ordering::SeqCst), 0)
+//! });
+//! let layer = disconnects.layer();
+//! // Add `layer` to the app, run the test server and disconnect clients.
+//! # drop(layer);
+//! disconnects.assert_safe();
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tower_layer::{Identity, Layer, Stack};
+use tower_service::Service;
+
+use crate::future::{abort, label, labeled, Abort, AbortReason, Labeled, Pinned};
+use crate::invariant::{self, CheckResult, InvariantError};
+
+/// Service which labels requests entering the inner service.
+#[derive(Clone, Debug)]
+pub struct Boundary<S> {
+    name: &'static str,
+    inner: S,
+}
+
+impl<S, Request> Service<Request> for Boundary<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Labeled<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        label(self.name);
+        labeled(self.name, self.inner.call(request))
+    }
+}
+
+/// Layer which wraps services in a `Boundary`.
+#[derive(Clone, Copy, Debug)]
+pub struct BoundaryLayer {
+    name: &'static str,
+}
+
+impl<S> Layer<S> for BoundaryLayer {
+    type Service = Boundary<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Boundary { name: self.name, inner }
+    }
+}
+
+/// Create a `BoundaryLayer` with the given name.
+pub fn boundary(name: &'static str) -> BoundaryLayer {
+    BoundaryLayer { name }
+}
+
+/// Builder of a middleware stack with a `Boundary` in front of every
+/// layer. Layers added first are outermost.
+#[derive(Clone, Debug)]
+pub struct LayerStack<L> {
+    layer: L,
+}
+
+impl LayerStack<Identity> {
+    /// Create an empty stack.
+    pub fn new() -> Self {
+        Self { layer: Identity::new() }
+    }
+}
+
+impl Default for LayerStack<Identity> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L> LayerStack<L> {
+    /// Add a layer behind a boundary with the given name.
+    pub fn layer<T>(self, name: &'static str, layer: T) -> LayerStack<Stack<Stack<T, BoundaryLayer>, L>> {
+        LayerStack {
+            layer: Stack::new(Stack::new(layer, boundary(name)), self.layer),
+        }
+    }
+
+    /// Wrap the service in all layers of the stack.
+    pub fn service<S>(&self, service: S) -> L::Service
+    where
+        L: Layer<S>,
+    {
+        self.layer.layer(service)
+    }
+}
+
+type DisconnectCheck = (String, Box<dyn FnMut() -> Vec<InvariantError> + Send>);
+
+/// Checks run on client disconnects and the disconnects recorded so far.
+///
+/// Clones share the checks and the recorded disconnects, so keep a clone
+/// to inspect them after the server shut down.
+#[derive(Clone, Default)]
+pub struct Disconnects {
+    checks: Arc<Mutex<Vec<DisconnectCheck>>>,
+    recorded: Arc<Mutex<Vec<Disconnect>>>,
+}
+
+/// Response future dropped before it completed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Disconnect {
+    /// Number of times the response future was polled.
+    pub num_polls: usize,
+    /// Message of the failed checks, if any.
+    pub failure: Option<String>,
+    /// Failed invariants of checks returning `InvariantError`s.
+    pub invariant_errors: Vec<InvariantError>,
+}
+
+impl Disconnect {
+    /// Returns `true` if all checks passed.
+    pub fn is_safe(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl Disconnects {
+    /// Create an empty set of checks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a named check. The name is added to its errors.
+    pub fn check_named<R>(self, name: impl Into<String>, mut check: impl FnMut() -> R + Send + 'static) -> Self
+    where
+        R: CheckResult,
+    {
+        let check = Box::new(move || check().into_errors());
+        self.checks.lock().unwrap_or_else(|e| e.into_inner()).push((name.into(), check));
+        self
+    }
+
+    /// Create a `DisconnectLayer` sharing the checks and the recorded
+    /// disconnects.
+    pub fn layer(&self) -> DisconnectLayer {
+        DisconnectLayer {
+            disconnects: self.clone(),
+        }
+    }
+
+    /// All disconnects recorded so far.
+    pub fn recorded(&self) -> Vec<Disconnect> {
+        self.recorded.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Disconnects for which a check failed.
+    pub fn violations(&self) -> Vec<Disconnect> {
+        self.recorded().into_iter().filter(|disconnect| !disconnect.is_safe()).collect()
+    }
+
+    /// Returns `true` if no check failed.
+    pub fn is_safe(&self) -> bool {
+        self.violations().is_empty()
+    }
+
+    /// Panic with the first violation unless `is_safe`.
+    pub fn assert_safe(&self) {
+        let violations = self.violations();
+        if let Some(violation) = violations.first() {
+            panic!(
+                "check failed after a client disconnected at poll {}: {} ({} violations)",
+                violation.num_polls,
+                violation.failure.as_deref().unwrap_or_default(),
+                violations.len()
+            );
+        }
+    }
+
+    /// Run all checks and record the disconnect.
+    fn record(&self, num_polls: usize) {
+        let mut check = |_: &()| {
+            let mut errors = Vec::new();
+            for (name, check) in self.checks.lock().unwrap_or_else(|e| e.into_inner()).iter_mut() {
+                for mut error in check() {
+                    error.name = Some(name.clone());
+                    errors.push(error);
+                }
+            }
+            Err::<(), _>(errors)
+        };
+        let (failure, invariant_errors) = invariant::evaluate(&mut check, &());
+        self.recorded.lock().unwrap_or_else(|e| e.into_inner()).push(Disconnect {
+            num_polls,
+            failure,
+            invariant_errors,
+        });
+    }
+}
+
+impl fmt::Debug for Disconnects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checks = self.checks.lock().unwrap_or_else(|e| e.into_inner());
+        let names: Vec<&str> = checks.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("Disconnects")
+            .field("checks", &names)
+            .field("recorded", &self.recorded.lock().unwrap_or_else(|e| e.into_inner()).len())
+            .finish()
+    }
+}
+
+/// Layer which wraps services in a `DisconnectService`.
+#[derive(Clone, Debug)]
+pub struct DisconnectLayer {
+    disconnects: Disconnects,
+}
+
+impl<S> Layer<S> for DisconnectLayer {
+    type Service = DisconnectService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DisconnectService {
+            disconnects: self.disconnects.clone(),
+            inner,
+        }
+    }
+}
+
+/// Service which runs the checks of its `Disconnects` when a response
+/// future is dropped before it completed.
+#[derive(Clone, Debug)]
+pub struct DisconnectService<S> {
+    disconnects: Disconnects,
+    inner: S,
+}
+
+impl<S, Request> Service<Request> for DisconnectService<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = OnDisconnect<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        OnDisconnect {
+            disconnects: self.disconnects.clone(),
+            num_polls: 0,
+            completed: false,
+            future: ManuallyDrop::new(Pinned::new(self.inner.call(request))),
+        }
+    }
+}
+
+/// Response future of a `DisconnectService`.
+pub struct OnDisconnect<T> {
+    disconnects: Disconnects,
+    num_polls: usize,
+    completed: bool,
+    future: ManuallyDrop<Pinned<T>>,
+}
+
+impl<T> Future for OnDisconnect<T>
+where
+    T: Future,
+{
+    type Output = T::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let me = unsafe { Pin::into_inner_unchecked(self) };
+        me.num_polls += 1;
+        let result = unsafe { me.future.as_pin_mut() }.poll(cx);
+        me.completed = result.is_ready();
+        result
+    }
+}
+
+impl<T> Drop for OnDisconnect<T> {
+    fn drop(&mut self) {
+        // The checks must see the state after the cleanup of the response
+        // future.
+        // Safety: the inner future is never used again after this.
+        unsafe { ManuallyDrop::drop(&mut self.future) };
+        if !self.completed {
+            self.disconnects.record(self.num_polls);
+        }
+    }
+}
+
+/// Error type of an `AbortService` like `tower::BoxError`. It holds the
+/// `Aborted` error if the response future was aborted.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Layer which wraps services in an `AbortService`.
+#[derive(Clone, Debug)]
+pub struct AbortLayer {
+    max_polls: usize,
+    reason: AbortReason,
+}
+
+impl AbortLayer {
+    /// Abort the response future of every call after `max_polls` polls
+    /// with `AbortReason::ClientDisconnect`.
+    pub fn at_poll(max_polls: usize) -> Self {
+        Self {
+            max_polls,
+            reason: AbortReason::ClientDisconnect,
+        }
+    }
+
+    /// Set the reason the response futures are aborted for.
+    pub fn with_reason(mut self, reason: AbortReason) -> Self {
+        self.reason = reason;
+        self
+    }
+}
+
+impl<S> Layer<S> for AbortLayer {
+    type Service = AbortService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AbortService {
+            max_polls: self.max_polls,
+            reason: self.reason.clone(),
+            inner,
+        }
+    }
+}
+
+/// Service which aborts the response future of every call after a number
+/// of polls. See `AbortLayer`.
+#[derive(Clone, Debug)]
+pub struct AbortService<S> {
+    max_polls: usize,
+    reason: AbortReason,
+    inner: S,
+}
+
+impl<S, Request> Service<Request> for AbortService<S>
+where
+    S: Service<Request>,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = AbortResponse<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let future = abort(self.inner.call(request), self.max_polls).with_reason(self.reason.clone());
+        AbortResponse {
+            future: Pinned::new(future),
+        }
+    }
+}
+
+/// Response future of an `AbortService`.
+pub struct AbortResponse<T>
+where
+    T: Future,
+{
+    future: Pinned<Abort<T>>,
+}
+
+impl<T, Response, Error> Future for AbortResponse<T>
+where
+    T: Future<Output = Result<Response, Error>>,
+    Error: Into<BoxError>,
+{
+    type Output = Result<Response, BoxError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: we never move `self.future`
+        let future = unsafe { self.get_unchecked_mut().future.as_pin_mut() };
+        future.poll(cx).map(|result| match result {
+            Ok(response) => response.map_err(Into::into),
+            Err(aborted) => Err(aborted.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use super::{AbortLayer, Disconnects, LayerStack};
+    use crate::{abort, after, AbortReason, InvariantError, Sweep};
+
+    /// Middleware which yields once before calling the inner service.
+    struct Yield<S>(S);
+
+    impl<S> tower_service::Service<u32> for Yield<S>
+    where
+        S: tower_service::Service<u32, Response = u32, Error = ()> + 'static,
+    {
+        type Response = u32;
+        type Error = ();
+        type Future = Pin<Box<dyn Future<Output = Result<u32, ()>>>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: u32) -> Self::Future {
+            let response = self.0.call(request);
+            Box::pin(async move {
+                after((), 1).await;
+                response.await
+            })
+        }
+    }
+
+    struct Echo;
+
+    impl tower_service::Service<u32> for Echo {
+        type Response = u32;
+        type Error = ();
+        type Future = crate::After<Result<u32, ()>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: u32) -> Self::Future {
+            after(Ok(request), 1)
+        }
+    }
+
+    async fn call_stack(_: &()) -> Result<u32, ()> {
+        use tower_service::Service;
+        let stack = LayerStack::new()
+            .layer("outer", tower_layer::layer_fn(Yield))
+            .layer("inner", tower_layer::layer_fn(Yield));
+        stack.service(Echo).call(7).await
+    }
+
+    #[tokio::test]
+    async fn tower_boundaries() {
+        let chain = |aborted: crate::Aborted| aborted.chain.iter().map(|s| s.label).collect::<Vec<_>>();
+        assert_eq!(chain(abort(call_stack(&()), 1).await.unwrap_err()), ["outer"]);
+        assert_eq!(chain(abort(call_stack(&()), 2).await.unwrap_err()), ["outer", "inner"]);
+        let report = Sweep::new().abort_after_label("inner").run(|| (), call_stack, |_| {}).await;
+        let points: Vec<_> = report.abort_points().map(|point| point.max_polls).collect();
+        assert_eq!(points, [1]);
+    }
+
+    #[tokio::test]
+    async fn tower_abort_layer() {
+        use tower_layer::Layer;
+        use tower_service::Service;
+        struct Slow;
+        impl Service<u32> for Slow {
+            type Response = u32;
+            type Error = std::convert::Infallible;
+            type Future = crate::After<Result<u32, Self::Error>>;
+
+            fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, request: u32) -> Self::Future {
+                after(Ok(request), 2)
+            }
+        }
+        let error = AbortLayer::at_poll(2).layer(Slow).call(1).await.unwrap_err();
+        let aborted = error.downcast::<crate::Aborted>().unwrap();
+        assert_eq!(aborted.num_polls, 2);
+        assert!(matches!(aborted.reason, AbortReason::ClientDisconnect));
+        assert_eq!(AbortLayer::at_poll(3).layer(Slow).call(1).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn tower_disconnects() {
+        use tower_layer::Layer;
+        use tower_service::Service;
+        let open = Arc::new(AtomicUsize::new(0));
+        let disconnects = Disconnects::new().check_named("open", {
+            let open = open.clone();
+            move || match open.load(Ordering::SeqCst) {
+                0 => Ok(()),
+                n => Err(InvariantError::new("connection left open").with("open", n)),
+            }
+        });
+        let mut service = disconnects.layer().layer(Yield(Echo));
+        assert_eq!(service.call(1).await, Ok(1));
+        assert!(abort(service.call(2), 1).await.is_err());
+        open.store(1, Ordering::SeqCst);
+        assert!(abort(service.call(3), 2).await.is_err());
+        let recorded = disconnects.recorded();
+        assert_eq!(recorded.len(), 2);
+        assert!(recorded[0].is_safe());
+        assert_eq!(recorded[1].num_polls, 2);
+        assert_eq!(recorded[1].failure.as_deref(), Some("open: connection left open {open: 1}"));
+        assert!(!disconnects.is_safe());
+    }
+}
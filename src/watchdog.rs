@@ -0,0 +1,167 @@
+//! Watchdog thread reporting polls which take too long.
+//!
+//! A poll which never returns, e.g. because of a blocking call or an
+//! endless loop, freezes the whole test without any output. While a
+//! `Sweep` with `Sweep::watchdog` polls the future a separate thread
+//! watches the poll and prints the abort point, the elapsed time and the
+//! events recorded so far, including the labels reached, once the poll
+//! exceeds the limit. The stack of the wedged thread cannot be captured
+//! with the standard library, so the labels stand in for it.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::harness::Trace;
+
+/// Poll which is currently running.
+struct InFlight {
+    name: Option<String>,
+    max_polls: usize,
+    poll: usize,
+    started: Instant,
+    trace: Trace,
+    reported: bool,
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: Option<InFlight>,
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Handle of a running watchdog thread. The thread stops when the handle
+/// is dropped.
+pub(crate) struct Watchdog {
+    limit: Duration,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Start a watchdog reporting polls which take longer than `limit`.
+    pub(crate) fn start(limit: Duration) -> Self {
+        let shared = Arc::new(Shared::default());
+        let thread = thread::Builder::new()
+            .name("fta-watchdog".into())
+            .spawn({
+                let shared = shared.clone();
+                move || watch(&shared, limit)
+            })
+            .expect("failed to spawn the watchdog thread");
+        Self {
+            limit,
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Start watching a poll.
+    pub(crate) fn enter(&self, name: Option<&str>, max_polls: usize, poll: usize, trace: &Trace) {
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight = Some(InFlight {
+            name: name.map(Into::into),
+            max_polls,
+            poll,
+            started: Instant::now(),
+            trace: trace.clone(),
+            reported: false,
+        });
+        self.shared.changed.notify_one();
+    }
+
+    /// Stop watching the current poll. Returns a failure message if the
+    /// poll exceeded the limit.
+    pub(crate) fn leave(&self) -> Option<String> {
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        let in_flight = state.in_flight.take()?;
+        let elapsed = in_flight.started.elapsed();
+        (elapsed >= self.limit).then(|| {
+            format!(
+                "poll {} took {:?}, more than the watchdog limit of {:?}",
+                in_flight.poll, elapsed, self.limit
+            )
+        })
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner()).stopped = true;
+        self.shared.changed.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(shared: &Shared, limit: Duration) {
+    let mut state = shared.state.lock().unwrap_or_else(|e| e.into_inner());
+    while !state.stopped {
+        let timeout = match &mut state.in_flight {
+            Some(in_flight) if !in_flight.reported => {
+                let elapsed = in_flight.started.elapsed();
+                if elapsed >= limit {
+                    in_flight.reported = true;
+                    eprintln!("{}", diagnostics(in_flight, elapsed));
+                    None
+                } else {
+                    Some(limit - elapsed)
+                }
+            }
+            _ => None,
+        };
+        state = match timeout {
+            Some(timeout) => shared.changed.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner()).0,
+            None => shared.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+}
+
+fn diagnostics(in_flight: &InFlight, elapsed: Duration) -> String {
+    let mut message = format!(
+        "fta watchdog: poll {} of {} at abort point {} is running for {:?}\n",
+        in_flight.poll,
+        in_flight.name.as_deref().unwrap_or("unnamed sweep"),
+        in_flight.max_polls,
+        elapsed
+    );
+    // The wedged thread may hold the lock of the trace forever.
+    match in_flight.trace.try_lock() {
+        Ok(trace) => {
+            message.push_str("events so far:\n");
+            for event in trace.iter() {
+                message.push_str(&format!("  {}\n", event));
+            }
+        }
+        Err(_) => message.push_str("events so far: unavailable\n"),
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{after, label, Sweep};
+
+    #[tokio::test]
+    async fn sweep_watchdog() {
+        async fn wedged(_: &()) {
+            label("before");
+            after((), 1).await;
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let report = Sweep::new().watchdog(Duration::from_millis(10)).report(|| (), wedged, |_| {}).await;
+        let failure = report.points[2].failure.as_deref().unwrap();
+        assert!(failure.starts_with("poll 1 took "), "{}", failure);
+        assert!(failure.ends_with("more than the watchdog limit of 10ms"));
+        assert!(report.points[1].is_safe());
+    }
+}